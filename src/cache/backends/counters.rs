@@ -0,0 +1,99 @@
+use crate::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Counts what happens to each cached collection, for [`FirestoreCacheCollectionMetrics`].
+///
+/// Shared by the cache backends. The collections are known upfront, so counting does not lock.
+pub(crate) struct FirestoreCacheCounters {
+    collections: HashMap<String, Arc<FirestoreCacheCollectionCounters>>,
+}
+
+#[derive(Default)]
+pub(crate) struct FirestoreCacheCollectionCounters {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub query_hits: AtomicU64,
+    pub query_fallbacks: AtomicU64,
+    pub listener_updates: AtomicU64,
+    pub listener_removals: AtomicU64,
+    pub evictions_expired: AtomicU64,
+    pub evictions_size: AtomicU64,
+}
+
+impl FirestoreCacheCollectionCounters {
+    #[inline]
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl FirestoreCacheCounters {
+    pub fn new(config: &FirestoreCacheConfiguration) -> Self {
+        Self {
+            collections: config
+                .collections
+                .keys()
+                .map(|collection_path| (collection_path.clone(), Arc::default()))
+                .collect(),
+        }
+    }
+
+    /// Returns the counters of a collection, to share them with the collection's cache.
+    #[cfg(feature = "caching-memory")]
+    pub fn collection(
+        &self,
+        collection_path: &str,
+    ) -> Option<Arc<FirestoreCacheCollectionCounters>> {
+        self.collections.get(collection_path).cloned()
+    }
+
+    fn record<F>(&self, collection_path: &str, counter: F)
+    where
+        F: FnOnce(&FirestoreCacheCollectionCounters) -> &AtomicU64,
+    {
+        if let Some(counters) = self.collections.get(collection_path) {
+            FirestoreCacheCollectionCounters::increment(counter(counters));
+        }
+    }
+
+    pub fn doc_read<T>(&self, collection_path: &str, cached: &Option<T>) {
+        self.record(collection_path, |counters| match cached {
+            Some(_) => &counters.hits,
+            None => &counters.misses,
+        })
+    }
+
+    pub fn query_served<T>(&self, collection_path: &str, cached: &FirestoreCachedValue<T>) {
+        self.record(collection_path, |counters| match cached {
+            FirestoreCachedValue::UseCached(_) => &counters.query_hits,
+            FirestoreCachedValue::SkipCache => &counters.query_fallbacks,
+        })
+    }
+
+    pub fn listener_update(&self, collection_path: &str) {
+        self.record(collection_path, |counters| &counters.listener_updates)
+    }
+
+    pub fn listener_removal(&self, collection_path: &str) {
+        self.record(collection_path, |counters| &counters.listener_removals)
+    }
+
+    pub fn snapshot(&self) -> Vec<FirestoreCacheCollectionMetrics> {
+        self.collections
+            .iter()
+            .map(|(collection_path, counters)| {
+                FirestoreCacheCollectionMetrics::new(collection_path.clone())
+                    .with_hits(counters.hits.load(Ordering::Relaxed))
+                    .with_misses(counters.misses.load(Ordering::Relaxed))
+                    .with_query_hits(counters.query_hits.load(Ordering::Relaxed))
+                    .with_query_fallbacks(counters.query_fallbacks.load(Ordering::Relaxed))
+                    .with_listener_updates(counters.listener_updates.load(Ordering::Relaxed))
+                    .with_listener_removals(counters.listener_removals.load(Ordering::Relaxed))
+                    .with_evictions_expired(counters.evictions_expired.load(Ordering::Relaxed))
+                    .with_evictions_size(counters.evictions_size.load(Ordering::Relaxed))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,719 @@
+use crate::cache::cache_filter_engine::compare_firestore_values;
+use crate::cache::cache_query_engine::{ordered_fields, DOCUMENT_NAME_FIELD};
+use crate::errors::{FirestoreCacheError, FirestoreErrorPublicGenericDetails};
+use crate::*;
+use gcloud_sdk::google::firestore::v1::value::ValueType;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::RwLock;
+
+/// The secondary indexes of the cached collections, as configured with
+/// [`FirestoreCacheCollectionConfiguration::with_index`].
+///
+/// Shared by the cache backends, which keep the documents themselves. The indexes map the values
+/// of the indexed fields to the keys of the documents in their cached collection (see
+/// [`FirestoreCacheConfiguration::cached_document_key`]) and are only ever used to narrow down and
+/// order the candidates of a query: the query filter is still applied to every candidate.
+pub(crate) struct FirestoreCacheIndexes {
+    collections: HashMap<String, RwLock<Vec<FirestoreCacheIndex>>>,
+}
+
+/// The documents found with an index, in the index order.
+pub(crate) struct FirestoreCacheIndexLookup {
+    pub document_ids: Vec<String>,
+    /// The documents are already in the order the query asks for, so they do not need sorting.
+    pub ordered: bool,
+}
+
+impl FirestoreCacheIndexes {
+    pub fn new(config: &FirestoreCacheConfiguration) -> Self {
+        let collections = config
+            .collections
+            .iter()
+            .filter_map(|(collection_path, collection_config)| {
+                let indexes: Vec<FirestoreCacheIndex> = collection_config
+                    .indices
+                    .iter()
+                    .filter(|index_config| index_config.validate().is_ok())
+                    .map(|index_config| FirestoreCacheIndex::new(index_config.clone()))
+                    .collect();
+                (!indexes.is_empty()).then(|| (collection_path.clone(), RwLock::new(indexes)))
+            })
+            .collect();
+        Self { collections }
+    }
+
+    /// Indexes a document under its key in the cached collection, replacing its previous entries.
+    ///
+    /// Fails without indexing the document when it has the same values in the fields of a unique
+    /// index as another document for which `is_cached` returns `true`. Conflicting entries of
+    /// documents that are not cached anymore are dropped instead.
+    pub fn insert_doc<F>(
+        &self,
+        collection_path: &str,
+        document_id: &str,
+        doc: &FirestoreDocument,
+        is_cached: F,
+    ) -> FirestoreResult<()>
+    where
+        F: Fn(&str) -> bool,
+    {
+        if let Some(indexes) = self.collections.get(collection_path) {
+            let mut indexes = indexes
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let keys: Vec<Option<FirestoreCacheIndexKey>> =
+                indexes.iter().map(|index| index.key_of(doc)).collect();
+
+            for (index, key) in indexes.iter_mut().zip(&keys) {
+                if let Some(key) = key {
+                    for conflicting_id in index.conflicting_doc_ids(document_id, key) {
+                        if is_cached(&conflicting_id) {
+                            return Err(unique_index_violation_error(
+                                doc,
+                                &index.config,
+                                &conflicting_id,
+                            ));
+                        }
+                        index.remove(&conflicting_id);
+                    }
+                }
+            }
+
+            for (index, key) in indexes.iter_mut().zip(keys) {
+                index.insert(document_id, key);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove_doc(&self, collection_path: &str, document_id: &str) {
+        self.with_indexes(collection_path, |indexes| {
+            for index in indexes.iter_mut() {
+                index.remove(document_id);
+            }
+        })
+    }
+
+    /// Removes the documents of the collection whose IDs are not in `retained_doc_ids`, or all of
+    /// them when it is `None`.
+    pub fn retain_docs(&self, collection_path: &str, retained_doc_ids: Option<&HashSet<String>>) {
+        self.with_indexes(collection_path, |indexes| {
+            for index in indexes.iter_mut() {
+                match retained_doc_ids {
+                    Some(retained_doc_ids) => {
+                        let removed_doc_ids: Vec<String> = index
+                            .doc_keys
+                            .keys()
+                            .filter(|document_id| !retained_doc_ids.contains(document_id.as_str()))
+                            .cloned()
+                            .collect();
+                        for document_id in removed_doc_ids {
+                            index.remove(&document_id);
+                        }
+                    }
+                    None => index.clear(),
+                }
+            }
+        })
+    }
+
+    pub fn clear(&self) {
+        for collection_path in self.collections.keys() {
+            self.retain_docs(collection_path, None);
+        }
+    }
+
+    /// Finds the candidate documents of a query with the most selective index of the
+    /// collection, if any of them helps.
+    pub fn lookup(
+        &self,
+        collection_path: &str,
+        query: &FirestoreQueryParams,
+    ) -> Option<FirestoreCacheIndexLookup> {
+        let indexes = self
+            .collections
+            .get(collection_path)?
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let constraints = FirestoreCacheQueryConstraints::new(query);
+        let (index, scan) = indexes
+            .iter()
+            .filter_map(|index| index.plan(&constraints).map(|scan| (index, scan)))
+            .max_by_key(|(_, scan)| scan.score())?;
+
+        Some(FirestoreCacheIndexLookup {
+            document_ids: index.scan(&scan),
+            ordered: scan.ordered,
+        })
+    }
+
+    fn with_indexes<F>(&self, collection_path: &str, f: F)
+    where
+        F: FnOnce(&mut Vec<FirestoreCacheIndex>),
+    {
+        if let Some(indexes) = self.collections.get(collection_path) {
+            f(&mut indexes
+                .write()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()))
+        }
+    }
+}
+
+fn unique_index_violation_error(
+    doc: &FirestoreDocument,
+    index_config: &FirestoreCacheIndexConfiguration,
+    conflicting_id: &str,
+) -> FirestoreError {
+    FirestoreError::CacheError(FirestoreCacheError::new(
+        FirestoreErrorPublicGenericDetails::new("CacheUniqueIndexViolation".into()),
+        format!(
+            "Document `{}` has the same values in the fields {:?} of a unique cache index as the \
+             cached document `{conflicting_id}`, so it is not cached.",
+            doc.name, index_config.fields
+        ),
+    ))
+}
+
+/// A field value in an index key, ordered and compared the way Firestore orders values.
+#[derive(Debug, Clone)]
+struct FirestoreCacheIndexValue(ValueType);
+
+impl PartialEq for FirestoreCacheIndexValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for FirestoreCacheIndexValue {}
+
+impl PartialOrd for FirestoreCacheIndexValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FirestoreCacheIndexValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_firestore_values(&self.0, &other.0)
+    }
+}
+
+/// The values of the indexed fields of a document, in the order of the index fields.
+type FirestoreCacheIndexKey = Vec<FirestoreCacheIndexValue>;
+
+struct FirestoreCacheIndex {
+    config: FirestoreCacheIndexConfiguration,
+    /// Documents without all the indexed fields are not indexed, as in Firestore.
+    entries: BTreeMap<FirestoreCacheIndexKey, BTreeSet<String>>,
+    doc_keys: HashMap<String, FirestoreCacheIndexKey>,
+}
+
+impl FirestoreCacheIndex {
+    fn new(config: FirestoreCacheIndexConfiguration) -> Self {
+        Self {
+            config,
+            entries: BTreeMap::new(),
+            doc_keys: HashMap::new(),
+        }
+    }
+
+    fn key_of(&self, doc: &FirestoreDocument) -> Option<FirestoreCacheIndexKey> {
+        self.config
+            .fields
+            .iter()
+            .map(|field| {
+                firestore_doc_get_field_by_path(doc, field)
+                    .cloned()
+                    .map(FirestoreCacheIndexValue)
+            })
+            .collect()
+    }
+
+    fn conflicting_doc_ids(&self, document_id: &str, key: &FirestoreCacheIndexKey) -> Vec<String> {
+        if !self.config.unique {
+            return Vec::new();
+        }
+        self.entries
+            .get(key)
+            .map(|document_ids| {
+                document_ids
+                    .iter()
+                    .filter(|indexed_id| indexed_id.as_str() != document_id)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn insert(&mut self, document_id: &str, key: Option<FirestoreCacheIndexKey>) {
+        self.remove(document_id);
+        if let Some(key) = key {
+            self.entries
+                .entry(key.clone())
+                .or_default()
+                .insert(document_id.to_string());
+            self.doc_keys.insert(document_id.to_string(), key);
+        }
+    }
+
+    fn remove(&mut self, document_id: &str) {
+        if let Some(key) = self.doc_keys.remove(document_id) {
+            if let Some(document_ids) = self.entries.get_mut(&key) {
+                document_ids.remove(document_id);
+                if document_ids.is_empty() {
+                    self.entries.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.doc_keys.clear();
+    }
+
+    /// Plans a scan of this index for the query, if the index can serve it.
+    ///
+    /// The leading index fields fixed by equality filters select a prefix of the index, and a
+    /// range filter on the next field narrows it down. Every indexed field must be required by
+    /// the query - filtered or ordered by - since documents missing any of them are not in the
+    /// index.
+    fn plan(
+        &self,
+        constraints: &FirestoreCacheQueryConstraints,
+    ) -> Option<FirestoreCacheIndexScan> {
+        let prefix: FirestoreCacheIndexKey = self
+            .config
+            .fields
+            .iter()
+            .map_while(|field| constraints.equalities.get(field.as_str()).cloned())
+            .collect();
+        let rest = &self.config.fields[prefix.len()..];
+
+        if !rest.iter().all(|field| {
+            constraints.ranges.contains_key(field.as_str())
+                || constraints
+                    .order_by
+                    .iter()
+                    .any(|(ordered, _)| ordered == field)
+        }) {
+            return None;
+        }
+
+        let (lower, upper) = rest
+            .first()
+            .and_then(|field| constraints.ranges.get(field.as_str()).cloned())
+            .unwrap_or((Bound::Unbounded, Bound::Unbounded));
+
+        // Fields fixed by equality filters do not change the order
+        let order_by: Vec<&(String, bool)> = constraints
+            .order_by
+            .iter()
+            .filter(|(field, _)| !constraints.equalities.contains_key(field.as_str()))
+            .collect();
+        let ordered = !constraints.orders_by_name
+            && order_by.len() == rest.len()
+            && order_by
+                .iter()
+                .zip(rest)
+                .all(|((field, descending), index_field)| {
+                    field == index_field && *descending == constraints.descending
+                });
+
+        let scan = FirestoreCacheIndexScan {
+            prefix,
+            lower,
+            upper,
+            descending: constraints.descending,
+            ordered,
+        };
+        (scan.score() > (0, false, false)).then_some(scan)
+    }
+
+    fn scan(&self, scan: &FirestoreCacheIndexScan) -> Vec<String> {
+        let prefix_len = scan.prefix.len();
+        let mut start = scan.prefix.clone();
+        if let Bound::Included(value) | Bound::Excluded(value) = &scan.lower {
+            start.push(value.clone());
+        }
+
+        let mut document_ids: Vec<String> = self
+            .entries
+            .range((Bound::Included(start), Bound::Unbounded))
+            .take_while(|(key, _)| {
+                key[..prefix_len] == scan.prefix[..]
+                    && match &scan.upper {
+                        Bound::Included(value) => key[prefix_len] <= *value,
+                        Bound::Excluded(value) => key[prefix_len] < *value,
+                        Bound::Unbounded => true,
+                    }
+            })
+            .filter(|(key, _)| match &scan.lower {
+                Bound::Excluded(value) => key[prefix_len] > *value,
+                _ => true,
+            })
+            .flat_map(|(_, document_ids)| document_ids.iter().cloned())
+            .collect();
+
+        if scan.descending {
+            document_ids.reverse();
+        }
+        document_ids
+    }
+}
+
+struct FirestoreCacheIndexScan {
+    /// The values of the leading index fields, fixed by equality filters.
+    prefix: FirestoreCacheIndexKey,
+    /// The range of the index field following the prefix.
+    lower: Bound<FirestoreCacheIndexValue>,
+    upper: Bound<FirestoreCacheIndexValue>,
+    descending: bool,
+    ordered: bool,
+}
+
+impl FirestoreCacheIndexScan {
+    /// Scans with more equality fields are more selective than range scans, which are more
+    /// selective than full ordered scans.
+    fn score(&self) -> (usize, bool, bool) {
+        (
+            self.prefix.len(),
+            !matches!(
+                (&self.lower, &self.upper),
+                (Bound::Unbounded, Bound::Unbounded)
+            ),
+            self.ordered,
+        )
+    }
+}
+
+/// The parts of a query an index can serve.
+struct FirestoreCacheQueryConstraints {
+    equalities: HashMap<String, FirestoreCacheIndexValue>,
+    ranges: HashMap<
+        String,
+        (
+            Bound<FirestoreCacheIndexValue>,
+            Bound<FirestoreCacheIndexValue>,
+        ),
+    >,
+    /// The ordered fields, explicitly or by inequality filters, with `true` for descending.
+    order_by: Vec<(String, bool)>,
+    orders_by_name: bool,
+    /// The direction of the implicit ordering by document name, which follows the last ordered
+    /// field.
+    descending: bool,
+}
+
+impl FirestoreCacheQueryConstraints {
+    fn new(query: &FirestoreQueryParams) -> Self {
+        let mut constraints = Self {
+            equalities: HashMap::new(),
+            ranges: HashMap::new(),
+            order_by: ordered_fields(query)
+                .iter()
+                .map(|ordered_field| {
+                    (
+                        ordered_field.field_name.clone(),
+                        matches!(ordered_field.direction, FirestoreQueryDirection::Descending),
+                    )
+                })
+                .collect(),
+            orders_by_name: false,
+            descending: false,
+        };
+        constraints.orders_by_name = constraints
+            .order_by
+            .iter()
+            .any(|(field, _)| field == DOCUMENT_NAME_FIELD);
+        constraints.descending = constraints
+            .order_by
+            .last()
+            .is_some_and(|(_, descending)| *descending);
+
+        if let Some(filter) = &query.filter {
+            constraints.add_filter(filter);
+        }
+        constraints
+    }
+
+    /// Collects the comparisons every result must satisfy. Disjunctions and other filters are
+    /// left to the filter engine.
+    fn add_filter(&mut self, filter: &FirestoreQueryFilter) {
+        match filter {
+            FirestoreQueryFilter::Composite(composite) => {
+                if matches!(
+                    composite.operator,
+                    FirestoreQueryFilterCompositeOperator::And
+                ) {
+                    for filter in &composite.for_all_filters {
+                        self.add_filter(filter);
+                    }
+                }
+            }
+            FirestoreQueryFilter::Compare(Some(compare)) => self.add_compare(compare),
+            FirestoreQueryFilter::Compare(None) | FirestoreQueryFilter::Unary(_) => {}
+        }
+    }
+
+    fn add_compare(&mut self, compare: &FirestoreQueryFilterCompare) {
+        let (field, value, lower, upper) = match compare {
+            FirestoreQueryFilterCompare::Equal(field, value) => {
+                if let Some(value_type) = &value.value.value_type {
+                    self.equalities
+                        .insert(field.clone(), FirestoreCacheIndexValue(value_type.clone()));
+                }
+                return;
+            }
+            FirestoreQueryFilterCompare::GreaterThan(field, value) => {
+                (field, value, Some(false), None)
+            }
+            FirestoreQueryFilterCompare::GreaterThanOrEqual(field, value) => {
+                (field, value, Some(true), None)
+            }
+            FirestoreQueryFilterCompare::LessThan(field, value) => {
+                (field, value, None, Some(false))
+            }
+            FirestoreQueryFilterCompare::LessThanOrEqual(field, value) => {
+                (field, value, None, Some(true))
+            }
+            _ => return,
+        };
+        let Some(value_type) = &value.value.value_type else {
+            return;
+        };
+        let value = FirestoreCacheIndexValue(value_type.clone());
+        let range = self
+            .ranges
+            .entry(field.clone())
+            .or_insert((Bound::Unbounded, Bound::Unbounded));
+
+        if let Some(inclusive) = lower {
+            let bound = if inclusive {
+                Bound::Included(value)
+            } else {
+                Bound::Excluded(value)
+            };
+            range.0 = tighter_bound(
+                std::mem::replace(&mut range.0, Bound::Unbounded),
+                bound,
+                Ordering::Greater,
+            );
+        } else if let Some(inclusive) = upper {
+            let bound = if inclusive {
+                Bound::Included(value)
+            } else {
+                Bound::Excluded(value)
+            };
+            range.1 = tighter_bound(
+                std::mem::replace(&mut range.1, Bound::Unbounded),
+                bound,
+                Ordering::Less,
+            );
+        }
+    }
+}
+
+/// Picks the bound that excludes more, where `tighter` is the ordering of a tighter value.
+fn tighter_bound(
+    current: Bound<FirestoreCacheIndexValue>,
+    bound: Bound<FirestoreCacheIndexValue>,
+    tighter: Ordering,
+) -> Bound<FirestoreCacheIndexValue> {
+    match (&current, &bound) {
+        (Bound::Unbounded, _) => bound,
+        (Bound::Included(a) | Bound::Excluded(a), Bound::Included(b) | Bound::Excluded(b)) => {
+            match b.cmp(a) {
+                ordering if ordering == tighter => bound,
+                Ordering::Equal if matches!(bound, Bound::Excluded(_)) => bound,
+                _ => current,
+            }
+        }
+        (_, Bound::Unbounded) => current,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gcloud_sdk::google::firestore::v1::Value;
+
+    const DOCS: &str = "projects/test/databases/(default)/documents";
+
+    fn indexes(index: FirestoreCacheIndexConfiguration) -> FirestoreCacheIndexes {
+        FirestoreCacheIndexes::new(
+            &FirestoreCacheConfiguration::new().add_collection_config_at(
+                DOCS,
+                FirestoreCacheCollectionConfiguration::new(
+                    "cities",
+                    FirestoreListenerTarget::new(1),
+                    FirestoreCacheCollectionLoadMode::PreloadAllDocs,
+                )
+                .with_index(index),
+            ),
+        )
+    }
+
+    fn doc(id: &str, fields: Vec<(&str, ValueType)>) -> FirestoreDocument {
+        FirestoreDocument {
+            name: format!("{DOCS}/cities/{id}"),
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| {
+                    (
+                        name.to_string(),
+                        Value {
+                            value_type: Some(value),
+                        },
+                    )
+                })
+                .collect(),
+            create_time: None,
+            update_time: None,
+        }
+    }
+
+    fn city(id: &str, country: &str, population: i64) -> FirestoreDocument {
+        doc(
+            id,
+            vec![
+                ("country", ValueType::StringValue(country.to_string())),
+                ("population", ValueType::IntegerValue(population)),
+            ],
+        )
+    }
+
+    fn query() -> FirestoreQueryParams {
+        FirestoreQueryParams::new(FirestoreQueryCollection::Single("cities".into()))
+    }
+
+    fn compare(compare: FirestoreQueryFilterCompare) -> FirestoreQueryFilter {
+        FirestoreQueryFilter::Compare(Some(compare))
+    }
+
+    fn and(filters: Vec<FirestoreQueryFilter>) -> FirestoreQueryFilter {
+        FirestoreQueryFilter::Composite(FirestoreQueryFilterComposite::new(
+            filters,
+            FirestoreQueryFilterCompositeOperator::And,
+        ))
+    }
+
+    fn insert_doc<F>(
+        indexes: &FirestoreCacheIndexes,
+        doc: &FirestoreDocument,
+        is_cached: F,
+    ) -> FirestoreResult<()>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let (collection_path, document_id) = split_document_path(&doc.name);
+        indexes.insert_doc(collection_path, document_id, doc, is_cached)
+    }
+
+    fn lookup(
+        indexes: &FirestoreCacheIndexes,
+        query: FirestoreQueryParams,
+    ) -> Option<(Vec<String>, bool)> {
+        indexes
+            .lookup(&format!("{DOCS}/cities"), &query)
+            .map(|lookup| (lookup.document_ids, lookup.ordered))
+    }
+
+    #[test]
+    fn scans_equality_prefix_and_range() {
+        let indexes = indexes(FirestoreCacheIndexConfiguration::new([
+            "country",
+            "population",
+        ]));
+        for city in [
+            city("a", "SE", 100),
+            city("b", "SE", 300),
+            city("c", "NO", 200),
+            city("d", "SE", 200),
+            doc("e", vec![("country", ValueType::StringValue("SE".into()))]),
+        ] {
+            insert_doc(&indexes, &city, |_| true).unwrap();
+        }
+
+        let by_country = query().with_filter(compare(FirestoreQueryFilterCompare::Equal(
+            "country".into(),
+            "SE".into(),
+        )));
+        // The document without a population is not indexed, so the query needs it ordered
+        assert_eq!(lookup(&indexes, by_country.clone()), None);
+
+        let ordered = by_country
+            .clone()
+            .with_order_by(vec![FirestoreQueryOrder::new(
+                "population".into(),
+                FirestoreQueryDirection::Descending,
+            )]);
+        assert_eq!(
+            lookup(&indexes, ordered),
+            Some((vec!["b".into(), "d".into(), "a".into()], true))
+        );
+
+        let ranged = query().with_filter(and(vec![
+            compare(FirestoreQueryFilterCompare::Equal(
+                "country".into(),
+                "SE".into(),
+            )),
+            compare(FirestoreQueryFilterCompare::GreaterThan(
+                "population".into(),
+                100.into(),
+            )),
+            compare(FirestoreQueryFilterCompare::LessThanOrEqual(
+                "population".into(),
+                300.into(),
+            )),
+        ]));
+        // Firestore orders by the population filtered by a range, as the index does
+        assert_eq!(
+            lookup(&indexes, ranged),
+            Some((vec!["d".into(), "b".into()], true))
+        );
+
+        indexes.remove_doc(&format!("{DOCS}/cities"), "d");
+        let ranged_after_removal = query()
+            .with_filter(compare(FirestoreQueryFilterCompare::GreaterThanOrEqual(
+                "country".into(),
+                "SE".into(),
+            )))
+            .with_order_by(vec![FirestoreQueryOrder::new(
+                "population".into(),
+                FirestoreQueryDirection::Ascending,
+            )]);
+        // Leading with a range, the index only narrows down the first field
+        assert_eq!(
+            lookup(&indexes, ranged_after_removal),
+            Some((vec!["a".into(), "b".into()], false))
+        );
+    }
+
+    #[test]
+    fn enforces_unique_indexes() {
+        let indexes = indexes(FirestoreCacheIndexConfiguration::new(["code"]).unique(true));
+        let code =
+            |id: &str, code: &str| doc(id, vec![("code", ValueType::StringValue(code.into()))]);
+
+        insert_doc(&indexes, &code("a", "x"), |_| true).unwrap();
+        insert_doc(&indexes, &code("a", "y"), |_| true).unwrap();
+        insert_doc(&indexes, &code("b", "x"), |_| true).unwrap();
+
+        assert!(matches!(
+            insert_doc(&indexes, &code("c", "y"), |_| true),
+            Err(FirestoreError::CacheError(_))
+        ));
+        // A document that is not cached anymore does not hold on to its values
+        insert_doc(&indexes, &code("c", "y"), |id| id != "a").unwrap();
+
+        let by_code = query().with_filter(compare(FirestoreQueryFilterCompare::Equal(
+            "code".into(),
+            "y".into(),
+        )));
+        assert_eq!(lookup(&indexes, by_code), Some((vec!["c".into()], true)));
+    }
+}
@@ -4,9 +4,16 @@ use crate::*;
 use async_trait::async_trait;
 use futures::stream::BoxStream;
 use moka::future::{Cache, CacheBuilder};
+use moka::notification::RemovalCause;
 
-use crate::cache::cache_query_engine::FirestoreCacheQueryEngine;
+use super::counters::{FirestoreCacheCollectionCounters, FirestoreCacheCounters};
+use super::indexes::FirestoreCacheIndexes;
+use super::sync_state::FirestoreCacheSyncState;
+use crate::cache::cache_query_engine::{is_doc_in_collection_scope, FirestoreCacheQueryEngine};
+use crate::timestamp_utils::from_timestamp;
 use futures::StreamExt;
+use gcloud_sdk::google::firestore::v1::target_change::TargetChangeType;
+use gcloud_sdk::google::firestore::v1::TargetChange;
 use std::collections::HashMap;
 use tracing::*;
 
@@ -42,6 +49,9 @@ pub type FirestoreMemCacheOptions = CacheBuilder<String, FirestoreDocument, Fire
 pub struct FirestoreMemoryCacheBackend {
     pub config: FirestoreCacheConfiguration,
     collection_caches: HashMap<String, FirestoreMemCache>,
+    sync_state: FirestoreCacheSyncState,
+    indexes: FirestoreCacheIndexes,
+    counters: FirestoreCacheCounters,
 }
 
 /// The maximum number of documents kept per collection unless configured otherwise.
@@ -144,6 +154,8 @@ impl FirestoreMemoryCacheBackend {
     #[doc(hidden)]
     /// Configures each collection's cache through the underlying `moka` builder. Not part of the
     /// supported API surface - prefer [`with_options`](Self::with_options).
+    ///
+    /// The eviction listener is used to count evictions, so one set on the builder is replaced.
     pub fn with_collection_options<FN>(
         config: FirestoreCacheConfiguration,
         collection_mem_options: FN,
@@ -151,20 +163,36 @@ impl FirestoreMemoryCacheBackend {
     where
         FN: Fn(&str) -> FirestoreMemCacheOptions,
     {
+        let counters = FirestoreCacheCounters::new(&config);
+
         let collection_caches = config
             .collections
             .keys()
             .map(|collection_path| {
-                (
-                    collection_path.clone(),
-                    collection_mem_options(collection_path.as_str()).build(),
-                )
+                let mut builder = collection_mem_options(collection_path.as_str());
+                if let Some(collection_counters) = counters.collection(collection_path) {
+                    builder = builder.eviction_listener(move |_, _, cause| match cause {
+                        RemovalCause::Expired => FirestoreCacheCollectionCounters::increment(
+                            &collection_counters.evictions_expired,
+                        ),
+                        RemovalCause::Size => FirestoreCacheCollectionCounters::increment(
+                            &collection_counters.evictions_size,
+                        ),
+                        RemovalCause::Explicit | RemovalCause::Replaced => {}
+                    });
+                }
+                (collection_path.clone(), builder.build())
             })
             .collect();
 
+        let indexes = FirestoreCacheIndexes::new(&config);
+
         Ok(Self {
             config,
             collection_caches,
+            sync_state: FirestoreCacheSyncState::new(),
+            indexes,
+            counters,
         })
     }
 
@@ -176,16 +204,7 @@ impl FirestoreMemoryCacheBackend {
                     if let Some(mem_cache) = self.collection_caches.get(collection_path.as_str()) {
                         debug!(collection_path, "Preloading collection.");
 
-                        let params = if let Some(parent) = &config.parent {
-                            db.fluent()
-                                .select()
-                                .from(config.collection_name.as_str())
-                                .parent(parent)
-                        } else {
-                            db.fluent().select().from(config.collection_name.as_str())
-                        };
-
-                        let stream = params.stream_query().await?;
+                        let stream = db.stream_query_doc(config.query_params()).await?;
 
                         stream
                             .enumerate()
@@ -200,8 +219,9 @@ impl FirestoreMemoryCacheBackend {
                                 docs
                             })
                             .for_each_concurrent(1, |doc| async move {
-                                let (_, document_id) = split_document_path(&doc.name);
-                                mem_cache.insert(document_id.to_string(), doc).await;
+                                if let Err(err) = self.cache_doc(doc).await {
+                                    error!(?err, "Error while preloading collection.");
+                                }
                             })
                             .await;
 
@@ -220,23 +240,189 @@ impl FirestoreMemoryCacheBackend {
         Ok(())
     }
 
+    /// Writes a document to the cache and its indexes. A document violating a unique index is
+    /// removed from the cache instead, since its cached version would be stale.
+    async fn cache_doc(&self, doc: FirestoreDocument) -> FirestoreResult<()> {
+        let Some((collection_path, document_key)) = self.config.cached_document_key(&doc.name)
+        else {
+            return Ok(());
+        };
+        let Some(mem_cache) = self.collection_caches.get(collection_path) else {
+            return Ok(());
+        };
+        if let Err(err) =
+            self.indexes
+                .insert_doc(collection_path, document_key, &doc, |indexed_key| {
+                    mem_cache.contains_key(indexed_key)
+                })
+        {
+            self.indexes.remove_doc(collection_path, document_key);
+            mem_cache.remove(document_key).await;
+            return Err(err);
+        }
+        let document_key = document_key.to_string();
+        mem_cache.insert(document_key, doc).await;
+        Ok(())
+    }
+
+    async fn remove_doc(&self, document_path: &str) {
+        if let Some((collection_path, document_key)) =
+            self.config.cached_document_key(document_path)
+        {
+            if let Some(mem_cache) = self.collection_caches.get(collection_path) {
+                trace!(removed_doc = document_path, "Removing document from cache.");
+                self.indexes.remove_doc(collection_path, document_key);
+                mem_cache.remove(document_key).await;
+            }
+        }
+    }
+
+    async fn remove_doc_on_listen_event(&self, document_path: &str) {
+        if let Some((collection_path, _)) = self.config.cached_document_key(document_path) {
+            self.counters.listener_removal(collection_path);
+        }
+        self.remove_doc(document_path).await;
+    }
+
+    fn list_cached_docs<'b>(
+        &self,
+        cached_collection_path: &str,
+        collection_path: &str,
+    ) -> FirestoreCachedValue<BoxStream<'b, FirestoreResult<FirestoreDocument>>> {
+        if !self.config.is_collection_listable(cached_collection_path)
+            || !self.sync_state.is_consistent(cached_collection_path)
+        {
+            return FirestoreCachedValue::SkipCache;
+        }
+
+        match self.collection_caches.get(cached_collection_path) {
+            Some(mem_cache) => {
+                let all_docs: Vec<FirestoreResult<FirestoreDocument>> = mem_cache
+                    .iter()
+                    .filter(|(_, doc)| {
+                        is_doc_in_collection_scope(collection_path, false, &doc.name)
+                    })
+                    .map(|(_, doc)| Ok(doc))
+                    .collect();
+                FirestoreCachedValue::UseCached(Box::pin(futures::stream::iter(all_docs)))
+            }
+            None => FirestoreCachedValue::SkipCache,
+        }
+    }
+
+    async fn on_target_change(&self, target_change: TargetChange) -> FirestoreResult<()> {
+        let collection_paths = self
+            .config
+            .collection_paths_for_targets(&target_change.target_ids);
+
+        match TargetChangeType::try_from(target_change.target_change_type) {
+            Ok(TargetChangeType::Reset) => {
+                for collection_path in &collection_paths {
+                    debug!(
+                        collection_path = collection_path.as_str(),
+                        "Listener target has been reset. Resynchronizing collection.",
+                    );
+                    self.sync_state.start_resync(collection_path);
+                }
+            }
+            Ok(TargetChangeType::Current) => {
+                for collection_path in &collection_paths {
+                    if let (Some(received_doc_ids), Some(mem_cache)) = (
+                        self.sync_state.finish_resync(collection_path),
+                        self.collection_caches.get(collection_path.as_str()),
+                    ) {
+                        let stale_doc_ids: Vec<_> = mem_cache
+                            .iter()
+                            .filter(|(document_id, _)| {
+                                !received_doc_ids.contains(document_id.as_str())
+                            })
+                            .map(|(document_id, _)| document_id)
+                            .collect();
+                        debug!(
+                            collection_path = collection_path.as_str(),
+                            removed_docs = stale_doc_ids.len(),
+                            "Collection has been resynchronized.",
+                        );
+                        self.indexes
+                            .retain_docs(collection_path, Some(&received_doc_ids));
+                        for document_id in stale_doc_ids {
+                            mem_cache.remove(document_id.as_str()).await;
+                        }
+                    }
+                }
+            }
+            Ok(TargetChangeType::Remove) => {
+                for collection_path in &collection_paths {
+                    error!(
+                        collection_path = collection_path.as_str(),
+                        cause = ?target_change.cause,
+                        "Listener target has been removed by the server. \
+                         The collection will be read from Firestore until the cache is loaded again.",
+                    );
+                    self.sync_state.removed(collection_path);
+                    self.indexes.retain_docs(collection_path, None);
+                    if let Some(mem_cache) = self.collection_caches.get(collection_path.as_str()) {
+                        mem_cache.invalidate_all();
+                        mem_cache.run_pending_tasks().await;
+                    }
+                }
+            }
+            Ok(TargetChangeType::NoChange) | Ok(TargetChangeType::Add) | Err(_) => {}
+        }
+
+        if let Some(read_time) = target_change.read_time {
+            let read_time = from_timestamp(read_time)?;
+            for collection_path in &collection_paths {
+                self.sync_state.synchronized_at(collection_path, read_time);
+            }
+        }
+
+        Ok(())
+    }
+
     async fn query_cached_docs<'b>(
         &self,
+        cached_collection_path: &str,
         collection_path: &str,
         query_engine: FirestoreCacheQueryEngine,
     ) -> FirestoreResult<BoxStream<'b, FirestoreResult<FirestoreDocument>>> {
-        match self.collection_caches.get(collection_path) {
+        match self.collection_caches.get(cached_collection_path) {
             Some(mem_cache) => {
-                let filtered_results: Vec<FirestoreResult<FirestoreDocument>> = mem_cache
-                    .iter()
-                    .filter(|(_, doc)| query_engine.matches_doc(doc))
-                    .map(|(_, doc)| Ok(doc))
+                let (candidates, ordered) = match self
+                    .indexes
+                    .lookup(cached_collection_path, &query_engine.query)
+                {
+                    Some(lookup) => {
+                        let mut docs = Vec::with_capacity(lookup.document_ids.len());
+                        for document_key in &lookup.document_ids {
+                            // Documents evicted from the cache may still be indexed
+                            if let Some(doc) = mem_cache.get(document_key).await {
+                                docs.push(doc);
+                            }
+                        }
+                        (docs, lookup.ordered)
+                    }
+                    None => (mem_cache.iter().map(|(_, doc)| doc).collect(), false),
+                };
+
+                let all_descendants = query_engine.query.all_descendants.unwrap_or(false);
+                let filtered_results: Vec<FirestoreResult<FirestoreDocument>> = candidates
+                    .into_iter()
+                    .filter(|doc| {
+                        is_doc_in_collection_scope(collection_path, all_descendants, &doc.name)
+                            && query_engine.matches_doc(doc)
+                    })
+                    .map(Ok)
                     .collect();
 
-                let filtered_stream = futures::stream::iter(filtered_results);
-                let output_stream = query_engine
-                    .process_query_stream(Box::pin(filtered_stream))
-                    .await?;
+                let filtered_stream = Box::pin(futures::stream::iter(filtered_results));
+                let output_stream = if ordered {
+                    query_engine
+                        .process_sorted_query_stream(filtered_stream)
+                        .await?
+                } else {
+                    query_engine.process_query_stream(filtered_stream).await?
+                };
 
                 Ok(output_stream)
             }
@@ -254,8 +440,16 @@ impl FirestoreCacheBackend for FirestoreMemoryCacheBackend {
     ) -> Result<Vec<FirestoreListenerTargetParams>, FirestoreError> {
         let read_from_time = FirestoreInstant::now();
 
+        self.sync_state.clear();
         self.preload_collections(db).await?;
 
+        // The listener resumes from the time the preloading started, so the cache is in sync
+        // with it as soon as the listener is running.
+        for collection_path in self.config.collections.keys() {
+            self.sync_state
+                .synchronized_at(collection_path, read_from_time);
+        }
+
         Ok(self
             .config
             .collections
@@ -263,12 +457,7 @@ impl FirestoreCacheBackend for FirestoreMemoryCacheBackend {
             .map(|collection_config| {
                 FirestoreListenerTargetParams::new(
                     collection_config.listener_target.clone(),
-                    FirestoreTargetType::Query(
-                        FirestoreQueryParams::new(
-                            collection_config.collection_name.as_str().into(),
-                        )
-                        .opt_parent(collection_config.parent.clone()),
-                    ),
+                    FirestoreTargetType::Query(collection_config.query_params()),
                     HashMap::new(),
                 )
                 .with_resume_type(FirestoreListenerTargetResumeType::ReadTime(read_from_time))
@@ -277,6 +466,7 @@ impl FirestoreCacheBackend for FirestoreMemoryCacheBackend {
     }
 
     async fn invalidate_all(&self) -> FirestoreResult<()> {
+        self.indexes.clear();
         for (collection_path, mem_cache) in &self.collection_caches {
             debug!(collection_path, "Invalidating cache for collection.");
             mem_cache.invalidate_all();
@@ -293,31 +483,71 @@ impl FirestoreCacheBackend for FirestoreMemoryCacheBackend {
         match event {
             FirestoreListenEvent::DocumentChange(doc_change) => {
                 if let Some(doc) = doc_change.document {
-                    let (collection_path, document_id) = split_document_path(&doc.name);
-                    if let Some(mem_cache) = self.collection_caches.get(collection_path) {
+                    if let Some((collection_path, document_key)) =
+                        self.config.cached_document_key(&doc.name)
+                    {
                         trace!(
                             doc_name = ?doc.name,
                             "Writing document to cache due to listener event.",
                         );
-                        mem_cache.insert(document_id.to_string(), doc).await;
+                        self.sync_state
+                            .document_received(collection_path, document_key);
+                        self.counters.listener_update(collection_path);
+                        self.cache_doc(doc).await?;
                     }
                 }
                 Ok(())
             }
             FirestoreListenEvent::DocumentDelete(doc_deleted) => {
-                let (collection_path, document_id) = split_document_path(&doc_deleted.document);
-                if let Some(mem_cache) = self.collection_caches.get(collection_path) {
-                    trace!(
-                        deleted_doc = ?doc_deleted.document.as_str(),
-                        "Removing document from cache due to listener event.",
-                    );
-                    mem_cache.remove(document_id).await;
-                }
+                self.remove_doc_on_listen_event(&doc_deleted.document).await;
+                Ok(())
+            }
+            FirestoreListenEvent::DocumentRemove(doc_removed) => {
+                self.remove_doc_on_listen_event(&doc_removed.document).await;
                 Ok(())
             }
-            _ => Ok(()),
+            FirestoreListenEvent::TargetChange(target_change) => {
+                self.on_target_change(target_change).await
+            }
+            // The document count of a resumed target, only useful to clients that can tell which
+            // of their documents were removed while they were not listening
+            FirestoreListenEvent::Filter(_) => Ok(()),
         }
     }
+
+    async fn last_synchronized_at(
+        &self,
+        collection_path: &str,
+    ) -> FirestoreResult<Option<FirestoreInstant>> {
+        Ok(self.sync_state.last_synchronized_at(collection_path))
+    }
+
+    async fn collections_status(&self) -> FirestoreResult<Vec<FirestoreCacheCollectionStatus>> {
+        let mut collections = Vec::with_capacity(self.config.collections.len());
+        for (collection_path, config) in &self.config.collections {
+            let document_count = match self.collection_caches.get(collection_path) {
+                Some(mem_cache) => {
+                    mem_cache.run_pending_tasks().await;
+                    mem_cache.entry_count()
+                }
+                None => 0,
+            };
+            collections.push(
+                FirestoreCacheCollectionStatus::new(
+                    collection_path.clone(),
+                    config.listener_target.clone(),
+                    config.collection_load_mode,
+                    document_count,
+                )
+                .opt_last_synchronized_at(self.sync_state.last_synchronized_at(collection_path)),
+            );
+        }
+        Ok(collections)
+    }
+
+    fn metrics(&self) -> Vec<FirestoreCacheCollectionMetrics> {
+        self.counters.snapshot()
+    }
 }
 
 #[async_trait]
@@ -326,44 +556,59 @@ impl FirestoreCacheDocsByPathSupport for FirestoreMemoryCacheBackend {
         &self,
         document_path: &str,
     ) -> FirestoreResult<Option<FirestoreDocument>> {
-        let (collection_path, document_id) = split_document_path(document_path);
+        let Some((collection_path, document_key)) = self.config.cached_document_key(document_path)
+        else {
+            return Ok(None);
+        };
 
         match self.collection_caches.get(collection_path) {
-            Some(mem_cache) => Ok(mem_cache.get(document_id).await),
+            Some(mem_cache) => {
+                let cached_doc = mem_cache.get(document_key).await;
+                self.counters.doc_read(collection_path, &cached_doc);
+                Ok(cached_doc)
+            }
             None => Ok(None),
         }
     }
 
-    async fn update_doc_by_path(&self, document: &FirestoreDocument) -> FirestoreResult<()> {
-        let (collection_path, document_id) = split_document_path(&document.name);
+    async fn peek_doc_by_path(
+        &self,
+        document_path: &str,
+    ) -> FirestoreResult<Option<FirestoreDocument>> {
+        let Some((collection_path, document_key)) = self.config.cached_document_key(document_path)
+        else {
+            return Ok(None);
+        };
 
         match self.collection_caches.get(collection_path) {
-            Some(mem_cache) => {
-                mem_cache
-                    .insert(document_id.to_string(), document.clone())
-                    .await;
-                Ok(())
-            }
-            None => Ok(()),
+            Some(mem_cache) => Ok(mem_cache.get(document_key).await),
+            None => Ok(None),
         }
     }
 
+    async fn update_doc_by_path(&self, document: &FirestoreDocument) -> FirestoreResult<()> {
+        self.cache_doc(document.clone()).await
+    }
+
+    async fn delete_doc_by_path(&self, document_path: &str) -> FirestoreResult<()> {
+        self.remove_doc(document_path).await;
+        Ok(())
+    }
+
     async fn list_all_docs<'b>(
         &self,
         collection_path: &str,
     ) -> FirestoreResult<FirestoreCachedValue<BoxStream<'b, FirestoreResult<FirestoreDocument>>>>
     {
-        if !self.config.is_collection_listable(collection_path) {
-            return Ok(FirestoreCachedValue::SkipCache);
-        }
-
-        match self.collection_caches.get(collection_path) {
-            Some(mem_cache) => {
-                let all_docs: Vec<FirestoreResult<FirestoreDocument>> =
-                    mem_cache.iter().map(|(_, doc)| Ok(doc)).collect();
-                Ok(FirestoreCachedValue::UseCached(Box::pin(
-                    futures::stream::iter(all_docs),
-                )))
+        match self
+            .config
+            .cached_collection_for_query(collection_path, false)
+        {
+            Some((cached_collection_path, _)) => {
+                let cached_docs = self.list_cached_docs(cached_collection_path, collection_path);
+                self.counters
+                    .query_served(cached_collection_path, &cached_docs);
+                Ok(cached_docs)
             }
             None => Ok(FirestoreCachedValue::SkipCache),
         }
@@ -375,18 +620,383 @@ impl FirestoreCacheDocsByPathSupport for FirestoreMemoryCacheBackend {
         query: &FirestoreQueryParams,
     ) -> FirestoreResult<FirestoreCachedValue<BoxStream<'b, FirestoreResult<FirestoreDocument>>>>
     {
-        if !self.config.is_collection_listable(collection_path) {
+        let Some((cached_collection_path, _)) = self
+            .config
+            .cached_collection_for_query(collection_path, query.all_descendants.unwrap_or(false))
+        else {
             return Ok(FirestoreCachedValue::SkipCache);
-        }
+        };
 
         let simple_query_engine = FirestoreCacheQueryEngine::new(query);
-        if simple_query_engine.params_supported() {
-            Ok(FirestoreCachedValue::UseCached(
-                self.query_cached_docs(collection_path, simple_query_engine)
-                    .await?,
-            ))
+        let cached_docs = if !self.config.is_collection_listable(cached_collection_path)
+            || !self.sync_state.is_consistent(cached_collection_path)
+            || !simple_query_engine.params_supported()
+        {
+            FirestoreCachedValue::SkipCache
         } else {
-            Ok(FirestoreCachedValue::SkipCache)
+            FirestoreCachedValue::UseCached(
+                self.query_cached_docs(
+                    cached_collection_path,
+                    collection_path,
+                    simple_query_engine,
+                )
+                .await?,
+            )
+        };
+        self.counters
+            .query_served(cached_collection_path, &cached_docs);
+        Ok(cached_docs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timestamp_utils::to_timestamp;
+    use gcloud_sdk::google::firestore::v1::value::ValueType;
+    use gcloud_sdk::google::firestore::v1::{DocumentChange, DocumentRemove};
+
+    const DOCS: &str = "projects/test/databases/(default)/documents";
+    const TARGET: u32 = 42;
+
+    fn backend() -> FirestoreMemoryCacheBackend {
+        let config = FirestoreCacheConfiguration::new().add_collection_config_at(
+            DOCS,
+            FirestoreCacheCollectionConfiguration::new(
+                "cities",
+                FirestoreListenerTarget::new(TARGET),
+                FirestoreCacheCollectionLoadMode::PreloadAllDocs,
+            ),
+        );
+        FirestoreMemoryCacheBackend::new(config).unwrap()
+    }
+
+    fn collection_path() -> String {
+        format!("{DOCS}/cities")
+    }
+
+    fn doc_change(id: &str) -> FirestoreListenEvent {
+        FirestoreListenEvent::DocumentChange(DocumentChange {
+            document: Some(FirestoreDocument {
+                name: format!("{DOCS}/cities/{id}"),
+                fields: HashMap::new(),
+                create_time: None,
+                update_time: None,
+            }),
+            target_ids: vec![TARGET as i32],
+            removed_target_ids: vec![],
+        })
+    }
+
+    fn target_change(
+        change_type: TargetChangeType,
+        read_time: Option<FirestoreInstant>,
+    ) -> FirestoreListenEvent {
+        FirestoreListenEvent::TargetChange(TargetChange {
+            target_change_type: change_type as i32,
+            target_ids: vec![TARGET as i32],
+            read_time: read_time.map(to_timestamp),
+            ..Default::default()
+        })
+    }
+
+    async fn cached_ids(backend: &FirestoreMemoryCacheBackend) -> Option<Vec<String>> {
+        match backend.list_all_docs(&collection_path()).await.unwrap() {
+            FirestoreCachedValue::UseCached(stream) => {
+                let mut ids: Vec<String> = stream
+                    .map(|doc| split_document_path(&doc.unwrap().name).1.to_string())
+                    .collect()
+                    .await;
+                ids.sort();
+                Some(ids)
+            }
+            FirestoreCachedValue::SkipCache => None,
         }
     }
+
+    #[tokio::test]
+    async fn removes_documents_that_stop_matching() {
+        let backend = backend();
+        backend.on_listen_event(doc_change("a")).await.unwrap();
+        backend.on_listen_event(doc_change("b")).await.unwrap();
+
+        backend
+            .on_listen_event(FirestoreListenEvent::DocumentRemove(DocumentRemove {
+                document: format!("{DOCS}/cities/a"),
+                removed_target_ids: vec![TARGET as i32],
+                read_time: None,
+            }))
+            .await
+            .unwrap();
+
+        assert_eq!(cached_ids(&backend).await, Some(vec!["b".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn resynchronizes_collection_after_reset() {
+        let backend = backend();
+        backend.on_listen_event(doc_change("a")).await.unwrap();
+        backend.on_listen_event(doc_change("b")).await.unwrap();
+
+        backend
+            .on_listen_event(target_change(TargetChangeType::Reset, None))
+            .await
+            .unwrap();
+        assert_eq!(cached_ids(&backend).await, None);
+
+        backend.on_listen_event(doc_change("b")).await.unwrap();
+        backend.on_listen_event(doc_change("c")).await.unwrap();
+        let read_time = FirestoreInstant::from_second(1_700_000_000).unwrap();
+        backend
+            .on_listen_event(target_change(TargetChangeType::Current, Some(read_time)))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            cached_ids(&backend).await,
+            Some(vec!["b".to_string(), "c".to_string()])
+        );
+        assert_eq!(
+            backend
+                .last_synchronized_at(&collection_path())
+                .await
+                .unwrap(),
+            Some(read_time)
+        );
+    }
+
+    #[tokio::test]
+    async fn queries_through_indexes() {
+        let config = FirestoreCacheConfiguration::new().add_collection_config_at(
+            DOCS,
+            FirestoreCacheCollectionConfiguration::new(
+                "cities",
+                FirestoreListenerTarget::new(TARGET),
+                FirestoreCacheCollectionLoadMode::PreloadAllDocs,
+            )
+            .with_index(FirestoreCacheIndexConfiguration::new(["code"]).unique(true)),
+        );
+        let backend = FirestoreMemoryCacheBackend::new(config).unwrap();
+
+        let city = |id: &str, code: &str| {
+            let mut doc = FirestoreDocument {
+                name: format!("{DOCS}/cities/{id}"),
+                fields: HashMap::new(),
+                create_time: None,
+                update_time: None,
+            };
+            doc.fields.insert(
+                "code".to_string(),
+                gcloud_sdk::google::firestore::v1::Value {
+                    value_type: Some(ValueType::StringValue(code.to_string())),
+                },
+            );
+            doc
+        };
+        backend.update_doc_by_path(&city("a", "x")).await.unwrap();
+        backend.update_doc_by_path(&city("b", "y")).await.unwrap();
+        assert!(backend.update_doc_by_path(&city("c", "y")).await.is_err());
+
+        let query = FirestoreQueryParams::new(FirestoreQueryCollection::Single("cities".into()))
+            .with_filter(FirestoreQueryFilter::Compare(Some(
+                FirestoreQueryFilterCompare::Equal("code".into(), "y".into()),
+            )));
+        let ids: Vec<String> = match backend
+            .query_docs(&collection_path(), &query)
+            .await
+            .unwrap()
+        {
+            FirestoreCachedValue::UseCached(stream) => {
+                stream
+                    .map(|doc| split_document_path(&doc.unwrap().name).1.to_string())
+                    .collect()
+                    .await
+            }
+            FirestoreCachedValue::SkipCache => panic!("The query should be served from cache"),
+        };
+        assert_eq!(ids, vec!["b".to_string()]);
+        assert_eq!(
+            backend
+                .get_doc_by_path(&format!("{DOCS}/cities/c"))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_collection_group_queries() {
+        let config = FirestoreCacheConfiguration::new().add_collection_config_at(
+            DOCS,
+            FirestoreCacheCollectionConfiguration::new(
+                "posts",
+                FirestoreListenerTarget::new(TARGET),
+                FirestoreCacheCollectionLoadMode::PreloadAllDocs,
+            )
+            .with_collection_group(true),
+        );
+        let backend = FirestoreMemoryCacheBackend::new(config).unwrap();
+        for document_path in ["users/u1/posts/a", "users/u2/posts/a", "posts/b"] {
+            backend
+                .update_doc_by_path(&FirestoreDocument {
+                    name: format!("{DOCS}/{document_path}"),
+                    fields: HashMap::new(),
+                    create_time: None,
+                    update_time: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let query_paths = |collection_path: String, all_descendants: bool| {
+            let backend = &backend;
+            async move {
+                let query =
+                    FirestoreQueryParams::new(FirestoreQueryCollection::Single("posts".into()))
+                        .with_all_descendants(all_descendants);
+                match backend.query_docs(&collection_path, &query).await.unwrap() {
+                    FirestoreCachedValue::UseCached(stream) => {
+                        let mut paths: Vec<String> = stream
+                            .map(|doc| doc.unwrap().name[DOCS.len() + 1..].to_string())
+                            .collect()
+                            .await;
+                        paths.sort();
+                        paths
+                    }
+                    FirestoreCachedValue::SkipCache => {
+                        panic!("The query should be served from cache")
+                    }
+                }
+            }
+        };
+
+        assert_eq!(
+            query_paths(format!("{DOCS}/posts"), true).await,
+            vec!["posts/b", "users/u1/posts/a", "users/u2/posts/a"]
+        );
+        assert_eq!(
+            query_paths(format!("{DOCS}/users/u1/posts"), true).await,
+            vec!["users/u1/posts/a"]
+        );
+        assert_eq!(
+            query_paths(format!("{DOCS}/posts"), false).await,
+            vec!["posts/b"]
+        );
+        assert!(backend
+            .get_doc_by_path(&format!("{DOCS}/users/u2/posts/a"))
+            .await
+            .unwrap()
+            .is_some());
+        assert!(matches!(
+            backend
+                .list_all_docs(&format!("{DOCS}/comments"))
+                .await
+                .unwrap(),
+            FirestoreCachedValue::SkipCache
+        ));
+    }
+
+    #[tokio::test]
+    async fn drops_collection_when_target_is_removed() {
+        let backend = backend();
+        backend.on_listen_event(doc_change("a")).await.unwrap();
+
+        backend
+            .on_listen_event(target_change(TargetChangeType::Remove, None))
+            .await
+            .unwrap();
+
+        assert_eq!(cached_ids(&backend).await, None);
+        assert_eq!(
+            backend
+                .get_doc_by_path(&format!("{DOCS}/cities/a"))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn deletes_documents_by_path() {
+        let backend = backend();
+        backend.on_listen_event(doc_change("a")).await.unwrap();
+        backend.on_listen_event(doc_change("b")).await.unwrap();
+
+        backend
+            .delete_doc_by_path(&format!("{DOCS}/cities/a"))
+            .await
+            .unwrap();
+
+        assert_eq!(cached_ids(&backend).await, Some(vec!["b".to_string()]));
+        assert_eq!(
+            backend
+                .get_doc_by_path(&format!("{DOCS}/cities/a"))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_collections_status() {
+        let backend = backend();
+        backend.on_listen_event(doc_change("a")).await.unwrap();
+        backend.on_listen_event(doc_change("b")).await.unwrap();
+        let read_time = FirestoreInstant::from_second(1_700_000_000).unwrap();
+        backend
+            .on_listen_event(target_change(TargetChangeType::Current, Some(read_time)))
+            .await
+            .unwrap();
+
+        let collections = backend.collections_status().await.unwrap();
+        assert_eq!(collections.len(), 1);
+        assert_eq!(collections[0].collection_path, collection_path());
+        assert_eq!(
+            collections[0].listener_target,
+            FirestoreListenerTarget::new(TARGET)
+        );
+        assert_eq!(collections[0].document_count, 2);
+        assert_eq!(collections[0].last_synchronized_at, Some(read_time));
+    }
+
+    #[tokio::test]
+    async fn counts_reads_and_listener_updates() {
+        let backend = backend();
+        backend.on_listen_event(doc_change("a")).await.unwrap();
+        backend
+            .on_listen_event(FirestoreListenEvent::DocumentRemove(DocumentRemove {
+                document: format!("{DOCS}/cities/a"),
+                removed_target_ids: vec![TARGET as i32],
+                read_time: None,
+            }))
+            .await
+            .unwrap();
+        backend.on_listen_event(doc_change("b")).await.unwrap();
+
+        backend
+            .get_doc_by_path(&format!("{DOCS}/cities/a"))
+            .await
+            .unwrap();
+        backend
+            .get_doc_by_path(&format!("{DOCS}/cities/b"))
+            .await
+            .unwrap();
+        assert!(cached_ids(&backend).await.is_some());
+        backend
+            .on_listen_event(target_change(TargetChangeType::Reset, None))
+            .await
+            .unwrap();
+        assert!(cached_ids(&backend).await.is_none());
+
+        assert_eq!(
+            backend.metrics(),
+            vec![FirestoreCacheCollectionMetrics::new(collection_path())
+                .with_hits(1)
+                .with_misses(1)
+                .with_query_hits(1)
+                .with_query_fallbacks(1)
+                .with_listener_updates(2)
+                .with_listener_removals(1)]
+        );
+    }
 }
@@ -1,3 +1,12 @@
+#[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
+mod sync_state;
+
+#[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
+mod indexes;
+
+#[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
+mod counters;
+
 #[cfg(feature = "caching-memory")]
 mod memory_backend;
 #[cfg(feature = "caching-memory")]
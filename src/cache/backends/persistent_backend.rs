@@ -3,13 +3,18 @@ use crate::*;
 use async_trait::async_trait;
 use futures::stream::BoxStream;
 
-use crate::cache::cache_query_engine::FirestoreCacheQueryEngine;
+use super::counters::FirestoreCacheCounters;
+use super::indexes::FirestoreCacheIndexes;
+use super::sync_state::FirestoreCacheSyncState;
+use crate::cache::cache_query_engine::{is_doc_in_collection_scope, FirestoreCacheQueryEngine};
+use crate::timestamp_utils::{from_timestamp, to_timestamp};
 use crate::FirestoreInstant;
 use futures::StreamExt;
-use gcloud_sdk::google::firestore::v1::Document;
+use gcloud_sdk::google::firestore::v1::target_change::TargetChangeType;
+use gcloud_sdk::google::firestore::v1::{Document, TargetChange};
 use gcloud_sdk::prost::Message;
 use redb::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use tracing::*;
 
@@ -36,8 +41,25 @@ use tracing::*;
 pub struct FirestorePersistentCacheBackend {
     pub config: FirestoreCacheConfiguration,
     redb: Database,
+    sync_state: FirestoreCacheSyncState,
+    /// When the synchronization time of each collection was last written to the database.
+    sync_state_written_at: std::sync::Mutex<HashMap<String, std::time::Instant>>,
+    indexes: FirestoreCacheIndexes,
+    counters: FirestoreCacheCounters,
 }
 
+/// Keeps the last synchronization time of each collection, keyed by the collection path, so it
+/// survives restarts along with the documents. Collection tables are named by their absolute
+/// paths, so this name cannot clash with them.
+const SYNC_STATE_TABLE: TableDefinition<&str, &[u8]> =
+    TableDefinition::new("__firestore_cache_sync_state");
+
+/// How often the synchronization times carried by every target change, heartbeats included, are
+/// written to the database. They are written right away when a target is reset or current, and
+/// on shutdown, so a crash only loses the progress since the last write, which makes the cache
+/// look less recent than it is after a restart.
+const SYNC_STATE_WRITE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
 /// Tuning options for [`FirestorePersistentCacheBackend`].
 #[derive(Debug, Clone, Default, Eq, PartialEq)]
 pub struct FirestorePersistentCacheOptions {
@@ -111,7 +133,218 @@ impl FirestorePersistentCacheBackend {
         db.compact()?;
         info!("Successfully opened database for persistent cache.");
 
-        Ok(Self { config, redb: db })
+        let indexes = FirestoreCacheIndexes::new(&config);
+        let counters = FirestoreCacheCounters::new(&config);
+
+        let backend = Self {
+            config,
+            redb: db,
+            sync_state: FirestoreCacheSyncState::new(),
+            sync_state_written_at: std::sync::Mutex::new(HashMap::new()),
+            indexes,
+            counters,
+        };
+        backend.restore_sync_state()?;
+        backend.rebuild_indexes()?;
+
+        Ok(backend)
+    }
+
+    fn restore_sync_state(&self) -> FirestoreResult<()> {
+        let read_tx = self.redb.begin_read()?;
+        if read_tx
+            .list_tables()?
+            .any(|t| t.name() == SYNC_STATE_TABLE.name())
+        {
+            let table = read_tx.open_table(SYNC_STATE_TABLE)?;
+            for collection_path in self.config.collections.keys() {
+                if let Some(value) = table.get(collection_path.as_str())? {
+                    let read_time =
+                        from_timestamp(gcloud_sdk::prost_types::Timestamp::decode(value.value())?)?;
+                    self.sync_state.synchronized_at(collection_path, read_time);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Indexes are kept in memory, so they are rebuilt from the documents stored by previous runs.
+    fn rebuild_indexes(&self) -> FirestoreResult<()> {
+        let read_tx = self.redb.begin_read()?;
+        let table_names: HashSet<String> = read_tx
+            .list_tables()?
+            .map(|t| t.name().to_string())
+            .collect();
+
+        for (collection_path, config) in &self.config.collections {
+            if config.indices.is_empty() || !table_names.contains(collection_path) {
+                continue;
+            }
+            let td: TableDefinition<&str, &[u8]> = TableDefinition::new(collection_path);
+            let table = read_tx.open_table(td)?;
+            for record in table.iter()? {
+                let (k, v) = record?;
+                let doc = Self::buf_to_document(v.value())?;
+                if let Err(err) = self
+                    .indexes
+                    .insert_doc(collection_path, k.value(), &doc, |_| true)
+                {
+                    error!(?err, "Error while indexing cached collection.");
+                }
+            }
+            debug!(collection_path, "Cached collection has been indexed.");
+        }
+        Ok(())
+    }
+
+    /// Records the synchronization time of a collection, and writes it to the database unless
+    /// it was written less than [`SYNC_STATE_WRITE_INTERVAL`] ago. `immediately` writes it
+    /// regardless.
+    fn write_synchronized_at(
+        &self,
+        collection_path: &str,
+        read_time: FirestoreInstant,
+        immediately: bool,
+    ) -> FirestoreResult<()> {
+        self.sync_state.synchronized_at(collection_path, read_time);
+
+        let now = std::time::Instant::now();
+        {
+            let mut written_at = self
+                .sync_state_written_at
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let due = written_at.get(collection_path).is_none_or(|written_at| {
+                now.duration_since(*written_at) >= SYNC_STATE_WRITE_INTERVAL
+            });
+            if !immediately && !due {
+                return Ok(());
+            }
+            written_at.insert(collection_path.to_string(), now);
+        }
+        self.persist_synchronized_at(collection_path)
+    }
+
+    fn persist_synchronized_at(&self, collection_path: &str) -> FirestoreResult<()> {
+        if let Some(synchronized_at) = self.sync_state.last_synchronized_at(collection_path) {
+            let write_txn = self.redb.begin_write()?;
+            {
+                let mut table = write_txn.open_table(SYNC_STATE_TABLE)?;
+                table.insert(
+                    collection_path,
+                    to_timestamp(synchronized_at).encode_to_vec().as_slice(),
+                )?;
+            }
+            write_txn.commit()?;
+        }
+        Ok(())
+    }
+
+    fn remove_document(&self, document_path: &str) -> FirestoreResult<()> {
+        if let Some((collection_path, document_key)) =
+            self.config.cached_document_key(document_path)
+        {
+            trace!(removed_doc = document_path, "Removing document from cache.");
+
+            let td: TableDefinition<&str, &[u8]> = TableDefinition::new(collection_path);
+
+            self.indexes.remove_doc(collection_path, document_key);
+
+            let write_txn = self.redb.begin_write()?;
+            {
+                let mut table = write_txn.open_table(td)?;
+                table.remove(document_key)?;
+            }
+            write_txn.commit()?;
+        }
+        Ok(())
+    }
+
+    fn remove_document_on_listen_event(&self, document_path: &str) -> FirestoreResult<()> {
+        if let Some((collection_path, _)) = self.config.cached_document_key(document_path) {
+            self.counters.listener_removal(collection_path);
+        }
+        self.remove_document(document_path)
+    }
+
+    /// Removes the documents of the collection whose IDs are not in `retained_doc_ids`,
+    /// or all of them when it is `None`.
+    fn retain_documents(
+        &self,
+        collection_path: &str,
+        retained_doc_ids: Option<&HashSet<String>>,
+    ) -> FirestoreResult<u64> {
+        self.indexes.retain_docs(collection_path, retained_doc_ids);
+
+        let td: TableDefinition<&str, &[u8]> = TableDefinition::new(collection_path);
+
+        let write_txn = self.redb.begin_write()?;
+        let removed_docs = {
+            let mut table = write_txn.open_table(td)?;
+            let len_before = table.len()?;
+            table.retain(|document_id, _| {
+                retained_doc_ids.is_some_and(|retained| retained.contains(document_id))
+            })?;
+            len_before - table.len()?
+        };
+        write_txn.commit()?;
+        Ok(removed_docs)
+    }
+
+    fn on_target_change(&self, target_change: TargetChange) -> FirestoreResult<()> {
+        let collection_paths = self
+            .config
+            .collection_paths_for_targets(&target_change.target_ids);
+
+        match TargetChangeType::try_from(target_change.target_change_type) {
+            Ok(TargetChangeType::Reset) => {
+                for collection_path in &collection_paths {
+                    debug!(
+                        collection_path = collection_path.as_str(),
+                        "Listener target has been reset. Resynchronizing collection.",
+                    );
+                    self.sync_state.start_resync(collection_path);
+                }
+            }
+            Ok(TargetChangeType::Current) => {
+                for collection_path in &collection_paths {
+                    if let Some(received_doc_ids) = self.sync_state.finish_resync(collection_path) {
+                        let removed_docs =
+                            self.retain_documents(collection_path, Some(&received_doc_ids))?;
+                        debug!(
+                            collection_path = collection_path.as_str(),
+                            removed_docs, "Collection has been resynchronized.",
+                        );
+                    }
+                }
+            }
+            Ok(TargetChangeType::Remove) => {
+                for collection_path in &collection_paths {
+                    error!(
+                        collection_path = collection_path.as_str(),
+                        cause = ?target_change.cause,
+                        "Listener target has been removed by the server. \
+                         The collection will be read from Firestore until the cache is loaded again.",
+                    );
+                    self.sync_state.removed(collection_path);
+                    self.retain_documents(collection_path, None)?;
+                }
+            }
+            Ok(TargetChangeType::NoChange) | Ok(TargetChangeType::Add) | Err(_) => {}
+        }
+
+        if let Some(read_time) = target_change.read_time {
+            let read_time = from_timestamp(read_time)?;
+            let immediately = matches!(
+                TargetChangeType::try_from(target_change.target_change_type),
+                Ok(TargetChangeType::Current) | Ok(TargetChangeType::Reset)
+            );
+            for collection_path in &collection_paths {
+                self.write_synchronized_at(collection_path, read_time, immediately)?;
+            }
+        }
+
+        Ok(())
     }
 
     async fn preload_collections(&self, db: &FirestoreDb) -> Result<(), FirestoreError> {
@@ -151,16 +384,7 @@ impl FirestorePersistentCacheBackend {
                         "Preloading collection."
                     );
 
-                    let params = if let Some(parent) = &config.parent {
-                        db.fluent()
-                            .select()
-                            .from(config.collection_name.as_str())
-                            .parent(parent)
-                    } else {
-                        db.fluent().select().from(config.collection_name.as_str())
-                    };
-
-                    let stream = params.stream_query().await?;
+                    let stream = db.stream_query_doc(config.query_params()).await?;
 
                     stream
                         .enumerate()
@@ -218,9 +442,20 @@ impl FirestorePersistentCacheBackend {
             let mut table = write_txn.open_table(td)?;
 
             for doc in docs {
-                let (_, document_id) = split_document_path(&doc.name);
+                let Some((_, document_key)) = self.config.cached_document_key(&doc.name) else {
+                    continue;
+                };
+                if let Err(err) =
+                    self.indexes
+                        .insert_doc(collection_path, document_key, &doc, |_| true)
+                {
+                    error!(?err, "Error while preloading collection.");
+                    self.indexes.remove_doc(collection_path, document_key);
+                    table.remove(document_key)?;
+                    continue;
+                }
                 let doc_bytes = Self::document_to_buf(&doc)?;
-                table.insert(document_id, doc_bytes.as_slice())?;
+                table.insert(document_key, doc_bytes.as_slice())?;
             }
         }
         write_txn.commit()?;
@@ -242,17 +477,36 @@ impl FirestorePersistentCacheBackend {
         Ok(doc)
     }
 
+    fn read_cached_doc(
+        &self,
+        collection_path: &str,
+        document_key: &str,
+    ) -> FirestoreResult<Option<FirestoreDocument>> {
+        let td: TableDefinition<&str, &[u8]> = TableDefinition::new(collection_path);
+        let read_tx = self.redb.begin_read()?;
+        let table = read_tx.open_table(td)?;
+        let value = table.get(document_key)?;
+        value.map(|v| Self::buf_to_document(v.value())).transpose()
+    }
+
     fn write_document(&self, doc: &Document) -> FirestoreResult<()> {
-        let (collection_path, document_id) = split_document_path(&doc.name);
+        if let Some((collection_path, document_key)) = self.config.cached_document_key(&doc.name) {
+            if let Err(err) = self
+                .indexes
+                .insert_doc(collection_path, document_key, doc, |_| true)
+            {
+                // The previously cached version of the document would be stale
+                self.remove_document(&doc.name)?;
+                return Err(err);
+            }
 
-        if self.config.collections.contains_key(collection_path) {
             let td: TableDefinition<&str, &[u8]> = TableDefinition::new(collection_path);
 
             let write_txn = self.redb.begin_write()?;
             {
                 let mut table = write_txn.open_table(td)?;
                 let doc_bytes = Self::document_to_buf(doc)?;
-                table.insert(document_id, doc_bytes.as_slice())?;
+                table.insert(document_key, doc_bytes.as_slice())?;
             }
             write_txn.commit()?;
             Ok(())
@@ -268,29 +522,122 @@ impl FirestorePersistentCacheBackend {
         Ok(len)
     }
 
+    fn list_cached_docs<'b>(
+        &self,
+        cached_collection_path: &str,
+        collection_path: &str,
+    ) -> FirestoreResult<FirestoreCachedValue<BoxStream<'b, FirestoreResult<FirestoreDocument>>>>
+    {
+        if self.config.is_collection_listable(cached_collection_path)
+            && self.sync_state.is_consistent(cached_collection_path)
+        {
+            let td: TableDefinition<&str, &[u8]> = TableDefinition::new(cached_collection_path);
+
+            let read_tx = self.redb.begin_read()?;
+            let table = read_tx.open_table(td)?;
+            let iter = table.iter()?;
+
+            // It seems there is no way to work with streaming for redb, so this is not efficient
+            let mut docs: Vec<FirestoreResult<FirestoreDocument>> = Vec::new();
+            for record in iter {
+                let (_, v) = record?;
+                let doc = Self::buf_to_document(v.value())?;
+                if is_doc_in_collection_scope(collection_path, false, &doc.name) {
+                    docs.push(Ok(doc));
+                }
+            }
+
+            Ok(FirestoreCachedValue::UseCached(Box::pin(
+                futures::stream::iter(docs),
+            )))
+        } else {
+            Ok(FirestoreCachedValue::SkipCache)
+        }
+    }
+
+    async fn query_cached_collection<'b>(
+        &self,
+        cached_collection_path: &str,
+        collection_path: &str,
+        query: &FirestoreQueryParams,
+    ) -> FirestoreResult<FirestoreCachedValue<BoxStream<'b, FirestoreResult<FirestoreDocument>>>>
+    {
+        if self.config.is_collection_listable(cached_collection_path)
+            && self.sync_state.is_consistent(cached_collection_path)
+        {
+            // For now only basic/simple query all supported
+            let simple_query_engine = FirestoreCacheQueryEngine::new(query);
+            if simple_query_engine.params_supported() {
+                Ok(FirestoreCachedValue::UseCached(
+                    self.query_cached_docs(
+                        cached_collection_path,
+                        collection_path,
+                        simple_query_engine,
+                    )
+                    .await?,
+                ))
+            } else {
+                Ok(FirestoreCachedValue::SkipCache)
+            }
+        } else {
+            Ok(FirestoreCachedValue::SkipCache)
+        }
+    }
+
     async fn query_cached_docs<'b>(
         &self,
+        cached_collection_path: &str,
         collection_path: &str,
         query_engine: FirestoreCacheQueryEngine,
     ) -> FirestoreResult<BoxStream<'b, FirestoreResult<FirestoreDocument>>> {
-        let td: TableDefinition<&str, &[u8]> = TableDefinition::new(collection_path);
+        let td: TableDefinition<&str, &[u8]> = TableDefinition::new(cached_collection_path);
 
         let read_tx = self.redb.begin_read()?;
         let table = read_tx.open_table(td)?;
-        let iter = table.iter()?;
+
+        let all_descendants = query_engine.query.all_descendants.unwrap_or(false);
+        let matches_doc = |doc: &FirestoreDocument| {
+            is_doc_in_collection_scope(collection_path, all_descendants, &doc.name)
+                && query_engine.matches_doc(doc)
+        };
 
         // It seems there is no way to work with streaming for redb, so this is not efficient
         let mut docs: Vec<FirestoreResult<FirestoreDocument>> = Vec::new();
-        for record in iter {
-            let (_, v) = record?;
-            let doc = Self::buf_to_document(v.value())?;
-            if query_engine.matches_doc(&doc) {
-                docs.push(Ok(doc));
+        let ordered = match self
+            .indexes
+            .lookup(cached_collection_path, &query_engine.query)
+        {
+            Some(lookup) => {
+                for document_key in &lookup.document_ids {
+                    if let Some(v) = table.get(document_key.as_str())? {
+                        let doc = Self::buf_to_document(v.value())?;
+                        if matches_doc(&doc) {
+                            docs.push(Ok(doc));
+                        }
+                    }
+                }
+                lookup.ordered
             }
-        }
+            None => {
+                for record in table.iter()? {
+                    let (_, v) = record?;
+                    let doc = Self::buf_to_document(v.value())?;
+                    if matches_doc(&doc) {
+                        docs.push(Ok(doc));
+                    }
+                }
+                false
+            }
+        };
 
         let filtered_stream = Box::pin(futures::stream::iter(docs));
-        let output_stream = query_engine.process_query_stream(filtered_stream).await?;
+        let output_stream = if ordered {
+            query_engine
+                .process_sorted_query_stream(filtered_stream)
+                .await?
+        } else {
+            query_engine.process_query_stream(filtered_stream).await?
+        };
 
         Ok(output_stream)
     }
@@ -305,35 +652,39 @@ impl FirestoreCacheBackend for FirestorePersistentCacheBackend {
     ) -> Result<Vec<FirestoreListenerTargetParams>, FirestoreError> {
         let read_from_time = FirestoreInstant::now();
 
+        // Collections the server stopped listening to are listened to again, only the stored
+        // synchronization times still apply
+        self.sync_state.clear();
+        self.restore_sync_state()?;
+
         self.preload_collections(db).await?;
 
-        Ok(self
-            .config
-            .collections
-            .iter()
-            .map(|(collection_path, collection_config)| {
-                let collection_table_len = self.table_len(collection_path).ok().unwrap_or(0);
-                let resume_type = if collection_table_len == 0 {
-                    Some(FirestoreListenerTargetResumeType::ReadTime(read_from_time))
-                } else {
-                    None
-                };
+        let mut target_params = Vec::with_capacity(self.config.collections.len());
+        for (collection_path, collection_config) in &self.config.collections {
+            let collection_table_len = self.table_len(collection_path).ok().unwrap_or(0);
+            let resume_type = if collection_table_len == 0 {
+                // Listening from the time the preloading started, so the collection is in sync
+                // with it as soon as the listener is running
+                self.write_synchronized_at(collection_path, read_from_time, true)?;
+                Some(FirestoreListenerTargetResumeType::ReadTime(read_from_time))
+            } else {
+                None
+            };
+            target_params.push(
                 FirestoreListenerTargetParams::new(
                     collection_config.listener_target.clone(),
-                    FirestoreTargetType::Query(
-                        FirestoreQueryParams::new(
-                            collection_config.collection_name.as_str().into(),
-                        )
-                        .opt_parent(collection_config.parent.clone()),
-                    ),
+                    FirestoreTargetType::Query(collection_config.query_params()),
                     HashMap::new(),
                 )
-                .opt_resume_type(resume_type)
-            })
-            .collect())
+                .opt_resume_type(resume_type),
+            );
+        }
+
+        Ok(target_params)
     }
 
     async fn invalidate_all(&self) -> FirestoreResult<()> {
+        self.indexes.clear();
         for collection_path in self.config.collections.keys() {
             let td: TableDefinition<&str, &[u8]> = TableDefinition::new(collection_path.as_str());
 
@@ -353,6 +704,9 @@ impl FirestoreCacheBackend for FirestorePersistentCacheBackend {
     }
 
     async fn shutdown(&self) -> Result<(), FirestoreError> {
+        for collection_path in self.config.collections.keys() {
+            self.persist_synchronized_at(collection_path)?;
+        }
         Ok(())
     }
 
@@ -365,32 +719,69 @@ impl FirestoreCacheBackend for FirestorePersistentCacheBackend {
                         "Writing document to cache due to listener event.",
                     );
 
+                    if let Some((collection_path, document_key)) =
+                        self.config.cached_document_key(&doc.name)
+                    {
+                        self.sync_state
+                            .document_received(collection_path, document_key);
+                        self.counters.listener_update(collection_path);
+                    }
                     self.write_document(&doc)?;
                 }
                 Ok(())
             }
             FirestoreListenEvent::DocumentDelete(doc_deleted) => {
-                let (collection_path, document_id) = split_document_path(&doc_deleted.document);
+                self.remove_document_on_listen_event(&doc_deleted.document)
+            }
+            FirestoreListenEvent::DocumentRemove(doc_removed) => {
+                self.remove_document_on_listen_event(&doc_removed.document)
+            }
+            FirestoreListenEvent::TargetChange(target_change) => {
+                self.on_target_change(target_change)
+            }
+            // The document count of a resumed target, only useful to clients that can tell which
+            // of their documents were removed while they were not listening
+            FirestoreListenEvent::Filter(_) => Ok(()),
+        }
+    }
 
-                if self.config.collections.contains_key(collection_path) {
-                    trace!(
-                        deleted_doc = ?doc_deleted.document.as_str(),
-                        "Removing document from cache due to listener event.",
-                    );
+    async fn last_synchronized_at(
+        &self,
+        collection_path: &str,
+    ) -> FirestoreResult<Option<FirestoreInstant>> {
+        Ok(self.sync_state.last_synchronized_at(collection_path))
+    }
 
+    async fn collections_status(&self) -> FirestoreResult<Vec<FirestoreCacheCollectionStatus>> {
+        let read_tx = self.redb.begin_read()?;
+        let table_names: HashSet<String> = read_tx
+            .list_tables()?
+            .map(|t| t.name().to_string())
+            .collect();
+
+        self.config
+            .collections
+            .iter()
+            .map(|(collection_path, config)| {
+                let document_count = if table_names.contains(collection_path) {
                     let td: TableDefinition<&str, &[u8]> = TableDefinition::new(collection_path);
+                    read_tx.open_table(td)?.len()?
+                } else {
+                    0
+                };
+                Ok(FirestoreCacheCollectionStatus::new(
+                    collection_path.clone(),
+                    config.listener_target.clone(),
+                    config.collection_load_mode,
+                    document_count,
+                )
+                .opt_last_synchronized_at(self.sync_state.last_synchronized_at(collection_path)))
+            })
+            .collect()
+    }
 
-                    let write_txn = self.redb.begin_write()?;
-                    {
-                        let mut table = write_txn.open_table(td)?;
-                        table.remove(document_id)?;
-                    }
-                    write_txn.commit()?;
-                }
-                Ok(())
-            }
-            _ => Ok(()),
-        }
+    fn metrics(&self) -> Vec<FirestoreCacheCollectionMetrics> {
+        self.counters.snapshot()
     }
 }
 
@@ -400,48 +791,54 @@ impl FirestoreCacheDocsByPathSupport for FirestorePersistentCacheBackend {
         &self,
         document_path: &str,
     ) -> FirestoreResult<Option<FirestoreDocument>> {
-        let (collection_path, document_id) = split_document_path(document_path);
-        if self.config.collections.contains_key(collection_path) {
-            let td: TableDefinition<&str, &[u8]> = TableDefinition::new(collection_path);
-            let read_tx = self.redb.begin_read()?;
-            let table = read_tx.open_table(td)?;
-            let value = table.get(document_id)?;
-            value.map(|v| Self::buf_to_document(v.value())).transpose()
+        if let Some((collection_path, document_key)) =
+            self.config.cached_document_key(document_path)
+        {
+            let cached_doc = self.read_cached_doc(collection_path, document_key)?;
+            self.counters.doc_read(collection_path, &cached_doc);
+            Ok(cached_doc)
         } else {
             Ok(None)
         }
     }
 
+    async fn peek_doc_by_path(
+        &self,
+        document_path: &str,
+    ) -> FirestoreResult<Option<FirestoreDocument>> {
+        match self.config.cached_document_key(document_path) {
+            Some((collection_path, document_key)) => {
+                self.read_cached_doc(collection_path, document_key)
+            }
+            None => Ok(None),
+        }
+    }
+
     async fn update_doc_by_path(&self, document: &FirestoreDocument) -> FirestoreResult<()> {
         self.write_document(document)?;
         Ok(())
     }
 
+    async fn delete_doc_by_path(&self, document_path: &str) -> FirestoreResult<()> {
+        self.remove_document(document_path)
+    }
+
     async fn list_all_docs<'b>(
         &self,
         collection_path: &str,
     ) -> FirestoreResult<FirestoreCachedValue<BoxStream<'b, FirestoreResult<FirestoreDocument>>>>
     {
-        if self.config.is_collection_listable(collection_path) {
-            let td: TableDefinition<&str, &[u8]> = TableDefinition::new(collection_path);
-
-            let read_tx = self.redb.begin_read()?;
-            let table = read_tx.open_table(td)?;
-            let iter = table.iter()?;
-
-            // It seems there is no way to work with streaming for redb, so this is not efficient
-            let mut docs: Vec<FirestoreResult<FirestoreDocument>> = Vec::new();
-            for record in iter {
-                let (_, v) = record?;
-                let doc = Self::buf_to_document(v.value())?;
-                docs.push(Ok(doc));
+        match self
+            .config
+            .cached_collection_for_query(collection_path, false)
+        {
+            Some((cached_collection_path, _)) => {
+                let cached_docs = self.list_cached_docs(cached_collection_path, collection_path)?;
+                self.counters
+                    .query_served(cached_collection_path, &cached_docs);
+                Ok(cached_docs)
             }
-
-            Ok(FirestoreCachedValue::UseCached(Box::pin(
-                futures::stream::iter(docs),
-            )))
-        } else {
-            Ok(FirestoreCachedValue::SkipCache)
+            None => Ok(FirestoreCachedValue::SkipCache),
         }
     }
 
@@ -451,19 +848,19 @@ impl FirestoreCacheDocsByPathSupport for FirestorePersistentCacheBackend {
         query: &FirestoreQueryParams,
     ) -> FirestoreResult<FirestoreCachedValue<BoxStream<'b, FirestoreResult<FirestoreDocument>>>>
     {
-        if self.config.is_collection_listable(collection_path) {
-            // For now only basic/simple query all supported
-            let simple_query_engine = FirestoreCacheQueryEngine::new(query);
-            if simple_query_engine.params_supported() {
-                Ok(FirestoreCachedValue::UseCached(
-                    self.query_cached_docs(collection_path, simple_query_engine)
-                        .await?,
-                ))
-            } else {
-                Ok(FirestoreCachedValue::SkipCache)
+        match self
+            .config
+            .cached_collection_for_query(collection_path, query.all_descendants.unwrap_or(false))
+        {
+            Some((cached_collection_path, _)) => {
+                let cached_docs = self
+                    .query_cached_collection(cached_collection_path, collection_path, query)
+                    .await?;
+                self.counters
+                    .query_served(cached_collection_path, &cached_docs);
+                Ok(cached_docs)
             }
-        } else {
-            Ok(FirestoreCachedValue::SkipCache)
+            None => Ok(FirestoreCachedValue::SkipCache),
         }
     }
 }
@@ -530,3 +927,191 @@ impl From<redb::CompactionError> for FirestoreError {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gcloud_sdk::google::firestore::v1::DocumentChange;
+
+    const DOCS: &str = "projects/test/databases/(default)/documents";
+    const TARGET: u32 = 42;
+
+    fn backend(data_dir: &tempfile::TempDir) -> FirestorePersistentCacheBackend {
+        let config = FirestoreCacheConfiguration::new().add_collection_config_at(
+            DOCS,
+            FirestoreCacheCollectionConfiguration::new(
+                "cities",
+                FirestoreListenerTarget::new(TARGET),
+                FirestoreCacheCollectionLoadMode::PreloadAllIfEmpty,
+            ),
+        );
+        FirestorePersistentCacheBackend::with_options(config, data_dir.path().join("cache.redb"))
+            .unwrap()
+    }
+
+    fn collection_path() -> String {
+        format!("{DOCS}/cities")
+    }
+
+    fn doc_change(id: &str) -> FirestoreListenEvent {
+        FirestoreListenEvent::DocumentChange(DocumentChange {
+            document: Some(FirestoreDocument {
+                name: format!("{DOCS}/cities/{id}"),
+                fields: HashMap::new(),
+                create_time: None,
+                update_time: None,
+            }),
+            target_ids: vec![TARGET as i32],
+            removed_target_ids: vec![],
+        })
+    }
+
+    async fn cached_ids(backend: &FirestorePersistentCacheBackend) -> Option<Vec<String>> {
+        match backend.list_all_docs(&collection_path()).await.unwrap() {
+            FirestoreCachedValue::UseCached(stream) => {
+                let mut ids: Vec<String> = stream
+                    .map(|doc| split_document_path(&doc.unwrap().name).1.to_string())
+                    .collect()
+                    .await;
+                ids.sort();
+                Some(ids)
+            }
+            FirestoreCachedValue::SkipCache => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_collection_again_after_target_removal_and_load() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let backend = backend(&data_dir);
+        backend.on_listen_event(doc_change("a")).await.unwrap();
+        assert_eq!(cached_ids(&backend).await, Some(vec!["a".to_string()]));
+
+        backend
+            .on_listen_event(FirestoreListenEvent::TargetChange(TargetChange {
+                target_change_type: TargetChangeType::Remove as i32,
+                target_ids: vec![TARGET as i32],
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        assert_eq!(cached_ids(&backend).await, None);
+
+        // Not empty anymore, so loading doesn't preload the collection from Firestore
+        backend.on_listen_event(doc_change("b")).await.unwrap();
+        let db = FirestoreDb::for_offline_tests().await;
+        backend
+            .load(
+                &FirestoreCacheOptions::new(FirestoreCacheName::new("test".into())),
+                &db,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(cached_ids(&backend).await, Some(vec!["b".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn writes_synchronization_times_of_heartbeats_at_intervals() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let backend = backend(&data_dir);
+        let target_change = |change_type: TargetChangeType, secs| {
+            FirestoreListenEvent::TargetChange(TargetChange {
+                target_change_type: change_type as i32,
+                target_ids: vec![TARGET as i32],
+                read_time: Some(to_timestamp(FirestoreInstant::from_second(secs).unwrap())),
+                ..Default::default()
+            })
+        };
+        let stored_synchronized_at = || {
+            let read_tx = backend.redb.begin_read().unwrap();
+            let table = read_tx.open_table(SYNC_STATE_TABLE).unwrap();
+            let value = table.get(collection_path().as_str()).unwrap().unwrap();
+            from_timestamp(gcloud_sdk::prost_types::Timestamp::decode(value.value()).unwrap())
+                .unwrap()
+        };
+
+        backend
+            .on_listen_event(target_change(TargetChangeType::Current, 100))
+            .await
+            .unwrap();
+        assert_eq!(
+            stored_synchronized_at(),
+            FirestoreInstant::from_second(100).unwrap()
+        );
+
+        backend
+            .on_listen_event(target_change(TargetChangeType::NoChange, 200))
+            .await
+            .unwrap();
+        assert_eq!(
+            backend
+                .last_synchronized_at(&collection_path())
+                .await
+                .unwrap(),
+            Some(FirestoreInstant::from_second(200).unwrap())
+        );
+        assert_eq!(
+            stored_synchronized_at(),
+            FirestoreInstant::from_second(100).unwrap()
+        );
+
+        backend.shutdown().await.unwrap();
+        assert_eq!(
+            stored_synchronized_at(),
+            FirestoreInstant::from_second(200).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_reads_and_listener_updates() {
+        let data_dir = tempfile::tempdir().unwrap();
+        let backend = backend(&data_dir);
+        backend.on_listen_event(doc_change("a")).await.unwrap();
+        backend
+            .on_listen_event(FirestoreListenEvent::DocumentRemove(
+                gcloud_sdk::google::firestore::v1::DocumentRemove {
+                    document: format!("{DOCS}/cities/a"),
+                    removed_target_ids: vec![TARGET as i32],
+                    read_time: None,
+                },
+            ))
+            .await
+            .unwrap();
+        backend.on_listen_event(doc_change("b")).await.unwrap();
+
+        backend
+            .get_doc_by_path(&format!("{DOCS}/cities/a"))
+            .await
+            .unwrap();
+        backend
+            .get_doc_by_path(&format!("{DOCS}/cities/b"))
+            .await
+            .unwrap();
+        backend
+            .peek_doc_by_path(&format!("{DOCS}/cities/b"))
+            .await
+            .unwrap();
+        assert!(cached_ids(&backend).await.is_some());
+        backend
+            .on_listen_event(FirestoreListenEvent::TargetChange(TargetChange {
+                target_change_type: TargetChangeType::Remove as i32,
+                target_ids: vec![TARGET as i32],
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        assert!(cached_ids(&backend).await.is_none());
+
+        assert_eq!(
+            backend.metrics(),
+            vec![FirestoreCacheCollectionMetrics::new(collection_path())
+                .with_hits(1)
+                .with_misses(1)
+                .with_query_hits(1)
+                .with_query_fallbacks(1)
+                .with_listener_updates(2)
+                .with_listener_removals(1)]
+        );
+    }
+}
@@ -0,0 +1,98 @@
+use crate::FirestoreInstant;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+/// Tracks how far each cached collection is synchronized with Firestore, as reported by the
+/// target changes of the cache listener.
+///
+/// Shared by the cache backends, which keep the documents themselves.
+#[derive(Default)]
+pub(crate) struct FirestoreCacheSyncState {
+    collections: Mutex<HashMap<String, FirestoreCacheCollectionSyncState>>,
+}
+
+#[derive(Default)]
+struct FirestoreCacheCollectionSyncState {
+    last_synchronized_at: Option<FirestoreInstant>,
+    /// The IDs of the documents received since the server reset the target, while the reset is
+    /// in progress. Whatever was not received again is gone once the target is current.
+    resync_doc_ids: Option<HashSet<String>>,
+    /// The server removed the target, so the collection is not kept current anymore.
+    removed: bool,
+}
+
+impl FirestoreCacheSyncState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn last_synchronized_at(&self, collection_path: &str) -> Option<FirestoreInstant> {
+        self.with_collection(collection_path, |state| state.last_synchronized_at)
+    }
+
+    /// Records a consistent snapshot of the collection, unless a reset is still in progress
+    /// (the snapshot would not include the documents the server has yet to resend).
+    pub fn synchronized_at(&self, collection_path: &str, read_time: FirestoreInstant) {
+        self.with_collection(collection_path, |state| {
+            if state.resync_doc_ids.is_none() && !state.removed {
+                state.last_synchronized_at = Some(
+                    state
+                        .last_synchronized_at
+                        .map_or(read_time, |last| last.max(read_time)),
+                );
+            }
+        })
+    }
+
+    /// Returns `true` when the cached documents can serve complete listings of the collection.
+    pub fn is_consistent(&self, collection_path: &str) -> bool {
+        self.with_collection(collection_path, |state| {
+            state.resync_doc_ids.is_none() && !state.removed
+        })
+    }
+
+    pub fn start_resync(&self, collection_path: &str) {
+        self.with_collection(collection_path, |state| {
+            state.resync_doc_ids = Some(HashSet::new());
+        })
+    }
+
+    pub fn document_received(&self, collection_path: &str, document_id: &str) {
+        self.with_collection(collection_path, |state| {
+            if let Some(resync_doc_ids) = state.resync_doc_ids.as_mut() {
+                resync_doc_ids.insert(document_id.to_string());
+            }
+        })
+    }
+
+    /// Finishes the reset in progress, returning the IDs of the documents that were received
+    /// again. The other cached documents of the collection should be removed.
+    pub fn finish_resync(&self, collection_path: &str) -> Option<HashSet<String>> {
+        self.with_collection(collection_path, |state| state.resync_doc_ids.take())
+    }
+
+    pub fn removed(&self, collection_path: &str) {
+        self.with_collection(collection_path, |state| {
+            state.removed = true;
+            state.resync_doc_ids = None;
+        })
+    }
+
+    pub fn clear(&self) {
+        self.collections
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clear();
+    }
+
+    fn with_collection<F, R>(&self, collection_path: &str, f: F) -> R
+    where
+        F: FnOnce(&mut FirestoreCacheCollectionSyncState) -> R,
+    {
+        let mut collections = self
+            .collections
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        f(collections.entry(collection_path.to_string()).or_default())
+    }
+}
@@ -124,6 +124,8 @@ pub struct FirestoreCacheCollection {
     parent: Option<String>,
     load_mode: FirestoreCacheCollectionLoadMode,
     listener_target: Option<u32>,
+    indices: Vec<FirestoreCacheIndexConfiguration>,
+    collection_group: bool,
 }
 
 impl FirestoreCacheCollection {
@@ -134,6 +136,8 @@ impl FirestoreCacheCollection {
             parent: None,
             load_mode,
             listener_target: None,
+            indices: Vec::new(),
+            collection_group: false,
         }
     }
 
@@ -148,6 +152,17 @@ impl FirestoreCacheCollection {
         }
     }
 
+    /// Caches the collection group: every collection with this ID under the parent, or in the
+    /// whole database without a parent, at any depth. Preloaded, it also serves collection group
+    /// queries. See [`FirestoreCacheCollectionConfiguration::with_collection_group`].
+    #[inline]
+    pub fn collection_group(self) -> Self {
+        Self {
+            collection_group: true,
+            ..self
+        }
+    }
+
     /// Downloads every document at startup. Enables cached `list` and `query`.
     #[inline]
     pub fn preload_all(self) -> Self {
@@ -184,6 +199,14 @@ impl FirestoreCacheCollection {
             ..self
         }
     }
+
+    /// Adds a secondary index, used to serve cached queries without scanning the whole
+    /// collection. See [`FirestoreCacheIndexConfiguration`].
+    #[inline]
+    pub fn index(mut self, index: FirestoreCacheIndexConfiguration) -> Self {
+        self.indices.push(index);
+        self
+    }
 }
 
 impl From<FirestoreCacheCollectionConfiguration> for FirestoreCacheCollection {
@@ -193,6 +216,8 @@ impl From<FirestoreCacheCollectionConfiguration> for FirestoreCacheCollection {
             parent: config.parent,
             load_mode: config.collection_load_mode,
             listener_target: Some(*config.listener_target.value()),
+            indices: config.indices,
+            collection_group: config.collection_group,
         }
     }
 }
@@ -223,6 +248,8 @@ where
     db: FirestoreDb,
     name: Option<FirestoreCacheName>,
     listener_params: Option<FirestoreListenerParams>,
+    max_staleness: Option<std::time::Duration>,
+    metrics_report_interval: Option<std::time::Duration>,
     listener_target_base: u32,
     collections: Vec<FirestoreCacheCollection>,
     backend_options: K::Options,
@@ -234,12 +261,15 @@ impl<K> FirestoreCacheBuilder<K, K::DefaultStorage>
 where
     K: FirestoreCacheBackendKind,
 {
+    #[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
     #[inline]
     pub(crate) fn new(db: &FirestoreDb) -> Self {
         Self {
             db: db.clone(),
             name: None,
             listener_params: None,
+            max_staleness: None,
+            metrics_report_interval: None,
             listener_target_base: FIRESTORE_CACHE_DEFAULT_LISTENER_TARGET_BASE,
             collections: Vec::new(),
             backend_options: K::Options::default(),
@@ -340,6 +370,27 @@ where
         }
     }
 
+    /// Stops `read_through_cache` from serving reads from the cache once the listener has been
+    /// disconnected for longer than `max_staleness`. Reads go to Firestore until the listener is
+    /// connected again. `read_cached_only` is not affected.
+    #[inline]
+    pub fn max_staleness(self, max_staleness: std::time::Duration) -> Self {
+        Self {
+            max_staleness: Some(max_staleness),
+            ..self
+        }
+    }
+
+    /// Emits the cache metrics as `tracing` events every `interval` while the cache is loaded.
+    /// See [`FirestoreCache::report_metrics`].
+    #[inline]
+    pub fn metrics_report_interval(self, interval: std::time::Duration) -> Self {
+        Self {
+            metrics_report_interval: Some(interval),
+            ..self
+        }
+    }
+
     /// Controls whether collections that are not preloaded may serve `list`/`query` from the
     /// cache. The default refuses to, because the results would be silently partial.
     #[inline]
@@ -365,6 +416,8 @@ where
             db: self.db,
             name: self.name,
             listener_params: self.listener_params,
+            max_staleness: self.max_staleness,
+            metrics_report_interval: self.metrics_report_interval,
             listener_target_base: self.listener_target_base,
             collections: self.collections,
             backend_options: self.backend_options,
@@ -399,7 +452,10 @@ where
             .name
             .unwrap_or_else(|| FirestoreCacheName::new(FIRESTORE_CACHE_DEFAULT_NAME.to_string()));
 
-        let options = FirestoreCacheOptions::new(name).opt_listener_params(self.listener_params);
+        let options = FirestoreCacheOptions::new(name)
+            .opt_listener_params(self.listener_params)
+            .opt_max_staleness(self.max_staleness)
+            .opt_metrics_report_interval(self.metrics_report_interval);
 
         FirestoreCache::create(options, &self.db, backend, self.storage).await
     }
@@ -541,10 +597,15 @@ fn build_configuration(
             &collection.collection_name,
             listener_target,
             collection.load_mode,
-        );
+        )
+        .with_collection_group(collection.collection_group);
         if let Some(ref parent) = collection.parent {
             collection_config = collection_config.with_parent(parent);
         }
+        for index in &collection.indices {
+            index.validate()?;
+            collection_config = collection_config.with_index(index.clone());
+        }
 
         let collection_path = collection_config.resolve_collection_path(documents_path);
         if !seen_paths.insert(collection_path.clone()) {
@@ -562,6 +623,30 @@ fn build_configuration(
         config = config.add_collection_config_at(documents_path, collection_config);
     }
 
+    // A document is cached in at most one place, so collections must not overlap groups
+    for (group_path, group_config) in config
+        .collections
+        .iter()
+        .filter(|(_, collection_config)| collection_config.collection_group)
+    {
+        if let Some(collection_path) = config.collections.keys().find(|collection_path| {
+            *collection_path != group_path
+                && group_config
+                    .relative_group_path(group_path, collection_path)
+                    .is_some()
+        }) {
+            return Err(FirestoreError::InvalidParametersError(
+                FirestoreInvalidParametersError::new(FirestoreInvalidParametersPublicDetails::new(
+                    "collection_group".into(),
+                    format!(
+                        "The collection `{collection_path}` is already cached as part of the \
+                         collection group `{group_path}`."
+                    ),
+                )),
+            ));
+        }
+    }
+
     Ok(config)
 }
 
@@ -635,6 +720,46 @@ mod tests {
         assert!(matches!(err, FirestoreError::InvalidParametersError(_)));
     }
 
+    #[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
+    #[test]
+    fn rejects_collections_cached_by_a_collection_group() {
+        let parent = format!("{DOCS}/users/u1");
+        let err = build_configuration(
+            DOCS,
+            1000,
+            &[
+                lazy("posts").collection_group(),
+                lazy("posts").parent(&parent),
+            ],
+            FirestoreCacheIncompleteCollectionPolicy::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, FirestoreError::InvalidParametersError(_)));
+
+        let config = build_configuration(
+            DOCS,
+            1000,
+            &[
+                lazy("posts").parent(&parent).collection_group(),
+                lazy("posts").parent(format!("{DOCS}/users/u2")),
+            ],
+            FirestoreCacheIncompleteCollectionPolicy::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            config.cached_document_key(&format!("{parent}/posts/p1/posts/p2")),
+            Some((&format!("{parent}/posts"), "posts/p1/posts/p2"))
+        );
+        assert_eq!(
+            config.cached_document_key(&format!("{DOCS}/users/u2/posts/p1")),
+            Some((&format!("{DOCS}/users/u2/posts"), "p1"))
+        );
+        assert_eq!(
+            config.cached_document_key(&format!("{DOCS}/users/u3/posts/p1")),
+            None
+        );
+    }
+
     #[test]
     fn rejects_the_same_collection_twice() {
         let err = build_configuration(
@@ -674,6 +799,21 @@ mod tests {
         assert!(matches!(err, FirestoreError::InvalidParametersError(_)));
     }
 
+    #[test]
+    fn rejects_indexes_without_document_fields() {
+        for fields in [vec![], vec!["__name__"]] {
+            let err = build_configuration(
+                DOCS,
+                1000,
+                &[lazy("a").index(FirestoreCacheIndexConfiguration::new(fields))],
+                FirestoreCacheIncompleteCollectionPolicy::default(),
+            )
+            .unwrap_err();
+
+            assert!(matches!(err, FirestoreError::InvalidParametersError(_)));
+        }
+    }
+
     #[test]
     fn resolves_sub_collections_under_their_parent() {
         let parent = format!("{DOCS}/users/user-1");
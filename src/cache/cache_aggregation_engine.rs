@@ -0,0 +1,238 @@
+use crate::*;
+use gcloud_sdk::google::firestore::v1::value::ValueType;
+use gcloud_sdk::google::firestore::v1::Value;
+use std::collections::HashMap;
+
+/// Evaluates aggregations (`count`, `sum`, `avg`) locally over a set of documents.
+///
+/// The result has the same shape as a `RunAggregationQuery` response converted by
+/// [`FirestoreDb`]: a nameless document with one field per aggregation alias.
+pub struct FirestoreCacheAggregationEngine<'a> {
+    aggregations: &'a [FirestoreAggregation],
+}
+
+impl<'a> FirestoreCacheAggregationEngine<'a> {
+    pub fn new(aggregations: &'a [FirestoreAggregation]) -> Self {
+        Self { aggregations }
+    }
+
+    pub fn aggregate_docs(&self, docs: &[FirestoreDocument]) -> FirestoreDocument {
+        let fields: HashMap<String, Value> = self
+            .aggregations
+            .iter()
+            .filter_map(|aggregation| {
+                aggregation.operator.as_ref().map(|operator| {
+                    (
+                        aggregation.alias.clone(),
+                        Value {
+                            value_type: Some(Self::aggregate_operator(operator, docs)),
+                        },
+                    )
+                })
+            })
+            .collect();
+
+        FirestoreDocument {
+            name: "".to_string(),
+            fields,
+            create_time: None,
+            update_time: None,
+        }
+    }
+
+    fn aggregate_operator(
+        operator: &FirestoreAggregationOperator,
+        docs: &[FirestoreDocument],
+    ) -> ValueType {
+        match operator {
+            FirestoreAggregationOperator::Count(count) => {
+                let total = count
+                    .up_to
+                    .map(|up_to| docs.len().min(up_to))
+                    .unwrap_or(docs.len());
+                ValueType::IntegerValue(total as i64)
+            }
+            FirestoreAggregationOperator::Sum(sum) => {
+                Self::sum_values(Self::numeric_values(docs, &sum.field_name))
+            }
+            FirestoreAggregationOperator::Avg(avg) => {
+                let values: Vec<&ValueType> = Self::numeric_values(docs, &avg.field_name).collect();
+                if values.is_empty() {
+                    ValueType::NullValue(0)
+                } else {
+                    let total: f64 = values.iter().map(|value| Self::as_f64(value)).sum();
+                    ValueType::DoubleValue(total / values.len() as f64)
+                }
+            }
+        }
+    }
+
+    /// Numeric values of the field; documents where the field is missing or holds a
+    /// non-numeric value are ignored, as Firestore does.
+    fn numeric_values<'d>(
+        docs: &'d [FirestoreDocument],
+        field_name: &'d str,
+    ) -> impl Iterator<Item = &'d ValueType> + 'd {
+        docs.iter()
+            .filter_map(move |doc| firestore_doc_get_field_by_path(doc, field_name))
+            .filter(|value| {
+                matches!(
+                    value,
+                    ValueType::IntegerValue(_) | ValueType::DoubleValue(_)
+                )
+            })
+    }
+
+    /// Sums the values, staying an integer while every value is an integer and the sum does not
+    /// overflow, and switching to a double otherwise.
+    fn sum_values<'d>(values: impl Iterator<Item = &'d ValueType>) -> ValueType {
+        let mut int_sum: Option<i64> = Some(0);
+        let mut double_sum: f64 = 0.0;
+
+        for value in values {
+            match (value, int_sum) {
+                (ValueType::IntegerValue(int_value), Some(current)) => {
+                    match current.checked_add(*int_value) {
+                        Some(sum) => int_sum = Some(sum),
+                        None => {
+                            double_sum = current as f64 + *int_value as f64;
+                            int_sum = None;
+                        }
+                    }
+                }
+                (value, Some(current)) => {
+                    double_sum = current as f64 + Self::as_f64(value);
+                    int_sum = None;
+                }
+                (value, None) => double_sum += Self::as_f64(value),
+            }
+        }
+
+        match int_sum {
+            Some(sum) => ValueType::IntegerValue(sum),
+            None => ValueType::DoubleValue(double_sum),
+        }
+    }
+
+    fn as_f64(value: &ValueType) -> f64 {
+        match value {
+            ValueType::IntegerValue(int_value) => *int_value as f64,
+            ValueType::DoubleValue(double_value) => *double_value,
+            _ => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn doc_with_value(value: ValueType) -> FirestoreDocument {
+        FirestoreDocument {
+            name: "projects/test/databases/(default)/documents/test/doc".to_string(),
+            fields: HashMap::from([(
+                "num".to_string(),
+                Value {
+                    value_type: Some(value),
+                },
+            )]),
+            create_time: None,
+            update_time: None,
+        }
+    }
+
+    fn aggregation(alias: &str, operator: FirestoreAggregationOperator) -> FirestoreAggregation {
+        FirestoreAggregation::new(alias.to_string()).with_operator(operator)
+    }
+
+    #[test]
+    fn aggregate_count_sum_avg() {
+        let docs = vec![
+            doc_with_value(ValueType::IntegerValue(1)),
+            doc_with_value(ValueType::IntegerValue(2)),
+            doc_with_value(ValueType::StringValue("ignored".to_string())),
+        ];
+
+        let aggregations = vec![
+            aggregation(
+                "cnt",
+                FirestoreAggregationOperator::Count(FirestoreAggregationOperatorCount::new()),
+            ),
+            aggregation(
+                "cnt_up_to",
+                FirestoreAggregationOperator::Count(
+                    FirestoreAggregationOperatorCount::new().with_up_to(2),
+                ),
+            ),
+            aggregation(
+                "sum",
+                FirestoreAggregationOperator::Sum(FirestoreAggregationOperatorSum::new(
+                    "num".to_string(),
+                )),
+            ),
+            aggregation(
+                "avg",
+                FirestoreAggregationOperator::Avg(FirestoreAggregationOperatorAvg::new(
+                    "num".to_string(),
+                )),
+            ),
+        ];
+
+        let result = FirestoreCacheAggregationEngine::new(&aggregations).aggregate_docs(&docs);
+
+        let field = |name: &str| result.fields.get(name).and_then(|v| v.value_type.clone());
+        assert_eq!(field("cnt"), Some(ValueType::IntegerValue(3)));
+        assert_eq!(field("cnt_up_to"), Some(ValueType::IntegerValue(2)));
+        assert_eq!(field("sum"), Some(ValueType::IntegerValue(3)));
+        assert_eq!(field("avg"), Some(ValueType::DoubleValue(1.5)));
+    }
+
+    #[test]
+    fn aggregate_sum_switches_to_double() {
+        let aggregations = vec![aggregation(
+            "sum",
+            FirestoreAggregationOperator::Sum(FirestoreAggregationOperatorSum::new(
+                "num".to_string(),
+            )),
+        )];
+        let engine = FirestoreCacheAggregationEngine::new(&aggregations);
+
+        let mixed = engine.aggregate_docs(&[
+            doc_with_value(ValueType::IntegerValue(1)),
+            doc_with_value(ValueType::DoubleValue(0.5)),
+        ]);
+        assert_eq!(
+            mixed.fields.get("sum").and_then(|v| v.value_type.clone()),
+            Some(ValueType::DoubleValue(1.5))
+        );
+
+        let overflow = engine.aggregate_docs(&[
+            doc_with_value(ValueType::IntegerValue(i64::MAX)),
+            doc_with_value(ValueType::IntegerValue(1)),
+        ]);
+        assert_eq!(
+            overflow
+                .fields
+                .get("sum")
+                .and_then(|v| v.value_type.clone()),
+            Some(ValueType::DoubleValue(i64::MAX as f64 + 1.0))
+        );
+    }
+
+    #[test]
+    fn aggregate_avg_of_nothing_is_null() {
+        let aggregations = vec![aggregation(
+            "avg",
+            FirestoreAggregationOperator::Avg(FirestoreAggregationOperatorAvg::new(
+                "num".to_string(),
+            )),
+        )];
+
+        let result = FirestoreCacheAggregationEngine::new(&aggregations).aggregate_docs(&[]);
+
+        assert_eq!(
+            result.fields.get("avg").and_then(|v| v.value_type.clone()),
+            Some(ValueType::NullValue(0))
+        );
+    }
+}
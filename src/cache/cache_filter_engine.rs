@@ -1,5 +1,6 @@
 use crate::FirestoreQueryFilter;
 use crate::*;
+use std::cmp::Ordering;
 
 pub struct FirestoreCacheFilterEngine<'a> {
     filter: &'a FirestoreQueryFilter,
@@ -257,203 +258,347 @@ pub(super) enum CompareOp {
     NotIn,
 }
 
+/// Compares a document field value `a` with the filter value `b` the way Firestore evaluates
+/// query filters.
+///
+/// Equality and range operators only match values of the same type (integers and doubles are
+/// both numbers), `!=` and `not-in` match values of any other type but never `null`, and the
+/// array operators expect `a` to be an array field, `b` being the value (or the array of values
+/// for `in`, `not-in` and `array-contains-any`) from the filter.
 pub(super) fn compare_values(
     op: CompareOp,
     a: &gcloud_sdk::google::firestore::v1::value::ValueType,
     b: &gcloud_sdk::google::firestore::v1::value::ValueType,
 ) -> bool {
-    match (op, a, b) {
-        // handle BooleanValue
-        (
-            CompareOp::Equal,
-            gcloud_sdk::google::firestore::v1::value::ValueType::BooleanValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::BooleanValue(b_val),
-        ) => a_val == b_val,
+    use gcloud_sdk::google::firestore::v1::value::ValueType;
 
-        (
-            CompareOp::NotEqual,
-            gcloud_sdk::google::firestore::v1::value::ValueType::BooleanValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::BooleanValue(b_val),
-        ) => a_val != b_val,
+    let array_contains = |array: &gcloud_sdk::google::firestore::v1::ArrayValue,
+                          value: &ValueType| {
+        array
+            .values
+            .iter()
+            .flat_map(|v| &v.value_type)
+            .any(|array_value| values_equal(array_value, value))
+    };
 
-        // handle IntegerValue
+    match (op, a, b) {
+        (CompareOp::Equal, a, b) => values_equal(a, b),
+        (CompareOp::NotEqual, ValueType::NullValue(_), _) => false,
+        (CompareOp::NotEqual, a, b) => !values_equal(a, b),
+        (CompareOp::LessThan, a, b) => same_type_ordering(a, b).is_some_and(Ordering::is_lt),
+        (CompareOp::LessThanOrEqual, a, b) => same_type_ordering(a, b).is_some_and(Ordering::is_le),
+        (CompareOp::GreaterThan, a, b) => same_type_ordering(a, b).is_some_and(Ordering::is_gt),
+        (CompareOp::GreaterThanOrEqual, a, b) => {
+            same_type_ordering(a, b).is_some_and(Ordering::is_ge)
+        }
+        (CompareOp::ArrayContains, ValueType::ArrayValue(a_val), b_val) => {
+            array_contains(a_val, b_val)
+        }
         (
-            CompareOp::Equal,
-            gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(b_val),
-        ) => a_val == b_val,
+            CompareOp::ArrayContainsAny,
+            ValueType::ArrayValue(a_val),
+            ValueType::ArrayValue(b_val),
+        ) => a_val
+            .values
+            .iter()
+            .flat_map(|v| &v.value_type)
+            .any(|a_val| array_contains(b_val, a_val)),
+        (CompareOp::In, a_val, ValueType::ArrayValue(b_val)) => array_contains(b_val, a_val),
+        (CompareOp::NotIn, ValueType::NullValue(_), _) => false,
+        (CompareOp::NotIn, a_val, ValueType::ArrayValue(b_val)) => {
+            !array_contains(b_val, &ValueType::NullValue(0)) && !array_contains(b_val, a_val)
+        }
+        // Any other combinations result in false
+        _ => false,
+    }
+}
 
-        (
-            CompareOp::NotEqual,
-            gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(b_val),
-        ) => a_val != b_val,
+fn values_equal(
+    a: &gcloud_sdk::google::firestore::v1::value::ValueType,
+    b: &gcloud_sdk::google::firestore::v1::value::ValueType,
+) -> bool {
+    same_type_ordering(a, b).is_some_and(Ordering::is_eq)
+}
 
-        (
-            CompareOp::LessThan,
-            gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(b_val),
-        ) => a_val < b_val,
+fn same_type_ordering(
+    a: &gcloud_sdk::google::firestore::v1::value::ValueType,
+    b: &gcloud_sdk::google::firestore::v1::value::ValueType,
+) -> Option<Ordering> {
+    (type_order(a) == type_order(b)).then(|| compare_firestore_values(a, b))
+}
 
-        (
-            CompareOp::LessThanOrEqual,
-            gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(b_val),
-        ) => a_val <= b_val,
+/// The position of the value type in the Firestore ordering of values of different types.
+fn type_order(value: &gcloud_sdk::google::firestore::v1::value::ValueType) -> u8 {
+    use gcloud_sdk::google::firestore::v1::value::ValueType;
+
+    match value {
+        ValueType::NullValue(_) => 0,
+        ValueType::BooleanValue(_) => 1,
+        ValueType::IntegerValue(_) | ValueType::DoubleValue(_) => 2,
+        ValueType::TimestampValue(_) => 3,
+        ValueType::StringValue(_) => 4,
+        ValueType::BytesValue(_) => 5,
+        ValueType::ReferenceValue(_) => 6,
+        ValueType::GeoPointValue(_) => 7,
+        ValueType::ArrayValue(_) => 8,
+        ValueType::MapValue(_) => 9,
+        // Expressions used only by pipelines, these are never stored in documents
+        ValueType::FieldReferenceValue(_)
+        | ValueType::VariableReferenceValue(_)
+        | ValueType::FunctionValue(_)
+        | ValueType::PipelineValue(_) => 10,
+    }
+}
 
-        (
-            CompareOp::GreaterThan,
-            gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(b_val),
-        ) => a_val > b_val,
+/// Compares two values with the total ordering Firestore uses for queries:
+/// `null` < booleans < numbers < timestamps < strings < bytes < references < geo points
+/// < arrays < maps.
+///
+/// Integers and doubles are compared by their numeric value, with `NaN` before all other numbers.
+/// Strings and bytes are compared by their bytes, references segment by segment, arrays
+/// element by element and maps entry by entry in key order.
+pub(crate) fn compare_firestore_values(
+    a: &gcloud_sdk::google::firestore::v1::value::ValueType,
+    b: &gcloud_sdk::google::firestore::v1::value::ValueType,
+) -> Ordering {
+    use gcloud_sdk::google::firestore::v1::value::ValueType;
+
+    match (a, b) {
+        (ValueType::NullValue(_), ValueType::NullValue(_)) => Ordering::Equal,
+        (ValueType::BooleanValue(a_val), ValueType::BooleanValue(b_val)) => a_val.cmp(b_val),
+        (ValueType::IntegerValue(a_val), ValueType::IntegerValue(b_val)) => a_val.cmp(b_val),
+        (ValueType::DoubleValue(a_val), ValueType::DoubleValue(b_val)) => {
+            compare_doubles(*a_val, *b_val)
+        }
+        (ValueType::IntegerValue(a_val), ValueType::DoubleValue(b_val)) => {
+            compare_integer_with_double(*a_val, *b_val)
+        }
+        (ValueType::DoubleValue(a_val), ValueType::IntegerValue(b_val)) => {
+            compare_integer_with_double(*b_val, *a_val).reverse()
+        }
+        (ValueType::TimestampValue(a_val), ValueType::TimestampValue(b_val)) => {
+            (a_val.seconds, a_val.nanos).cmp(&(b_val.seconds, b_val.nanos))
+        }
+        (ValueType::StringValue(a_val), ValueType::StringValue(b_val)) => a_val.cmp(b_val),
+        (ValueType::BytesValue(a_val), ValueType::BytesValue(b_val)) => a_val.cmp(b_val),
+        (ValueType::ReferenceValue(a_val), ValueType::ReferenceValue(b_val)) => {
+            a_val.split('/').cmp(b_val.split('/'))
+        }
+        (ValueType::GeoPointValue(a_val), ValueType::GeoPointValue(b_val)) => {
+            compare_doubles(a_val.latitude, b_val.latitude)
+                .then_with(|| compare_doubles(a_val.longitude, b_val.longitude))
+        }
+        (ValueType::ArrayValue(a_val), ValueType::ArrayValue(b_val)) => {
+            compare_value_lists(&a_val.values, &b_val.values)
+        }
+        (ValueType::MapValue(a_val), ValueType::MapValue(b_val)) => {
+            let mut a_entries: Vec<_> = a_val.fields.iter().collect();
+            let mut b_entries: Vec<_> = b_val.fields.iter().collect();
+            a_entries.sort_by_key(|(key, _)| *key);
+            b_entries.sort_by_key(|(key, _)| *key);
+            a_entries
+                .iter()
+                .zip(b_entries.iter())
+                .map(|((a_key, a_value), (b_key, b_value))| {
+                    a_key
+                        .cmp(b_key)
+                        .then_with(|| compare_optional_values(a_value, b_value))
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or_else(|| a_entries.len().cmp(&b_entries.len()))
+        }
+        (ValueType::FieldReferenceValue(a_val), ValueType::FieldReferenceValue(b_val))
+        | (ValueType::VariableReferenceValue(a_val), ValueType::VariableReferenceValue(b_val)) => {
+            a_val.cmp(b_val)
+        }
+        (a, b) => type_order(a).cmp(&type_order(b)),
+    }
+}
 
-        (
-            CompareOp::GreaterThanOrEqual,
-            gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::IntegerValue(b_val),
-        ) => a_val >= b_val,
+fn compare_value_lists(
+    a: &[gcloud_sdk::google::firestore::v1::Value],
+    b: &[gcloud_sdk::google::firestore::v1::Value],
+) -> Ordering {
+    a.iter()
+        .zip(b.iter())
+        .map(|(a_value, b_value)| compare_optional_values(a_value, b_value))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or_else(|| a.len().cmp(&b.len()))
+}
 
-        // For DoubleValue
-        (
-            CompareOp::Equal,
-            gcloud_sdk::google::firestore::v1::value::ValueType::DoubleValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::DoubleValue(b_val),
-        ) => a_val == b_val,
+fn compare_optional_values(
+    a: &gcloud_sdk::google::firestore::v1::Value,
+    b: &gcloud_sdk::google::firestore::v1::Value,
+) -> Ordering {
+    match (&a.value_type, &b.value_type) {
+        (Some(a_val), Some(b_val)) => compare_firestore_values(a_val, b_val),
+        (a_val, b_val) => a_val.is_some().cmp(&b_val.is_some()),
+    }
+}
 
-        (
-            CompareOp::NotEqual,
-            gcloud_sdk::google::firestore::v1::value::ValueType::DoubleValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::DoubleValue(b_val),
-        ) => a_val != b_val,
+/// Doubles ordered with `NaN` first and `-0.0` equal to `0.0`.
+fn compare_doubles(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+    }
+}
 
-        (
-            CompareOp::LessThan,
-            gcloud_sdk::google::firestore::v1::value::ValueType::DoubleValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::DoubleValue(b_val),
-        ) => a_val < b_val,
+/// Compares an integer with a double exactly, without losing the precision of large integers
+/// to a conversion to `f64`.
+fn compare_integer_with_double(a: i64, b: f64) -> Ordering {
+    // 2^63, the first double above the range of i64
+    const I64_UPPER_BOUND: f64 = 9_223_372_036_854_775_808.0;
+
+    if b.is_nan() {
+        Ordering::Greater
+    } else if b >= I64_UPPER_BOUND {
+        Ordering::Less
+    } else if b < -I64_UPPER_BOUND {
+        Ordering::Greater
+    } else {
+        let b_trunc = b.trunc();
+        a.cmp(&(b_trunc as i64))
+            .then_with(|| compare_doubles(0.0, b - b_trunc))
+    }
+}
 
-        (
-            CompareOp::LessThanOrEqual,
-            gcloud_sdk::google::firestore::v1::value::ValueType::DoubleValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::DoubleValue(b_val),
-        ) => a_val <= b_val,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gcloud_sdk::google::firestore::v1::value::ValueType;
+    use gcloud_sdk::google::firestore::v1::{ArrayValue, MapValue, Value};
+    use std::collections::HashMap;
+
+    fn array(values: Vec<ValueType>) -> ValueType {
+        ValueType::ArrayValue(ArrayValue {
+            values: values
+                .into_iter()
+                .map(|value| Value {
+                    value_type: Some(value),
+                })
+                .collect(),
+        })
+    }
 
-        (
-            CompareOp::GreaterThan,
-            gcloud_sdk::google::firestore::v1::value::ValueType::DoubleValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::DoubleValue(b_val),
-        ) => a_val > b_val,
+    fn timestamp(seconds: i64, nanos: i32) -> ValueType {
+        ValueType::TimestampValue(gcloud_sdk::prost_types::Timestamp { seconds, nanos })
+    }
 
-        (
-            CompareOp::GreaterThanOrEqual,
-            gcloud_sdk::google::firestore::v1::value::ValueType::DoubleValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::DoubleValue(b_val),
-        ) => a_val >= b_val,
+    #[test]
+    fn orders_values_of_different_types() {
+        let ordered = vec![
+            ValueType::NullValue(0),
+            ValueType::BooleanValue(false),
+            ValueType::BooleanValue(true),
+            ValueType::DoubleValue(f64::NAN),
+            ValueType::DoubleValue(f64::NEG_INFINITY),
+            ValueType::IntegerValue(-1),
+            ValueType::DoubleValue(0.5),
+            ValueType::IntegerValue(1),
+            ValueType::DoubleValue(1.5),
+            timestamp(1, 999_999_999),
+            timestamp(2, 0),
+            ValueType::StringValue("a".to_string()),
+            ValueType::StringValue("b".to_string()),
+            ValueType::BytesValue(vec![0]),
+            ValueType::ReferenceValue("projects/p/databases/d/documents/a/b".to_string()),
+            ValueType::ReferenceValue("projects/p/databases/d/documents/a-c/b".to_string()),
+            ValueType::GeoPointValue(gcloud_sdk::google::r#type::LatLng {
+                latitude: 1.0,
+                longitude: 2.0,
+            }),
+            array(vec![ValueType::IntegerValue(1)]),
+            array(vec![ValueType::IntegerValue(1), ValueType::IntegerValue(0)]),
+            array(vec![ValueType::IntegerValue(2)]),
+            ValueType::MapValue(MapValue {
+                fields: HashMap::new(),
+            }),
+        ];
+
+        for (index, a) in ordered.iter().enumerate() {
+            for (other_index, b) in ordered.iter().enumerate() {
+                assert_eq!(
+                    compare_firestore_values(a, b),
+                    index.cmp(&other_index),
+                    "{a:?} vs {b:?}"
+                );
+            }
+        }
+    }
 
-        // For TimestampValue, assumes it's a numerical timestamp; if it's a string or date type, adjust accordingly
-        (
+    #[test]
+    fn compares_integers_with_doubles_numerically() {
+        assert!(compare_values(
             CompareOp::Equal,
-            gcloud_sdk::google::firestore::v1::value::ValueType::TimestampValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::TimestampValue(b_val),
-        ) => a_val == b_val,
-
-        (
-            CompareOp::NotEqual,
-            gcloud_sdk::google::firestore::v1::value::ValueType::TimestampValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::TimestampValue(b_val),
-        ) => a_val != b_val,
-
-        (
+            &ValueType::IntegerValue(1),
+            &ValueType::DoubleValue(1.0)
+        ));
+        assert!(compare_values(
             CompareOp::LessThan,
-            gcloud_sdk::google::firestore::v1::value::ValueType::TimestampValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::TimestampValue(b_val),
-        ) => a_val.seconds < b_val.seconds && a_val.nanos < b_val.nanos,
-
-        (
-            CompareOp::LessThanOrEqual,
-            gcloud_sdk::google::firestore::v1::value::ValueType::TimestampValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::TimestampValue(b_val),
-        ) => a_val.seconds <= b_val.seconds && a_val.nanos <= b_val.nanos,
-
-        (
+            &ValueType::IntegerValue(i64::MAX),
+            &ValueType::DoubleValue(9_223_372_036_854_775_808.0)
+        ));
+        assert!(compare_values(
             CompareOp::GreaterThan,
-            gcloud_sdk::google::firestore::v1::value::ValueType::TimestampValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::TimestampValue(b_val),
-        ) => a_val.seconds > b_val.seconds && a_val.nanos > b_val.nanos,
-
-        (
-            CompareOp::GreaterThanOrEqual,
-            gcloud_sdk::google::firestore::v1::value::ValueType::TimestampValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::TimestampValue(b_val),
-        ) => a_val.seconds >= b_val.seconds && a_val.nanos >= b_val.nanos,
-
-        // For StringType only Equal, NotEqual operations make sense in a general context
-        (
-            CompareOp::Equal,
-            gcloud_sdk::google::firestore::v1::value::ValueType::StringValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::StringValue(b_val),
-        ) => a_val == b_val,
+            &ValueType::DoubleValue(2.5),
+            &ValueType::IntegerValue(2)
+        ));
+    }
 
-        (
+    #[test]
+    fn range_filters_match_only_the_same_type() {
+        assert!(!compare_values(
+            CompareOp::GreaterThan,
+            &ValueType::StringValue("a".to_string()),
+            &ValueType::IntegerValue(1)
+        ));
+        assert!(compare_values(
             CompareOp::NotEqual,
-            gcloud_sdk::google::firestore::v1::value::ValueType::StringValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::StringValue(b_val),
-        ) => a_val != b_val,
+            &ValueType::StringValue("a".to_string()),
+            &ValueType::IntegerValue(1)
+        ));
+        assert!(!compare_values(
+            CompareOp::NotEqual,
+            &ValueType::NullValue(0),
+            &ValueType::IntegerValue(1)
+        ));
+        assert!(compare_values(
+            CompareOp::LessThan,
+            &timestamp(1, 500),
+            &timestamp(2, 100)
+        ));
+    }
 
-        //  Array Operation
-        (
+    #[test]
+    fn array_and_membership_filters() {
+        let field = array(vec![ValueType::IntegerValue(1), ValueType::IntegerValue(2)]);
+        assert!(compare_values(
             CompareOp::ArrayContains,
-            gcloud_sdk::google::firestore::v1::value::ValueType::ArrayValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::ArrayValue(b_val),
-        ) => a_val
-            .values
-            .iter()
-            .flat_map(|v| &v.value_type)
-            .any(|a_val| {
-                b_val
-                    .values
-                    .iter()
-                    .flat_map(|v| &v.value_type)
-                    .all(|b_val| compare_values(CompareOp::Equal, a_val, b_val))
-            }),
-
-        (
+            &field,
+            &ValueType::DoubleValue(2.0)
+        ));
+        assert!(compare_values(
             CompareOp::ArrayContainsAny,
-            gcloud_sdk::google::firestore::v1::value::ValueType::ArrayValue(a_val),
-            gcloud_sdk::google::firestore::v1::value::ValueType::ArrayValue(b_val),
-        ) => a_val
-            .values
-            .iter()
-            .flat_map(|v| &v.value_type)
-            .any(|a_val| {
-                b_val
-                    .values
-                    .iter()
-                    .flat_map(|v| &v.value_type)
-                    .any(|b_val| compare_values(CompareOp::Equal, a_val, b_val))
-            }),
-
-        (
+            &field,
+            &array(vec![ValueType::IntegerValue(3), ValueType::IntegerValue(1)])
+        ));
+        assert!(compare_values(
             CompareOp::In,
-            gcloud_sdk::google::firestore::v1::value::ValueType::ArrayValue(a_val),
-            b_val,
-        ) => a_val
-            .values
-            .iter()
-            .flat_map(|v| &v.value_type)
-            .any(|a_val| compare_values(CompareOp::Equal, a_val, b_val)),
-
-        (
+            &ValueType::IntegerValue(2),
+            &array(vec![ValueType::IntegerValue(1), ValueType::IntegerValue(2)])
+        ));
+        assert!(!compare_values(
             CompareOp::NotIn,
-            gcloud_sdk::google::firestore::v1::value::ValueType::ArrayValue(a_val),
-            b_val,
-        ) => a_val
-            .values
-            .iter()
-            .flat_map(|v| &v.value_type)
-            .any(|a_val| !compare_values(CompareOp::Equal, a_val, b_val)),
-
-        // Any other combinations result in false
-        _ => false,
+            &ValueType::IntegerValue(2),
+            &array(vec![ValueType::IntegerValue(1), ValueType::IntegerValue(2)])
+        ));
+        assert!(compare_values(
+            CompareOp::NotIn,
+            &ValueType::IntegerValue(3),
+            &array(vec![ValueType::IntegerValue(1), ValueType::IntegerValue(2)])
+        ));
     }
 }
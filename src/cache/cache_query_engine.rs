@@ -1,10 +1,93 @@
-use crate::cache::cache_filter_engine::FirestoreCacheFilterEngine;
+use crate::cache::cache_filter_engine::{compare_firestore_values, FirestoreCacheFilterEngine};
 use crate::*;
 use futures::stream::BoxStream;
 use futures::stream::StreamExt;
 use futures::{future, TryStreamExt};
+use gcloud_sdk::google::firestore::v1::value::ValueType;
+use std::borrow::Cow;
 use std::cmp::Ordering;
 
+/// The special field path for the document name, which Firestore uses to order the results
+/// after the requested fields.
+pub(crate) const DOCUMENT_NAME_FIELD: &str = "__name__";
+
+/// Returns `true` when a document belongs to the collection at `collection_path`, or with
+/// `all_descendants` to a collection with the same ID at any depth under the same parent, as
+/// collection group queries do.
+#[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
+pub(crate) fn is_doc_in_collection_scope(
+    collection_path: &str,
+    all_descendants: bool,
+    document_path: &str,
+) -> bool {
+    let (doc_collection_path, _) = split_document_path(document_path);
+    if all_descendants {
+        let (parent, collection_id) = split_document_path(collection_path);
+        let (doc_parent, doc_collection_id) = split_document_path(doc_collection_path);
+        doc_collection_id == collection_id
+            && doc_parent
+                .strip_prefix(parent)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    } else {
+        doc_collection_path == collection_path
+    }
+}
+
+/// The fields Firestore orders the results of a query by, before the document name: the
+/// requested ones, then the fields of the inequality filters that are not requested. These
+/// follow in field path order and in the direction of the last requested field.
+pub(crate) fn ordered_fields(query: &FirestoreQueryParams) -> Vec<FirestoreQueryOrder> {
+    let mut order_by = query.order_by.clone().unwrap_or_default();
+    let direction = order_by
+        .last()
+        .map(|ordered_field| ordered_field.direction.clone())
+        .unwrap_or(FirestoreQueryDirection::Ascending);
+
+    let mut inequality_fields = Vec::new();
+    if let Some(filter) = &query.filter {
+        collect_inequality_fields(filter, &mut inequality_fields);
+    }
+    inequality_fields.sort_by(|a, b| a.split('.').cmp(b.split('.')));
+    inequality_fields.dedup();
+
+    for field_name in inequality_fields {
+        if field_name != DOCUMENT_NAME_FIELD
+            && !order_by
+                .iter()
+                .any(|ordered_field| ordered_field.field_name == field_name)
+        {
+            order_by.push(FirestoreQueryOrder::new(
+                field_name.to_string(),
+                direction.clone(),
+            ));
+        }
+    }
+    order_by
+}
+
+fn collect_inequality_fields<'f>(filter: &'f FirestoreQueryFilter, fields: &mut Vec<&'f str>) {
+    match filter {
+        FirestoreQueryFilter::Composite(composite) => {
+            for filter in &composite.for_all_filters {
+                collect_inequality_fields(filter, fields);
+            }
+        }
+        FirestoreQueryFilter::Compare(Some(
+            FirestoreQueryFilterCompare::LessThan(field_name, _)
+            | FirestoreQueryFilterCompare::LessThanOrEqual(field_name, _)
+            | FirestoreQueryFilterCompare::GreaterThan(field_name, _)
+            | FirestoreQueryFilterCompare::GreaterThanOrEqual(field_name, _)
+            | FirestoreQueryFilterCompare::NotEqual(field_name, _)
+            | FirestoreQueryFilterCompare::NotIn(field_name, _),
+        ))
+        | FirestoreQueryFilter::Unary(
+            FirestoreQueryFilterUnary::IsNotNan(field_name)
+            | FirestoreQueryFilterUnary::IsNotNull(field_name),
+        ) => fields.push(field_name),
+        FirestoreQueryFilter::Compare(_) | FirestoreQueryFilter::Unary(_) => {}
+    }
+}
+
 #[derive(Clone)]
 pub struct FirestoreCacheQueryEngine {
     pub query: FirestoreQueryParams,
@@ -17,8 +100,13 @@ impl FirestoreCacheQueryEngine {
         }
     }
 
+    /// Returns `false` for queries the engine cannot answer, such as vector searches.
+    ///
+    /// Collection group queries are supported, but only a cached collection group holds all of
+    /// their documents.
+    #[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
     pub fn params_supported(&self) -> bool {
-        self.query.all_descendants.iter().all(|x| !*x)
+        self.query.find_nearest.is_none()
     }
 
     pub fn matches_doc(&self, doc: &FirestoreDocument) -> bool {
@@ -34,51 +122,19 @@ impl FirestoreCacheQueryEngine {
         &'a self,
         input: BoxStream<'b, FirestoreResult<FirestoreDocument>>,
     ) -> FirestoreResult<BoxStream<'b, FirestoreResult<FirestoreDocument>>> {
-        if let Some(order_by) = &self.query.order_by {
-            let mut collected: Vec<FirestoreDocument> = input.try_collect().await?;
-            collected.sort_by(|doc_a, doc_b| {
-                let mut current_ordering = Ordering::Equal;
-                for sort_field in order_by {
-                    match (
-                        firestore_doc_get_field_by_path(doc_a, &sort_field.field_name),
-                        firestore_doc_get_field_by_path(doc_b, &sort_field.field_name),
-                    ) {
-                        (Some(field_a), Some(field_b)) => {
-                            if cache::cache_filter_engine::compare_values(
-                                cache::cache_filter_engine::CompareOp::Equal,
-                                field_a,
-                                field_b,
-                            ) {
-                                continue;
-                            }
-
-                            if cache::cache_filter_engine::compare_values(
-                                cache::cache_filter_engine::CompareOp::LessThan,
-                                field_a,
-                                field_b,
-                            ) {
-                                current_ordering = match sort_field.direction {
-                                    FirestoreQueryDirection::Ascending => Ordering::Less,
-                                    FirestoreQueryDirection::Descending => Ordering::Greater,
-                                }
-                            } else {
-                                current_ordering = match sort_field.direction {
-                                    FirestoreQueryDirection::Ascending => Ordering::Greater,
-                                    FirestoreQueryDirection::Descending => Ordering::Less,
-                                }
-                            }
-                        }
-                        (None, None) => current_ordering = Ordering::Equal,
-                        (None, Some(_)) => current_ordering = Ordering::Equal,
-                        (Some(_), None) => current_ordering = Ordering::Equal,
-                    }
-                }
-                current_ordering
-            });
-            Ok(futures::stream::iter(collected.into_iter().map(Ok)).boxed())
-        } else {
-            Ok(input)
-        }
+        let explicit_order_by = self.query.order_by.clone().unwrap_or_default();
+        let order_by = self.effective_order_by();
+        let mut collected: Vec<FirestoreDocument> = input
+            // Ordering by a field also filters out the documents that do not have it
+            .try_filter(|doc| {
+                future::ready(explicit_order_by.iter().all(|ordered_field| {
+                    Self::doc_field_value(doc, &ordered_field.field_name).is_some()
+                }))
+            })
+            .try_collect()
+            .await?;
+        collected.sort_by(|doc_a, doc_b| Self::compare_docs(&order_by, doc_a, doc_b));
+        Ok(futures::stream::iter(collected.into_iter().map(Ok)).boxed())
     }
 
     pub async fn limit_stream<'a, 'b>(
@@ -101,6 +157,21 @@ impl FirestoreCacheQueryEngine {
         }
     }
 
+    /// Applies the `return_only_fields` projection to the results, the way Firestore applies the
+    /// `select` of a query. It comes last, since filters and ordering may use any field.
+    pub async fn projection_stream<'a, 'b>(
+        &'a self,
+        input: BoxStream<'b, FirestoreResult<FirestoreDocument>>,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<FirestoreDocument>>> {
+        if let Some(return_only_fields) = self.query.return_only_fields.clone() {
+            Ok(input
+                .map_ok(move |doc| firestore_doc_project_fields(&doc, &return_only_fields))
+                .boxed())
+        } else {
+            Ok(input)
+        }
+    }
+
     pub async fn offset_stream<'a, 'b>(
         &'a self,
         input: BoxStream<'b, FirestoreResult<FirestoreDocument>>,
@@ -117,73 +188,20 @@ impl FirestoreCacheQueryEngine {
         input: BoxStream<'b, FirestoreResult<FirestoreDocument>>,
     ) -> FirestoreResult<BoxStream<'b, FirestoreResult<FirestoreDocument>>> {
         if let Some(start_at) = &self.query.start_at {
-            if let Some(order_by) = &self.query.order_by {
-                let start_at = start_at.clone();
-                let order_by = order_by.clone();
-                Ok(input
-                    .skip_while(move |doc_res| match doc_res {
-                        Ok(doc) => match &start_at {
-                            FirestoreQueryCursor::BeforeValue(values) => {
-                                let result = values.iter().zip(&order_by).any(
-                                    |(value, ordered_field)| {
-                                        let order_by_comp = match ordered_field.direction {
-                                            FirestoreQueryDirection::Ascending => cache::cache_filter_engine::CompareOp::LessThan,
-                                            FirestoreQueryDirection::Descending => cache::cache_filter_engine::CompareOp::GreaterThan
-                                        };
-                                        match (
-                                            firestore_doc_get_field_by_path(
-                                                doc,
-                                                &ordered_field.field_name,
-                                            ),
-                                            &value.value.value_type,
-                                        ) {
-                                            (Some(field_a), Some(field_b)) => {
-                                                cache::cache_filter_engine::compare_values(
-                                                    order_by_comp,
-                                                    field_a,
-                                                    field_b,
-                                                )
-                                            }
-                                            (_, _) => false,
-                                        }
-                                    },
-                                );
-                                future::ready(result)
-                            }
-                            FirestoreQueryCursor::AfterValue(values) => {
-                                let result = values.iter().zip(&order_by).any(
-                                    |(value, ordered_field)| {
-                                        let order_by_comp = match ordered_field.direction {
-                                            FirestoreQueryDirection::Ascending => cache::cache_filter_engine::CompareOp::LessThanOrEqual,
-                                            FirestoreQueryDirection::Descending => cache::cache_filter_engine::CompareOp::GreaterThanOrEqual
-                                        };
-                                        match (
-                                            firestore_doc_get_field_by_path(
-                                                doc,
-                                                &ordered_field.field_name,
-                                            ),
-                                            &value.value.value_type,
-                                        ) {
-                                            (Some(field_a), Some(field_b)) => {
-                                                cache::cache_filter_engine::compare_values(
-                                                    order_by_comp,
-                                                    field_a,
-                                                    field_b,
-                                                )
-                                            }
-                                            (_, _) => false,
-                                        }
-                                    },
-                                );
-                                future::ready(result)
-                            }
-                        },
-                        Err(_) => future::ready(false),
+            let start_at = start_at.clone();
+            let order_by = self.effective_order_by();
+            Ok(input
+                .try_filter(move |doc| {
+                    future::ready(match &start_at {
+                        FirestoreQueryCursor::BeforeValue(values) => {
+                            Self::compare_doc_with_cursor(&order_by, doc, values).is_ge()
+                        }
+                        FirestoreQueryCursor::AfterValue(values) => {
+                            Self::compare_doc_with_cursor(&order_by, doc, values).is_gt()
+                        }
                     })
-                    .boxed())
-            } else {
-                Ok(input)
-            }
+                })
+                .boxed())
         } else {
             Ok(input)
         }
@@ -194,73 +212,20 @@ impl FirestoreCacheQueryEngine {
         input: BoxStream<'b, FirestoreResult<FirestoreDocument>>,
     ) -> FirestoreResult<BoxStream<'b, FirestoreResult<FirestoreDocument>>> {
         if let Some(end_at) = &self.query.end_at {
-            if let Some(order_by) = &self.query.order_by {
-                let end_at = end_at.clone();
-                let order_by = order_by.clone();
-                Ok(input
-                    .take_while(move |doc_res| match doc_res {
-                        Ok(doc) => match &end_at {
-                            FirestoreQueryCursor::BeforeValue(values) => {
-                                let result = values.iter().zip(&order_by).any(
-                                    |(value, ordered_field)| {
-                                        let order_by_comp = match ordered_field.direction {
-                                            FirestoreQueryDirection::Ascending => cache::cache_filter_engine::CompareOp::LessThan,
-                                            FirestoreQueryDirection::Descending => cache::cache_filter_engine::CompareOp::GreaterThan
-                                        };
-                                        match (
-                                            firestore_doc_get_field_by_path(
-                                                doc,
-                                                &ordered_field.field_name,
-                                            ),
-                                            &value.value.value_type,
-                                        ) {
-                                            (Some(field_a), Some(field_b)) => {
-                                                cache::cache_filter_engine::compare_values(
-                                                    order_by_comp,
-                                                    field_a,
-                                                    field_b,
-                                                )
-                                            }
-                                            (_, _) => false,
-                                        }
-                                    },
-                                );
-                                future::ready(result)
-                            }
-                            FirestoreQueryCursor::AfterValue(values) => {
-                                let result = values.iter().zip(&order_by).any(
-                                    |(value, ordered_field)| {
-                                        let order_by_comp = match ordered_field.direction {
-                                            FirestoreQueryDirection::Ascending => cache::cache_filter_engine::CompareOp::LessThanOrEqual,
-                                            FirestoreQueryDirection::Descending => cache::cache_filter_engine::CompareOp::GreaterThanOrEqual
-                                        };
-                                        match (
-                                            firestore_doc_get_field_by_path(
-                                                doc,
-                                                &ordered_field.field_name,
-                                            ),
-                                            &value.value.value_type,
-                                        ) {
-                                            (Some(field_a), Some(field_b)) => {
-                                                cache::cache_filter_engine::compare_values(
-                                                    order_by_comp,
-                                                    field_a,
-                                                    field_b,
-                                                )
-                                            }
-                                            (_, _) => false,
-                                        }
-                                    },
-                                );
-                                future::ready(result)
-                            }
-                        },
-                        Err(_) => future::ready(false),
+            let end_at = end_at.clone();
+            let order_by = self.effective_order_by();
+            Ok(input
+                .try_filter(move |doc| {
+                    future::ready(match &end_at {
+                        FirestoreQueryCursor::BeforeValue(values) => {
+                            Self::compare_doc_with_cursor(&order_by, doc, values).is_lt()
+                        }
+                        FirestoreQueryCursor::AfterValue(values) => {
+                            Self::compare_doc_with_cursor(&order_by, doc, values).is_le()
+                        }
                     })
-                    .boxed())
-            } else {
-                Ok(input)
-            }
+                })
+                .boxed())
         } else {
             Ok(input)
         }
@@ -271,10 +236,320 @@ impl FirestoreCacheQueryEngine {
         input: BoxStream<'b, FirestoreResult<FirestoreDocument>>,
     ) -> FirestoreResult<BoxStream<'b, FirestoreResult<FirestoreDocument>>> {
         let input = self.sort_stream(input).await?;
-        let input = self.limit_stream(input).await?;
-        let input = self.offset_stream(input).await?;
+        self.process_sorted_query_stream(input).await
+    }
+
+    /// Same as [`process_query_stream`](Self::process_query_stream) for an input that is
+    /// already in the query order, such as the results of an index scan.
+    pub async fn process_sorted_query_stream<'a, 'b>(
+        &'a self,
+        input: BoxStream<'b, FirestoreResult<FirestoreDocument>>,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<FirestoreDocument>>> {
+        // Same order as Firestore: the cursors bound the ordered results first, then the offset
+        // skips into what is left, and only then the limit is applied.
         let input = self.start_at_stream(input).await?;
         let input = self.end_at_stream(input).await?;
+        let input = self.offset_stream(input).await?;
+        let input = self.limit_stream(input).await?;
+        let input = self.projection_stream(input).await?;
         Ok(input)
     }
+
+    /// The ordering Firestore applies to the results: the [`ordered_fields`] followed by the
+    /// document name, in the direction of the last ordered field.
+    fn effective_order_by(&self) -> Vec<FirestoreQueryOrder> {
+        let mut order_by = ordered_fields(&self.query);
+        if !order_by
+            .iter()
+            .any(|ordered_field| ordered_field.field_name == DOCUMENT_NAME_FIELD)
+        {
+            let direction = order_by
+                .last()
+                .map(|ordered_field| ordered_field.direction.clone())
+                .unwrap_or(FirestoreQueryDirection::Ascending);
+            order_by.push(FirestoreQueryOrder::new(
+                DOCUMENT_NAME_FIELD.to_string(),
+                direction,
+            ));
+        }
+        order_by
+    }
+
+    fn doc_field_value<'d>(
+        doc: &'d FirestoreDocument,
+        field_name: &str,
+    ) -> Option<Cow<'d, ValueType>> {
+        if field_name == DOCUMENT_NAME_FIELD {
+            Some(Cow::Owned(ValueType::ReferenceValue(doc.name.clone())))
+        } else {
+            firestore_doc_get_field_by_path(doc, field_name).map(Cow::Borrowed)
+        }
+    }
+
+    fn compare_docs(
+        order_by: &[FirestoreQueryOrder],
+        doc_a: &FirestoreDocument,
+        doc_b: &FirestoreDocument,
+    ) -> Ordering {
+        order_by
+            .iter()
+            .map(|ordered_field| {
+                Self::directed(
+                    &ordered_field.direction,
+                    Self::compare_optional_fields(
+                        Self::doc_field_value(doc_a, &ordered_field.field_name).as_deref(),
+                        Self::doc_field_value(doc_b, &ordered_field.field_name).as_deref(),
+                    ),
+                )
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+
+    /// Compares the document position with a cursor, where the cursor values are compared
+    /// lexicographically with the ordered fields of the document.
+    fn compare_doc_with_cursor(
+        order_by: &[FirestoreQueryOrder],
+        doc: &FirestoreDocument,
+        cursor_values: &[FirestoreValue],
+    ) -> Ordering {
+        cursor_values
+            .iter()
+            .zip(order_by)
+            .map(|(cursor_value, ordered_field)| {
+                Self::directed(
+                    &ordered_field.direction,
+                    Self::compare_optional_fields(
+                        Self::doc_field_value(doc, &ordered_field.field_name).as_deref(),
+                        cursor_value.value.value_type.as_ref(),
+                    ),
+                )
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+
+    fn compare_optional_fields(a: Option<&ValueType>, b: Option<&ValueType>) -> Ordering {
+        match (a, b) {
+            (Some(a), Some(b)) => compare_firestore_values(a, b),
+            (a, b) => a.is_some().cmp(&b.is_some()),
+        }
+    }
+
+    fn directed(direction: &FirestoreQueryDirection, ordering: Ordering) -> Ordering {
+        match direction {
+            FirestoreQueryDirection::Ascending => ordering,
+            FirestoreQueryDirection::Descending => ordering.reverse(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gcloud_sdk::google::firestore::v1::Value;
+    use std::collections::HashMap;
+
+    fn doc(id: &str, fields: Vec<(&str, ValueType)>) -> FirestoreDocument {
+        FirestoreDocument {
+            name: format!("projects/test/databases/(default)/documents/test/{id}"),
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| {
+                    (
+                        name.to_string(),
+                        Value {
+                            value_type: Some(value),
+                        },
+                    )
+                })
+                .collect::<HashMap<_, _>>(),
+            create_time: None,
+            update_time: None,
+        }
+    }
+
+    async fn query_ids(query: FirestoreQueryParams, docs: Vec<FirestoreDocument>) -> Vec<String> {
+        let engine = FirestoreCacheQueryEngine::new(&query);
+        let results: Vec<FirestoreDocument> = engine
+            .process_query_stream(futures::stream::iter(docs.into_iter().map(Ok)).boxed())
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        results
+            .into_iter()
+            .map(|doc| doc.name.rsplit('/').next().unwrap().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn orders_and_pages_with_lexicographic_cursors() {
+        let docs = vec![
+            doc(
+                "d",
+                vec![
+                    ("a", ValueType::IntegerValue(2)),
+                    ("b", ValueType::DoubleValue(0.5)),
+                ],
+            ),
+            doc(
+                "c",
+                vec![
+                    ("a", ValueType::IntegerValue(1)),
+                    ("b", ValueType::IntegerValue(3)),
+                ],
+            ),
+            doc("missing", vec![("b", ValueType::IntegerValue(1))]),
+            doc(
+                "b",
+                vec![
+                    ("a", ValueType::DoubleValue(1.0)),
+                    ("b", ValueType::IntegerValue(2)),
+                ],
+            ),
+            doc(
+                "a",
+                vec![
+                    ("a", ValueType::StringValue("x".to_string())),
+                    ("b", ValueType::IntegerValue(0)),
+                ],
+            ),
+        ];
+
+        let order_by = vec![
+            FirestoreQueryOrder::new("a".to_string(), FirestoreQueryDirection::Ascending),
+            FirestoreQueryOrder::new("b".to_string(), FirestoreQueryDirection::Descending),
+        ];
+        let query = FirestoreQueryParams::new(FirestoreQueryCollection::Single("test".into()))
+            .with_order_by(order_by);
+
+        assert_eq!(
+            query_ids(query.clone(), docs.clone()).await,
+            vec!["c", "b", "d", "a"]
+        );
+
+        assert_eq!(
+            query_ids(
+                query
+                    .clone()
+                    .with_start_at(FirestoreQueryCursor::AfterValue(vec![1.into(), 3.into()]))
+                    .with_end_at(FirestoreQueryCursor::BeforeValue(vec!["x".into()])),
+                docs
+            )
+            .await,
+            vec!["b", "d"]
+        );
+    }
+
+    #[tokio::test]
+    async fn orders_by_inequality_fields_like_firestore() {
+        let city = |id: &str, population: i64, country: &str| {
+            doc(
+                id,
+                vec![
+                    ("population", ValueType::IntegerValue(population)),
+                    ("country", ValueType::StringValue(country.to_string())),
+                ],
+            )
+        };
+        // The documents the filter lets through, as the backends pass them on
+        let docs = vec![
+            city("a", 5, "SE"),
+            city("c", 9, "NO"),
+            city("d", 3, "SE"),
+            city("e", 5, "NO"),
+        ];
+        let more_than_two = FirestoreQueryParams::new(FirestoreQueryCollection::Single(
+            "test".into(),
+        ))
+        .with_filter(FirestoreQueryFilter::Compare(Some(
+            FirestoreQueryFilterCompare::GreaterThan("population".to_string(), 2.into()),
+        )));
+
+        // The orders Firestore returns: by population, then by document name
+        assert_eq!(
+            query_ids(more_than_two.clone().with_limit(3), docs.clone()).await,
+            vec!["d", "a", "e"]
+        );
+        assert_eq!(
+            query_ids(
+                more_than_two
+                    .clone()
+                    .with_start_at(FirestoreQueryCursor::AfterValue(vec![3.into()]))
+                    .with_limit(2),
+                docs.clone()
+            )
+            .await,
+            vec!["a", "e"]
+        );
+
+        // Requested fields come first, and the inequality fields follow in their direction
+        assert_eq!(
+            query_ids(
+                more_than_two.with_order_by(vec![FirestoreQueryOrder::new(
+                    "country".to_string(),
+                    FirestoreQueryDirection::Descending,
+                )]),
+                docs
+            )
+            .await,
+            vec!["a", "d", "c", "e"]
+        );
+    }
+
+    #[tokio::test]
+    async fn projects_results_after_filtering_and_ordering() {
+        let address = ValueType::MapValue(gcloud_sdk::google::firestore::v1::MapValue {
+            fields: HashMap::from([
+                (
+                    "city".to_string(),
+                    Value {
+                        value_type: Some(ValueType::StringValue("Stockholm".to_string())),
+                    },
+                ),
+                (
+                    "zip".to_string(),
+                    Value {
+                        value_type: Some(ValueType::StringValue("111 22".to_string())),
+                    },
+                ),
+            ]),
+        });
+        let docs = vec![
+            doc(
+                "a",
+                vec![("rank", ValueType::IntegerValue(2)), ("address", address)],
+            ),
+            doc("b", vec![("rank", ValueType::IntegerValue(1))]),
+        ];
+        let query = FirestoreQueryParams::new(FirestoreQueryCollection::Single("test".into()))
+            .with_order_by(vec![FirestoreQueryOrder::new(
+                "rank".to_string(),
+                FirestoreQueryDirection::Descending,
+            )])
+            .with_return_only_fields(vec!["address.city".to_string()]);
+
+        let results: Vec<FirestoreDocument> = FirestoreCacheQueryEngine::new(&query)
+            .process_query_stream(futures::stream::iter(docs.into_iter().map(Ok)).boxed())
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 2);
+        assert!(results[0].name.ends_with("/a"));
+        assert_eq!(
+            firestore_doc_get_field_by_path(&results[0], "address.city"),
+            Some(&ValueType::StringValue("Stockholm".to_string()))
+        );
+        assert_eq!(
+            firestore_doc_get_field_by_path(&results[0], "address.zip"),
+            None
+        );
+        assert_eq!(firestore_doc_get_field_by_path(&results[0], "rank"), None);
+        assert!(results[1].fields.is_empty());
+    }
 }
@@ -1,4 +1,11 @@
-use crate::{FirestoreDb, FirestoreListenerTarget};
+use crate::cache::cache_query_engine::DOCUMENT_NAME_FIELD;
+use crate::db::split_document_path;
+use crate::errors::{FirestoreInvalidParametersError, FirestoreInvalidParametersPublicDetails};
+#[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
+use crate::FirestoreQueryParams;
+use crate::{FirestoreDb, FirestoreError, FirestoreListenerTarget, FirestoreResult};
+#[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
+use rvstruct::ValueStruct;
 use std::collections::HashMap;
 
 /// Describes which collections a [`FirestoreCache`](crate::FirestoreCache) holds and how each of
@@ -67,6 +74,78 @@ impl FirestoreCacheConfiguration {
         self
     }
 
+    /// Returns the paths of the collections kept current by the given listener targets, or of
+    /// all collections when `target_ids` is empty, as Firestore does in target changes.
+    #[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
+    pub(crate) fn collection_paths_for_targets(&self, target_ids: &[i32]) -> Vec<&String> {
+        self.collections
+            .iter()
+            .filter(|(_, config)| {
+                target_ids.is_empty()
+                    || target_ids
+                        .iter()
+                        .any(|target_id| *target_id as u32 == *config.listener_target.value())
+            })
+            .map(|(collection_path, _)| collection_path)
+            .collect()
+    }
+
+    /// Finds the cached collection holding a document, and the key of the document in it.
+    ///
+    /// The key is the document ID, or for collection groups the document path relative to the
+    /// parent of the group, since documents of different collections in a group can share IDs.
+    #[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
+    pub(crate) fn cached_document_key<'a>(
+        &self,
+        document_path: &'a str,
+    ) -> Option<(&String, &'a str)> {
+        let (collection_path, document_id) = split_document_path(document_path);
+        match self.collections.get_key_value(collection_path) {
+            Some((cached_collection_path, config)) if !config.collection_group => {
+                Some((cached_collection_path, document_id))
+            }
+            _ => self
+                .collections
+                .iter()
+                .filter(|(_, config)| config.collection_group)
+                .find_map(|(cached_collection_path, config)| {
+                    config
+                        .relative_group_path(cached_collection_path, collection_path)
+                        .map(|_| {
+                            let group_parent_len =
+                                cached_collection_path.len() - config.collection_name.len();
+                            (cached_collection_path, &document_path[group_parent_len..])
+                        })
+                }),
+        }
+    }
+
+    /// Finds the cached collection that holds every document of a queried collection, given its
+    /// full path, or of a collection group query when `all_descendants` is set.
+    #[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
+    pub(crate) fn cached_collection_for_query<'a>(
+        &'a self,
+        collection_path: &str,
+        all_descendants: bool,
+    ) -> Option<(&'a String, &'a FirestoreCacheCollectionConfiguration)> {
+        match self.collections.get_key_value(collection_path) {
+            Some((cached_collection_path, config))
+                if config.collection_group || !all_descendants =>
+            {
+                Some((cached_collection_path, config))
+            }
+            _ => self
+                .collections
+                .iter()
+                .filter(|(_, config)| config.collection_group)
+                .find(|(cached_collection_path, config)| {
+                    config
+                        .relative_group_path(cached_collection_path, collection_path)
+                        .is_some()
+                }),
+        }
+    }
+
     /// Returns `true` when the cache is configured to hold a *complete* copy of the collection at
     /// `collection_path`, meaning `list` and `query` requests may be served from the cache.
     ///
@@ -130,9 +209,13 @@ pub struct FirestoreCacheCollectionConfiguration {
     /// How this collection is populated at startup, which also determines whether `list`/`query`
     /// may be served from the cache.
     pub collection_load_mode: FirestoreCacheCollectionLoadMode,
-    #[doc(hidden)]
-    /// Not implemented and has no effect. Scheduled for removal.
+    /// Secondary indexes used to serve cached queries of this collection. See
+    /// [`FirestoreCacheIndexConfiguration`].
     pub indices: Vec<FirestoreCacheIndexConfiguration>,
+    /// Caches the collection group: every collection with this ID under the parent, or in the
+    /// whole database without a parent, at any depth. See
+    /// [`with_collection_group`](Self::with_collection_group).
+    pub collection_group: bool,
 }
 
 impl FirestoreCacheCollectionConfiguration {
@@ -152,6 +235,7 @@ impl FirestoreCacheCollectionConfiguration {
             listener_target,
             collection_load_mode,
             indices: Vec::new(),
+            collection_group: false,
         }
     }
 
@@ -169,11 +253,8 @@ impl FirestoreCacheCollectionConfiguration {
         }
     }
 
-    #[doc(hidden)]
-    #[deprecated(
-        since = "0.52.0",
-        note = "Cache indices are not implemented and have no effect. This method is scheduled for removal."
-    )]
+    /// Adds a secondary index, used to serve cached queries of this collection without scanning
+    /// all of its documents.
     #[inline]
     pub fn with_index(self, index: FirestoreCacheIndexConfiguration) -> Self {
         let mut indices = self.indices;
@@ -181,6 +262,49 @@ impl FirestoreCacheCollectionConfiguration {
         Self { indices, ..self }
     }
 
+    /// Caches the collection group instead of a single collection: every collection with this ID
+    /// under the parent, or in the whole database without a parent, at any depth.
+    ///
+    /// Besides reads by ID, a preloaded group serves collection group queries
+    /// ([`FirestoreQueryCollection::Group`](crate::FirestoreQueryCollection::Group), or queries
+    /// with `all_descendants`) under the parent, and queries or listings of any single collection
+    /// of the group.
+    #[inline]
+    pub fn with_collection_group(self, collection_group: bool) -> Self {
+        Self {
+            collection_group,
+            ..self
+        }
+    }
+
+    /// Returns the path of a collection relative to the parent of this collection group, when
+    /// the collection belongs to the group cached at `cached_collection_path`.
+    pub(crate) fn relative_group_path<'a>(
+        &self,
+        cached_collection_path: &str,
+        collection_path: &'a str,
+    ) -> Option<&'a str> {
+        let group_parent = cached_collection_path
+            .strip_suffix(self.collection_name.as_str())?
+            .strip_suffix('/')?;
+        let (_, collection_id) = split_document_path(collection_path);
+        if collection_id != self.collection_name {
+            return None;
+        }
+        collection_path
+            .strip_prefix(group_parent)?
+            .strip_prefix('/')
+    }
+
+    /// Returns the query for all documents of this collection, or of this collection group, used
+    /// to preload the collection and to listen to its changes.
+    #[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
+    pub(crate) fn query_params(&self) -> FirestoreQueryParams {
+        FirestoreQueryParams::new(self.collection_name.as_str().into())
+            .opt_parent(self.parent.clone())
+            .opt_all_descendants(self.collection_group.then_some(true))
+    }
+
     /// Resolves the absolute path of this collection against the given documents path.
     #[inline]
     pub(crate) fn resolve_collection_path(&self, documents_path: &str) -> String {
@@ -223,15 +347,36 @@ impl FirestoreCacheCollectionLoadMode {
     }
 }
 
-#[doc(hidden)]
-/// Not implemented and has no effect. Scheduled for removal.
+/// A secondary index of a cached collection, on one field or on several fields in order.
+///
+/// Cached queries use an index when equality filters fix its leading fields, optionally followed
+/// by a range filter or an ordering on the next field, so that only the matching documents are
+/// read instead of the whole collection. When the query is ordered by the remaining fields of the
+/// index, the documents are also read in order and need no sorting. As in Firestore, documents
+/// missing any of the indexed fields are left out of the index.
+///
+/// Indexes are kept in memory by both backends; the persistent backend rebuilds them from its
+/// stored documents when it is opened.
+///
+/// ```rust
+/// # use firestore::*;
+/// let index = FirestoreCacheIndexConfiguration::new(["country", "population"]);
+/// let unique_index = FirestoreCacheIndexConfiguration::new(["code"]).unique(true);
+/// ```
 #[derive(Debug, Clone)]
 pub struct FirestoreCacheIndexConfiguration {
+    /// The indexed field paths, in order.
     pub fields: Vec<String>,
+    /// No two cached documents may have the same values in the indexed fields.
+    ///
+    /// Firestore itself does not enforce this, so the cache refuses to store a document that
+    /// violates it and reports an error instead. Note that such a document is then missing from
+    /// cached listings of the collection.
     pub unique: bool,
 }
 
 impl FirestoreCacheIndexConfiguration {
+    /// Creates a non-unique index on the given field paths.
     #[inline]
     pub fn new<I>(fields: I) -> Self
     where
@@ -244,6 +389,7 @@ impl FirestoreCacheIndexConfiguration {
         }
     }
 
+    /// Sets whether the index is unique.
     #[inline]
     pub fn unique(self, value: bool) -> Self {
         Self {
@@ -251,4 +397,26 @@ impl FirestoreCacheIndexConfiguration {
             ..self
         }
     }
+
+    /// Checks that the index names at least one field, and only document fields.
+    pub fn validate(&self) -> FirestoreResult<()> {
+        if self.fields.is_empty()
+            || self
+                .fields
+                .iter()
+                .any(|field| field.is_empty() || field == DOCUMENT_NAME_FIELD)
+        {
+            Err(FirestoreError::InvalidParametersError(
+                FirestoreInvalidParametersError::new(FirestoreInvalidParametersPublicDetails::new(
+                    "fields".to_string(),
+                    format!(
+                        "Cache index fields must name at least one document field, got {:?}",
+                        self.fields
+                    ),
+                )),
+            ))
+        } else {
+            Ok(())
+        }
+    }
 }
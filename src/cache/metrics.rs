@@ -0,0 +1,71 @@
+use crate::FirestoreCacheName;
+use rsb_derive::Builder;
+use rvstruct::ValueStruct;
+use tracing::*;
+
+/// Counters of a cached collection, returned by [`FirestoreCache::metrics`](crate::FirestoreCache::metrics).
+///
+/// The counters start at zero when the backend is created and only grow. Compare two snapshots
+/// to get rates, e.g. the hit ratio over the last minute.
+#[derive(Debug, Clone, Eq, PartialEq, Builder)]
+pub struct FirestoreCacheCollectionMetrics {
+    /// The full path to the collection.
+    pub collection_path: String,
+    /// Reads by ID served from the cache.
+    #[default = "0"]
+    pub hits: u64,
+    /// Reads by ID of documents that were not in the cache, or while the cache was too stale to
+    /// serve them.
+    #[default = "0"]
+    pub misses: u64,
+    /// `list` and `query` requests served from the cache.
+    #[default = "0"]
+    pub query_hits: u64,
+    /// `list` and `query` requests the cache could not serve, or while it was too stale to serve
+    /// them. `read_through_cache` sends them to Firestore.
+    #[default = "0"]
+    pub query_fallbacks: u64,
+    /// Documents written to the cache from listener events.
+    #[default = "0"]
+    pub listener_updates: u64,
+    /// Documents deleted, or no longer matching the collection's listener target, as delivered by
+    /// listener events. They are removed from the cache.
+    #[default = "0"]
+    pub listener_removals: u64,
+    /// Documents evicted because they expired, after their time to live or time to idle.
+    #[default = "0"]
+    pub evictions_expired: u64,
+    /// Documents evicted to keep the cache within its maximum capacity.
+    #[default = "0"]
+    pub evictions_size: u64,
+}
+
+impl FirestoreCacheCollectionMetrics {
+    /// Returns the share of reads by ID served from the cache, or `None` before the first read.
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let reads = self.hits + self.misses;
+        (reads > 0).then(|| self.hits as f64 / reads as f64)
+    }
+}
+
+/// Emits the metrics of a cache as `tracing` events, one per collection.
+pub(crate) fn report_cache_metrics(
+    cache_name: &FirestoreCacheName,
+    metrics: &[FirestoreCacheCollectionMetrics],
+) {
+    for collection in metrics {
+        info!(
+            cache_name = cache_name.value(),
+            collection_path = collection.collection_path,
+            hits = collection.hits,
+            misses = collection.misses,
+            query_hits = collection.query_hits,
+            query_fallbacks = collection.query_fallbacks,
+            listener_updates = collection.listener_updates,
+            listener_removals = collection.listener_removals,
+            evictions_expired = collection.evictions_expired,
+            evictions_size = collection.evictions_size,
+            "Cache metrics.",
+        );
+    }
+}
@@ -47,6 +47,9 @@
 //!   for the rest. This is the mode to reach for by default.
 //! - [`FirestoreDb::read_cached_only`] never contacts Firestore. Reads by ID return `None` on a
 //!   miss, and requests the cache cannot answer completely return an error.
+//! - [`FirestoreDb::write_through_cache`] reads like `read_through_cache`, and also updates the
+//!   cache with the documents you create, update or delete through it, as soon as Firestore has
+//!   accepted the write. Use it when a read right after your own write must see that write.
 //!
 //! Which operations use the cache:
 //!
@@ -55,7 +58,14 @@
 //! | Read by ID, batch read by IDs | yes, for any cached collection |
 //! | `list` a collection | only for **preloaded** collections |
 //! | `query` a collection | only for **preloaded** collections, and only for supported filters |
-//! | Paged listing, query with metadata, aggregations, transactions, writes | never |
+//! | Aggregations (`count`, `sum`, `avg`) | same as `query`, evaluated over the cached results |
+//! | Collection group queries | only for **preloaded** collection groups |
+//! | Paged listing, query with metadata, reads in transactions | never |
+//! | Writes, committed transactions, simple batch writes | update the cache with `write_through_cache` |
+//!
+//! Field projections (`.fields(...)`) are applied to the cached documents, so cached results have
+//! the same shape as those Firestore returns. Documents read from Firestore with a projection are
+//! partial and are not cached.
 //!
 //! # Preloading, and why listings need it
 //!
@@ -69,13 +79,82 @@
 //! [`FirestoreCacheCollectionLoadMode`] for the individual modes, and
 //! [`FirestoreCacheIncompleteCollectionPolicy`] if you would rather accept partial results.
 //!
+//! # Indexes
+//!
+//! Cached queries scan the whole collection unless it has a suitable secondary index. Add
+//! indexes on the fields you filter and order by with [`FirestoreCacheCollection::index`]:
+//!
+//! ```rust,no_run
+//! # use firestore::*;
+//! # async fn example(db: &FirestoreDb) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//! let cache = FirestoreCache::memory(db)
+//!     .collection_with("cities", |c| {
+//!         c.preload_all()
+//!             .index(FirestoreCacheIndexConfiguration::new(["country", "population"]))
+//!             .index(FirestoreCacheIndexConfiguration::new(["code"]).unique(true))
+//!     })
+//!     .build()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! See [`FirestoreCacheIndexConfiguration`] for the queries an index can serve.
+//!
+//! # Collection groups
+//!
+//! [`FirestoreCacheCollection::collection_group`] caches every collection with the same ID under
+//! a parent, or in the whole database, at any depth. The listener keeps all of them current, and
+//! once preloaded the group serves collection group queries under its parent as well as listings
+//! and queries of any single collection in it:
+//!
+//! ```rust,no_run
+//! # use firestore::*;
+//! # async fn example(db: &FirestoreDb) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//! let cache = FirestoreCache::memory(db)
+//!     .collection_with("comments", |c| c.preload_all().collection_group())
+//!     .build()
+//!     .await?;
+//!
+//! let comments = db
+//!     .read_cached_only(&cache)
+//!     .fluent()
+//!     .select()
+//!     .from("comments")
+//!     .all_descendants()
+//!     .query()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A collection cannot be configured on its own when a group of the cache already holds it.
+//!
+//! # Metrics
+//!
+//! [`FirestoreCache::metrics`] returns per-collection counters: reads served from the cache and
+//! missed, listings that fell back to Firestore, documents updated by the listener, and
+//! evictions by cause. Poll it, or have the cache emit the counters as `tracing` events with
+//! [`FirestoreCacheBuilder::metrics_report_interval`].
+//!
 //! # Consistency
 //!
 //! Cached results are **eventually consistent**. They reflect the last state the listener
-//! delivered, so a write may take a moment to appear, and a stalled or reset listener can leave
-//! the cache stale without saying so. Cached listings are never *partial* by construction, but
-//! they are not guaranteed to be current. Do not cache data that must be read at strong
-//! consistency - read that through Firestore directly, or inside a transaction.
+//! delivered, so a write may take a moment to appear, and a stalled listener can leave the cache
+//! stale. [`FirestoreCache::last_synchronized_at`] tells how current a cached collection is, and
+//! [`FirestoreCache::status`] reports the state of the listener, when it last received events and
+//! how many errors it ran into - use it for health checks.
+//!
+//! To stop serving stale data, set [`FirestoreCacheBuilder::max_staleness`]: once the listener
+//! has been disconnected for longer than that, `read_through_cache` reads from Firestore until
+//! the listener is connected again.
+//!
+//! When Firestore resets the listener target of a collection, the collection is resynchronized
+//! and is not used for `list` or `query` until the server has sent it again. When Firestore
+//! removes the target, the collection is dropped from the cache and read from Firestore from then
+//! on. Cached listings are never *partial* by construction, but they are not guaranteed to be
+//! current. Do not cache data that must be read at strong consistency - read that through
+//! Firestore directly, or inside a transaction.
 //!
 //! # Lifecycle
 //!
@@ -123,16 +202,26 @@ pub use configuration::*;
 mod builder;
 pub use builder::*;
 
+#[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
 mod backends;
+#[cfg(any(feature = "caching-memory", feature = "caching-persistent"))]
 pub use backends::*;
 
+mod status;
+pub use status::*;
+
+mod metrics;
+pub use metrics::*;
+
 use async_trait::async_trait;
 use futures::stream::BoxStream;
 use futures::StreamExt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tracing::*;
 
-mod cache_filter_engine;
-mod cache_query_engine;
+pub(crate) mod cache_aggregation_engine;
+pub(crate) mod cache_filter_engine;
+pub(crate) mod cache_query_engine;
 
 /// Manages a cache of Firestore data.
 ///
@@ -167,6 +256,12 @@ where
     /// Behind a mutex so that `load`/`shutdown` can take `&self`, which lets a built cache be
     /// shared directly as `Arc<FirestoreCache<..>>` in application state.
     pub listener: tokio::sync::Mutex<FirestoreListener<FirestoreDb, LS>>,
+    /// Follows the listener status without locking the listener.
+    pub staleness_check: FirestoreCacheStalenessCheck,
+    /// How many listener events the backend failed to apply.
+    pub update_error_count: Arc<AtomicU64>,
+    /// The task emitting metrics periodically, if configured and loaded.
+    pub metrics_reporter: std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
     /// A clone of the Firestore database client.
     pub db: FirestoreDb,
 }
@@ -344,11 +439,17 @@ where
             db.create_listener(listener_storage).await?
         };
 
+        let staleness_check =
+            FirestoreCacheStalenessCheck::new(listener.shared_status(), options.max_staleness);
+
         Ok(Self {
             inner: FirestoreCacheInner {
                 options,
                 backend: Arc::new(backend),
                 listener: tokio::sync::Mutex::new(listener),
+                staleness_check,
+                update_error_count: Arc::new(AtomicU64::new(0)),
+                metrics_reporter: std::sync::Mutex::new(None),
                 db: db.clone(),
             },
         })
@@ -382,25 +483,66 @@ where
         }
 
         let backend = self.inner.backend.clone();
+        let update_error_count = self.inner.update_error_count.clone();
         listener
-            .start(move |event| {
+            .start_with_target_changes(move |event| {
                 let backend = backend.clone();
+                let update_error_count = update_error_count.clone();
                 async move {
                     if let Err(err) = backend.on_listen_event(event).await {
+                        update_error_count.fetch_add(1, Ordering::Relaxed);
                         error!(?err, "Error occurred while updating cache.");
                     };
                     Ok(())
                 }
             })
             .await?;
+
+        if let Some(interval) = self.inner.options.metrics_report_interval {
+            let backend = self.inner.backend.clone();
+            let staleness_check = self.inner.staleness_check.clone();
+            let cache_name = self.inner.options.name.clone();
+            let metrics_reporter = tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(interval);
+                // The first tick completes immediately
+                ticks.tick().await;
+                loop {
+                    ticks.tick().await;
+                    report_cache_metrics(
+                        &cache_name,
+                        &staleness_check.with_stale_reads(backend.metrics()),
+                    );
+                }
+            });
+            if let Some(previous_reporter) = self.replace_metrics_reporter(Some(metrics_reporter)) {
+                previous_reporter.abort();
+            }
+        }
         Ok(())
     }
 
+    fn replace_metrics_reporter(
+        &self,
+        metrics_reporter: Option<tokio::task::JoinHandle<()>>,
+    ) -> Option<tokio::task::JoinHandle<()>> {
+        std::mem::replace(
+            &mut *self
+                .inner
+                .metrics_reporter
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            metrics_reporter,
+        )
+    }
+
     /// Shuts down the Firestore listener and the cache backend.
     ///
     /// # Returns
     /// A `Result` indicating success or failure.
     pub async fn shutdown(&self) -> Result<(), FirestoreError> {
+        if let Some(metrics_reporter) = self.replace_metrics_reporter(None) {
+            metrics_reporter.abort();
+        }
         self.inner.listener.lock().await.shutdown().await?;
         self.inner.backend.shutdown().await?;
         Ok(())
@@ -411,6 +553,89 @@ where
         self.inner.backend.clone()
     }
 
+    /// Returns the backend for `read_through_cache` sessions, which skips the cache while it is
+    /// stale if a maximum staleness is configured.
+    pub(crate) fn read_through_backend(&self) -> FirestoreSharedCacheBackend {
+        staleness_guarded_backend(self.inner.backend.clone(), &self.inner.staleness_check)
+    }
+
+    /// Reports the health of the cache: the cached collections with their document counts and
+    /// synchronization times, the state of the listener and the errors it ran into.
+    ///
+    /// Use it for health checks and to tell whether cached reads can be trusted: a listener that
+    /// keeps reconnecting, or collections that have not received an event for long, mean the
+    /// cache may be stale.
+    pub async fn status(&self) -> FirestoreResult<FirestoreCacheStatus> {
+        let listener = self.inner.staleness_check.listener_status();
+
+        let collections = self
+            .inner
+            .backend
+            .collections_status()
+            .await?
+            .into_iter()
+            .map(|collection| {
+                match listener
+                    .targets
+                    .iter()
+                    .find(|target_status| target_status.target == collection.listener_target)
+                {
+                    Some(target_status) => collection
+                        .opt_last_event_at(target_status.last_event_at)
+                        .opt_last_resume_token_at(target_status.last_resume_token_at),
+                    None => collection,
+                }
+            })
+            .collect();
+
+        Ok(FirestoreCacheStatus::new(
+            self.inner.options.name.clone(),
+            listener,
+            collections,
+            self.inner.update_error_count.load(Ordering::Relaxed),
+            self.inner.staleness_check.is_stale(),
+        ))
+    }
+
+    /// Returns the hit, miss, fallback, listener update and eviction counters of each cached
+    /// collection, as reported by the backend.
+    ///
+    /// Use them to tune the cache: many size evictions with a low hit ratio suggest a larger
+    /// capacity, and many expired evictions a longer time to live.
+    pub fn metrics(&self) -> Vec<FirestoreCacheCollectionMetrics> {
+        self.inner
+            .staleness_check
+            .with_stale_reads(self.inner.backend.metrics())
+    }
+
+    /// Emits the cache metrics as `tracing` events at the `INFO` level, one per collection, with
+    /// the target `firestore::cache::metrics`.
+    ///
+    /// To emit them periodically instead, configure
+    /// [`FirestoreCacheBuilder::metrics_report_interval`].
+    pub fn report_metrics(&self) {
+        report_cache_metrics(&self.inner.options.name, &self.metrics());
+    }
+
+    /// Returns the read time at which the collection was last known to be in sync with Firestore,
+    /// as reported by the backend.
+    ///
+    /// Cached reads reflect at least this point in time. Comparing it with the current time tells
+    /// how stale the cached collection may be.
+    ///
+    /// # Arguments
+    /// * `collection_path`: The full path to the collection, e.g.
+    ///   `format!("{}/countries", db.get_documents_path())`.
+    pub async fn last_synchronized_at(
+        &self,
+        collection_path: &str,
+    ) -> FirestoreResult<Option<FirestoreInstant>> {
+        self.inner
+            .backend
+            .last_synchronized_at(collection_path)
+            .await
+    }
+
     /// Invalidates all data in the cache.
     ///
     /// This calls the `invalidate_all` method on the cache backend.
@@ -473,6 +698,36 @@ pub trait FirestoreCacheBackend: FirestoreCacheDocsByPathSupport {
     /// # Returns
     /// A `FirestoreResult` indicating success or failure of processing the event.
     async fn on_listen_event(&self, event: FirestoreListenEvent) -> FirestoreResult<()>;
+
+    /// Returns the read time at which the collection was last known to be in sync with Firestore.
+    ///
+    /// This is the latest consistent snapshot the listener reported for the collection's target,
+    /// or `None` if there has been none yet, if the collection is not cached, or if the backend
+    /// does not track it. The default implementation returns `None`.
+    ///
+    /// # Arguments
+    /// * `collection_path`: The full path to the collection (e.g., "projects/P/databases/D/documents/C").
+    async fn last_synchronized_at(
+        &self,
+        _collection_path: &str,
+    ) -> FirestoreResult<Option<FirestoreInstant>> {
+        Ok(None)
+    }
+
+    /// Returns the status of the cached collections, for [`FirestoreCache::status`].
+    ///
+    /// The listener event and resume token times are filled in by the cache and can be left
+    /// unset. The default implementation reports no collections.
+    async fn collections_status(&self) -> FirestoreResult<Vec<FirestoreCacheCollectionStatus>> {
+        Ok(Vec::new())
+    }
+
+    /// Returns the counters of the cached collections, for [`FirestoreCache::metrics`].
+    ///
+    /// The default implementation reports no collections.
+    fn metrics(&self) -> Vec<FirestoreCacheCollectionMetrics> {
+        Vec::new()
+    }
 }
 
 /// Defines support for retrieving and updating cached documents by their full path.
@@ -491,6 +746,25 @@ pub trait FirestoreCacheDocsByPathSupport {
         document_path: &str,
     ) -> FirestoreResult<Option<FirestoreDocument>>;
 
+    /// Retrieves a single document from the cache like [`Self::get_doc_by_path`], without
+    /// counting the read in the metrics.
+    ///
+    /// Used by [`FirestoreDbSessionCacheMode::WriteThroughCache`](crate::FirestoreDbSessionCacheMode::WriteThroughCache)
+    /// sessions to compare a written document with its cached version. The default
+    /// implementation calls `get_doc_by_path`.
+    ///
+    /// # Arguments
+    /// * `document_path`: The full path to the document (e.g., "projects/P/databases/D/documents/C/ID").
+    async fn peek_doc_by_path(
+        &self,
+        document_path: &str,
+    ) -> FirestoreResult<Option<FirestoreDocument>>
+    where
+        Self: Sync,
+    {
+        self.get_doc_by_path(document_path).await
+    }
+
     /// Retrieves multiple documents from the cache by their full Firestore paths.
     ///
     /// This default implementation iterates over `full_doc_ids` and calls `get_doc_by_path`
@@ -541,6 +815,21 @@ pub trait FirestoreCacheDocsByPathSupport {
     /// A `FirestoreResult` indicating success or failure.
     async fn update_doc_by_path(&self, document: &FirestoreDocument) -> FirestoreResult<()>;
 
+    /// Removes a document from the cache, if it is there.
+    ///
+    /// Used by [`FirestoreDbSessionCacheMode::WriteThroughCache`](crate::FirestoreDbSessionCacheMode::WriteThroughCache)
+    /// sessions. The default implementation does nothing, so the document stays cached until the
+    /// listener delivers its deletion.
+    ///
+    /// # Arguments
+    /// * `document_path`: The full path to the document (e.g., "projects/P/databases/D/documents/C/ID").
+    ///
+    /// # Returns
+    /// A `FirestoreResult` indicating success or failure.
+    async fn delete_doc_by_path(&self, _document_path: &str) -> FirestoreResult<()> {
+        Ok(())
+    }
+
     /// Lists all documents in the cache for a given collection path.
     ///
     /// # Arguments
@@ -13,11 +13,19 @@ pub struct FirestoreCacheName(String);
 /// Prefer configuring these through the builder - [`FirestoreCache::memory`](crate::FirestoreCache::memory)
 /// or [`FirestoreCache::persistent`](crate::FirestoreCache::persistent) - rather than
 /// constructing this type directly.
-#[derive(Debug, Eq, PartialEq, Clone, Builder)]
+#[derive(Debug, PartialEq, Clone, Builder)]
 pub struct FirestoreCacheOptions {
     /// The name of this cache instance.
     pub name: FirestoreCacheName,
     /// Options for the Firestore listener that keeps the cache up to date, such as its retry
     /// delay. When unset, the listener defaults are used.
     pub listener_params: Option<crate::FirestoreListenerParams>,
+    /// How long the listener may stay disconnected before `read_through_cache` stops serving reads
+    /// from the cache and reads from Firestore instead, until the listener is connected again.
+    /// When unset, the cache is used however long the listener is disconnected.
+    pub max_staleness: Option<std::time::Duration>,
+    /// How often to emit the cache metrics as `tracing` events while the cache is loaded. When
+    /// unset, metrics are only available through
+    /// [`FirestoreCache::metrics`](crate::FirestoreCache::metrics).
+    pub metrics_report_interval: Option<std::time::Duration>,
 }
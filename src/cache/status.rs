@@ -0,0 +1,368 @@
+use crate::*;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use rsb_derive::Builder;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// A snapshot of the health of a [`FirestoreCache`], returned by [`FirestoreCache::status`].
+#[derive(Debug, Clone, Builder)]
+pub struct FirestoreCacheStatus {
+    /// The name of the cache.
+    pub name: FirestoreCacheName,
+    /// The status of the listener that keeps the cache current.
+    pub listener: FirestoreListenerStatus,
+    /// The cached collections, as reported by the backend.
+    pub collections: Vec<FirestoreCacheCollectionStatus>,
+    /// How many listener events the backend failed to apply. Each of them may have left the cache
+    /// stale.
+    pub update_error_count: u64,
+    /// `true` when the listener has been disconnected for longer than the configured maximum
+    /// staleness, so that `read_through_cache` reads from Firestore instead of the cache.
+    pub is_stale: bool,
+}
+
+/// The status of a cached collection.
+#[derive(Debug, Clone, Builder)]
+pub struct FirestoreCacheCollectionStatus {
+    /// The full path to the collection.
+    pub collection_path: String,
+    /// The listener target that keeps the collection current.
+    pub listener_target: FirestoreListenerTarget,
+    pub load_mode: FirestoreCacheCollectionLoadMode,
+    /// The number of documents in the cache. It can be approximate for backends that evict
+    /// documents in the background.
+    pub document_count: u64,
+    /// See [`FirestoreCache::last_synchronized_at`].
+    pub last_synchronized_at: Option<FirestoreInstant>,
+    /// When the listener last received an event for the collection. Filled in by
+    /// [`FirestoreCache::status`] from the listener status.
+    pub last_event_at: Option<FirestoreInstant>,
+    /// When the listener last received a resume token for the collection. Filled in by
+    /// [`FirestoreCache::status`] from the listener status.
+    pub last_resume_token_at: Option<FirestoreInstant>,
+}
+
+/// Tells whether a cache is too stale to read through, from the status of its listener.
+#[derive(Clone)]
+pub(crate) struct FirestoreCacheStalenessCheck {
+    listener_status: FirestoreListenerSharedStatus,
+    max_staleness: Option<std::time::Duration>,
+    /// The reads sent to Firestore because the cache was stale, by collection path, counted as
+    /// misses and query fallbacks.
+    stale_reads: Arc<Mutex<HashMap<String, FirestoreCacheCollectionMetrics>>>,
+}
+
+impl FirestoreCacheStalenessCheck {
+    pub fn new(
+        listener_status: FirestoreListenerSharedStatus,
+        max_staleness: Option<std::time::Duration>,
+    ) -> Self {
+        Self {
+            listener_status,
+            max_staleness,
+            stale_reads: Arc::default(),
+        }
+    }
+
+    pub fn listener_status(&self) -> FirestoreListenerStatus {
+        self.listener_status
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.max_staleness.is_some_and(|max_staleness| {
+            self.listener_status()
+                .disconnected_for()
+                .is_some_and(|disconnected_for| disconnected_for > max_staleness)
+        })
+    }
+
+    fn record_stale_read<F>(&self, collection_path: &str, counter: F)
+    where
+        F: FnOnce(&mut FirestoreCacheCollectionMetrics) -> &mut u64,
+    {
+        let mut stale_reads = self
+            .stale_reads
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let collection_reads = stale_reads
+            .entry(collection_path.to_string())
+            .or_insert_with(|| FirestoreCacheCollectionMetrics::new(collection_path.to_string()));
+        *counter(collection_reads) += 1;
+    }
+
+    /// Adds the reads sent to Firestore because the cache was stale to the metrics of the backend.
+    pub fn with_stale_reads(
+        &self,
+        mut metrics: Vec<FirestoreCacheCollectionMetrics>,
+    ) -> Vec<FirestoreCacheCollectionMetrics> {
+        let stale_reads = self
+            .stale_reads
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for collection in metrics.iter_mut() {
+            if let Some(collection_reads) = stale_reads.get(&collection.collection_path) {
+                collection.misses += collection_reads.misses;
+                collection.query_fallbacks += collection_reads.query_fallbacks;
+            }
+        }
+        metrics
+    }
+}
+
+/// Wraps the backend of a cache for `read_through_cache` sessions, and serves nothing from it
+/// while the cache is stale, so that reads go to Firestore instead.
+pub(crate) struct FirestoreStalenessGuardedCacheBackend {
+    backend: FirestoreSharedCacheBackend,
+    staleness_check: FirestoreCacheStalenessCheck,
+}
+
+impl FirestoreStalenessGuardedCacheBackend {
+    pub fn new(
+        backend: FirestoreSharedCacheBackend,
+        staleness_check: FirestoreCacheStalenessCheck,
+    ) -> Self {
+        Self {
+            backend,
+            staleness_check,
+        }
+    }
+
+    fn is_stale(&self) -> bool {
+        let is_stale = self.staleness_check.is_stale();
+        if is_stale {
+            tracing::debug!("The cache listener is disconnected for too long. Skipping cache.");
+        }
+        is_stale
+    }
+
+    /// Returns `true` when a read by ID must go to Firestore, counting it as a miss.
+    fn is_stale_doc_read(&self, document_path: &str) -> bool {
+        let is_stale = self.is_stale();
+        if is_stale {
+            let collection_path = crate::db::split_document_path(document_path).0;
+            self.staleness_check
+                .record_stale_read(collection_path, |counters| &mut counters.misses);
+        }
+        is_stale
+    }
+
+    /// Returns `true` when a list or a query must go to Firestore, counting it as a fallback.
+    fn is_stale_query(&self, collection_path: &str) -> bool {
+        let is_stale = self.is_stale();
+        if is_stale {
+            self.staleness_check
+                .record_stale_read(collection_path, |counters| &mut counters.query_fallbacks);
+        }
+        is_stale
+    }
+}
+
+#[async_trait]
+impl FirestoreCacheBackend for FirestoreStalenessGuardedCacheBackend {
+    async fn load(
+        &self,
+        options: &FirestoreCacheOptions,
+        db: &FirestoreDb,
+    ) -> Result<Vec<FirestoreListenerTargetParams>, FirestoreError> {
+        self.backend.load(options, db).await
+    }
+
+    async fn invalidate_all(&self) -> FirestoreResult<()> {
+        self.backend.invalidate_all().await
+    }
+
+    async fn shutdown(&self) -> FirestoreResult<()> {
+        self.backend.shutdown().await
+    }
+
+    async fn on_listen_event(&self, event: FirestoreListenEvent) -> FirestoreResult<()> {
+        self.backend.on_listen_event(event).await
+    }
+
+    async fn last_synchronized_at(
+        &self,
+        collection_path: &str,
+    ) -> FirestoreResult<Option<FirestoreInstant>> {
+        self.backend.last_synchronized_at(collection_path).await
+    }
+
+    async fn collections_status(&self) -> FirestoreResult<Vec<FirestoreCacheCollectionStatus>> {
+        self.backend.collections_status().await
+    }
+
+    fn metrics(&self) -> Vec<FirestoreCacheCollectionMetrics> {
+        self.staleness_check
+            .with_stale_reads(self.backend.metrics())
+    }
+}
+
+#[async_trait]
+impl FirestoreCacheDocsByPathSupport for FirestoreStalenessGuardedCacheBackend {
+    async fn get_doc_by_path(
+        &self,
+        document_path: &str,
+    ) -> FirestoreResult<Option<FirestoreDocument>> {
+        if self.is_stale_doc_read(document_path) {
+            Ok(None)
+        } else {
+            self.backend.get_doc_by_path(document_path).await
+        }
+    }
+
+    async fn get_docs_by_paths<'a>(
+        &'a self,
+        full_doc_ids: &'a [String],
+    ) -> FirestoreResult<BoxStream<'a, FirestoreResult<(String, Option<FirestoreDocument>)>>>
+    where
+        Self: Sync,
+    {
+        if self.is_stale() {
+            for document_path in full_doc_ids {
+                let collection_path = crate::db::split_document_path(document_path).0;
+                self.staleness_check
+                    .record_stale_read(collection_path, |counters| &mut counters.misses);
+            }
+            Ok(Box::pin(futures::stream::empty()))
+        } else {
+            self.backend.get_docs_by_paths(full_doc_ids).await
+        }
+    }
+
+    async fn peek_doc_by_path(
+        &self,
+        document_path: &str,
+    ) -> FirestoreResult<Option<FirestoreDocument>> {
+        self.backend.peek_doc_by_path(document_path).await
+    }
+
+    async fn update_doc_by_path(&self, document: &FirestoreDocument) -> FirestoreResult<()> {
+        self.backend.update_doc_by_path(document).await
+    }
+
+    async fn delete_doc_by_path(&self, document_path: &str) -> FirestoreResult<()> {
+        self.backend.delete_doc_by_path(document_path).await
+    }
+
+    async fn list_all_docs<'b>(
+        &self,
+        collection_path: &str,
+    ) -> FirestoreResult<FirestoreCachedValue<BoxStream<'b, FirestoreResult<FirestoreDocument>>>>
+    {
+        if self.is_stale_query(collection_path) {
+            Ok(FirestoreCachedValue::SkipCache)
+        } else {
+            self.backend.list_all_docs(collection_path).await
+        }
+    }
+
+    async fn query_docs<'b>(
+        &self,
+        collection_path: &str,
+        query: &FirestoreQueryParams,
+    ) -> FirestoreResult<FirestoreCachedValue<BoxStream<'b, FirestoreResult<FirestoreDocument>>>>
+    {
+        if self.is_stale_query(collection_path) {
+            Ok(FirestoreCachedValue::SkipCache)
+        } else {
+            self.backend.query_docs(collection_path, query).await
+        }
+    }
+}
+
+/// Wraps a cache backend for read-through sessions when a maximum staleness is configured.
+pub(crate) fn staleness_guarded_backend(
+    backend: FirestoreSharedCacheBackend,
+    staleness_check: &FirestoreCacheStalenessCheck,
+) -> FirestoreSharedCacheBackend {
+    if staleness_check.max_staleness.is_some() {
+        Arc::new(FirestoreStalenessGuardedCacheBackend::new(
+            backend,
+            staleness_check.clone(),
+        ))
+    } else {
+        backend
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn staleness_check(
+        connection_state: FirestoreListenerConnectionState,
+        since_secs_ago: i64,
+    ) -> FirestoreCacheStalenessCheck {
+        let status = FirestoreListenerStatus::new(connection_state, Vec::new(), 0, 0)
+            .with_connection_state_since(
+                FirestoreInstant::now() - FirestoreDuration::from_secs(since_secs_ago),
+            );
+        FirestoreCacheStalenessCheck::new(
+            Arc::new(Mutex::new(status)),
+            Some(std::time::Duration::from_secs(60)),
+        )
+    }
+
+    #[test]
+    fn is_stale_only_when_disconnected_for_too_long() {
+        use FirestoreListenerConnectionState::*;
+
+        assert!(!staleness_check(Connected, 3600).is_stale());
+        assert!(!staleness_check(NotStarted, 3600).is_stale());
+        assert!(!staleness_check(Reconnecting, 10).is_stale());
+        assert!(staleness_check(Reconnecting, 120).is_stale());
+        assert!(staleness_check(Stopped, 120).is_stale());
+    }
+
+    #[cfg(feature = "caching-memory")]
+    #[tokio::test]
+    async fn counts_stale_reads_as_misses_and_fallbacks() {
+        const DOCS: &str = "projects/test/databases/(default)/documents";
+        let collection_path = format!("{DOCS}/cities");
+        let config = FirestoreCacheConfiguration::new().add_collection_config_at(
+            DOCS,
+            FirestoreCacheCollectionConfiguration::new(
+                "cities",
+                FirestoreListenerTarget::new(42),
+                FirestoreCacheCollectionLoadMode::PreloadNone,
+            ),
+        );
+        let backend: FirestoreSharedCacheBackend =
+            Arc::new(FirestoreMemoryCacheBackend::new(config).unwrap());
+        let staleness_check = staleness_check(FirestoreListenerConnectionState::Reconnecting, 120);
+        let guarded = staleness_guarded_backend(backend.clone(), &staleness_check);
+
+        assert!(guarded
+            .get_doc_by_path(&format!("{collection_path}/a"))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            guarded.list_all_docs(&collection_path).await.unwrap(),
+            FirestoreCachedValue::SkipCache
+        ));
+        assert!(matches!(
+            guarded
+                .query_docs(
+                    &collection_path,
+                    &FirestoreQueryParams::new("cities".into())
+                )
+                .await
+                .unwrap(),
+            FirestoreCachedValue::SkipCache
+        ));
+
+        let expected_metrics = vec![FirestoreCacheCollectionMetrics::new(collection_path)
+            .with_misses(1)
+            .with_query_fallbacks(2)];
+        assert_eq!(
+            staleness_check.with_stale_reads(backend.metrics()),
+            expected_metrics
+        );
+        assert_eq!(guarded.metrics(), expected_metrics);
+    }
+}
@@ -1,6 +1,10 @@
 #![allow(clippy::derive_partial_eq_without_eq)] // Since we may not be able to implement Eq for the changes coming from Firestore protos
 
+#[cfg(feature = "caching")]
+use crate::cache::cache_aggregation_engine::FirestoreCacheAggregationEngine;
 use crate::db::support::FirestoreAggregatedQuerySupport;
+#[cfg(feature = "caching")]
+use crate::FirestoreCachedValue;
 use crate::FirestoreInstant;
 use crate::{FirestoreDb, FirestoreError, FirestoreQueryParams, FirestoreResult};
 use async_trait::async_trait;
@@ -109,6 +113,15 @@ impl FirestoreAggregatedQuerySupport for FirestoreDb {
         &self,
         params: FirestoreAggregatedQueryParams,
     ) -> FirestoreResult<Vec<Document>> {
+        #[cfg(feature = "caching")]
+        {
+            if let FirestoreCachedValue::UseCached(doc) =
+                self.aggregated_query_doc_from_cache(&params).await?
+            {
+                return Ok(vec![doc]);
+            }
+        }
+
         let collection_str = params.query_params.collection_id.to_string();
 
         let span = span!(
@@ -125,6 +138,15 @@ impl FirestoreAggregatedQuerySupport for FirestoreDb {
         &self,
         params: FirestoreAggregatedQueryParams,
     ) -> FirestoreResult<BoxStream<'b, Document>> {
+        #[cfg(feature = "caching")]
+        {
+            if let FirestoreCachedValue::UseCached(doc) =
+                self.aggregated_query_doc_from_cache(&params).await?
+            {
+                return Ok(futures::stream::once(future::ready(doc)).boxed());
+            }
+        }
+
         let collection_str = params.query_params.collection_id.to_string();
 
         let span = span!(
@@ -154,6 +176,15 @@ impl FirestoreAggregatedQuerySupport for FirestoreDb {
         &self,
         params: FirestoreAggregatedQueryParams,
     ) -> FirestoreResult<BoxStream<'b, FirestoreResult<Document>>> {
+        #[cfg(feature = "caching")]
+        {
+            if let FirestoreCachedValue::UseCached(doc) =
+                self.aggregated_query_doc_from_cache(&params).await?
+            {
+                return Ok(futures::stream::once(future::ready(Ok(doc))).boxed());
+            }
+        }
+
         let collection_str = params.query_params.collection_id.to_string();
 
         let span = span!(
@@ -398,6 +429,25 @@ impl FirestoreDb {
             .boxed()
     }
 
+    /// Evaluates the aggregations over the documents of the query when the cache can serve the
+    /// query, following the cache mode of the session just like plain queries.
+    #[cfg(feature = "caching")]
+    async fn aggregated_query_doc_from_cache(
+        &self,
+        params: &FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<FirestoreCachedValue<Document>> {
+        match self.query_docs_from_cache(&params.query_params).await? {
+            FirestoreCachedValue::UseCached(stream) => {
+                let docs: Vec<Document> = stream.try_collect().await?;
+                Ok(FirestoreCachedValue::UseCached(
+                    FirestoreCacheAggregationEngine::new(&params.aggregations)
+                        .aggregate_docs(&docs),
+                ))
+            }
+            FirestoreCachedValue::SkipCache => Ok(FirestoreCachedValue::SkipCache),
+        }
+    }
+
     fn aggregated_response_to_doc(mut agg_res: RunAggregationQueryResponse) -> Option<Document> {
         agg_res.result.take().map(|agg_res_doc| Document {
             name: "".to_string(),
@@ -407,3 +457,130 @@ impl FirestoreDb {
         })
     }
 }
+
+#[cfg(all(test, feature = "caching-memory"))]
+mod tests {
+    use super::*;
+    use crate::timestamp_utils::to_timestamp;
+    use crate::*;
+    use gcloud_sdk::google::firestore::v1::target_change::TargetChangeType;
+    use gcloud_sdk::google::firestore::v1::value::ValueType;
+    use gcloud_sdk::google::firestore::v1::Value;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    const DOCS: &str = "projects/test-project/databases/(default)/documents";
+    const TARGET: u32 = 42;
+
+    /// A read-through session over a memory cache of cities, in sync with Firestore.
+    async fn cached_db() -> FirestoreDb {
+        let config = FirestoreCacheConfiguration::new().add_collection_config_at(
+            DOCS,
+            FirestoreCacheCollectionConfiguration::new(
+                "cities",
+                FirestoreListenerTarget::new(TARGET),
+                FirestoreCacheCollectionLoadMode::PreloadAllDocs,
+            ),
+        );
+        let backend = FirestoreMemoryCacheBackend::new(config).unwrap();
+        for (id, population) in [("a", 1), ("b", 2), ("c", 6)] {
+            backend
+                .on_listen_event(FirestoreListenEvent::DocumentChange(DocumentChange {
+                    document: Some(Document {
+                        name: format!("{DOCS}/cities/{id}"),
+                        fields: HashMap::from([(
+                            "population".to_string(),
+                            Value {
+                                value_type: Some(ValueType::IntegerValue(population)),
+                            },
+                        )]),
+                        create_time: None,
+                        update_time: None,
+                    }),
+                    target_ids: vec![TARGET as i32],
+                    removed_target_ids: vec![],
+                }))
+                .await
+                .unwrap();
+        }
+        backend
+            .on_listen_event(FirestoreListenEvent::TargetChange(TargetChange {
+                target_change_type: TargetChangeType::Current as i32,
+                target_ids: vec![TARGET as i32],
+                read_time: Some(to_timestamp(FirestoreInstant::now())),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+
+        FirestoreDb::for_offline_tests().await.with_cache(
+            FirestoreDbSessionCacheMode::ReadThroughCache(Arc::new(backend)),
+        )
+    }
+
+    fn aggregations() -> Vec<FirestoreAggregation> {
+        vec![
+            FirestoreAggregation::new("count".to_string()).with_operator(
+                FirestoreAggregationOperator::Count(FirestoreAggregationOperatorCount::new()),
+            ),
+            FirestoreAggregation::new("sum".to_string()).with_operator(
+                FirestoreAggregationOperator::Sum(FirestoreAggregationOperatorSum::new(
+                    "population".to_string(),
+                )),
+            ),
+            FirestoreAggregation::new("avg".to_string()).with_operator(
+                FirestoreAggregationOperator::Avg(FirestoreAggregationOperatorAvg::new(
+                    "population".to_string(),
+                )),
+            ),
+        ]
+    }
+
+    #[tokio::test]
+    async fn aggregates_the_cached_documents_of_the_query() {
+        let db = cached_db().await;
+        let params = FirestoreAggregatedQueryParams::new(
+            FirestoreQueryParams::new("cities".into()).with_filter(FirestoreQueryFilter::Compare(
+                Some(FirestoreQueryFilterCompare::GreaterThan(
+                    "population".to_string(),
+                    1.into(),
+                )),
+            )),
+            aggregations(),
+        );
+
+        let FirestoreCachedValue::UseCached(doc) =
+            db.aggregated_query_doc_from_cache(&params).await.unwrap()
+        else {
+            panic!("The query should be served from the cache");
+        };
+        let field = |name: &str| doc.fields.get(name).and_then(|v| v.value_type.clone());
+        assert_eq!(field("count"), Some(ValueType::IntegerValue(2)));
+        assert_eq!(field("sum"), Some(ValueType::IntegerValue(8)));
+        assert_eq!(field("avg"), Some(ValueType::DoubleValue(4.0)));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_firestore_for_queries_the_cache_cannot_serve() {
+        let db = cached_db().await;
+        let unsupported_queries = [
+            FirestoreQueryParams::new("cities".into()).with_find_nearest(
+                FirestoreFindNearestOptions::new(
+                    "embedding".to_string(),
+                    FirestoreVector::new(vec![1.0, 0.0]),
+                    FirestoreFindNearestDistanceMeasure::Euclidean,
+                    3,
+                ),
+            ),
+            FirestoreQueryParams::new("countries".into()),
+        ];
+
+        for query_params in unsupported_queries {
+            let params = FirestoreAggregatedQueryParams::new(query_params, aggregations());
+            assert!(matches!(
+                db.aggregated_query_doc_from_cache(&params).await.unwrap(),
+                FirestoreCachedValue::SkipCache
+            ));
+        }
+    }
+}
@@ -67,7 +67,7 @@ impl FirestoreBatchWriter for FirestoreSimpleBatchWriter {
                 .resolve_request_options(self.options.request_options.as_ref()),
         };
 
-        backoff::future::retry(backoff, || {
+        let response = backoff::future::retry(backoff, || {
             async {
                 let response = self
                     .db
@@ -93,7 +93,26 @@ impl FirestoreBatchWriter for FirestoreSimpleBatchWriter {
             }
             .map_err(firestore_err_to_backoff)
         })
-        .await
+        .await?;
+
+        #[cfg(feature = "caching")]
+        if self.db.is_writing_through_cache() {
+            // Only the writes that succeeded are applied to the cache
+            let (succeeded_writes, succeeded_results): (Vec<Write>, Vec<FirestoreWriteResult>) =
+                request
+                    .writes
+                    .into_iter()
+                    .zip(response.write_results.iter().cloned())
+                    .zip(response.statuses.iter())
+                    .filter(|(_, status)| status.code == 0)
+                    .map(|(write_with_result, _)| write_with_result)
+                    .unzip();
+            self.db
+                .write_through_cache_writes(&succeeded_writes, &succeeded_results)
+                .await;
+        }
+
+        Ok(response)
     }
 }
 
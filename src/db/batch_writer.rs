@@ -1,4 +1,7 @@
-use crate::db::transaction_ops::{TransformObjectOperation, UpdateObjectOperation};
+use crate::db::transaction_ops::{
+    document_id_or_generate, CreateDocumentOperation, CreateObjectOperation,
+    TransformObjectOperation, UpdateObjectOperation,
+};
 use crate::db::DeleteOperation;
 use crate::errors::FirestoreError;
 use crate::FirestoreInstant;
@@ -7,7 +10,7 @@ use crate::{
     FirestoreWriteResult,
 };
 use async_trait::async_trait;
-use gcloud_sdk::google::firestore::v1::Write;
+use gcloud_sdk::google::firestore::v1::{Document, Write};
 use gcloud_sdk::google::rpc::Status;
 use rsb_derive::*;
 use serde::Serialize;
@@ -111,6 +114,78 @@ where
         })
     }
 
+    pub fn create_object<T, S>(
+        &mut self,
+        collection_id: &str,
+        document_id: Option<S>,
+        obj: &T,
+    ) -> FirestoreResult<&mut Self>
+    where
+        T: Serialize + Sync + Send,
+        S: AsRef<str>,
+    {
+        self.create_object_at(
+            self.db.get_documents_path(),
+            collection_id,
+            document_id,
+            obj,
+        )
+    }
+
+    pub fn create_object_at<T, S>(
+        &mut self,
+        parent: &str,
+        collection_id: &str,
+        document_id: Option<S>,
+        obj: &T,
+    ) -> FirestoreResult<&mut Self>
+    where
+        T: Serialize + Sync + Send,
+        S: AsRef<str>,
+    {
+        self.add(CreateObjectOperation {
+            parent: parent.to_string(),
+            collection_id: collection_id.to_string(),
+            document_id: document_id_or_generate(document_id),
+            obj,
+        })
+    }
+
+    pub fn create_document<S>(
+        &mut self,
+        collection_id: &str,
+        document_id: Option<S>,
+        document: Document,
+    ) -> FirestoreResult<&mut Self>
+    where
+        S: AsRef<str>,
+    {
+        self.create_document_at(
+            self.db.get_documents_path(),
+            collection_id,
+            document_id,
+            document,
+        )
+    }
+
+    pub fn create_document_at<S>(
+        &mut self,
+        parent: &str,
+        collection_id: &str,
+        document_id: Option<S>,
+        document: Document,
+    ) -> FirestoreResult<&mut Self>
+    where
+        S: AsRef<str>,
+    {
+        self.add(CreateDocumentOperation {
+            parent: parent.to_string(),
+            collection_id: collection_id.to_string(),
+            document_id: document_id_or_generate(document_id),
+            document,
+        })
+    }
+
     pub fn delete_by_id<S>(
         &mut self,
         collection_id: &str,
@@ -0,0 +1,396 @@
+use crate::timestamp_utils::to_timestamp;
+use crate::*;
+use gcloud_sdk::google::firestore::v1::document_transform::field_transform::TransformType;
+use gcloud_sdk::google::firestore::v1::document_transform::FieldTransform;
+use gcloud_sdk::google::firestore::v1::precondition::ConditionType;
+use gcloud_sdk::google::firestore::v1::{write, Document, Write};
+use gcloud_sdk::prost_types::Timestamp;
+use tracing::*;
+
+impl FirestoreDb {
+    /// The cache this session writes through, if any.
+    #[inline]
+    fn write_through_cache_backend(&self) -> Option<&FirestoreSharedCacheBackend> {
+        match self.session_params.cache_mode {
+            FirestoreDbSessionCacheMode::WriteThroughCache(ref cache) => Some(cache),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` when this session writes through a cache.
+    #[inline]
+    pub(crate) fn is_writing_through_cache(&self) -> bool {
+        self.write_through_cache_backend().is_some()
+    }
+
+    /// Caches a complete document returned by Firestore after a write.
+    pub(crate) async fn write_through_cache_doc(&self, document: &Document) {
+        if let Some(cache) = self.write_through_cache_backend() {
+            if let Err(err) = Self::apply_doc_to_cache(cache, document).await {
+                warn!(
+                    ?err,
+                    document_path = document.name,
+                    "Failed to write the document through the cache."
+                );
+            }
+        }
+    }
+
+    async fn apply_doc_to_cache(
+        cache: &FirestoreSharedCacheBackend,
+        document: &Document,
+    ) -> FirestoreResult<()> {
+        let cached_doc = cache.peek_doc_by_path(&document.name).await?;
+        if is_cached_doc_newer(cached_doc.as_ref(), document.update_time) {
+            return Ok(());
+        }
+        cache.update_doc_by_path(document).await
+    }
+
+    /// Caches a written document when Firestore returned only some of its fields, from the
+    /// written fields and the cached version of the document.
+    pub(crate) async fn write_through_cache_update(
+        &self,
+        input_doc: &Document,
+        update_only: Option<&[String]>,
+        written_doc: &Document,
+    ) {
+        if let Some(cache) = self.write_through_cache_backend() {
+            if let Err(err) = Self::apply_update_to_cache(
+                cache,
+                input_doc,
+                update_only,
+                &[],
+                &[],
+                written_doc.update_time,
+                written_doc.create_time,
+            )
+            .await
+            {
+                warn!(
+                    ?err,
+                    document_path = input_doc.name,
+                    "Failed to write the document through the cache."
+                );
+            }
+        }
+    }
+
+    /// Removes a deleted document from the cache.
+    pub(crate) async fn write_through_cache_delete(&self, document_path: &str) {
+        if let Some(cache) = self.write_through_cache_backend() {
+            if let Err(err) = cache.delete_doc_by_path(document_path).await {
+                warn!(
+                    ?err,
+                    document_path, "Failed to delete the document through the cache."
+                );
+            }
+        }
+    }
+
+    /// Applies committed writes to the cache, given their results in the same order.
+    pub(crate) async fn write_through_cache_writes(
+        &self,
+        writes: &[Write],
+        write_results: &[FirestoreWriteResult],
+    ) {
+        if let Some(cache) = self.write_through_cache_backend() {
+            for (write, write_result) in writes.iter().zip(write_results) {
+                if let Err(err) = Self::apply_write_to_cache(cache, write, write_result).await {
+                    warn!(?err, "Failed to write a committed write through the cache.");
+                }
+            }
+        }
+    }
+
+    async fn apply_write_to_cache(
+        cache: &FirestoreSharedCacheBackend,
+        write: &Write,
+        write_result: &FirestoreWriteResult,
+    ) -> FirestoreResult<()> {
+        match &write.operation {
+            Some(write::Operation::Update(input_doc)) => {
+                let is_create = matches!(
+                    write
+                        .current_document
+                        .as_ref()
+                        .and_then(|precondition| precondition.condition_type.as_ref()),
+                    Some(ConditionType::Exists(false))
+                );
+                Self::apply_update_to_cache(
+                    cache,
+                    input_doc,
+                    write
+                        .update_mask
+                        .as_ref()
+                        .map(|update_mask| update_mask.field_paths.as_slice()),
+                    &write.update_transforms,
+                    &write_result.transform_results,
+                    write_result.update_time.map(to_timestamp),
+                    is_create
+                        .then(|| write_result.update_time.map(to_timestamp))
+                        .flatten(),
+                )
+                .await
+            }
+            Some(write::Operation::Delete(document_path)) => {
+                cache.delete_doc_by_path(document_path).await
+            }
+            // The result of a standalone transform is not known without the document
+            Some(write::Operation::Transform(transform)) => {
+                cache.delete_doc_by_path(&transform.document).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn apply_update_to_cache(
+        cache: &FirestoreSharedCacheBackend,
+        input_doc: &Document,
+        update_only: Option<&[String]>,
+        field_transforms: &[FieldTransform],
+        transform_results: &[FirestoreValue],
+        update_time: Option<Timestamp>,
+        create_time: Option<Timestamp>,
+    ) -> FirestoreResult<()> {
+        let cached_doc = cache.peek_doc_by_path(&input_doc.name).await?;
+        if is_cached_doc_newer(cached_doc.as_ref(), update_time) {
+            return Ok(());
+        }
+
+        let mut fields = match (update_only, &cached_doc) {
+            (Some(update_only), Some(cached_doc)) => {
+                let mut fields = cached_doc.fields.clone();
+                firestore_fields_apply_update_mask(&mut fields, input_doc, update_only);
+                fields
+            }
+            // The fields outside of the mask are not known, so the listener has to deliver them
+            (Some(_), None) => return Ok(()),
+            (None, _) => input_doc.fields.clone(),
+        };
+
+        if field_transforms.len() > transform_results.len() {
+            return cache.delete_doc_by_path(&input_doc.name).await;
+        }
+        for (field_transform, transform_result) in field_transforms.iter().zip(transform_results) {
+            match field_transform.transform_type {
+                Some(TransformType::SetToServerValue(_))
+                | Some(TransformType::Increment(_))
+                | Some(TransformType::Maximum(_))
+                | Some(TransformType::Minimum(_)) => firestore_fields_set_by_path_arr(
+                    &mut fields,
+                    &split_field_path(&field_transform.field_path),
+                    transform_result.value.clone(),
+                ),
+                // Firestore does not return the resulting arrays
+                _ => return cache.delete_doc_by_path(&input_doc.name).await,
+            }
+        }
+
+        let create_time = cached_doc
+            .as_ref()
+            .and_then(|cached_doc| cached_doc.create_time)
+            .or(create_time);
+
+        cache
+            .update_doc_by_path(&Document {
+                name: input_doc.name.clone(),
+                fields,
+                create_time,
+                update_time: update_time
+                    .or_else(|| cached_doc.and_then(|cached_doc| cached_doc.update_time)),
+            })
+            .await
+    }
+}
+
+/// Returns `true` when the cached document is a later version than the one written at
+/// `update_time`, which happens when the listener delivers it before the write returns.
+fn is_cached_doc_newer(cached_doc: Option<&Document>, update_time: Option<Timestamp>) -> bool {
+    match (
+        cached_doc.and_then(|cached_doc| cached_doc.update_time),
+        update_time,
+    ) {
+        (Some(cached_update_time), Some(update_time)) => {
+            let newer = (cached_update_time.seconds, cached_update_time.nanos)
+                > (update_time.seconds, update_time.nanos);
+            if newer {
+                debug!(
+                    document_path = cached_doc.map(|doc| doc.name.as_str()),
+                    "The cached document is newer than the written one, skipping it."
+                );
+            }
+            newer
+        }
+        _ => false,
+    }
+}
+
+#[cfg(all(test, feature = "caching-memory"))]
+mod tests {
+    use super::*;
+    use gcloud_sdk::google::firestore::v1::value::ValueType;
+    use gcloud_sdk::google::firestore::v1::Value;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    const DOCS: &str = "projects/test/databases/(default)/documents";
+
+    fn cache() -> FirestoreSharedCacheBackend {
+        let config = FirestoreCacheConfiguration::new().add_collection_config_at(
+            DOCS,
+            FirestoreCacheCollectionConfiguration::new(
+                "cities",
+                FirestoreListenerTarget::new(42),
+                FirestoreCacheCollectionLoadMode::PreloadAllDocs,
+            ),
+        );
+        Arc::new(FirestoreMemoryCacheBackend::new(config).unwrap())
+    }
+
+    fn integer(value: i64) -> Value {
+        Value {
+            value_type: Some(ValueType::IntegerValue(value)),
+        }
+    }
+
+    fn at(seconds: i64) -> Option<Timestamp> {
+        Some(Timestamp { seconds, nanos: 0 })
+    }
+
+    fn doc(fields: Vec<(&str, i64)>, update_time: Option<Timestamp>) -> Document {
+        Document {
+            name: format!("{DOCS}/cities/sto"),
+            fields: fields
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), integer(value)))
+                .collect::<HashMap<_, _>>(),
+            create_time: at(1),
+            update_time,
+        }
+    }
+
+    async fn cached_doc(cache: &FirestoreSharedCacheBackend) -> Option<Document> {
+        cache
+            .peek_doc_by_path(&format!("{DOCS}/cities/sto"))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn keeps_newer_cached_document() {
+        let cache = cache();
+        cache
+            .update_doc_by_path(&doc(vec![("population", 2)], at(20)))
+            .await
+            .unwrap();
+
+        FirestoreDb::apply_doc_to_cache(&cache, &doc(vec![("population", 1)], at(10)))
+            .await
+            .unwrap();
+        assert_eq!(
+            cached_doc(&cache).await,
+            Some(doc(vec![("population", 2)], at(20)))
+        );
+
+        FirestoreDb::apply_doc_to_cache(&cache, &doc(vec![("population", 3)], at(30)))
+            .await
+            .unwrap();
+        assert_eq!(
+            cached_doc(&cache).await,
+            Some(doc(vec![("population", 3)], at(30)))
+        );
+
+        // Checking the cached version is not a read of the application
+        assert_eq!(
+            cache.metrics(),
+            vec![FirestoreCacheCollectionMetrics::new(format!(
+                "{DOCS}/cities"
+            ))]
+        );
+    }
+
+    #[tokio::test]
+    async fn merges_masked_updates_and_transforms_into_cached_document() {
+        let cache = cache();
+        cache
+            .update_doc_by_path(&doc(vec![("population", 1), ("area", 100)], at(10)))
+            .await
+            .unwrap();
+
+        FirestoreDb::apply_update_to_cache(
+            &cache,
+            &doc(vec![("population", 2), ("area", 0)], None),
+            Some(&["population".to_string()]),
+            &[FieldTransform {
+                field_path: "visits".to_string(),
+                transform_type: Some(TransformType::Increment(integer(1))),
+            }],
+            &[FirestoreValue::from(integer(5))],
+            at(20),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            cached_doc(&cache).await,
+            Some(doc(
+                vec![("population", 2), ("area", 100), ("visits", 5)],
+                at(20)
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_late_masked_updates() {
+        let cache = cache();
+        cache
+            .update_doc_by_path(&doc(vec![("population", 3), ("area", 100)], at(30)))
+            .await
+            .unwrap();
+
+        FirestoreDb::apply_update_to_cache(
+            &cache,
+            &doc(vec![("population", 2)], None),
+            Some(&["population".to_string()]),
+            &[],
+            &[],
+            at(20),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            cached_doc(&cache).await,
+            Some(doc(vec![("population", 3), ("area", 100)], at(30)))
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_documents_with_unknown_transform_results() {
+        let cache = cache();
+        cache
+            .update_doc_by_path(&doc(vec![("population", 1)], at(10)))
+            .await
+            .unwrap();
+
+        FirestoreDb::apply_update_to_cache(
+            &cache,
+            &doc(vec![("population", 2)], None),
+            Some(&["population".to_string()]),
+            &[FieldTransform {
+                field_path: "tags".to_string(),
+                transform_type: Some(TransformType::AppendMissingElements(Default::default())),
+            }],
+            &[FirestoreValue::from(Value { value_type: None })],
+            at(20),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(cached_doc(&cache).await, None);
+    }
+}
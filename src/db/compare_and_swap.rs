@@ -0,0 +1,288 @@
+use crate::db::safe_document_path;
+use crate::errors::*;
+use crate::{
+    FirestoreDb, FirestoreGetByIdSupport, FirestoreResult, FirestoreUpdateSupport,
+    FirestoreVersioned,
+};
+use backoff::backoff::Backoff;
+use backoff::ExponentialBackoffBuilder;
+use rsb_derive::*;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::*;
+
+/// Options for [`FirestoreDb::compare_and_swap_at_with_options`].
+#[derive(Debug, Eq, PartialEq, Clone, Builder)]
+pub struct FirestoreCompareAndSwapOptions {
+    /// How many times the document is read, modified and written before giving up, when other
+    /// writers keep updating it in between.
+    #[default = "10"]
+    pub max_attempts: usize,
+    /// The delay before the second attempt. It grows exponentially with the next attempts, and
+    /// is randomized so that competing writers don't retry in lockstep.
+    #[default = "Duration::from_millis(50)"]
+    pub initial_retry_delay: Duration,
+    /// The longest delay between two attempts.
+    #[default = "Duration::from_secs(2)"]
+    pub max_retry_delay: Duration,
+}
+
+impl FirestoreDb {
+    /// Updates a document without a transaction: reads it, applies `func` to the object and
+    /// writes the result back with a precondition on the update time that was read.
+    ///
+    /// If the document was updated by someone else in between, the write fails with
+    /// `FAILED_PRECONDITION` and the whole cycle runs again on the fresh document, after a
+    /// randomized delay growing with each attempt. So `func` may be called several times and shouldn't have side effects. Errors returned by `func`
+    /// stop the loop and are returned as they are. Fails with a [`FirestoreError::DataNotFoundError`]
+    /// if the document doesn't exist.
+    ///
+    /// Returns the written object with its new update time.
+    pub async fn compare_and_swap<T, S, FN>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+        func: FN,
+    ) -> FirestoreResult<FirestoreVersioned<T>>
+    where
+        T: Serialize + Sync + Send,
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+        FN: FnMut(T) -> FirestoreResult<T>,
+    {
+        self.compare_and_swap_at_with_options(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            func,
+            FirestoreCompareAndSwapOptions::new(),
+        )
+        .await
+    }
+
+    pub async fn compare_and_swap_at<T, S, FN>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        func: FN,
+    ) -> FirestoreResult<FirestoreVersioned<T>>
+    where
+        T: Serialize + Sync + Send,
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+        FN: FnMut(T) -> FirestoreResult<T>,
+    {
+        self.compare_and_swap_at_with_options(
+            parent,
+            collection_id,
+            document_id,
+            func,
+            FirestoreCompareAndSwapOptions::new(),
+        )
+        .await
+    }
+
+    pub async fn compare_and_swap_at_with_options<T, S, FN>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        func: FN,
+        options: FirestoreCompareAndSwapOptions,
+    ) -> FirestoreResult<FirestoreVersioned<T>>
+    where
+        T: Serialize + Sync + Send,
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+        FN: FnMut(T) -> FirestoreResult<T>,
+    {
+        compare_and_swap(self, parent, collection_id, document_id, func, options).await
+    }
+}
+
+/// Runs the compare and swap loop of [`FirestoreDb::compare_and_swap_at_with_options`] against any
+/// database, so it also runs against the in-memory one.
+pub(crate) async fn compare_and_swap<D, T, S, FN>(
+    db: &D,
+    parent: &str,
+    collection_id: &str,
+    document_id: S,
+    mut func: FN,
+    options: FirestoreCompareAndSwapOptions,
+) -> FirestoreResult<FirestoreVersioned<T>>
+where
+    D: FirestoreGetByIdSupport + FirestoreUpdateSupport + Sync,
+    T: Serialize + Sync + Send,
+    for<'de> T: Deserialize<'de>,
+    S: AsRef<str> + Send,
+    FN: FnMut(T) -> FirestoreResult<T>,
+{
+    let document_path = safe_document_path(parent, collection_id, document_id.as_ref())?;
+    let mut backoff = ExponentialBackoffBuilder::new()
+        .with_initial_interval(options.initial_retry_delay)
+        .with_max_interval(options.max_retry_delay)
+        .with_max_elapsed_time(None)
+        .build();
+
+    let mut attempt = 1;
+    loop {
+        // A cached document may be older than the one in Firestore, and would never pass the precondition
+        let doc = db
+            .get_doc_at_uncached(parent, collection_id, document_id.as_ref())
+            .await?;
+        let current: FirestoreVersioned<T> = FirestoreVersioned::from_doc(&doc)?;
+        let precondition = current.precondition();
+        let swapped = func(current.object)?;
+
+        match db
+            .update_doc(
+                collection_id,
+                FirestoreDb::serialize_to_doc(document_path.as_str(), &swapped)?,
+                None,
+                None,
+                Some(precondition),
+            )
+            .await
+        {
+            Ok(updated_doc) => return FirestoreVersioned::from_doc(&updated_doc),
+            Err(FirestoreError::DatabaseError(ref db_err))
+                if db_err.public.code == "FailedPrecondition" && attempt < options.max_attempts =>
+            {
+                let delay = backoff.next_backoff().unwrap_or(options.max_retry_delay);
+                debug!(
+                    document_path,
+                    attempt,
+                    delay_milliseconds = delay.as_millis(),
+                    "Document was updated concurrently. Retrying compare and swap."
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "in-memory-db"))]
+mod tests {
+    use super::*;
+    use crate::FirestoreInMemoryDb;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+    struct Counter {
+        value: u64,
+    }
+
+    fn options() -> FirestoreCompareAndSwapOptions {
+        FirestoreCompareAndSwapOptions::new()
+            .with_initial_retry_delay(Duration::from_millis(1))
+            .with_max_retry_delay(Duration::from_millis(5))
+    }
+
+    async fn db_with_counter() -> FirestoreResult<FirestoreInMemoryDb> {
+        let db = FirestoreInMemoryDb::new("test-project");
+        db.fluent()
+            .insert()
+            .into("counters")
+            .document_id("c1")
+            .object(&Counter { value: 0 })
+            .execute::<()>()
+            .await?;
+        Ok(db)
+    }
+
+    /// Increments the counter, after another writer updated it on the first `conflicts` calls.
+    async fn increment_with_conflicts(
+        db: &FirestoreInMemoryDb,
+        conflicts: usize,
+        options: FirestoreCompareAndSwapOptions,
+    ) -> (FirestoreResult<FirestoreVersioned<Counter>>, usize) {
+        let calls = AtomicUsize::new(0);
+        let result = compare_and_swap(
+            db,
+            db.get_documents_path(),
+            "counters",
+            "c1",
+            |counter: Counter| {
+                if calls.fetch_add(1, Ordering::Relaxed) < conflicts {
+                    futures::executor::block_on(
+                        db.fluent()
+                            .update()
+                            .in_col("counters")
+                            .document_id("c1")
+                            .object(&Counter {
+                                value: counter.value + 100,
+                            })
+                            .execute::<()>(),
+                    )?;
+                }
+                Ok(Counter {
+                    value: counter.value + 1,
+                })
+            },
+            options,
+        )
+        .await;
+        (result, calls.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn retries_until_no_one_else_updated_the_document() -> FirestoreResult<()> {
+        let db = db_with_counter().await?;
+
+        let (result, calls) = increment_with_conflicts(&db, 2, options()).await;
+        assert_eq!(result?.object, Counter { value: 201 });
+        assert_eq!(calls, 3);
+
+        let (result, calls) =
+            increment_with_conflicts(&db, 5, options().with_max_attempts(3)).await;
+        assert!(matches!(
+            result,
+            Err(FirestoreError::DatabaseError(ref err)) if err.public.code == "FailedPrecondition"
+        ));
+        assert_eq!(calls, 3);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_writers_all_succeed() -> FirestoreResult<()> {
+        let db = Arc::new(db_with_counter().await?);
+
+        let writers = (0..8).map(|_| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                compare_and_swap(
+                    db.as_ref(),
+                    db.get_documents_path(),
+                    "counters",
+                    "c1",
+                    |counter: Counter| {
+                        Ok(Counter {
+                            value: counter.value + 1,
+                        })
+                    },
+                    options().with_max_attempts(50),
+                )
+                .await
+            })
+        });
+        for writer in futures::future::join_all(writers).await {
+            writer.unwrap()?;
+        }
+
+        let counter: Option<Counter> = db
+            .fluent()
+            .select()
+            .by_id_in("counters")
+            .obj()
+            .one("c1")
+            .await?;
+        assert_eq!(counter, Some(Counter { value: 8 }));
+
+        Ok(())
+    }
+}
@@ -47,6 +47,11 @@ impl FirestoreCreateSupport for FirestoreDb {
             "/firestore/document_name" = field::Empty,
         );
 
+        // Firestore returns only some of the fields, so the cache needs the written ones
+        #[cfg(feature = "caching")]
+        let written_fields = (return_only_fields.is_some() && self.is_writing_through_cache())
+            .then(|| input_doc.fields.clone());
+
         let create_document_request = gcloud_sdk::tonic::Request::new(CreateDocumentRequest {
             parent: parent.into(),
             document_id: document_id
@@ -86,6 +91,21 @@ impl FirestoreCreateSupport for FirestoreDb {
             );
         });
 
+        #[cfg(feature = "caching")]
+        match written_fields {
+            Some(fields) => {
+                let written_doc = Document {
+                    name: response_inner.name.clone(),
+                    fields,
+                    create_time: None,
+                    update_time: None,
+                };
+                self.write_through_cache_update(&written_doc, None, &response_inner)
+                    .await;
+            }
+            None => self.write_through_cache_doc(&response_inner).await,
+        }
+
         Ok(response_inner)
     }
 
@@ -47,7 +47,7 @@ impl FirestoreDeleteSupport for FirestoreDb {
         );
 
         let request = gcloud_sdk::tonic::Request::new(DeleteDocumentRequest {
-            name: document_path,
+            name: document_path.clone(),
             current_document: precondition.map(|cond| cond.try_into()).transpose()?,
             request_options: self.resolve_request_options(None),
         });
@@ -67,6 +67,9 @@ impl FirestoreDeleteSupport for FirestoreDb {
             );
         });
 
+        #[cfg(feature = "caching")]
+        self.write_through_cache_delete(&document_path).await;
+
         Ok(())
     }
 }
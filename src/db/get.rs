@@ -53,6 +53,40 @@ impl FirestoreGetByIdSupport for FirestoreDb {
         .await
     }
 
+    async fn get_doc_uncached<S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+    ) -> FirestoreResult<Document>
+    where
+        S: AsRef<str> + Send,
+    {
+        self.get_doc_at_uncached(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+        )
+        .await
+    }
+
+    async fn get_doc_at_uncached<S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+    ) -> FirestoreResult<Document>
+    where
+        S: AsRef<str> + Send,
+    {
+        self.clone_with_session_params(
+            (*self.session_params)
+                .clone()
+                .with_cache_mode(FirestoreDbSessionCacheMode::None),
+        )
+        .get_doc_at(parent, collection_id, document_id, None)
+        .await
+    }
+
     async fn get_obj<T, S>(&self, collection_id: &str, document_id: S) -> FirestoreResult<T>
     where
         for<'de> T: Deserialize<'de>,
@@ -457,7 +491,12 @@ impl FirestoreDb {
                     let doc = doc_response.into_inner();
                     #[cfg(feature = "caching")]
                     if _return_only_fields_empty {
-                        self.offer_doc_update_to_cache(&doc).await?;
+                        // The document has been read, so failing to cache it is not an error
+                        if let Err(err) = self.offer_doc_update_to_cache(&doc).await {
+                            span.in_scope(|| {
+                                warn!(?err, document_path, "Failed to cache the read document.")
+                            });
+                        }
                     }
                     Ok(doc)
                 }
@@ -517,6 +556,8 @@ impl FirestoreDb {
             "/firestore/ids_count" = full_doc_ids.len()
         );
 
+        let _return_only_fields_empty = return_only_fields.is_none();
+
         let request = gcloud_sdk::tonic::Request::new(BatchGetDocumentsRequest {
             database: self.get_database_path().clone(),
             documents: full_doc_ids,
@@ -551,7 +592,10 @@ impl FirestoreDb {
                                         .unwrap_or_else(|| document.name.clone());
                                     #[cfg(feature = "caching")]
                                     {
-                                        self.offer_doc_update_to_cache(&document).await.ok();
+                                        // Projected documents are not cached, they are partial
+                                        if _return_only_fields_empty {
+                                            self.offer_doc_update_to_cache(&document).await.ok();
+                                        }
 
                                         Some(Ok((doc_id, Some(document))))
                                     }
@@ -587,9 +631,10 @@ impl FirestoreDb {
         &self,
         collection_id: &str,
         document_path: &str,
-        _return_only_fields: &Option<Vec<String>>,
+        return_only_fields: &Option<Vec<String>>,
     ) -> FirestoreResult<FirestoreCachedValue<FirestoreDocument>> {
         if let FirestoreDbSessionCacheMode::ReadThroughCache(ref cache)
+        | FirestoreDbSessionCacheMode::WriteThroughCache(ref cache)
         | FirestoreDbSessionCacheMode::ReadCachedOnly(ref cache) = self.session_params.cache_mode
         {
             let begin_query_utc: FirestoreInstant = FirestoreInstant::now();
@@ -618,7 +663,10 @@ impl FirestoreDb {
                     );
                 });
 
-                return Ok(FirestoreCachedValue::UseCached(doc));
+                return Ok(FirestoreCachedValue::UseCached(project_cached_doc(
+                    doc,
+                    return_only_fields,
+                )));
             } else {
                 span.record("/firestore/cache_result", "miss");
                 span.in_scope(|| {
@@ -645,11 +693,12 @@ impl FirestoreDb {
         &self,
         collection_id: &str,
         full_doc_ids: &[String],
-        _return_only_fields: &Option<Vec<String>>,
+        return_only_fields: &Option<Vec<String>>,
     ) -> FirestoreResult<
         FirestoreCachedValue<BoxStream<'_, FirestoreResult<(String, Option<Document>)>>>,
     > {
         if let FirestoreDbSessionCacheMode::ReadThroughCache(ref cache)
+        | FirestoreDbSessionCacheMode::WriteThroughCache(ref cache)
         | FirestoreDbSessionCacheMode::ReadCachedOnly(ref cache) = self.session_params.cache_mode
         {
             let span = span!(
@@ -687,9 +736,14 @@ impl FirestoreDb {
                         "Reading documents from cache."
                     );
                 });
+                let return_only_fields = return_only_fields.clone();
                 return Ok(FirestoreCachedValue::UseCached(Box::pin(
-                    futures::stream::iter(cached_vec)
-                        .map(|(doc_id, maybe_doc)| Ok((doc_id, maybe_doc))),
+                    futures::stream::iter(cached_vec).map(move |(doc_id, maybe_doc)| {
+                        Ok((
+                            doc_id,
+                            maybe_doc.map(|doc| project_cached_doc(doc, &return_only_fields)),
+                        ))
+                    }),
                 )));
             } else {
                 span.record("/firestore/cache_result", "miss");
@@ -708,7 +762,8 @@ impl FirestoreDb {
         &self,
         document: &FirestoreDocument,
     ) -> FirestoreResult<()> {
-        if let FirestoreDbSessionCacheMode::ReadThroughCache(ref cache) =
+        if let FirestoreDbSessionCacheMode::ReadThroughCache(ref cache)
+        | FirestoreDbSessionCacheMode::WriteThroughCache(ref cache) =
             self.session_params.cache_mode
         {
             cache.update_doc_by_path(document).await?;
@@ -716,3 +771,16 @@ impl FirestoreDb {
         Ok(())
     }
 }
+
+/// Applies the `return_only_fields` projection to a document read from the cache, so that it has
+/// the same shape as the one Firestore would return.
+#[cfg(feature = "caching")]
+fn project_cached_doc(
+    doc: FirestoreDocument,
+    return_only_fields: &Option<Vec<String>>,
+) -> FirestoreDocument {
+    match return_only_fields {
+        Some(return_only_fields) => firestore_doc_project_fields(&doc, return_only_fields),
+        None => doc,
+    }
+}
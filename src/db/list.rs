@@ -501,12 +501,13 @@ impl FirestoreDb {
 
 /// Returns `true` when a listing request can be served from a cache.
 ///
-/// The cached listing path returns every document of a collection, unordered and unprojected, so
-/// it can only answer requests that do not ask for ordering, a field projection or a page.
+/// The cached listing path returns every document of a collection unordered, so it can only
+/// answer requests that do not ask for ordering or a page. Field projections are applied to the
+/// cached documents.
 #[cfg(feature = "caching")]
 #[inline]
 fn list_params_supported(params: &FirestoreListDocParams) -> bool {
-    params.order_by.is_none() && params.return_only_fields.is_none() && params.page_token.is_none()
+    params.order_by.is_none() && params.page_token.is_none()
 }
 
 impl FirestoreDb {
@@ -518,7 +519,8 @@ impl FirestoreDb {
     ) -> FirestoreResult<FirestoreCachedValue<BoxStream<'b, FirestoreResult<FirestoreDocument>>>>
     {
         if let FirestoreDbSessionCacheMode::ReadCachedOnly(ref cache)
-        | FirestoreDbSessionCacheMode::ReadThroughCache(ref cache) =
+        | FirestoreDbSessionCacheMode::ReadThroughCache(ref cache)
+        | FirestoreDbSessionCacheMode::WriteThroughCache(ref cache) =
             self.session_params.cache_mode
         {
             let span = span!(
@@ -541,9 +543,9 @@ impl FirestoreDb {
                 )
             };
 
-            // The cached listing path ignores ordering, field projection and paging, so it must
-            // not be used when any of them is requested - it would silently return data that does
-            // not match what was asked for.
+            // The cached listing path ignores ordering and paging, so it must not be used when
+            // either of them is requested - it would silently return data that does not match
+            // what was asked for.
             let cached_result = if list_params_supported(params) {
                 cache.list_all_docs(&collection_path).await?
             } else {
@@ -565,7 +567,16 @@ impl FirestoreDb {
                         );
                     });
 
-                    Ok(FirestoreCachedValue::UseCached(stream))
+                    match params.return_only_fields.clone() {
+                        Some(return_only_fields) => Ok(FirestoreCachedValue::UseCached(
+                            stream
+                                .map_ok(move |doc| {
+                                    firestore_doc_project_fields(&doc, &return_only_fields)
+                                })
+                                .boxed(),
+                        )),
+                        None => Ok(FirestoreCachedValue::UseCached(stream)),
+                    }
                 }
                 FirestoreCachedValue::SkipCache => {
                     span.record("/firestore/cache_result", "miss");
@@ -574,11 +585,12 @@ impl FirestoreDb {
                         FirestoreDbSessionCacheMode::ReadCachedOnly(_)
                     ) {
                         let reason = if list_params_supported(params) {
-                            "the collection is not configured to be preloaded, so the cache only \
-                             holds an arbitrary subset of it"
+                            "either the collection is not configured to be preloaded, so the \
+                             cache only holds an arbitrary subset of it, or it is being \
+                             resynchronized with Firestore"
                         } else {
-                            "the request uses ordering, field projection or paging, which the \
-                             cached listing path does not support"
+                            "the request uses ordering or paging, which the cached listing path \
+                             does not support"
                         };
                         span.in_scope(|| {
                             debug!(
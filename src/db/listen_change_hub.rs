@@ -0,0 +1,551 @@
+#[cfg(feature = "caching")]
+use crate::cache::cache_filter_engine::FirestoreCacheFilterEngine;
+use crate::db::split_document_path;
+use crate::db::support::FirestoreListenSupport;
+use crate::{
+    FirestoreDocument, FirestoreListenChange, FirestoreListener, FirestoreResult,
+    FirestoreResumeStateStorage,
+};
+#[cfg(feature = "caching")]
+use crate::{FirestoreListenDocChange, FirestoreQueryFilter};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use rsb_derive::*;
+use serde::Deserialize;
+#[cfg(feature = "caching")]
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tracing::*;
+
+type FirestoreSharedChange = Arc<FirestoreListenChange<FirestoreDocument>>;
+
+#[derive(Debug, Clone, Eq, PartialEq, Builder)]
+pub struct FirestoreChangeHubParams {
+    /// How many changes are kept for the slowest subscriber. A subscriber further behind misses
+    /// the oldest ones, and is told so with [`FirestoreChangeHubEvent::Lagged`].
+    #[default = "1024"]
+    pub capacity: usize,
+}
+
+/// Shares the changes of one listener with many subscribers.
+///
+/// The hub owns a [`FirestoreListener`] and broadcasts the changes to the result sets of its
+/// targets. Each subscriber receives the changes passing its own filters, with the documents
+/// deserialized to its own type.
+///
+/// Subscribers receive the changes from when they subscribed. One that does not keep up misses
+/// the changes older than the hub capacity, and receives a [`FirestoreChangeHubEvent::Lagged`]
+/// event instead: it may need to read the documents again.
+pub struct FirestoreChangeHub {
+    sender: Option<broadcast::Sender<FirestoreSharedChange>>,
+    missed_changes: Arc<AtomicU64>,
+    shutdown_writer: Option<UnboundedSender<i8>>,
+    shutdown_handle: Option<JoinHandle<()>>,
+}
+
+impl FirestoreChangeHub {
+    /// Starts the listener and shares its changes. The listener is shut down with the hub.
+    pub async fn start<D, S>(
+        listener: FirestoreListener<D, S>,
+        params: FirestoreChangeHubParams,
+    ) -> FirestoreResult<Self>
+    where
+        D: FirestoreListenSupport + Clone + Send + Sync + 'static,
+        S: FirestoreResumeStateStorage + Clone + Send + Sync + 'static,
+    {
+        Ok(Self::with_changes_stream(
+            listener.start_changes_stream().await?,
+            params,
+        ))
+    }
+
+    pub(crate) fn with_changes_stream(
+        mut changes: BoxStream<'static, FirestoreResult<FirestoreListenChange<FirestoreDocument>>>,
+        params: FirestoreChangeHubParams,
+    ) -> Self {
+        let (sender, _) = broadcast::channel(params.capacity.max(1));
+        let (shutdown_writer, mut shutdown_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let changes_sender = sender.clone();
+        let shutdown_handle = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = shutdown_receiver.recv() => break,
+                    change = changes.next() => match change {
+                        Some(Ok(change)) => {
+                            // Nobody to receive it is not an error
+                            changes_sender.send(Arc::new(change)).ok();
+                        }
+                        Some(Err(err)) => {
+                            error!(%err, "Change hub listener error occurred.");
+                        }
+                        None => {
+                            debug!("Change hub listener stopped. Exiting...");
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            sender: Some(sender),
+            missed_changes: Arc::new(AtomicU64::new(0)),
+            shutdown_writer: Some(shutdown_writer),
+            shutdown_handle: Some(shutdown_handle),
+        }
+    }
+
+    /// Subscribes to the changes passing the filters, with the documents deserialized to `T`.
+    pub fn subscribe<T>(
+        &self,
+        params: FirestoreChangeSubscriptionParams,
+    ) -> FirestoreChangeSubscription<T>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        self.subscription(params, |change| change.deserialize_object())
+    }
+
+    /// Subscribes to the changes passing the filters, with the raw documents.
+    pub fn subscribe_docs(
+        &self,
+        params: FirestoreChangeSubscriptionParams,
+    ) -> FirestoreChangeSubscription<FirestoreDocument> {
+        self.subscription(params, Ok)
+    }
+
+    fn subscription<T>(
+        &self,
+        params: FirestoreChangeSubscriptionParams,
+        convert: fn(
+            FirestoreListenChange<FirestoreDocument>,
+        ) -> FirestoreResult<FirestoreListenChange<T>>,
+    ) -> FirestoreChangeSubscription<T> {
+        // A hub shut down gives subscriptions ending right away
+        let receiver = match &self.sender {
+            Some(sender) => sender.subscribe(),
+            None => broadcast::channel(1).0.subscribe(),
+        };
+        FirestoreChangeSubscription {
+            receiver,
+            params,
+            convert,
+            missed_changes: 0,
+            hub_missed_changes: self.missed_changes.clone(),
+            #[cfg(feature = "caching")]
+            matching_docs: HashSet::new(),
+        }
+    }
+
+    /// The number of subscriptions receiving changes.
+    pub fn subscribers_count(&self) -> usize {
+        self.sender
+            .as_ref()
+            .map(|sender| sender.receiver_count())
+            .unwrap_or(0)
+    }
+
+    /// How many changes the subscribers missed, in total, for not keeping up.
+    pub fn missed_changes(&self) -> u64 {
+        self.missed_changes.load(Ordering::Relaxed)
+    }
+
+    /// Shuts the listener down. Subscriptions end once they received the changes already shared.
+    pub async fn shutdown(&mut self) -> FirestoreResult<()> {
+        debug!("Shutting down Firestore change hub...");
+        if let Some(shutdown_writer) = self.shutdown_writer.take() {
+            shutdown_writer.send(1).ok();
+        }
+        if let Some(handle) = self.shutdown_handle.take() {
+            if let Err(err) = handle.await {
+                warn!(%err, "Firestore change hub exit error!");
+            }
+        }
+        self.sender = None;
+        Ok(())
+    }
+}
+
+/// The filters of a [`FirestoreChangeSubscription`]. All of those set must pass.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FirestoreChangeSubscriptionParams {
+    /// Only the changes to documents of collections with this ID.
+    pub collection_id: Option<String>,
+    /// Only the changes to documents whose ID starts with this prefix.
+    pub document_id_prefix: Option<String>,
+    /// Only the changes to documents matching this filter, evaluated locally. Removed and deleted
+    /// documents are not there to evaluate it, so their changes pass it. A document modified so
+    /// that it no longer matches is received as removed, if it matched before.
+    #[cfg(feature = "caching")]
+    pub filter: Option<FirestoreQueryFilter>,
+}
+
+impl FirestoreChangeSubscriptionParams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_collection_id(self, collection_id: &str) -> Self {
+        Self {
+            collection_id: Some(collection_id.to_string()),
+            ..self
+        }
+    }
+
+    pub fn with_document_id_prefix(self, document_id_prefix: &str) -> Self {
+        Self {
+            document_id_prefix: Some(document_id_prefix.to_string()),
+            ..self
+        }
+    }
+
+    #[cfg(feature = "caching")]
+    pub fn with_filter(self, filter: FirestoreQueryFilter) -> Self {
+        Self {
+            filter: Some(filter),
+            ..self
+        }
+    }
+
+    fn matches(&self, change: &FirestoreListenChange<FirestoreDocument>) -> bool {
+        let doc_change = change.doc_change();
+        let (parent_path, document_id) = split_document_path(&doc_change.document_path);
+
+        if let Some(collection_id) = &self.collection_id {
+            if split_document_path(parent_path).1 != collection_id {
+                return false;
+            }
+        }
+        if let Some(document_id_prefix) = &self.document_id_prefix {
+            if !document_id.starts_with(document_id_prefix.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// What a [`FirestoreChangeSubscription`] receives.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FirestoreChangeHubEvent<T> {
+    Change(FirestoreListenChange<T>),
+    /// The subscriber did not keep up, and missed this number of changes, filtered or not.
+    Lagged {
+        missed: u64,
+    },
+}
+
+/// A subscription to the changes of a [`FirestoreChangeHub`].
+pub struct FirestoreChangeSubscription<T> {
+    receiver: broadcast::Receiver<FirestoreSharedChange>,
+    params: FirestoreChangeSubscriptionParams,
+    convert:
+        fn(FirestoreListenChange<FirestoreDocument>) -> FirestoreResult<FirestoreListenChange<T>>,
+    missed_changes: u64,
+    hub_missed_changes: Arc<AtomicU64>,
+    /// The paths of the documents received as matching the filter, to tell when one stops
+    /// matching it.
+    #[cfg(feature = "caching")]
+    matching_docs: HashSet<String>,
+}
+
+impl<T> FirestoreChangeSubscription<T> {
+    /// Receives the next event. Returns `None` once the hub is shut down and the changes shared
+    /// before were received. Errors deserializing a document are returned, and the subscription
+    /// goes on with the next changes.
+    pub async fn recv(&mut self) -> Option<FirestoreResult<FirestoreChangeHubEvent<T>>> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(change) => {
+                    if let Some(change) = self.passing_change(&change) {
+                        return Some((self.convert)(change).map(FirestoreChangeHubEvent::Change));
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!(missed, "Change hub subscriber lagged behind.");
+                    self.missed_changes += missed;
+                    self.hub_missed_changes.fetch_add(missed, Ordering::Relaxed);
+                    return Some(Ok(FirestoreChangeHubEvent::Lagged { missed }));
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// How many changes this subscription missed for not keeping up.
+    pub fn missed_changes(&self) -> u64 {
+        self.missed_changes
+    }
+
+    /// The change to receive for a shared one, if it passes the filters of the subscription.
+    fn passing_change(
+        &mut self,
+        change: &FirestoreSharedChange,
+    ) -> Option<FirestoreListenChange<FirestoreDocument>> {
+        if !self.params.matches(change) {
+            return None;
+        }
+        #[cfg(feature = "caching")]
+        if let Some(filter) = &self.params.filter {
+            let document_path = &change.doc_change().document_path;
+            return match change.as_ref() {
+                FirestoreListenChange::Added(doc_change)
+                | FirestoreListenChange::Modified(doc_change) => {
+                    match &doc_change.object {
+                        Some(doc)
+                            if FirestoreCacheFilterEngine::matches_doc_filter(doc, filter) =>
+                        {
+                            self.matching_docs.insert(document_path.clone());
+                            Some(change.as_ref().clone())
+                        }
+                        // The document left the result set of this subscription
+                        _ if self.matching_docs.remove(document_path) => {
+                            Some(FirestoreListenChange::Removed(FirestoreListenDocChange {
+                                object: None,
+                                new_update_time: None,
+                                ..doc_change.clone()
+                            }))
+                        }
+                        _ => None,
+                    }
+                }
+                FirestoreListenChange::Removed(_) | FirestoreListenChange::Deleted(_) => {
+                    self.matching_docs.remove(document_path);
+                    Some(change.as_ref().clone())
+                }
+            };
+        }
+        Some(change.as_ref().clone())
+    }
+
+    /// Turns the subscription into a stream of its events.
+    pub fn into_stream<'b>(self) -> BoxStream<'b, FirestoreResult<FirestoreChangeHubEvent<T>>>
+    where
+        T: Send + 'b,
+    {
+        futures::stream::unfold(self, |mut subscription| async move {
+            subscription.recv().await.map(|event| (event, subscription))
+        })
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{FirestoreListenDocChange, FirestoreListenerTarget};
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct City {
+        name: String,
+    }
+
+    fn added(path: &str, name: &str) -> FirestoreResult<FirestoreListenChange<FirestoreDocument>> {
+        Ok(FirestoreListenChange::Added(FirestoreListenDocChange {
+            target: FirestoreListenerTarget::new(1),
+            document_path: format!("projects/test/databases/(default)/documents/{path}"),
+            object: Some(FirestoreDocument {
+                name: format!("projects/test/databases/(default)/documents/{path}"),
+                fields: HashMap::from([(
+                    "name".to_string(),
+                    gcloud_sdk::google::firestore::v1::Value {
+                        value_type: Some(
+                            gcloud_sdk::google::firestore::v1::value::ValueType::StringValue(
+                                name.to_string(),
+                            ),
+                        ),
+                    },
+                )]),
+                create_time: None,
+                update_time: None,
+            }),
+            old_update_time: None,
+            new_update_time: None,
+        }))
+    }
+
+    #[cfg(feature = "caching")]
+    fn modified(
+        path: &str,
+        name: &str,
+    ) -> FirestoreResult<FirestoreListenChange<FirestoreDocument>> {
+        Ok(FirestoreListenChange::Modified(
+            added(path, name)?.into_doc_change(),
+        ))
+    }
+
+    fn change_name<T>(event: FirestoreChangeHubEvent<T>) -> String
+    where
+        T: std::fmt::Debug,
+    {
+        match event {
+            FirestoreChangeHubEvent::Change(change) => {
+                change.doc_change().document_id().to_string()
+            }
+            other => panic!("Unexpected event: {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn fans_out_filtered_changes() {
+        let (changes_tx, changes_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut hub = FirestoreChangeHub::with_changes_stream(
+            tokio_stream::wrappers::UnboundedReceiverStream::new(changes_rx).boxed(),
+            FirestoreChangeHubParams::new(),
+        );
+        let mut cities = hub.subscribe::<City>(
+            FirestoreChangeSubscriptionParams::new().with_collection_id("cities"),
+        );
+        let mut prefixed = hub.subscribe_docs(
+            FirestoreChangeSubscriptionParams::new().with_document_id_prefix("new-"),
+        );
+        assert_eq!(hub.subscribers_count(), 2);
+
+        for change in [
+            added("cities/paris", "Paris"),
+            added("towns/new-town", "New Town"),
+            added("cities/new-york", "New York"),
+        ] {
+            changes_tx.send(change).unwrap();
+        }
+
+        match cities.recv().await.unwrap().unwrap() {
+            FirestoreChangeHubEvent::Change(FirestoreListenChange::Added(doc_change)) => {
+                assert_eq!(
+                    doc_change.object,
+                    Some(City {
+                        name: "Paris".to_string()
+                    })
+                )
+            }
+            other => panic!("Unexpected event: {other:?}"),
+        }
+        assert_eq!(
+            change_name(cities.recv().await.unwrap().unwrap()),
+            "new-york"
+        );
+        assert_eq!(
+            change_name(prefixed.recv().await.unwrap().unwrap()),
+            "new-town"
+        );
+        assert_eq!(
+            change_name(prefixed.recv().await.unwrap().unwrap()),
+            "new-york"
+        );
+
+        hub.shutdown().await.unwrap();
+        assert!(cities.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_lagging_subscribers() {
+        let (changes_tx, changes_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut hub = FirestoreChangeHub::with_changes_stream(
+            tokio_stream::wrappers::UnboundedReceiverStream::new(changes_rx).boxed(),
+            FirestoreChangeHubParams::new().with_capacity(2),
+        );
+        let mut subscription = hub.subscribe_docs(FirestoreChangeSubscriptionParams::new());
+
+        for id in ["c1", "c2", "c3", "c4", "c5"] {
+            changes_tx.send(added(&format!("cities/{id}"), id)).unwrap();
+        }
+        drop(changes_tx);
+        hub.shutdown_handle.take().unwrap().await.unwrap();
+
+        assert_eq!(
+            subscription.recv().await.unwrap().unwrap(),
+            FirestoreChangeHubEvent::Lagged { missed: 3 }
+        );
+        assert_eq!(
+            change_name(subscription.recv().await.unwrap().unwrap()),
+            "c4"
+        );
+        assert_eq!(
+            change_name(subscription.recv().await.unwrap().unwrap()),
+            "c5"
+        );
+        assert_eq!(subscription.missed_changes(), 3);
+        assert_eq!(hub.missed_changes(), 3);
+
+        hub.shutdown().await.unwrap();
+        assert!(subscription.recv().await.is_none());
+    }
+
+    #[cfg(feature = "caching")]
+    #[tokio::test]
+    async fn filters_changes_locally() {
+        let (changes_tx, changes_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut hub = FirestoreChangeHub::with_changes_stream(
+            tokio_stream::wrappers::UnboundedReceiverStream::new(changes_rx).boxed(),
+            FirestoreChangeHubParams::new(),
+        );
+        let mut subscription =
+            hub.subscribe::<City>(FirestoreChangeSubscriptionParams::new().with_filter(
+                FirestoreQueryFilter::Compare(Some(crate::FirestoreQueryFilterCompare::Equal(
+                    "name".to_string(),
+                    "Lyon".into(),
+                ))),
+            ));
+
+        changes_tx.send(added("cities/paris", "Paris")).unwrap();
+        changes_tx.send(added("cities/lyon", "Lyon")).unwrap();
+
+        assert_eq!(
+            change_name(subscription.recv().await.unwrap().unwrap()),
+            "lyon"
+        );
+
+        hub.shutdown().await.unwrap();
+    }
+
+    #[cfg(feature = "caching")]
+    #[tokio::test]
+    async fn removes_documents_modified_out_of_the_filter() {
+        use crate::db::listen_changes_test_fixtures::change_summary;
+
+        let (changes_tx, changes_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut hub = FirestoreChangeHub::with_changes_stream(
+            tokio_stream::wrappers::UnboundedReceiverStream::new(changes_rx).boxed(),
+            FirestoreChangeHubParams::new(),
+        );
+        let mut subscription =
+            hub.subscribe_docs(FirestoreChangeSubscriptionParams::new().with_filter(
+                FirestoreQueryFilter::Compare(Some(crate::FirestoreQueryFilterCompare::Equal(
+                    "name".to_string(),
+                    "Lyon".into(),
+                ))),
+            ));
+
+        changes_tx.send(added("cities/lyon", "Lyon")).unwrap();
+        changes_tx.send(modified("cities/paris", "Paris")).unwrap();
+        changes_tx
+            .send(modified("cities/lyon", "Lugdunum"))
+            .unwrap();
+        changes_tx
+            .send(modified("cities/lyon", "Lugdunum"))
+            .unwrap();
+        changes_tx.send(modified("cities/paris", "Lyon")).unwrap();
+        drop(changes_tx);
+        hub.shutdown_handle.take().unwrap().await.unwrap();
+        hub.shutdown().await.unwrap();
+
+        let mut received = vec![];
+        while let Some(event) = subscription.recv().await {
+            match event.unwrap() {
+                FirestoreChangeHubEvent::Change(change) => received.push(change_summary(&change)),
+                other => panic!("Unexpected event: {other:?}"),
+            }
+        }
+        assert_eq!(
+            received,
+            vec!["added lyon", "removed lyon", "modified paris"]
+        );
+    }
+}
@@ -1,12 +1,17 @@
 use crate::db::safe_document_path;
-use crate::db::support::FirestoreListenSupport;
+use crate::db::support::{FirestoreListenSupport, FirestoreListenerTargetUpdate};
 use crate::errors::*;
-use crate::timestamp_utils::to_timestamp;
+use crate::timestamp_utils::{from_timestamp, to_timestamp};
 use crate::FirestoreInstant;
 use crate::{
-    FirestoreDb, FirestoreQueryParams, FirestoreRequestOptions, FirestoreResult,
+    FirestoreDb, FirestoreQueryCollection, FirestoreQueryCursor, FirestoreQueryDirection,
+    FirestoreQueryOrder, FirestoreQueryParams, FirestoreRequestOptions, FirestoreResult,
     FirestoreResumeStateStorage,
 };
+use crate::{
+    FirestoreListenerHooks, FirestoreListenerResumeState, FirestoreListenerRetries,
+    FirestoreListenerRetryPolicy,
+};
 use async_trait::async_trait;
 use futures::stream::BoxStream;
 use futures::StreamExt;
@@ -15,10 +20,10 @@ use futures::TryStreamExt;
 use gcloud_sdk::google::firestore::v1::*;
 use rsb_derive::*;
 use rvstruct::ValueStruct;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::future::Future;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::task::JoinHandle;
 use tracing::*;
@@ -28,6 +33,9 @@ pub struct FirestoreListenerTargetParams {
     pub target: FirestoreListenerTarget,
     pub target_type: FirestoreTargetType,
     pub resume_type: Option<FirestoreListenerTargetResumeType>,
+    /// The read time to resume from when the resume token of the target is rejected, for
+    /// instance because it expired. Defaults to the read time stored with the token, if any.
+    pub fallback_read_time: Option<FirestoreInstant>,
     pub add_target_once: Option<bool>,
     pub labels: HashMap<String, String>,
 
@@ -38,6 +46,7 @@ pub struct FirestoreListenerTargetParams {
 impl FirestoreListenerTargetParams {
     pub fn validate(&self) -> FirestoreResult<()> {
         self.target.validate()?;
+        self.target_type.validate()?;
         Ok(())
     }
 }
@@ -56,6 +65,155 @@ pub enum FirestoreTargetType {
     Documents(FirestoreCollectionDocuments),
 }
 
+impl FirestoreTargetType {
+    /// Checks that Firestore can listen to the target.
+    ///
+    /// Listened queries support filters, ordering, limits, cursors, projections and collection
+    /// groups of a single collection ID. They cannot be explained, use offsets or vector search.
+    pub fn validate(&self) -> FirestoreResult<()> {
+        match self {
+            FirestoreTargetType::Query(query_params) => validate_listen_query(query_params),
+            FirestoreTargetType::Documents(collection_documents) => {
+                if collection_documents.documents.is_empty() {
+                    Err(invalid_listen_target(
+                        "documents",
+                        "A documents target needs at least one document",
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Limits a query target to its last `limit` documents in its ordering.
+    pub(crate) fn limit_to_last(self, limit: u32) -> FirestoreResult<Self> {
+        match self {
+            FirestoreTargetType::Query(query_params) => Ok(FirestoreTargetType::Query(
+                limit_to_last_query(query_params, limit)?,
+            )),
+            FirestoreTargetType::Documents(_) => Err(invalid_listen_target(
+                "limit_to_last",
+                "Only query targets can be limited to their last documents",
+            )),
+        }
+    }
+}
+
+fn validate_listen_query(query_params: &FirestoreQueryParams) -> FirestoreResult<()> {
+    match &query_params.collection_id {
+        FirestoreQueryCollection::Single(collection_id) if collection_id.is_empty() => {
+            return Err(invalid_listen_target(
+                "collection_id",
+                "A listened query needs a collection ID",
+            ));
+        }
+        FirestoreQueryCollection::Group(collection_ids) if collection_ids.len() != 1 => {
+            return Err(invalid_listen_target(
+                "collection_id",
+                "A listened collection group query needs exactly one collection ID",
+            ));
+        }
+        _ => {}
+    }
+    if query_params.offset.is_some_and(|offset| offset > 0) {
+        return Err(invalid_listen_target(
+            "offset",
+            "Listened queries do not support offsets. Use cursors instead",
+        ));
+    }
+    if query_params.limit == Some(0) {
+        return Err(invalid_listen_target(
+            "limit",
+            "The limit of a listened query must be positive",
+        ));
+    }
+    if query_params.find_nearest.is_some() {
+        return Err(invalid_listen_target(
+            "find_nearest",
+            "Vector search queries cannot be listened to",
+        ));
+    }
+    if query_params.explain_options.is_some() {
+        return Err(invalid_listen_target(
+            "explain_options",
+            "Listened queries cannot be explained",
+        ));
+    }
+
+    // The documents are implicitly ordered by name after the ordered fields
+    let max_cursor_values = query_params.order_by.as_ref().map_or(0, Vec::len) + 1;
+    for (field_name, cursor) in [
+        ("start_at", &query_params.start_at),
+        ("end_at", &query_params.end_at),
+    ] {
+        let cursor_values = match cursor {
+            Some(FirestoreQueryCursor::BeforeValue(values))
+            | Some(FirestoreQueryCursor::AfterValue(values)) => values.len(),
+            None => continue,
+        };
+        if cursor_values == 0 || cursor_values > max_cursor_values {
+            return Err(invalid_listen_target(
+                field_name,
+                &format!(
+                    "A cursor of a listened query needs from 1 to {max_cursor_values} values, one per ordered field and the document name. {cursor_values} are specified"
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites a query to listen to its last `limit` documents: Firestore only limits from the
+/// start, so the ordering is reversed, and the cursors are swapped with their inclusiveness kept.
+fn limit_to_last_query(
+    query_params: FirestoreQueryParams,
+    limit: u32,
+) -> FirestoreResult<FirestoreQueryParams> {
+    if query_params.limit.is_some() {
+        return Err(invalid_listen_target(
+            "limit",
+            "A query cannot have both a limit and a limit to last",
+        ));
+    }
+    let order_by = match &query_params.order_by {
+        Some(order_by) if !order_by.is_empty() => order_by
+            .iter()
+            .map(|order| FirestoreQueryOrder {
+                field_name: order.field_name.clone(),
+                direction: match order.direction {
+                    FirestoreQueryDirection::Ascending => FirestoreQueryDirection::Descending,
+                    FirestoreQueryDirection::Descending => FirestoreQueryDirection::Ascending,
+                },
+            })
+            .collect(),
+        _ => {
+            return Err(invalid_listen_target(
+                "order_by",
+                "A query limited to its last documents needs an ordering",
+            ))
+        }
+    };
+    let swap_cursor = |cursor: FirestoreQueryCursor| match cursor {
+        FirestoreQueryCursor::BeforeValue(values) => FirestoreQueryCursor::AfterValue(values),
+        FirestoreQueryCursor::AfterValue(values) => FirestoreQueryCursor::BeforeValue(values),
+    };
+    let start_at = query_params.end_at.clone().map(swap_cursor);
+    let end_at = query_params.start_at.clone().map(swap_cursor);
+
+    Ok(query_params
+        .with_order_by(order_by)
+        .with_limit(limit)
+        .opt_start_at(start_at)
+        .opt_end_at(end_at))
+}
+
+fn invalid_listen_target(field: &str, error: &str) -> FirestoreError {
+    FirestoreError::InvalidParametersError(FirestoreInvalidParametersError::new(
+        FirestoreInvalidParametersPublicDetails::new(field.to_string(), error.to_string()),
+    ))
+}
+
 #[derive(Debug, Clone)]
 pub enum FirestoreListenerTargetResumeType {
     Token(FirestoreListenerToken),
@@ -81,6 +239,37 @@ impl FirestoreListenSupport for FirestoreDb {
 
         Ok(response.into_inner().map_err(|e| e.into()).boxed())
     }
+
+    async fn listen_doc_changes_with_updates<'a, 'b>(
+        &'a self,
+        targets: Vec<FirestoreListenerTargetParams>,
+        target_updates: BoxStream<'static, FirestoreListenerTargetUpdate>,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>> {
+        let listen_requests = targets
+            .into_iter()
+            .map(|target_params| self.create_listen_request(target_params))
+            .collect::<FirestoreResult<Vec<ListenRequest>>>()?;
+
+        // Sent from a task to keep the request stream type concrete for tonic
+        let (requests_tx, requests_rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut update_requests = self.create_listen_update_requests(target_updates);
+        tokio::spawn(async move {
+            while let Some(listen_request) = update_requests.next().await {
+                if requests_tx.send(listen_request).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let request =
+            gcloud_sdk::tonic::Request::new(futures::stream::iter(listen_requests).chain(
+                tokio_stream::wrappers::UnboundedReceiverStream::new(requests_rx),
+            ));
+
+        let response = self.client().get().listen(request).await?;
+
+        Ok(response.into_inner().map_err(|e| e.into()).boxed())
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash, ValueStruct)]
@@ -229,15 +418,249 @@ impl FirestoreDb {
             })),
         })
     }
+
+    fn create_listen_update_requests(
+        &self,
+        target_updates: BoxStream<'static, FirestoreListenerTargetUpdate>,
+    ) -> BoxStream<'static, ListenRequest> {
+        let db = self.clone();
+        target_updates
+            .filter_map(move |target_update| {
+                let listen_request = match target_update {
+                    FirestoreListenerTargetUpdate::Add(target_params) => {
+                        db.create_listen_request(target_params)
+                    }
+                    FirestoreListenerTargetUpdate::Remove(target) => {
+                        db.create_remove_listen_request(target)
+                    }
+                };
+                futures::future::ready(
+                    listen_request
+                        .map_err(|err| error!(%err, "Listener target update error occurred."))
+                        .ok(),
+                )
+            })
+            .boxed()
+    }
+
+    fn create_remove_listen_request(
+        &self,
+        target: FirestoreListenerTarget,
+    ) -> FirestoreResult<ListenRequest> {
+        Ok(ListenRequest {
+            database: self.get_database_path().to_string(),
+            labels: HashMap::new(),
+            request_options: self.resolve_request_options(None),
+            target_change: Some(listen_request::TargetChange::RemoveTarget(
+                target.try_into()?,
+            )),
+        })
+    }
 }
 
 pub type FirestoreListenEvent = listen_response::ResponseType;
 
-#[derive(Debug, Clone, Eq, PartialEq, Builder)]
+#[derive(Debug, Clone, PartialEq, Builder)]
 pub struct FirestoreListenerParams {
+    /// A fixed delay before reconnecting after an error. Ignored when a `retry_policy` is set.
     pub retry_delay: Option<std::time::Duration>,
+    /// How the listener reconnects after an error. Defaults to exponential backoff from 1 second
+    /// up to 1 minute, without a maximum number of attempts.
+    pub retry_policy: Option<FirestoreListenerRetryPolicy>,
+    /// Callbacks on the connection and failures of the listener.
+    pub lifecycle_hooks: Option<FirestoreListenerHooks>,
+    /// How many event batches [`FirestoreListener::start_stream`] buffers before it stops reading
+    /// from Firestore. Defaults to 16.
+    pub stream_buffer_size: Option<usize>,
+}
+
+/// The events delivered by [`FirestoreListener::start_stream`] up to a resume token.
+///
+/// Acknowledge the batch with [`ack`](Self::ack) once its events are handled: the resume tokens
+/// of the batch are only stored then, after the ones of all previous batches. A batch that is
+/// not acknowledged, with [`nack`](Self::nack) or by dropping it, makes the listener reconnect
+/// from the last acknowledged batch and deliver the following events again. Batches already
+/// buffered by then are still delivered, but acknowledging them has no effect.
+#[derive(Debug)]
+pub struct FirestoreListenEventBatch {
+    pub events: Vec<FirestoreListenEvent>,
+    ack_sender: tokio::sync::oneshot::Sender<bool>,
+}
+
+impl FirestoreListenEventBatch {
+    /// Marks the events of the batch as handled.
+    pub fn ack(self) {
+        self.ack_sender.send(true).ok();
+    }
+
+    /// Asks for the events of the batch to be delivered again.
+    pub fn nack(self) {
+        self.ack_sender.send(false).ok();
+    }
+}
+
+/// A batch delivered by a listener stream, waiting for its acknowledgement.
+struct FirestoreListenUnackedBatch {
+    acked: tokio::sync::oneshot::Receiver<bool>,
+    resume_states: Vec<FirestoreListenerResumeState>,
+}
+
+/// The state of a listener's connection to Firestore.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FirestoreListenerConnectionState {
+    /// The listener has not been started yet.
+    NotStarted,
+    /// The listen stream is open and delivering changes.
+    Connected,
+    /// The listen stream failed or ended, and the listener is reconnecting.
+    Reconnecting,
+    /// The listener was shut down, or stopped on an error it cannot recover from.
+    Stopped,
+}
+
+/// The status of a single listener target.
+#[derive(Debug, Clone, Builder)]
+pub struct FirestoreListenerTargetStatus {
+    pub target: FirestoreListenerTarget,
+    /// When the last event concerning this target was received.
+    pub last_event_at: Option<FirestoreInstant>,
+    /// When the last resume token for this target was received.
+    pub last_resume_token_at: Option<FirestoreInstant>,
+}
+
+/// A snapshot of the status of a [`FirestoreListener`], returned by
+/// [`FirestoreListener::status`].
+#[derive(Debug, Clone, Builder)]
+pub struct FirestoreListenerStatus {
+    pub connection_state: FirestoreListenerConnectionState,
+    /// When the listener entered its current connection state.
+    pub connection_state_since: Option<FirestoreInstant>,
+    /// When the last event was received, for any target.
+    pub last_event_at: Option<FirestoreInstant>,
+    /// When the last resume token was received, for any target.
+    pub last_resume_token_at: Option<FirestoreInstant>,
+    pub targets: Vec<FirestoreListenerTargetStatus>,
+    /// How many times the listen stream could not be opened or failed.
+    pub stream_error_count: u64,
+    /// How many times the listener callback returned an error.
+    pub callback_error_count: u64,
+    /// How many times Firestore rejected the resume token of a target.
+    #[default = "0"]
+    pub rejected_resume_token_count: u64,
+    /// How many times Firestore removed a target because of an error other than a rejected
+    /// resume token, such as a missing permission.
+    #[default = "0"]
+    pub target_error_count: u64,
+}
+
+impl FirestoreListenerStatus {
+    /// Returns how long the listener has not been connected, or `None` while it is connected or
+    /// has not been started.
+    pub fn disconnected_for(&self) -> Option<std::time::Duration> {
+        match self.connection_state {
+            FirestoreListenerConnectionState::Reconnecting
+            | FirestoreListenerConnectionState::Stopped => {
+                self.connection_state_since.map(|since| {
+                    std::time::Duration::try_from(FirestoreInstant::now().duration_since(since))
+                        .unwrap_or_default()
+                })
+            }
+            FirestoreListenerConnectionState::NotStarted
+            | FirestoreListenerConnectionState::Connected => None,
+        }
+    }
+
+    fn set_connection_state(&mut self, connection_state: FirestoreListenerConnectionState) {
+        if self.connection_state != connection_state {
+            self.connection_state = connection_state;
+            self.connection_state_since = Some(FirestoreInstant::now());
+        }
+    }
+
+    fn targets_mut<'a>(
+        &'a mut self,
+        target_ids: &'a [i32],
+    ) -> impl Iterator<Item = &'a mut FirestoreListenerTargetStatus> + 'a {
+        self.targets.iter_mut().filter(move |status| {
+            target_ids
+                .iter()
+                .any(|target_id| *target_id as u32 == *status.target.value())
+        })
+    }
+
+    fn event_received(&mut self, event: &FirestoreListenEvent) {
+        let now = FirestoreInstant::now();
+        self.last_event_at = Some(now);
+
+        let target_ids: Vec<i32> = match event {
+            // A target change without target IDs applies to all targets
+            listen_response::ResponseType::TargetChange(target_change)
+                if target_change.target_ids.is_empty() =>
+            {
+                self.targets
+                    .iter()
+                    .map(|status| *status.target.value() as i32)
+                    .collect()
+            }
+            listen_response::ResponseType::TargetChange(target_change) => {
+                target_change.target_ids.clone()
+            }
+            listen_response::ResponseType::DocumentChange(doc_change) => doc_change
+                .target_ids
+                .iter()
+                .chain(doc_change.removed_target_ids.iter())
+                .copied()
+                .collect(),
+            listen_response::ResponseType::DocumentDelete(doc_delete) => {
+                doc_delete.removed_target_ids.clone()
+            }
+            listen_response::ResponseType::DocumentRemove(doc_remove) => {
+                doc_remove.removed_target_ids.clone()
+            }
+            listen_response::ResponseType::Filter(filter) => vec![filter.target_id],
+        };
+        for target_status in self.targets_mut(&target_ids) {
+            target_status.last_event_at = Some(now);
+        }
+    }
+
+    fn resume_token_received(&mut self, target: &FirestoreListenerTarget) {
+        let now = FirestoreInstant::now();
+        self.last_resume_token_at = Some(now);
+        if let Some(target_status) = self
+            .targets
+            .iter_mut()
+            .find(|status| status.target == *target)
+        {
+            target_status.last_resume_token_at = Some(now);
+        }
+    }
+}
+
+impl Default for FirestoreListenerStatus {
+    fn default() -> Self {
+        Self::new(
+            FirestoreListenerConnectionState::NotStarted,
+            Vec::new(),
+            0,
+            0,
+        )
+    }
 }
 
+/// Updates the shared status of a listener.
+fn update_listener_status<F>(status: &FirestoreListenerSharedStatus, f: F)
+where
+    F: FnOnce(&mut FirestoreListenerStatus),
+{
+    f(&mut status
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()))
+}
+
+/// The status of a listener, shared with its listener loop.
+pub(crate) type FirestoreListenerSharedStatus = Arc<Mutex<FirestoreListenerStatus>>;
+
 pub struct FirestoreListener<D, S>
 where
     D: FirestoreListenSupport,
@@ -247,9 +670,14 @@ where
     storage: S,
     listener_params: FirestoreListenerParams,
     targets: Vec<FirestoreListenerTargetParams>,
+    status: FirestoreListenerSharedStatus,
     shutdown_flag: Arc<AtomicBool>,
     shutdown_handle: Option<JoinHandle<()>>,
     shutdown_writer: Option<Arc<UnboundedSender<i8>>>,
+    target_updates_writer: Option<UnboundedSender<FirestoreListenerTargetUpdate>>,
+    /// Whether targets without a resume type resume from the state in the storage when the
+    /// listener starts.
+    resume_from_storage: bool,
 }
 
 impl<D, S> FirestoreListener<D, S>
@@ -267,25 +695,198 @@ where
             storage,
             listener_params,
             targets: vec![],
+            status: Arc::new(Mutex::new(FirestoreListenerStatus::default())),
             shutdown_flag: Arc::new(AtomicBool::new(false)),
             shutdown_handle: None,
             shutdown_writer: None,
+            target_updates_writer: None,
+            resume_from_storage: true,
         })
     }
 
+    /// Adds a target to the listener. On a running listener, the target is added to the open
+    /// listen stream, resuming from its stored resume state when it has none.
     pub fn add_target(
         &mut self,
         target_params: FirestoreListenerTargetParams,
     ) -> FirestoreResult<()> {
         target_params.validate()?;
+        if self
+            .targets
+            .iter()
+            .any(|existing| existing.target == target_params.target)
+        {
+            return Err(FirestoreError::InvalidParametersError(
+                FirestoreInvalidParametersError::new(FirestoreInvalidParametersPublicDetails::new(
+                    "target_id".to_string(),
+                    format!(
+                        "Listener target ID {} is already used",
+                        target_params.target.value()
+                    ),
+                )),
+            ));
+        }
+        if let Some(target_updates_writer) = &self.target_updates_writer {
+            target_updates_writer
+                .send(FirestoreListenerTargetUpdate::Add(target_params.clone()))
+                .ok();
+        }
         self.targets.push(target_params);
         Ok(())
     }
 
+    /// Removes a target from the listener. On a running listener, the target is removed from the
+    /// open listen stream and its resume state is deleted from the storage.
+    pub fn remove_target(&mut self, target: &FirestoreListenerTarget) -> FirestoreResult<()> {
+        let Some(position) = self
+            .targets
+            .iter()
+            .position(|existing| &existing.target == target)
+        else {
+            return Err(FirestoreError::InvalidParametersError(
+                FirestoreInvalidParametersError::new(FirestoreInvalidParametersPublicDetails::new(
+                    "target_id".to_string(),
+                    format!("Listener target ID {} is not registered", target.value()),
+                )),
+            ));
+        };
+        self.targets.remove(position);
+        if let Some(target_updates_writer) = &self.target_updates_writer {
+            target_updates_writer
+                .send(FirestoreListenerTargetUpdate::Remove(target.clone()))
+                .ok();
+        }
+        Ok(())
+    }
+
+    /// Returns a snapshot of the listener's connection state, event times and error counts.
+    pub fn status(&self) -> FirestoreListenerStatus {
+        self.status
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Returns the status shared with the listener loop, to follow it without the listener.
+    #[cfg(feature = "caching")]
+    pub(crate) fn shared_status(&self) -> FirestoreListenerSharedStatus {
+        self.status.clone()
+    }
+
     pub async fn start<FN, F>(&mut self, cb: FN) -> FirestoreResult<()>
     where
         FN: Fn(FirestoreListenEvent) -> F + Send + Sync + 'static,
         F: Future<Output = AnyBoxedErrResult<()>> + Send + 'static,
+    {
+        self.start_listener_loop(false, cb).await
+    }
+
+    /// Like [`start`](Self::start), but also calls `cb` with the target changes that carry a
+    /// resume token, so it can follow the consistent snapshots of the targets.
+    pub(crate) async fn start_with_target_changes<FN, F>(&mut self, cb: FN) -> FirestoreResult<()>
+    where
+        FN: Fn(FirestoreListenEvent) -> F + Send + Sync + 'static,
+        F: Future<Output = AnyBoxedErrResult<()>> + Send + 'static,
+    {
+        self.start_listener_loop(true, cb).await
+    }
+
+    async fn start_listener_loop<FN, F>(
+        &mut self,
+        with_target_changes: bool,
+        cb: FN,
+    ) -> FirestoreResult<()>
+    where
+        FN: Fn(FirestoreListenEvent) -> F + Send + Sync + 'static,
+        F: Future<Output = AnyBoxedErrResult<()>> + Send + 'static,
+    {
+        let Some(initial_states) = self.prepare_start().await? else {
+            return Ok(());
+        };
+
+        let (tx, rx): (UnboundedSender<i8>, UnboundedReceiver<i8>) =
+            tokio::sync::mpsc::unbounded_channel();
+
+        let (target_updates_tx, target_updates_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        self.shutdown_writer = Some(Arc::new(tx));
+        self.target_updates_writer = Some(target_updates_tx);
+        self.shutdown_handle = Some(tokio::spawn(Self::listener_loop(
+            self.db.clone(),
+            self.storage.clone(),
+            self.status.clone(),
+            self.shutdown_flag.clone(),
+            initial_states,
+            self.listener_params.clone(),
+            rx,
+            target_updates_rx,
+            with_target_changes,
+            cb,
+        )));
+        Ok(())
+    }
+
+    /// Starts the listener and returns its events as a bounded stream of batches, as an
+    /// alternative to the callback of [`start`](Self::start).
+    ///
+    /// The listener stops reading from Firestore while the stream buffer is full, so a slow
+    /// consumer slows the listen stream down instead of queueing events in memory. Resume tokens
+    /// are only stored once the consumer acknowledges their batch, which gives at-least-once
+    /// delivery: see [`FirestoreListenEventBatch`].
+    ///
+    /// The stream ends when the listener is shut down or dropped, or stops on an error it cannot
+    /// recover from. Dropping the stream stops the listener.
+    pub async fn start_stream<'b>(
+        &mut self,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreListenEventBatch>> {
+        let Some(initial_states) = self.prepare_start().await? else {
+            return Ok(futures::stream::empty().boxed());
+        };
+
+        let (tx, rx): (UnboundedSender<i8>, UnboundedReceiver<i8>) =
+            tokio::sync::mpsc::unbounded_channel();
+        let (batches_sender, batches_receiver) = tokio::sync::mpsc::channel(
+            self.listener_params.stream_buffer_size.unwrap_or(16).max(1),
+        );
+
+        let (target_updates_tx, target_updates_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        self.shutdown_writer = Some(Arc::new(tx));
+        self.target_updates_writer = Some(target_updates_tx);
+        self.shutdown_handle = Some(tokio::spawn(Self::stream_listener_loop(
+            self.db.clone(),
+            self.storage.clone(),
+            self.status.clone(),
+            self.shutdown_flag.clone(),
+            initial_states,
+            self.listener_params.clone(),
+            rx,
+            target_updates_rx,
+            batches_sender,
+        )));
+
+        Ok(
+            futures::stream::unfold(batches_receiver, |mut batches_receiver| async move {
+                batches_receiver
+                    .recv()
+                    .await
+                    .map(|batch| (batch, batches_receiver))
+            })
+            .boxed(),
+        )
+    }
+
+    /// Starts the targets without a resume type from scratch rather than from the resume states
+    /// in the storage, so their first events describe the complete result sets.
+    pub(crate) fn ignore_stored_resume_states(&mut self) {
+        self.resume_from_storage = false;
+    }
+
+    /// Reads the initial resume states of the targets and marks the listener as connecting.
+    /// Returns `None` when there is nothing to listen to.
+    async fn prepare_start(
+        &mut self,
+    ) -> FirestoreResult<Option<HashMap<FirestoreListenerTarget, FirestoreListenerTargetParams>>>
     {
         info!(
             num_targets = self.targets.len(),
@@ -302,6 +903,9 @@ where
                         target_params.clone().with_resume_type(resume_type.clone()),
                     );
                 }
+                None if !self.resume_from_storage => {
+                    initial_states.insert(target_params.target.clone(), target_params.clone());
+                }
                 None => {
                     let resume_type = self
                         .storage
@@ -323,23 +927,19 @@ where
 
         if initial_states.is_empty() {
             warn!("No initial states for listener targets. Exiting...");
-            return Ok(());
+            return Ok(None);
         }
 
-        let (tx, rx): (UnboundedSender<i8>, UnboundedReceiver<i8>) =
-            tokio::sync::mpsc::unbounded_channel();
+        update_listener_status(&self.status, |status| {
+            status.targets = initial_states
+                .keys()
+                .cloned()
+                .map(FirestoreListenerTargetStatus::new)
+                .collect();
+            status.set_connection_state(FirestoreListenerConnectionState::Reconnecting);
+        });
 
-        self.shutdown_writer = Some(Arc::new(tx));
-        self.shutdown_handle = Some(tokio::spawn(Self::listener_loop(
-            self.db.clone(),
-            self.storage.clone(),
-            self.shutdown_flag.clone(),
-            initial_states,
-            self.listener_params.clone(),
-            rx,
-            cb,
-        )));
-        Ok(())
+        Ok(Some(initial_states))
     }
 
     pub async fn shutdown(&mut self) -> FirestoreResult<()> {
@@ -348,11 +948,15 @@ where
         if let Some(shutdown_writer) = self.shutdown_writer.take() {
             shutdown_writer.send(1).ok();
         }
+        self.target_updates_writer = None;
         if let Some(signaller) = self.shutdown_handle.take() {
             if let Err(err) = signaller.await {
                 warn!(%err, "Firestore listener exit error!");
             };
         }
+        update_listener_status(&self.status, |status| {
+            status.set_connection_state(FirestoreListenerConnectionState::Stopped)
+        });
         debug!("Shutting down Firestore listener has been finished...");
         Ok(())
     }
@@ -360,19 +964,20 @@ where
     async fn listener_loop<FN, F>(
         db: D,
         storage: S,
+        status: FirestoreListenerSharedStatus,
         shutdown_flag: Arc<AtomicBool>,
         mut targets_state: HashMap<FirestoreListenerTarget, FirestoreListenerTargetParams>,
         listener_params: FirestoreListenerParams,
         mut shutdown_receiver: UnboundedReceiver<i8>,
+        mut target_updates: UnboundedReceiver<FirestoreListenerTargetUpdate>,
+        with_target_changes: bool,
         cb: FN,
     ) where
         D: FirestoreListenSupport + Clone + Send + Sync,
         FN: Fn(FirestoreListenEvent) -> F + Send + Sync,
         F: Future<Output = AnyBoxedErrResult<()>> + Send,
     {
-        let effective_delay = listener_params
-            .retry_delay
-            .unwrap_or_else(|| std::time::Duration::from_secs(5));
+        let mut retries = FirestoreListenerRetries::new(&listener_params);
 
         while !shutdown_flag.load(Ordering::Relaxed) {
             debug!(
@@ -380,16 +985,14 @@ where
                 "Start listening on targets..."
             );
 
-            match db
-                .listen_doc_changes(targets_state.values().cloned().collect())
-                .await
+            if let Some((mut listen_stream, connection_updates_tx)) =
+                Self::connect(&db, &status, &shutdown_flag, &mut retries, &targets_state).await
             {
-                Err(err) => {
-                    if Self::check_listener_if_permanent_error(err, effective_delay).await {
-                        shutdown_flag.store(true, Ordering::Relaxed);
-                    }
-                }
-                Ok(mut listen_stream) => loop {
+                retries.connected();
+                loop {
+                    update_listener_status(&status, |status| {
+                        status.set_connection_state(FirestoreListenerConnectionState::Connected)
+                    });
                     tokio::select! {
                         shutdown_trigger = shutdown_receiver.recv() => {
                             if shutdown_trigger.is_none() {
@@ -400,6 +1003,9 @@ where
                             shutdown_receiver.close();
                             break;
                         }
+                        Some(target_update) = target_updates.recv() => {
+                            Self::apply_target_update(&storage, &status, &mut targets_state, &connection_updates_tx, target_update).await;
+                        }
                         tried = listen_stream.try_next() => {
                             if shutdown_flag.load(Ordering::Relaxed) {
                                 break;
@@ -409,86 +1015,1112 @@ where
                                     Ok(Some(event)) => {
                                         trace!(?event, "Received a listen response event to handle.");
 
-                                        match event.response_type {
-                                            Some(listen_response::ResponseType::TargetChange(ref target_change))
-                                                if !target_change.resume_token.is_empty() =>
-                                            {
-                                                for target_id_num in &target_change.target_ids {
-                                                    match FirestoreListenerTarget::try_from(*target_id_num) {
-                                                        Ok(target_id) => {
-                                                            if let Some(target) = targets_state.get_mut(&target_id) {
-                                                                let new_token: FirestoreListenerToken = target_change.resume_token.clone().into();
-
-                                                                if let Err(err) = storage.update_resume_token(&target.target, new_token.clone()).await {
-                                                                    error!(%err, "Listener token storage error occurred.");
-                                                                    break;
-                                                                }
-                                                                else {
-                                                                    target.resume_type = Some(FirestoreListenerTargetResumeType::Token(new_token))
-                                                                }
-                                                            }
-                                                        },
-                                                        Err(err) => {
-                                                            error!(%err, target_id_num, "Listener system error - unexpected target ID.");
-                                                            break;
-                                                        }
-                                                    }
-                                                }
+                                        if let Some(ref response_type) = event.response_type {
+                                            Self::on_event_received(&storage, &status, &mut retries, &mut targets_state, &connection_updates_tx, response_type).await;
 
+                                            let resume_states = Self::resume_states_of(response_type, &targets_state);
+                                            if let Err(err) = Self::store_resume_states(&storage, &status, &mut targets_state, resume_states).await {
+                                                error!(%err, "Listener token storage error occurred.");
                                             }
-                                            Some(response_type) => {
-                                                if let Err(err) = cb(response_type).await {
-                                                    error!(%err, "Listener callback function error occurred.");
-                                                    break;
-                                                }
+                                        }
+
+                                        let delivered = match &event.response_type {
+                                            Some(listen_response::ResponseType::TargetChange(target_change)) => {
+                                                with_target_changes || target_change.resume_token.is_empty()
+                                            }
+                                            Some(_) => true,
+                                            None => false,
+                                        };
+
+                                        if let Some(response_type) = event.response_type.filter(|_| delivered) {
+                                            if let Err(err) = cb(response_type).await {
+                                                error!(%err, "Listener callback function error occurred.");
+                                                update_listener_status(&status, |status| {
+                                                    status.callback_error_count += 1;
+                                                    status.set_connection_state(FirestoreListenerConnectionState::Reconnecting);
+                                                });
+                                                retries.reconnecting();
+                                                break;
                                             }
-                                            None  =>  {}
                                         }
                                     }
-                                    Ok(None) => break,
+                                    Ok(None) => {
+                                        Self::on_stream_closed(&status, &shutdown_flag, &mut retries, None).await;
+                                        break;
+                                    }
                                     Err(err) => {
-                                        if Self::check_listener_if_permanent_error(err, effective_delay).await {
+                                        Self::on_stream_closed(&status, &shutdown_flag, &mut retries, Some(err)).await;
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Self::flush_storage(&storage).await;
+        update_listener_status(&status, |status| {
+            status.set_connection_state(FirestoreListenerConnectionState::Stopped)
+        });
+    }
+
+    async fn stream_listener_loop(
+        db: D,
+        storage: S,
+        status: FirestoreListenerSharedStatus,
+        shutdown_flag: Arc<AtomicBool>,
+        mut targets_state: HashMap<FirestoreListenerTarget, FirestoreListenerTargetParams>,
+        listener_params: FirestoreListenerParams,
+        mut shutdown_receiver: UnboundedReceiver<i8>,
+        mut target_updates: UnboundedReceiver<FirestoreListenerTargetUpdate>,
+        batches_sender: tokio::sync::mpsc::Sender<FirestoreListenEventBatch>,
+    ) {
+        let mut retries = FirestoreListenerRetries::new(&listener_params);
+
+        // Acknowledged in order, so that a stored resume token never skips unhandled events
+        let mut unacked_batches: VecDeque<FirestoreListenUnackedBatch> = VecDeque::new();
+
+        while !shutdown_flag.load(Ordering::Relaxed) {
+            debug!(
+                num_targets = targets_state.len(),
+                "Start streaming on targets..."
+            );
+
+            if let Some((mut listen_stream, connection_updates_tx)) =
+                Self::connect(&db, &status, &shutdown_flag, &mut retries, &targets_state).await
+            {
+                retries.connected();
+                update_listener_status(&status, |status| {
+                    status.set_connection_state(FirestoreListenerConnectionState::Connected)
+                });
+                let mut events: Vec<FirestoreListenEvent> = Vec::new();
+
+                loop {
+                    tokio::select! {
+                        shutdown_trigger = shutdown_receiver.recv() => {
+                            if shutdown_trigger.is_none() {
+                                debug!("Listener dropped. Exiting...");
+                            }
+                            shutdown_flag.store(true, Ordering::Relaxed);
+                            shutdown_receiver.close();
+                            break;
+                        }
+                        _ = batches_sender.closed() => {
+                            debug!("Listener stream dropped. Exiting...");
+                            shutdown_flag.store(true, Ordering::Relaxed);
+                            break;
+                        }
+                        acked = async { (&mut unacked_batches[0].acked).await }, if !unacked_batches.is_empty() => {
+                            let Some(batch) = unacked_batches.pop_front() else {
+                                continue;
+                            };
+                            if acked.unwrap_or(false) {
+                                if let Err(err) = Self::store_resume_states(&storage, &status, &mut targets_state, batch.resume_states).await {
+                                    error!(%err, "Listener token storage error occurred.");
+                                    update_listener_status(&status, |status| {
+                                        status.set_connection_state(FirestoreListenerConnectionState::Reconnecting)
+                                    });
+                                    break;
+                                }
+                            } else {
+                                debug!("Listen event batch has not been acknowledged. Resuming from the last acknowledged one...");
+                                unacked_batches.clear();
+                                update_listener_status(&status, |status| {
+                                    status.callback_error_count += 1;
+                                    status.set_connection_state(FirestoreListenerConnectionState::Reconnecting);
+                                });
+                                retries.reconnecting();
+                                break;
+                            }
+                        }
+                        Some(target_update) = target_updates.recv() => {
+                            Self::apply_target_update(&storage, &status, &mut targets_state, &connection_updates_tx, target_update).await;
+                        }
+                        tried = listen_stream.try_next() => {
+                            match tried {
+                                Ok(Some(event)) => {
+                                    trace!(?event, "Received a listen response event to stream.");
+                                    let Some(response_type) = event.response_type else {
+                                        continue;
+                                    };
+                                    Self::on_event_received(&storage, &status, &mut retries, &mut targets_state, &connection_updates_tx, &response_type).await;
+
+                                    let resume_states = Self::resume_states_of(&response_type, &targets_state);
+                                    events.push(response_type);
+                                    if resume_states.is_empty() {
+                                        continue;
+                                    }
+
+                                    let (ack_sender, acked) = tokio::sync::oneshot::channel();
+                                    let batch = FirestoreListenEventBatch {
+                                        events: std::mem::take(&mut events),
+                                        ack_sender,
+                                    };
+                                    // Waiting for room in the buffer stops reading the listen stream
+                                    tokio::select! {
+                                        sent = batches_sender.send(batch) => {
+                                            if sent.is_err() {
+                                                debug!("Listener stream dropped. Exiting...");
+                                                shutdown_flag.store(true, Ordering::Relaxed);
+                                                break;
+                                            }
+                                            unacked_batches.push_back(FirestoreListenUnackedBatch {
+                                                acked,
+                                                resume_states,
+                                            });
+                                        }
+                                        _ = shutdown_receiver.recv() => {
                                             shutdown_flag.store(true, Ordering::Relaxed);
+                                            shutdown_receiver.close();
+                                            break;
                                         }
-                                        break;
                                     }
                                 }
+                                Ok(None) => {
+                                    Self::on_stream_closed(&status, &shutdown_flag, &mut retries, None).await;
+                                    break;
+                                }
+                                Err(err) => {
+                                    Self::on_stream_closed(&status, &shutdown_flag, &mut retries, Some(err)).await;
+                                    break;
+                                }
                             }
                         }
                     }
-                },
+                }
             }
         }
+
+        Self::flush_storage(&storage).await;
+        update_listener_status(&status, |status| {
+            status.set_connection_state(FirestoreListenerConnectionState::Stopped)
+        });
     }
 
-    async fn check_listener_if_permanent_error(
-        err: FirestoreError,
-        delay: std::time::Duration,
-    ) -> bool {
+    /// Stores the resume states the storage held back, as the listener stops.
+    async fn flush_storage(storage: &S) {
+        if let Err(err) = storage.flush().await {
+            error!(%err, "Listener token storage error occurred.");
+        }
+    }
+
+    /// Opens a listen stream on the targets, with the sender of the target updates to apply to
+    /// it. Returns `None` after a connection error, once it is time to retry.
+    async fn connect(
+        db: &D,
+        status: &FirestoreListenerSharedStatus,
+        shutdown_flag: &AtomicBool,
+        retries: &mut FirestoreListenerRetries,
+        targets_state: &HashMap<FirestoreListenerTarget, FirestoreListenerTargetParams>,
+    ) -> Option<(
+        BoxStream<'static, FirestoreResult<ListenResponse>>,
+        UnboundedSender<FirestoreListenerTargetUpdate>,
+    )> {
+        let (connection_updates_tx, connection_updates_rx) = tokio::sync::mpsc::unbounded_channel();
+        match db
+            .listen_doc_changes_with_updates(
+                targets_state.values().cloned().collect(),
+                Self::target_updates_stream(connection_updates_rx),
+            )
+            .await
+        {
+            Ok(listen_stream) => Some((listen_stream, connection_updates_tx)),
+            Err(err) => {
+                Self::on_stream_closed(status, shutdown_flag, retries, Some(err)).await;
+                None
+            }
+        }
+    }
+
+    /// Handles the end of a listen stream, or the error that closed it, and returns once it is
+    /// time to reconnect. Sets the shutdown flag when the retry policy gives up.
+    async fn on_stream_closed(
+        status: &FirestoreListenerSharedStatus,
+        shutdown_flag: &AtomicBool,
+        retries: &mut FirestoreListenerRetries,
+        err: Option<FirestoreError>,
+    ) {
+        update_listener_status(status, |status| {
+            if err.is_some() {
+                status.stream_error_count += 1;
+            }
+            status.set_connection_state(FirestoreListenerConnectionState::Reconnecting);
+        });
         match err {
-            FirestoreError::DatabaseError(ref db_err)
-                if db_err.details.contains("unexpected end of file")
-                    || db_err.details.contains("stream error received") =>
-            {
-                debug!(%err, ?delay, "Listen EOF.. Restarting after the specified delay...");
-                tokio::time::sleep(delay).await;
-                false
+            Some(err) => {
+                if retries.on_error(err).await {
+                    shutdown_flag.store(true, Ordering::Relaxed);
+                }
+            }
+            None => retries.reconnecting(),
+        }
+    }
+
+    /// Records an event in the status and the retries, resumes the targets whose resume token it
+    /// rejects and reports the other targets it removes with an error.
+    async fn on_event_received(
+        storage: &S,
+        status: &FirestoreListenerSharedStatus,
+        retries: &mut FirestoreListenerRetries,
+        targets_state: &mut HashMap<FirestoreListenerTarget, FirestoreListenerTargetParams>,
+        connection_updates: &UnboundedSender<FirestoreListenerTargetUpdate>,
+        event: &FirestoreListenEvent,
+    ) {
+        retries.event_received();
+        update_listener_status(status, |status| status.event_received(event));
+        let (rejected, target_errors): (Vec<_>, Vec<_>) = Self::target_errors(event)
+            .into_iter()
+            .partition(|(target, code, _)| {
+                Self::is_rejected_resume_token(targets_state, target, *code)
+            });
+        for (target, _, cause) in target_errors {
+            error!(%cause, target = *target.value(), "Listener target removed with an error.");
+            update_listener_status(status, |status| status.target_error_count += 1);
+            retries.target_error(&target, &cause);
+        }
+        let rejected = rejected
+            .into_iter()
+            .map(|(target, _, cause)| (target, cause))
+            .collect();
+        Self::resume_rejected_targets(
+            storage,
+            status,
+            retries,
+            targets_state,
+            connection_updates,
+            rejected,
+        )
+        .await;
+    }
+
+    /// Returns the resume states carried by an event for the listened targets. Target changes
+    /// without target IDs apply to all targets.
+    fn resume_states_of(
+        event: &FirestoreListenEvent,
+        targets_state: &HashMap<FirestoreListenerTarget, FirestoreListenerTargetParams>,
+    ) -> Vec<FirestoreListenerResumeState> {
+        let FirestoreListenEvent::TargetChange(target_change) = event else {
+            return Vec::new();
+        };
+        if target_change.resume_token.is_empty() {
+            return Vec::new();
+        }
+        let token: FirestoreListenerToken = target_change.resume_token.clone().into();
+        let read_time = target_change
+            .read_time
+            .and_then(|read_time| from_timestamp(read_time).ok());
+        let resume_state = |target_params: &FirestoreListenerTargetParams| {
+            FirestoreListenerResumeState::new(
+                target_params.target.clone(),
+                token.clone(),
+                target_params.labels.clone(),
+            )
+            .opt_read_time(read_time)
+        };
+
+        if target_change.target_ids.is_empty() {
+            targets_state.values().map(resume_state).collect()
+        } else {
+            target_change
+                .target_ids
+                .iter()
+                .filter_map(|target_id_num| {
+                    match FirestoreListenerTarget::try_from(*target_id_num) {
+                        Ok(target) => Some(target),
+                        Err(err) => {
+                            error!(%err, target_id_num, "Listener system error - unexpected target ID.");
+                            None
+                        }
+                    }
+                })
+                .filter_map(|target| targets_state.get(&target).map(resume_state))
+                .collect()
+        }
+    }
+
+    /// Returns the targets that Firestore removed with an error, with its code.
+    fn target_errors(
+        event: &FirestoreListenEvent,
+    ) -> Vec<(
+        FirestoreListenerTarget,
+        gcloud_sdk::tonic::Code,
+        FirestoreError,
+    )> {
+        let FirestoreListenEvent::TargetChange(change) = event else {
+            return Vec::new();
+        };
+        let Some(cause) = &change.cause else {
+            return Vec::new();
+        };
+        if change.target_change_type != target_change::TargetChangeType::Remove as i32 {
+            return Vec::new();
+        }
+        let code = gcloud_sdk::tonic::Code::from_i32(cause.code);
+        change
+            .target_ids
+            .iter()
+            .filter_map(|target_id_num| FirestoreListenerTarget::try_from(*target_id_num).ok())
+            .map(|target| {
+                let cause = gcloud_sdk::tonic::Status::new(code, cause.message.clone());
+                (target, code, cause.into())
+            })
+            .collect()
+    }
+
+    /// Whether Firestore removed a target resuming from a token with one of the codes it uses
+    /// for a bad or expired resume token.
+    fn is_rejected_resume_token(
+        targets_state: &HashMap<FirestoreListenerTarget, FirestoreListenerTargetParams>,
+        target: &FirestoreListenerTarget,
+        code: gcloud_sdk::tonic::Code,
+    ) -> bool {
+        matches!(
+            code,
+            gcloud_sdk::tonic::Code::InvalidArgument | gcloud_sdk::tonic::Code::OutOfRange
+        ) && matches!(
+            targets_state
+                .get(target)
+                .and_then(|target_params| target_params.resume_type.as_ref()),
+            Some(FirestoreListenerTargetResumeType::Token(_))
+        )
+    }
+
+    /// Adds the targets whose resume token was rejected again to the open listen stream, from
+    /// their fallback read time or the one stored with the token, or from now without either.
+    async fn resume_rejected_targets(
+        storage: &S,
+        status: &FirestoreListenerSharedStatus,
+        retries: &FirestoreListenerRetries,
+        targets_state: &mut HashMap<FirestoreListenerTarget, FirestoreListenerTargetParams>,
+        connection_updates: &UnboundedSender<FirestoreListenerTargetUpdate>,
+        rejected: Vec<(FirestoreListenerTarget, FirestoreError)>,
+    ) {
+        for (target, cause) in rejected {
+            let Some(target_params) = targets_state.get_mut(&target) else {
+                continue;
+            };
+            let read_time = match target_params.fallback_read_time {
+                Some(read_time) => Some(read_time),
+                None => storage
+                    .read_resume_read_time(&target)
+                    .await
+                    .unwrap_or_else(|err| {
+                        error!(%err, "Listener resume state error occurred.");
+                        None
+                    }),
+            };
+            warn!(
+                %cause,
+                target = *target.value(),
+                ?read_time,
+                "Listener resume token rejected. Adding the target again...",
+            );
+            update_listener_status(status, |status| status.rejected_resume_token_count += 1);
+            retries.resume_token_rejected(&target, &cause);
+            target_params.resume_type = read_time.map(FirestoreListenerTargetResumeType::ReadTime);
+            connection_updates
+                .send(FirestoreListenerTargetUpdate::Add(target_params.clone()))
+                .ok();
+        }
+    }
+
+    fn target_updates_stream(
+        target_updates: UnboundedReceiver<FirestoreListenerTargetUpdate>,
+    ) -> BoxStream<'static, FirestoreListenerTargetUpdate> {
+        futures::stream::unfold(target_updates, |mut target_updates| async move {
+            target_updates
+                .recv()
+                .await
+                .map(|target_update| (target_update, target_updates))
+        })
+        .boxed()
+    }
+
+    /// Applies a target added to or removed from the running listener, and forwards it to the
+    /// open listen stream.
+    async fn apply_target_update(
+        storage: &S,
+        status: &FirestoreListenerSharedStatus,
+        targets_state: &mut HashMap<FirestoreListenerTarget, FirestoreListenerTargetParams>,
+        connection_updates: &UnboundedSender<FirestoreListenerTargetUpdate>,
+        target_update: FirestoreListenerTargetUpdate,
+    ) {
+        match target_update {
+            FirestoreListenerTargetUpdate::Add(target_params) => {
+                let target_params = match target_params.resume_type {
+                    Some(_) => target_params,
+                    None => match storage.read_resume_state(&target_params.target).await {
+                        Ok(resume_type) => target_params.opt_resume_type(resume_type),
+                        Err(err) => {
+                            error!(%err, "Listener resume state error occurred. Listening to the target from now...");
+                            target_params
+                        }
+                    },
+                };
+                let target = target_params.target.clone();
+                debug!(
+                    target = *target.value(),
+                    "Adding a target to the running listener..."
+                );
+                update_listener_status(status, |status| {
+                    status
+                        .targets
+                        .push(FirestoreListenerTargetStatus::new(target.clone()))
+                });
+                targets_state.insert(target, target_params.clone());
+                connection_updates
+                    .send(FirestoreListenerTargetUpdate::Add(target_params))
+                    .ok();
+            }
+            FirestoreListenerTargetUpdate::Remove(target) => {
+                debug!(
+                    target = *target.value(),
+                    "Removing a target from the running listener..."
+                );
+                update_listener_status(status, |status| {
+                    status
+                        .targets
+                        .retain(|target_status| target_status.target != target)
+                });
+                targets_state.remove(&target);
+                if let Err(err) = storage.delete_resume_state(&target).await {
+                    error!(%err, "Listener resume state storage error occurred.");
+                }
+                connection_updates
+                    .send(FirestoreListenerTargetUpdate::Remove(target))
+                    .ok();
+            }
+        }
+    }
+
+    async fn store_resume_states(
+        storage: &S,
+        status: &FirestoreListenerSharedStatus,
+        targets_state: &mut HashMap<FirestoreListenerTarget, FirestoreListenerTargetParams>,
+        resume_states: Vec<FirestoreListenerResumeState>,
+    ) -> AnyBoxedErrResult<()> {
+        for resume_state in resume_states {
+            // Removed from the listener since the batch was delivered
+            let Some(target_params) = targets_state.get_mut(&resume_state.target) else {
+                continue;
+            };
+            let token = resume_state.token.clone();
+            storage.update_resume_state(resume_state).await?;
+            update_listener_status(status, |status| {
+                status.resume_token_received(&target_params.target)
+            });
+            target_params.resume_type = Some(FirestoreListenerTargetResumeType::Token(token));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::listen_changes_test_fixtures::ScriptedListenSupport;
+    use crate::FirestoreMemListenStateStorage;
+
+    fn doc_change(id: &str) -> ListenResponse {
+        ListenResponse {
+            response_type: Some(FirestoreListenEvent::DocumentChange(DocumentChange {
+                document: Some(Document {
+                    name: format!("projects/test/databases/(default)/documents/cities/{id}"),
+                    ..Default::default()
+                }),
+                target_ids: vec![1],
+                removed_target_ids: vec![],
+            })),
+        }
+    }
+
+    fn resume_token(token: &str) -> ListenResponse {
+        ListenResponse {
+            response_type: Some(FirestoreListenEvent::TargetChange(TargetChange {
+                target_change_type: target_change::TargetChangeType::NoChange as i32,
+                target_ids: vec![],
+                resume_token: token.as_bytes().to_vec(),
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn query_target(target: u32) -> FirestoreListenerTargetParams {
+        FirestoreListenerTargetParams::new(
+            FirestoreListenerTarget::new(target),
+            FirestoreTargetType::Query(FirestoreQueryParams::new("cities".into())),
+            HashMap::new(),
+        )
+    }
+
+    #[tokio::test]
+    async fn calls_back_with_target_changes_carrying_resume_tokens_on_request_only() {
+        for (with_target_changes, expected_events) in [
+            (false, vec!["document a", "current", "document b"]),
+            (
+                true,
+                vec!["document a", "no change", "current", "document b"],
+            ),
+        ] {
+            let db = ScriptedListenSupport::default();
+            *db.connections.lock().unwrap() = vec![vec![
+                doc_change("a"),
+                resume_token("t1"),
+                ListenResponse {
+                    response_type: Some(FirestoreListenEvent::TargetChange(TargetChange {
+                        target_change_type: target_change::TargetChangeType::Current as i32,
+                        target_ids: vec![1],
+                        ..Default::default()
+                    })),
+                },
+                doc_change("b"),
+            ]];
+
+            let mut listener = FirestoreListener::new(
+                db,
+                FirestoreMemListenStateStorage::new(),
+                FirestoreListenerParams::new(),
+            )
+            .await
+            .unwrap();
+            listener.add_target(query_target(1)).unwrap();
+
+            let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let received = events.clone();
+            let cb = move |event: FirestoreListenEvent| {
+                received.lock().unwrap().push(match event {
+                    FirestoreListenEvent::DocumentChange(change) => format!(
+                        "document {}",
+                        change.document.unwrap().name.rsplit('/').next().unwrap()
+                    ),
+                    FirestoreListenEvent::TargetChange(change)
+                        if change.target_change_type
+                            == target_change::TargetChangeType::Current as i32 =>
+                    {
+                        "current".to_string()
+                    }
+                    FirestoreListenEvent::TargetChange(_) => "no change".to_string(),
+                    _ => "other".to_string(),
+                });
+                async { Ok(()) }
+            };
+            if with_target_changes {
+                listener.start_with_target_changes(cb).await.unwrap();
+            } else {
+                listener.start(cb).await.unwrap();
             }
-            FirestoreError::DatabaseError(ref db_err)
-                if db_err.public.code.contains("InvalidArgument") =>
+
+            tokio::time::timeout(std::time::Duration::from_secs(5), async {
+                while events.lock().unwrap().last().map(String::as_str) != Some("document b") {
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+            assert_eq!(*events.lock().unwrap(), expected_events);
+
+            listener.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn stores_resume_tokens_for_all_targets_without_target_ids() {
+        let db = ScriptedListenSupport::default();
+        *db.connections.lock().unwrap() = vec![vec![doc_change("a"), resume_token("t1")]];
+        let storage = FirestoreMemListenStateStorage::new();
+
+        let mut listener =
+            FirestoreListener::new(db, storage.clone(), FirestoreListenerParams::new())
+                .await
+                .unwrap();
+        listener.add_target(query_target(1)).unwrap();
+        listener.add_target(query_target(2)).unwrap();
+        listener.start(|_| async { Ok(()) }).await.unwrap();
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while storage
+                .get_token(&FirestoreListenerTarget::new(2))
+                .await
+                .is_none()
             {
-                error!(%err, "Listen error. Exiting...");
-                true
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        for target_id in [1, 2] {
+            assert_eq!(
+                storage
+                    .get_token(&FirestoreListenerTarget::new(target_id))
+                    .await
+                    .map(|token| token.into_value()),
+                Some(b"t1".to_vec())
+            );
+        }
+
+        listener.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn adds_and_removes_targets_of_a_running_listener() {
+        let db = ScriptedListenSupport::default();
+        let storage = FirestoreMemListenStateStorage::new();
+        for (target_id, token) in [(1, b"t1"), (2, b"t2")] {
+            storage
+                .update_resume_token(
+                    &FirestoreListenerTarget::new(target_id),
+                    token.to_vec().into(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let mut listener =
+            FirestoreListener::new(db.clone(), storage.clone(), FirestoreListenerParams::new())
+                .await
+                .unwrap();
+        listener.add_target(query_target(1)).unwrap();
+        listener.start(|_| async { Ok(()) }).await.unwrap();
+
+        listener.add_target(query_target(2)).unwrap();
+        assert!(listener.add_target(query_target(2)).is_err());
+        listener
+            .remove_target(&FirestoreListenerTarget::new(1))
+            .unwrap();
+        assert!(listener
+            .remove_target(&FirestoreListenerTarget::new(1))
+            .is_err());
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while db.target_updates.lock().unwrap().len() < 2 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            *db.target_updates.lock().unwrap(),
+            vec!["add 2 from token", "remove 1"]
+        );
+        assert!(storage
+            .get_token(&FirestoreListenerTarget::new(1))
+            .await
+            .is_none());
+        assert_eq!(
+            listener
+                .status()
+                .targets
+                .into_iter()
+                .map(|target_status| target_status.target)
+                .collect::<Vec<_>>(),
+            vec![FirestoreListenerTarget::new(2)]
+        );
+
+        listener.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stores_resume_tokens_of_acknowledged_batches_only() {
+        let db = ScriptedListenSupport::default();
+        *db.connections.lock().unwrap() = vec![
+            vec![
+                doc_change("a"),
+                resume_token("t1"),
+                doc_change("b"),
+                resume_token("t2"),
+            ],
+            vec![doc_change("b"), resume_token("t3")],
+        ];
+        let storage = FirestoreMemListenStateStorage::new();
+        let target = FirestoreListenerTarget::new(1);
+
+        let mut listener = FirestoreListener::new(
+            db.clone(),
+            storage.clone(),
+            FirestoreListenerParams::new().with_stream_buffer_size(1),
+        )
+        .await
+        .unwrap();
+        listener.add_target(query_target(1)).unwrap();
+        let mut batches = listener.start_stream().await.unwrap();
+
+        let batch = batches.next().await.unwrap();
+        assert_eq!(batch.events.len(), 2);
+        batch.ack();
+        let batch = batches.next().await.unwrap();
+        assert_eq!(batch.events.len(), 2);
+        batch.nack();
+
+        // Delivered again from the last acknowledged batch
+        let batch = batches.next().await.unwrap();
+        assert_eq!(batch.events.len(), 2);
+        assert_eq!(
+            *db.resumed_from.lock().unwrap(),
+            vec![None, Some(b"t1".to_vec())]
+        );
+        batch.ack();
+
+        let stored_token = || async {
+            match storage.read_resume_state(&target).await.unwrap() {
+                Some(FirestoreListenerTargetResumeType::Token(token)) => token.value().clone(),
+                _ => Vec::new(),
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while stored_token().await != b"t3".to_vec() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        listener.shutdown().await.unwrap();
+        assert!(batches.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_stream_is_dropped_while_idle() {
+        let mut listener = FirestoreListener::new(
+            ScriptedListenSupport::default(),
+            FirestoreMemListenStateStorage::new(),
+            FirestoreListenerParams::new(),
+        )
+        .await
+        .unwrap();
+        listener.add_target(query_target(1)).unwrap();
+        let batches = listener.start_stream().await.unwrap();
+
+        // No events come in, so only the closed stream can stop the listener
+        drop(batches);
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while listener.status().connection_state != FirestoreListenerConnectionState::Stopped {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        listener.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn calls_the_lifecycle_hooks_until_it_gives_up() {
+        #[derive(Default)]
+        struct RecordedHooks(Mutex<Vec<String>>);
+
+        impl crate::FirestoreListenerLifecycleHooks for Arc<RecordedHooks> {
+            fn on_connected(&self) {
+                self.0.lock().unwrap().push("connected".to_string());
+            }
+
+            fn on_disconnected(
+                &self,
+                error: Option<&FirestoreError>,
+                _retry_in: std::time::Duration,
+            ) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("disconnected {:?}", error.and_then(grpc_code)));
+            }
+
+            fn on_permanent_failure(&self, error: &FirestoreError) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("gave up {:?}", grpc_code(error)));
+            }
+        }
+
+        fn grpc_code(error: &FirestoreError) -> Option<gcloud_sdk::tonic::Code> {
+            match error {
+                FirestoreError::DatabaseError(err) => err.grpc_code,
+                FirestoreError::DataNotFoundError(err) => err.grpc_code,
+                _ => None,
             }
-            FirestoreError::InvalidParametersError(_) => {
-                error!(%err, "Listen error. Exiting...");
-                true
+        }
+
+        let status_error =
+            |code| FirestoreError::from(gcloud_sdk::tonic::Status::new(code, "test"));
+        let db = ScriptedListenSupport::default();
+        *db.connection_errors.lock().unwrap() =
+            vec![status_error(gcloud_sdk::tonic::Code::Unavailable)];
+        *db.connections.lock().unwrap() = vec![vec![doc_change("a")]];
+        *db.stream_errors.lock().unwrap() =
+            vec![status_error(gcloud_sdk::tonic::Code::PermissionDenied)];
+        let hooks = Arc::new(RecordedHooks::default());
+
+        let mut listener = FirestoreListener::new(
+            db,
+            FirestoreMemListenStateStorage::new(),
+            FirestoreListenerParams::new()
+                .with_retry_policy(crate::FirestoreListenerRetryPolicy::fixed_delay(
+                    std::time::Duration::from_millis(1),
+                ))
+                .with_lifecycle_hooks(crate::FirestoreListenerHooks::new(hooks.clone())),
+        )
+        .await
+        .unwrap();
+        listener.add_target(query_target(1)).unwrap();
+        listener.start(|_| async { Ok(()) }).await.unwrap();
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while listener.status().connection_state != FirestoreListenerConnectionState::Stopped {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            *hooks.0.lock().unwrap(),
+            vec![
+                "disconnected Some(Unavailable)",
+                "connected",
+                "gave up Some(PermissionDenied)",
+            ]
+        );
+
+        listener.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumes_from_a_read_time_when_the_token_is_rejected() {
+        #[derive(Default)]
+        struct RejectionsCounter(std::sync::atomic::AtomicUsize);
+
+        impl crate::FirestoreListenerLifecycleHooks for Arc<RejectionsCounter> {
+            fn on_resume_token_rejected(
+                &self,
+                _target: &FirestoreListenerTarget,
+                _cause: &FirestoreError,
+            ) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let db = ScriptedListenSupport::default();
+        *db.connections.lock().unwrap() = vec![vec![ListenResponse {
+            response_type: Some(FirestoreListenEvent::TargetChange(TargetChange {
+                target_change_type: target_change::TargetChangeType::Remove as i32,
+                target_ids: vec![1],
+                cause: Some(gcloud_sdk::google::rpc::Status {
+                    code: gcloud_sdk::tonic::Code::InvalidArgument as i32,
+                    message: "resume token expired".to_string(),
+                    details: vec![],
+                }),
+                ..Default::default()
+            })),
+        }]];
+        let storage = FirestoreMemListenStateStorage::new();
+        storage
+            .update_resume_state(
+                FirestoreListenerResumeState::new(
+                    FirestoreListenerTarget::new(1),
+                    b"expired".to_vec().into(),
+                    HashMap::new(),
+                )
+                .with_read_time(FirestoreInstant::now()),
+            )
+            .await
+            .unwrap();
+        let rejections = Arc::new(RejectionsCounter::default());
+
+        let mut listener = FirestoreListener::new(
+            db.clone(),
+            storage,
+            FirestoreListenerParams::new()
+                .with_lifecycle_hooks(crate::FirestoreListenerHooks::new(rejections.clone())),
+        )
+        .await
+        .unwrap();
+        listener.add_target(query_target(1)).unwrap();
+        listener.start(|_| async { Ok(()) }).await.unwrap();
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while db.target_updates.lock().unwrap().is_empty() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            *db.resumed_from.lock().unwrap(),
+            vec![Some(b"expired".to_vec())]
+        );
+        assert_eq!(
+            *db.target_updates.lock().unwrap(),
+            vec!["add 1 from read time"]
+        );
+        assert_eq!(listener.status().rejected_resume_token_count, 1);
+        assert_eq!(rejections.0.load(Ordering::Relaxed), 1);
+
+        listener.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_targets_removed_for_other_errors_than_their_resume_token() {
+        #[derive(Default)]
+        struct TargetHooks(std::sync::Mutex<Vec<String>>);
+
+        impl crate::FirestoreListenerLifecycleHooks for Arc<TargetHooks> {
+            fn on_resume_token_rejected(
+                &self,
+                target: &FirestoreListenerTarget,
+                _cause: &FirestoreError,
+            ) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("token rejected {}", target.value()));
             }
-            _ => {
-                error!(%err, ?delay, "Listen error. Restarting after the specified delay...");
-                tokio::time::sleep(delay).await;
-                false
+
+            fn on_target_error(&self, target: &FirestoreListenerTarget, _cause: &FirestoreError) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("target error {}", target.value()));
             }
         }
+
+        let db = ScriptedListenSupport::default();
+        *db.connections.lock().unwrap() = vec![vec![ListenResponse {
+            response_type: Some(FirestoreListenEvent::TargetChange(TargetChange {
+                target_change_type: target_change::TargetChangeType::Remove as i32,
+                target_ids: vec![1],
+                cause: Some(gcloud_sdk::google::rpc::Status {
+                    code: gcloud_sdk::tonic::Code::PermissionDenied as i32,
+                    message: "missing permission".to_string(),
+                    details: vec![],
+                }),
+                ..Default::default()
+            })),
+        }]];
+        let storage = FirestoreMemListenStateStorage::new();
+        storage
+            .update_resume_token(&FirestoreListenerTarget::new(1), b"t1".to_vec().into())
+            .await
+            .unwrap();
+        let hooks = Arc::new(TargetHooks::default());
+
+        let mut listener = FirestoreListener::new(
+            db.clone(),
+            storage.clone(),
+            FirestoreListenerParams::new()
+                .with_lifecycle_hooks(crate::FirestoreListenerHooks::new(hooks.clone())),
+        )
+        .await
+        .unwrap();
+        listener.add_target(query_target(1)).unwrap();
+        listener.start(|_| async { Ok(()) }).await.unwrap();
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while listener.status().target_error_count == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(*hooks.0.lock().unwrap(), vec!["target error 1"]);
+        assert_eq!(listener.status().rejected_resume_token_count, 0);
+        assert!(db.target_updates.lock().unwrap().is_empty());
+        assert_eq!(
+            storage
+                .get_token(&FirestoreListenerTarget::new(1))
+                .await
+                .map(|token| token.into_value()),
+            Some(b"t1".to_vec())
+        );
+
+        listener.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn validates_listened_queries() {
+        let query = FirestoreQueryParams::new("cities".into());
+        let validate = |query_params: FirestoreQueryParams| {
+            FirestoreTargetType::Query(query_params).validate()
+        };
+
+        assert!(validate(
+            query
+                .clone()
+                .with_collection_id(FirestoreQueryCollection::Group(vec!["cities".into()]))
+                .with_all_descendants(true)
+                .with_order_by(vec![
+                    ("population", FirestoreQueryDirection::Descending).into()
+                ])
+                .with_limit(10)
+                .with_start_at(FirestoreQueryCursor::AfterValue(vec![
+                    1000.into(),
+                    "cities/c1".into()
+                ]))
+        )
+        .is_ok());
+
+        for (invalid_query, field) in [
+            (
+                query
+                    .clone()
+                    .with_collection_id(FirestoreQueryCollection::Group(vec![
+                        "cities".into(),
+                        "towns".into(),
+                    ])),
+                "collection_id",
+            ),
+            (query.clone().with_offset(5), "offset"),
+            (query.clone().with_limit(0), "limit"),
+            (
+                query
+                    .clone()
+                    .with_end_at(FirestoreQueryCursor::BeforeValue(vec![1.into(), 2.into()])),
+                "end_at",
+            ),
+        ] {
+            match validate(invalid_query) {
+                Err(FirestoreError::InvalidParametersError(err)) => {
+                    assert_eq!(err.public.field, field)
+                }
+                other => panic!("Unexpected validation of {field}: {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn limits_queries_to_their_last_documents() {
+        let query = FirestoreQueryParams::new("cities".into())
+            .with_order_by(vec![
+                ("population", FirestoreQueryDirection::Ascending).into()
+            ])
+            .with_start_at(FirestoreQueryCursor::BeforeValue(vec![1000.into()]));
+
+        let FirestoreTargetType::Query(limited) = FirestoreTargetType::Query(query.clone())
+            .limit_to_last(3)
+            .unwrap()
+        else {
+            panic!("Unexpected target type");
+        };
+        assert_eq!(limited.limit, Some(3));
+        assert_eq!(
+            limited.order_by,
+            Some(vec![
+                ("population", FirestoreQueryDirection::Descending).into()
+            ])
+        );
+        assert_eq!(limited.start_at, None);
+        assert_eq!(
+            limited.end_at,
+            Some(FirestoreQueryCursor::AfterValue(vec![1000.into()]))
+        );
+
+        assert!(FirestoreTargetType::Query(query.clone().with_limit(3))
+            .limit_to_last(3)
+            .is_err());
+        assert!(
+            FirestoreTargetType::Query(FirestoreQueryParams::new("cities".into()))
+                .limit_to_last(3)
+                .is_err()
+        );
     }
 }
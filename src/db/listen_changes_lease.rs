@@ -0,0 +1,589 @@
+use crate::errors::*;
+use crate::timestamp_utils::from_timestamp;
+use crate::{
+    FirestoreDb, FirestoreListenEvent, FirestoreListener, FirestoreListenerConnectionState,
+    FirestoreListenerParams, FirestoreListenerTargetParams, FirestoreResult,
+    FirestoreResumeStateStorage, FirestoreTimestamp, FirestoreWritePrecondition,
+};
+use gcloud_sdk::google::firestore::v1::Document;
+use rsb_derive::*;
+use rvstruct::ValueStruct;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tracing::*;
+
+/// The lease that elects the active listener among the instances listening to the same targets.
+#[derive(Debug, Clone, Eq, PartialEq, Builder)]
+pub struct FirestoreListenerLeaseParams {
+    /// The collection of the lease documents.
+    pub collection_id: String,
+    /// The ID of the lease document, naming the set of targets the instances listen to.
+    pub lease_id: String,
+    /// Identifies this instance in the lease document. Random by default.
+    #[default = "hex::encode(rand::random::<[u8; 8]>())"]
+    pub holder_id: String,
+    /// How long the lease is held without being renewed. Other instances take over after that.
+    #[default = "Duration::from_secs(30)"]
+    pub lease_duration: Duration,
+    /// How often the active instance renews its lease, and the others try to acquire it.
+    #[default = "Duration::from_secs(10)"]
+    pub renew_interval: Duration,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FirestoreListenerLeaseDoc {
+    holder_id: String,
+    expires_at: FirestoreTimestamp,
+    renewed_at: FirestoreTimestamp,
+}
+
+impl FirestoreListenerLeaseDoc {
+    /// How long the holder's lease lasts from its last renewal, measured on the holder's clock.
+    fn lease_duration(&self) -> Duration {
+        Duration::try_from(
+            self.expires_at
+                .value()
+                .duration_since(*self.renewed_at.value()),
+        )
+        .unwrap_or(Duration::ZERO)
+    }
+}
+
+/// Tracks when this instance first saw the current version of a lease held by another instance.
+///
+/// The expiry written by the holder is not compared against the local clock, as the clocks of
+/// the instances may disagree. The lease expires once its document stayed unchanged for its
+/// duration, as measured by this instance's monotonic clock since it first saw that version of
+/// the document. So an instance taking over waits for a whole lease duration after it starts
+/// watching the lease, even if the lease expired earlier.
+#[derive(Debug, Default)]
+struct FirestoreListenerLeaseObserver {
+    observed: Option<(FirestoreTimestamp, Instant)>,
+}
+
+impl FirestoreListenerLeaseObserver {
+    fn is_held_by_other(
+        &mut self,
+        holder_id: &str,
+        lease_doc: &FirestoreListenerLeaseDoc,
+        update_time: &FirestoreTimestamp,
+        now: Instant,
+    ) -> bool {
+        if lease_doc.holder_id == holder_id {
+            return false;
+        }
+        let observed_at = match &self.observed {
+            Some((observed_update_time, observed_at)) if observed_update_time == update_time => {
+                *observed_at
+            }
+            _ => {
+                self.observed = Some((*update_time, now));
+                now
+            }
+        };
+        now.duration_since(observed_at) < lease_doc.lease_duration()
+    }
+}
+
+impl FirestoreDb {
+    pub fn create_listener_lease_coordinator<S>(
+        &self,
+        storage: S,
+        listener_params: FirestoreListenerParams,
+        lease_params: FirestoreListenerLeaseParams,
+    ) -> FirestoreListenerLeaseCoordinator<S>
+    where
+        S: FirestoreResumeStateStorage + Clone + Send + Sync + 'static,
+    {
+        FirestoreListenerLeaseCoordinator::new(self.clone(), storage, listener_params, lease_params)
+    }
+}
+
+/// Runs a listener on only one of the instances listening to the same targets.
+///
+/// The instances compete for a lease document, written with an update time precondition so that
+/// only one of them acquires it. The holder runs a [`FirestoreListener`] and renews the lease every
+/// `renew_interval`; the others try to acquire it as often, and take over once it has not been
+/// renewed for `lease_duration`. A holder that cannot renew its lease stops listening before the
+/// lease expires, and a holder whose listener stopped, for example because its retry policy gave
+/// up, releases the lease. The instances measure the lease duration on their own monotonic
+/// clocks, so their system clocks don't need to agree.
+///
+/// The new holder resumes from the resume tokens in the storage, so the storage must be shared
+/// by the instances, as [`FirestoreDocListenStateStorage`](crate::FirestoreDocListenStateStorage)
+/// is. Events handled by the previous holder after its last stored token are delivered again.
+pub struct FirestoreListenerLeaseCoordinator<S>
+where
+    S: FirestoreResumeStateStorage,
+{
+    db: FirestoreDb,
+    storage: S,
+    listener_params: FirestoreListenerParams,
+    lease_params: FirestoreListenerLeaseParams,
+    targets: Vec<FirestoreListenerTargetParams>,
+    is_leader: Arc<AtomicBool>,
+    shutdown_handle: Option<JoinHandle<()>>,
+    shutdown_writer: Option<UnboundedSender<i8>>,
+}
+
+impl<S> FirestoreListenerLeaseCoordinator<S>
+where
+    S: FirestoreResumeStateStorage + Clone + Send + Sync + 'static,
+{
+    pub fn new(
+        db: FirestoreDb,
+        storage: S,
+        listener_params: FirestoreListenerParams,
+        lease_params: FirestoreListenerLeaseParams,
+    ) -> Self {
+        Self {
+            db,
+            storage,
+            listener_params,
+            lease_params,
+            targets: vec![],
+            is_leader: Arc::new(AtomicBool::new(false)),
+            shutdown_handle: None,
+            shutdown_writer: None,
+        }
+    }
+
+    /// Adds a target to listen to while holding the lease. Targets are added before starting.
+    pub fn add_target(
+        &mut self,
+        target_params: FirestoreListenerTargetParams,
+    ) -> FirestoreResult<()> {
+        target_params.validate()?;
+        if self.shutdown_handle.is_some() {
+            return Err(FirestoreError::InvalidParametersError(
+                FirestoreInvalidParametersError::new(FirestoreInvalidParametersPublicDetails::new(
+                    "target_id".to_string(),
+                    "Targets cannot be added to a started lease coordinator".to_string(),
+                )),
+            ));
+        }
+        if self
+            .targets
+            .iter()
+            .any(|existing| existing.target == target_params.target)
+        {
+            return Err(FirestoreError::InvalidParametersError(
+                FirestoreInvalidParametersError::new(FirestoreInvalidParametersPublicDetails::new(
+                    "target_id".to_string(),
+                    format!(
+                        "Listener target ID {} is already used",
+                        target_params.target.value()
+                    ),
+                )),
+            ));
+        }
+        self.targets.push(target_params);
+        Ok(())
+    }
+
+    /// Whether this instance holds the lease and runs the listener.
+    pub fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::Relaxed)
+    }
+
+    /// Starts competing for the lease, calling `cb` with the events of the listener while
+    /// holding it.
+    pub async fn start<FN, F>(&mut self, cb: FN) -> FirestoreResult<()>
+    where
+        FN: Fn(FirestoreListenEvent) -> F + Send + Sync + 'static,
+        F: Future<Output = AnyBoxedErrResult<()>> + Send + 'static,
+    {
+        if self.shutdown_handle.is_some() {
+            return Ok(());
+        }
+
+        info!(
+            lease_id = self.lease_params.lease_id,
+            holder_id = self.lease_params.holder_id,
+            num_targets = self.targets.len(),
+            "Starting a Firestore listener lease coordinator...",
+        );
+
+        let (tx, rx): (UnboundedSender<i8>, UnboundedReceiver<i8>) =
+            tokio::sync::mpsc::unbounded_channel();
+        self.shutdown_writer = Some(tx);
+        self.shutdown_handle = Some(tokio::spawn(Self::coordinator_loop(
+            FirestoreListenerLease::new(self.db.clone(), self.lease_params.clone()),
+            self.storage.clone(),
+            self.listener_params.clone(),
+            self.targets.clone(),
+            self.is_leader.clone(),
+            rx,
+            Arc::new(cb),
+        )));
+        Ok(())
+    }
+
+    /// Stops the listener if running, and releases the lease for another instance to take over.
+    pub async fn shutdown(&mut self) -> FirestoreResult<()> {
+        debug!("Shutting down Firestore listener lease coordinator...");
+        if let Some(shutdown_writer) = self.shutdown_writer.take() {
+            shutdown_writer.send(1).ok();
+        }
+        if let Some(handle) = self.shutdown_handle.take() {
+            if let Err(err) = handle.await {
+                warn!(%err, "Firestore listener lease coordinator exit error!");
+            }
+        }
+        Ok(())
+    }
+
+    async fn coordinator_loop<FN, F>(
+        lease: FirestoreListenerLease,
+        storage: S,
+        listener_params: FirestoreListenerParams,
+        targets: Vec<FirestoreListenerTargetParams>,
+        is_leader: Arc<AtomicBool>,
+        mut shutdown_receiver: UnboundedReceiver<i8>,
+        cb: Arc<FN>,
+    ) where
+        FN: Fn(FirestoreListenEvent) -> F + Send + Sync + 'static,
+        F: Future<Output = AnyBoxedErrResult<()>> + Send + 'static,
+    {
+        let renew_interval = lease.params.renew_interval;
+
+        loop {
+            let acquired_at = Instant::now();
+            match lease.try_acquire().await {
+                Ok(true) => {
+                    info!(
+                        lease_id = lease.params.lease_id,
+                        holder_id = lease.params.holder_id,
+                        "Listener lease acquired. Starting the listener..."
+                    );
+                    match Self::start_listener(
+                        &lease.db,
+                        &storage,
+                        &listener_params,
+                        &targets,
+                        cb.clone(),
+                    )
+                    .await
+                    {
+                        Ok(listener) => {
+                            let shutdown = Self::hold_lease(
+                                &lease,
+                                listener,
+                                acquired_at,
+                                &is_leader,
+                                &mut shutdown_receiver,
+                            )
+                            .await;
+                            if shutdown {
+                                lease.release().await;
+                                return;
+                            }
+                        }
+                        Err(err) => {
+                            error!(%err, "Listener start error. Releasing the lease and retrying...");
+                            lease.release().await;
+                        }
+                    }
+                }
+                Ok(false) => {
+                    trace!(
+                        lease_id = lease.params.lease_id,
+                        "Listener lease is held by another instance."
+                    );
+                }
+                Err(err) => {
+                    warn!(%err, "Listener lease acquisition error. Retrying...");
+                }
+            }
+
+            tokio::select! {
+                _ = shutdown_receiver.recv() => return,
+                _ = tokio::time::sleep(renew_interval) => {}
+            }
+        }
+    }
+
+    /// Runs the listener while renewing the lease, until the lease is lost or the coordinator is
+    /// shut down. Returns `true` on shutdown.
+    async fn hold_lease(
+        lease: &FirestoreListenerLease,
+        mut listener: FirestoreListener<FirestoreDb, S>,
+        acquired_at: Instant,
+        is_leader: &AtomicBool,
+        shutdown_receiver: &mut UnboundedReceiver<i8>,
+    ) -> bool {
+        let renew_interval = lease.params.renew_interval;
+        let mut last_renewed_at = acquired_at;
+        is_leader.store(true, Ordering::Relaxed);
+
+        let shutdown = loop {
+            tokio::select! {
+                _ = shutdown_receiver.recv() => break true,
+                _ = tokio::time::sleep(renew_interval) => {}
+            }
+            if listener.status().connection_state == FirestoreListenerConnectionState::Stopped {
+                error!(
+                    lease_id = lease.params.lease_id,
+                    "Listener has stopped. Releasing the lease for another instance..."
+                );
+                lease.release().await;
+                break false;
+            }
+            // Other instances count the lease duration from when they see the renewal, later
+            let renewing_at = Instant::now();
+            match lease.try_acquire().await {
+                Ok(true) => last_renewed_at = renewing_at,
+                Ok(false) => {
+                    warn!(
+                        lease_id = lease.params.lease_id,
+                        "Listener lease taken over by another instance. Stopping the listener..."
+                    );
+                    break false;
+                }
+                Err(err)
+                    if last_renewed_at.elapsed() + renew_interval
+                        >= lease.params.lease_duration =>
+                {
+                    error!(%err, "Listener lease renewal error. Stopping the listener before the lease expires...");
+                    break false;
+                }
+                Err(err) => {
+                    warn!(%err, "Listener lease renewal error. Retrying...");
+                }
+            }
+        };
+
+        is_leader.store(false, Ordering::Relaxed);
+        if let Err(err) = listener.shutdown().await {
+            warn!(%err, "Listener shutdown error.");
+        }
+        shutdown
+    }
+
+    async fn start_listener<FN, F>(
+        db: &FirestoreDb,
+        storage: &S,
+        listener_params: &FirestoreListenerParams,
+        targets: &[FirestoreListenerTargetParams],
+        cb: Arc<FN>,
+    ) -> FirestoreResult<FirestoreListener<FirestoreDb, S>>
+    where
+        FN: Fn(FirestoreListenEvent) -> F + Send + Sync + 'static,
+        F: Future<Output = AnyBoxedErrResult<()>> + Send + 'static,
+    {
+        let mut listener =
+            FirestoreListener::new(db.clone(), storage.clone(), listener_params.clone()).await?;
+        for target_params in targets {
+            listener.add_target(target_params.clone())?;
+        }
+        listener.start(move |event| cb(event)).await?;
+        Ok(listener)
+    }
+}
+
+/// Reads and writes the lease document.
+struct FirestoreListenerLease {
+    db: FirestoreDb,
+    params: FirestoreListenerLeaseParams,
+    observer: Mutex<FirestoreListenerLeaseObserver>,
+}
+
+impl FirestoreListenerLease {
+    fn new(db: FirestoreDb, params: FirestoreListenerLeaseParams) -> Self {
+        Self {
+            db,
+            params,
+            observer: Mutex::new(FirestoreListenerLeaseObserver::default()),
+        }
+    }
+
+    /// Acquires or renews the lease. Returns `false` when another instance holds it.
+    async fn try_acquire(&self) -> FirestoreResult<bool> {
+        let now = FirestoreTimestamp::now();
+        let Some(precondition) = self.write_precondition().await? else {
+            return Ok(false);
+        };
+        let expires_at = now
+            .value()
+            .checked_add(self.params.lease_duration)
+            .map_err(|err| {
+                FirestoreError::InvalidParametersError(FirestoreInvalidParametersError::new(
+                    FirestoreInvalidParametersPublicDetails::new(
+                        "lease_duration".to_string(),
+                        format!("Invalid lease duration: {err}"),
+                    ),
+                ))
+            })?;
+        self.write_lease(
+            precondition,
+            FirestoreTimestamp(expires_at).truncated_to_firestore_precision(),
+            now,
+        )
+        .await
+    }
+
+    /// Lets another instance acquire the lease right away.
+    async fn release(&self) {
+        let now = FirestoreTimestamp::now();
+        let result = match self.write_precondition().await {
+            Ok(Some(precondition)) => self.write_lease(precondition, now, now).await.map(|_| ()),
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            warn!(%err, lease_id = self.params.lease_id, "Listener lease release error.");
+        }
+    }
+
+    /// Returns the precondition to write the lease with, or `None` when another instance holds
+    /// it.
+    async fn write_precondition(&self) -> FirestoreResult<Option<FirestoreWritePrecondition>> {
+        let doc: Option<Document> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(&self.params.collection_id)
+            .one(&self.params.lease_id)
+            .await?;
+
+        match doc {
+            None => Ok(Some(FirestoreWritePrecondition::Exists(false))),
+            Some(doc) => {
+                let lease_doc: FirestoreListenerLeaseDoc = FirestoreDb::deserialize_doc_to(&doc)?;
+                let update_time =
+                    FirestoreTimestamp(from_timestamp(doc.update_time.ok_or_else(|| {
+                        FirestoreError::DeserializeError(FirestoreSerializationError::from_message(
+                            "Listener lease document without an update time",
+                        ))
+                    })?)?);
+                let is_held_by_other = self
+                    .observer
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .is_held_by_other(
+                        &self.params.holder_id,
+                        &lease_doc,
+                        &update_time,
+                        Instant::now(),
+                    );
+                if is_held_by_other {
+                    return Ok(None);
+                }
+                Ok(Some(FirestoreWritePrecondition::UpdateTime(
+                    *update_time.value(),
+                )))
+            }
+        }
+    }
+
+    async fn write_lease(
+        &self,
+        precondition: FirestoreWritePrecondition,
+        expires_at: FirestoreTimestamp,
+        now: FirestoreTimestamp,
+    ) -> FirestoreResult<bool> {
+        let lease_doc = FirestoreListenerLeaseDoc {
+            holder_id: self.params.holder_id.clone(),
+            expires_at,
+            renewed_at: now,
+        };
+        match self
+            .db
+            .fluent()
+            .update()
+            .in_col(&self.params.collection_id)
+            .precondition(precondition)
+            .document_id(&self.params.lease_id)
+            .object(&lease_doc)
+            .execute::<()>()
+            .await
+        {
+            Ok(()) => Ok(true),
+            Err(err) if is_lost_race(&err) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Whether a write failed because another instance wrote the lease document first.
+fn is_lost_race(err: &FirestoreError) -> bool {
+    match err {
+        FirestoreError::DataConflictError(_) | FirestoreError::DataNotFoundError(_) => true,
+        FirestoreError::DatabaseError(err) => err.public.code == "FailedPrecondition",
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lease_is_held_by_other_holders_until_it_expires() {
+        // The holder's clock is an hour ahead
+        let renewed_at = FirestoreTimestamp(
+            FirestoreTimestamp::now()
+                .value()
+                .checked_add(Duration::from_secs(3600))
+                .unwrap(),
+        );
+        let lease_doc =
+            |renewed_at: &FirestoreTimestamp, lease_duration: Duration| FirestoreListenerLeaseDoc {
+                holder_id: "holder-1".to_string(),
+                expires_at: FirestoreTimestamp(
+                    renewed_at.value().checked_add(lease_duration).unwrap(),
+                ),
+                renewed_at: *renewed_at,
+            };
+        let held = lease_doc(&renewed_at, Duration::from_secs(30));
+        let mut observer = FirestoreListenerLeaseObserver::default();
+        let start = Instant::now();
+        let after = |secs| start + Duration::from_secs(secs);
+
+        assert!(!observer.is_held_by_other("holder-1", &held, &renewed_at, start));
+        assert!(observer.is_held_by_other("holder-2", &held, &renewed_at, start));
+        assert!(observer.is_held_by_other("holder-2", &held, &renewed_at, after(29)));
+
+        // Renewed, so it is held for another lease duration from now on
+        let renewed_at = FirestoreTimestamp(
+            renewed_at
+                .value()
+                .checked_add(Duration::from_secs(10))
+                .unwrap(),
+        );
+        let renewed = lease_doc(&renewed_at, Duration::from_secs(30));
+        assert!(observer.is_held_by_other("holder-2", &renewed, &renewed_at, after(29)));
+        assert!(observer.is_held_by_other("holder-2", &renewed, &renewed_at, after(58)));
+        assert!(!observer.is_held_by_other("holder-2", &renewed, &renewed_at, after(59)));
+
+        // Released
+        let released = lease_doc(&renewed_at, Duration::ZERO);
+        let released_at = FirestoreTimestamp(
+            renewed_at
+                .value()
+                .checked_add(Duration::from_secs(1))
+                .unwrap(),
+        );
+        assert!(!observer.is_held_by_other("holder-2", &released, &released_at, after(60)));
+    }
+
+    #[test]
+    fn precondition_failures_are_lost_races() {
+        use gcloud_sdk::tonic::{Code, Status};
+
+        assert!(is_lost_race(
+            &Status::new(Code::FailedPrecondition, "test").into()
+        ));
+        assert!(is_lost_race(
+            &Status::new(Code::AlreadyExists, "test").into()
+        ));
+        assert!(!is_lost_race(
+            &Status::new(Code::Unavailable, "test").into()
+        ));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::errors::FirestoreError;
+use crate::{FirestoreListenerParams, FirestoreListenerTarget};
+use gcloud_sdk::tonic::Code;
+use rsb_derive::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::*;
+
+/// What a listener does after its listen stream failed.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum FirestoreListenerRetryDecision {
+    /// Reconnect after the backoff delay.
+    Retry,
+    /// Stop the listener.
+    GiveUp,
+}
+
+/// How a [`FirestoreListener`](crate::FirestoreListener) reconnects after its listen stream
+/// failed.
+///
+/// The delay before each attempt grows exponentially from `initial_delay` up to `max_delay`, and
+/// is randomized by up to `randomization_factor` of itself in both directions, so that listeners
+/// failing together do not reconnect together. Failed attempts are counted until the listener
+/// receives an event again.
+///
+/// Errors with the gRPC codes `InvalidArgument`, `PermissionDenied`, `NotFound` and
+/// `Unimplemented`, and invalid parameters, make the listener give up by default. Everything else
+/// is retried. Use [`on_code`](Self::on_code) to decide otherwise for a gRPC code.
+#[derive(Debug, Clone, PartialEq, Builder)]
+pub struct FirestoreListenerRetryPolicy {
+    #[default = "Duration::from_secs(1)"]
+    pub initial_delay: Duration,
+    #[default = "Duration::from_secs(60)"]
+    pub max_delay: Duration,
+    #[default = "2.0"]
+    pub multiplier: f64,
+    #[default = "0.5"]
+    pub randomization_factor: f64,
+    /// How many consecutive failed attempts to retry before giving up. Unlimited when unset.
+    pub max_attempts: Option<u32>,
+    /// Decisions for gRPC status codes, overriding the defaults.
+    #[default = "HashMap::new()"]
+    pub code_decisions: HashMap<Code, FirestoreListenerRetryDecision>,
+}
+
+impl Default for FirestoreListenerRetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FirestoreListenerRetryPolicy {
+    /// A policy retrying after the same delay every time, as listeners did with only a
+    /// `retry_delay`.
+    pub fn fixed_delay(delay: Duration) -> Self {
+        Self::new()
+            .with_initial_delay(delay)
+            .with_max_delay(delay)
+            .with_multiplier(1.0)
+            .with_randomization_factor(0.0)
+    }
+
+    /// Sets the decision for errors with a gRPC status code.
+    pub fn on_code(mut self, code: Code, decision: FirestoreListenerRetryDecision) -> Self {
+        self.code_decisions.insert(code, decision);
+        self
+    }
+
+    /// Decides whether to retry after an error, `attempt` being the number of consecutive failed
+    /// attempts including this one.
+    pub fn decide(&self, error: &FirestoreError, attempt: u32) -> FirestoreListenerRetryDecision {
+        if self
+            .max_attempts
+            .is_some_and(|max_attempts| attempt > max_attempts)
+        {
+            return FirestoreListenerRetryDecision::GiveUp;
+        }
+
+        let code = grpc_code(error);
+        if let Some(decision) = code.and_then(|code| self.code_decisions.get(&code)) {
+            return *decision;
+        }
+
+        match (error, code) {
+            (FirestoreError::InvalidParametersError(_), _)
+            | (
+                _,
+                Some(
+                    Code::InvalidArgument
+                    | Code::PermissionDenied
+                    | Code::NotFound
+                    | Code::Unimplemented,
+                ),
+            ) => FirestoreListenerRetryDecision::GiveUp,
+            _ => FirestoreListenerRetryDecision::Retry,
+        }
+    }
+
+    /// Returns the delay before an attempt, `attempt` being the number of consecutive failed
+    /// attempts so far.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        let jitter = if self.randomization_factor > 0.0 {
+            rand::random_range(-self.randomization_factor..=self.randomization_factor)
+        } else {
+            0.0
+        };
+        Duration::try_from_secs_f64((delay * (1.0 + jitter)).max(0.0)).unwrap_or(self.max_delay)
+    }
+}
+
+fn grpc_code(error: &FirestoreError) -> Option<Code> {
+    match error {
+        FirestoreError::DatabaseError(err) => err.grpc_code,
+        FirestoreError::DataNotFoundError(err) => err.grpc_code,
+        FirestoreError::DataConflictError(err) => err.grpc_code,
+        _ => None,
+    }
+}
+
+/// Callbacks on the lifecycle of a listener, to follow its connection and alert when it gives
+/// up.
+///
+/// They are called from the listener task, so they should return quickly.
+pub trait FirestoreListenerLifecycleHooks: Send + Sync {
+    /// The listen stream has been opened.
+    fn on_connected(&self) {}
+
+    /// The listen stream failed or ended, and the listener reconnects after `retry_in`. `error`
+    /// is `None` when the stream ended without an error, or when the listener reconnects to have
+    /// events delivered again.
+    fn on_disconnected(&self, _error: Option<&FirestoreError>, _retry_in: Duration) {}
+
+    /// The listener gave up after an error, and stopped.
+    fn on_permanent_failure(&self, _error: &FirestoreError) {}
+
+    /// Firestore rejected the resume token of a target, for instance because it expired, and the
+    /// listener added the target again from a read time or from now.
+    fn on_resume_token_rejected(&self, _target: &FirestoreListenerTarget, _cause: &FirestoreError) {
+    }
+
+    /// Firestore removed a target because of another error, such as a missing permission. The
+    /// listener receives no more events for it until it reconnects.
+    fn on_target_error(&self, _target: &FirestoreListenerTarget, _cause: &FirestoreError) {}
+}
+
+/// Shareable [`FirestoreListenerLifecycleHooks`] for [`FirestoreListenerParams`].
+#[derive(Clone)]
+pub struct FirestoreListenerHooks(Arc<dyn FirestoreListenerLifecycleHooks>);
+
+impl FirestoreListenerHooks {
+    pub fn new<H>(hooks: H) -> Self
+    where
+        H: FirestoreListenerLifecycleHooks + 'static,
+    {
+        Self(Arc::new(hooks))
+    }
+}
+
+impl std::fmt::Debug for FirestoreListenerHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("FirestoreListenerHooks")
+    }
+}
+
+impl PartialEq for FirestoreListenerHooks {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for FirestoreListenerHooks {}
+
+/// Applies the retry policy and calls the lifecycle hooks of a running listener.
+pub(crate) struct FirestoreListenerRetries {
+    policy: FirestoreListenerRetryPolicy,
+    hooks: Option<FirestoreListenerHooks>,
+    failed_attempts: u32,
+}
+
+impl FirestoreListenerRetries {
+    pub fn new(listener_params: &FirestoreListenerParams) -> Self {
+        let policy = match (&listener_params.retry_policy, listener_params.retry_delay) {
+            (Some(policy), _) => policy.clone(),
+            (None, Some(retry_delay)) => FirestoreListenerRetryPolicy::fixed_delay(retry_delay),
+            (None, None) => FirestoreListenerRetryPolicy::new(),
+        };
+        Self {
+            policy,
+            hooks: listener_params.lifecycle_hooks.clone(),
+            failed_attempts: 0,
+        }
+    }
+
+    pub fn connected(&self) {
+        if let Some(hooks) = &self.hooks {
+            hooks.0.on_connected();
+        }
+    }
+
+    pub fn event_received(&mut self) {
+        self.failed_attempts = 0;
+    }
+
+    /// The listener reconnects right away, without an error.
+    pub fn reconnecting(&self) {
+        if let Some(hooks) = &self.hooks {
+            hooks.0.on_disconnected(None, Duration::ZERO);
+        }
+    }
+
+    pub fn resume_token_rejected(&self, target: &FirestoreListenerTarget, cause: &FirestoreError) {
+        if let Some(hooks) = &self.hooks {
+            hooks.0.on_resume_token_rejected(target, cause);
+        }
+    }
+
+    pub fn target_error(&self, target: &FirestoreListenerTarget, cause: &FirestoreError) {
+        if let Some(hooks) = &self.hooks {
+            hooks.0.on_target_error(target, cause);
+        }
+    }
+
+    /// Waits before the next attempt after an error. Returns `true` when the listener should stop
+    /// instead.
+    pub async fn on_error(&mut self, err: FirestoreError) -> bool {
+        self.failed_attempts = self.failed_attempts.saturating_add(1);
+        match self.policy.decide(&err, self.failed_attempts) {
+            FirestoreListenerRetryDecision::GiveUp => {
+                error!(%err, attempt = self.failed_attempts, "Listen error. Exiting...");
+                if let Some(hooks) = &self.hooks {
+                    hooks.0.on_permanent_failure(&err);
+                }
+                true
+            }
+            FirestoreListenerRetryDecision::Retry => {
+                let delay = self.policy.delay(self.failed_attempts);
+                warn!(%err, attempt = self.failed_attempts, ?delay, "Listen error. Restarting after a delay...");
+                if let Some(hooks) = &self.hooks {
+                    hooks.0.on_disconnected(Some(&err), delay);
+                }
+                tokio::time::sleep(delay).await;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::*;
+
+    fn grpc_error(code: Code) -> FirestoreError {
+        FirestoreError::from(gcloud_sdk::tonic::Status::new(code, "test"))
+    }
+
+    #[test]
+    fn decides_by_grpc_code_and_attempts() {
+        let policy = FirestoreListenerRetryPolicy::new()
+            .with_max_attempts(3)
+            .on_code(
+                Code::PermissionDenied,
+                FirestoreListenerRetryDecision::Retry,
+            )
+            .on_code(
+                Code::ResourceExhausted,
+                FirestoreListenerRetryDecision::GiveUp,
+            );
+
+        use FirestoreListenerRetryDecision::*;
+        assert_eq!(policy.decide(&grpc_error(Code::Unavailable), 1), Retry);
+        assert_eq!(policy.decide(&grpc_error(Code::Unavailable), 4), GiveUp);
+        assert_eq!(policy.decide(&grpc_error(Code::InvalidArgument), 1), GiveUp);
+        assert_eq!(policy.decide(&grpc_error(Code::PermissionDenied), 1), Retry);
+        assert_eq!(
+            policy.decide(&grpc_error(Code::ResourceExhausted), 1),
+            GiveUp
+        );
+        assert_eq!(
+            policy.decide(
+                &FirestoreError::InvalidParametersError(FirestoreInvalidParametersError::new(
+                    FirestoreInvalidParametersPublicDetails::new("field".into(), "test".into())
+                )),
+                1
+            ),
+            GiveUp
+        );
+    }
+
+    #[test]
+    fn delays_grow_exponentially_within_the_jitter() {
+        let policy = FirestoreListenerRetryPolicy::new()
+            .with_initial_delay(Duration::from_secs(1))
+            .with_max_delay(Duration::from_secs(10))
+            .with_randomization_factor(0.5);
+
+        for (attempt, base_secs) in [(1, 1.0), (2, 2.0), (3, 4.0), (4, 8.0), (10, 10.0)] {
+            let delay = policy.delay(attempt).as_secs_f64();
+            assert!(
+                delay >= base_secs * 0.5 && delay <= base_secs * 1.5,
+                "attempt {attempt}: {delay}"
+            );
+        }
+        assert_eq!(
+            FirestoreListenerRetryPolicy::fixed_delay(Duration::from_secs(5)).delay(7),
+            Duration::from_secs(5)
+        );
+    }
+}
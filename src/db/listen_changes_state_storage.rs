@@ -1,12 +1,29 @@
 use crate::errors::AnyBoxedErrResult;
-use crate::{FirestoreListenerTarget, FirestoreListenerTargetResumeType, FirestoreListenerToken};
+use crate::{
+    FirestoreDb, FirestoreInstant, FirestoreListenerTarget, FirestoreListenerTargetResumeType,
+    FirestoreListenerToken, FirestoreResult, FirestoreTimestamp,
+};
 use async_trait::async_trait;
+use rsb_derive::*;
 use rvstruct::ValueStruct;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tracing::*;
 
+/// The resume state of a listener target, as the listener stores it after receiving a resume
+/// token.
+#[derive(Debug, Clone, Builder)]
+pub struct FirestoreListenerResumeState {
+    pub target: FirestoreListenerTarget,
+    pub token: FirestoreListenerToken,
+    /// The labels of the target.
+    pub labels: HashMap<String, String>,
+    /// The read time the token was received with, if any.
+    pub read_time: Option<FirestoreInstant>,
+}
+
 #[async_trait]
 pub trait FirestoreResumeStateStorage {
     async fn read_resume_state(
@@ -19,6 +36,48 @@ pub trait FirestoreResumeStateStorage {
         target: &FirestoreListenerTarget,
         token: FirestoreListenerToken,
     ) -> AnyBoxedErrResult<()>;
+
+    /// Stores the resume state of a target. This is what listeners call, so that storages can
+    /// keep more than the token. Defaults to [`update_resume_token`](Self::update_resume_token).
+    async fn update_resume_state(
+        &self,
+        state: FirestoreListenerResumeState,
+    ) -> AnyBoxedErrResult<()>
+    where
+        Self: Sync,
+    {
+        self.update_resume_token(&state.target, state.token).await
+    }
+
+    /// Deletes the resume state of a target, for example when it is removed from a listener.
+    /// The default implementation keeps it.
+    async fn delete_resume_state(&self, _target: &FirestoreListenerTarget) -> AnyBoxedErrResult<()>
+    where
+        Self: Sync,
+    {
+        Ok(())
+    }
+
+    /// Reads the read time stored with the resume token of a target, which listeners resume from
+    /// when Firestore rejects the token. The default implementation stores none.
+    async fn read_resume_read_time(
+        &self,
+        _target: &FirestoreListenerTarget,
+    ) -> AnyBoxedErrResult<Option<FirestoreInstant>>
+    where
+        Self: Sync,
+    {
+        Ok(None)
+    }
+
+    /// Stores the resume states held back by a storage that does not store each one right away.
+    /// Listeners call it when they stop. The default implementation does nothing.
+    async fn flush(&self) -> AnyBoxedErrResult<()>
+    where
+        Self: Sync,
+    {
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -86,17 +145,29 @@ impl FirestoreResumeStateStorage for FirestoreTempFilesListenStateStorage {
             hex::encode(token.value()),
         )?)
     }
+
+    async fn delete_resume_state(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match std::fs::remove_file(self.get_file_path(target)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(Box::new(err)),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct FirestoreMemListenStateStorage {
     tokens: Arc<RwLock<HashMap<FirestoreListenerTarget, FirestoreListenerToken>>>,
+    read_times: Arc<RwLock<HashMap<FirestoreListenerTarget, FirestoreInstant>>>,
 }
 
 impl FirestoreMemListenStateStorage {
     pub fn new() -> Self {
         Self {
             tokens: Arc::new(RwLock::new(HashMap::new())),
+            read_times: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
@@ -129,4 +200,458 @@ impl FirestoreResumeStateStorage for FirestoreMemListenStateStorage {
         self.tokens.write().await.insert(target.clone(), token);
         Ok(())
     }
+
+    async fn update_resume_state(
+        &self,
+        state: FirestoreListenerResumeState,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match state.read_time {
+            Some(read_time) => self
+                .read_times
+                .write()
+                .await
+                .insert(state.target.clone(), read_time),
+            None => self.read_times.write().await.remove(&state.target),
+        };
+        self.update_resume_token(&state.target, state.token).await
+    }
+
+    async fn delete_resume_state(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.tokens.write().await.remove(target);
+        self.read_times.write().await.remove(target);
+        Ok(())
+    }
+
+    async fn read_resume_read_time(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> Result<Option<FirestoreInstant>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.read_times.read().await.get(target).copied())
+    }
+}
+
+/// Stores resume tokens as documents in a Firestore collection, one document per target, so that
+/// listeners running on different machines can share them.
+///
+/// Besides the token, each document keeps the labels of the target, the read time the token was
+/// received with and when it was stored. Use a collection dedicated to this, or a key prefix per
+/// application when several share one, as documents are named after the target IDs.
+///
+/// Every write is a billed Firestore operation, and listeners receive resume tokens often, so a
+/// target's state is written at most once per write interval, 10 seconds by default. The latest
+/// state held back in between is written with the next state after the interval, or when the
+/// listener stops. A listener resuming after a crash may receive again the events of up to one
+/// interval.
+#[derive(Clone, Debug)]
+pub struct FirestoreDocListenStateStorage {
+    db: FirestoreDb,
+    collection_id: String,
+    key_prefix: String,
+    write_interval: std::time::Duration,
+    writes: Arc<std::sync::Mutex<FirestoreDocListenStateWrites>>,
+}
+
+/// The resume states a [`FirestoreDocListenStateStorage`] holds back, to write the state of a
+/// target at most once per write interval.
+#[derive(Debug, Default)]
+struct FirestoreDocListenStateWrites {
+    written_at: HashMap<FirestoreListenerTarget, std::time::Instant>,
+    pending: HashMap<FirestoreListenerTarget, FirestoreListenerResumeState>,
+}
+
+impl FirestoreDocListenStateWrites {
+    /// Returns the state to write now, or holds it back when the state of its target was written
+    /// less than `write_interval` ago.
+    fn write_or_hold(
+        &mut self,
+        state: FirestoreListenerResumeState,
+        write_interval: std::time::Duration,
+        now: std::time::Instant,
+    ) -> Option<FirestoreListenerResumeState> {
+        match self.written_at.get(&state.target) {
+            Some(written_at) if now.duration_since(*written_at) < write_interval => {
+                self.pending.insert(state.target.clone(), state);
+                None
+            }
+            _ => {
+                self.pending.remove(&state.target);
+                Some(state)
+            }
+        }
+    }
+
+    fn written(&mut self, target: &FirestoreListenerTarget, now: std::time::Instant) {
+        self.written_at.insert(target.clone(), now);
+    }
+
+    fn forget(&mut self, target: &FirestoreListenerTarget) {
+        self.written_at.remove(target);
+        self.pending.remove(target);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FirestoreStoredListenState {
+    target_id: u32,
+    resume_token: String,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    read_time: Option<FirestoreTimestamp>,
+    updated_at: FirestoreTimestamp,
+}
+
+impl FirestoreDocListenStateStorage {
+    pub fn new(db: FirestoreDb, collection_id: &str) -> Self {
+        Self {
+            db,
+            collection_id: collection_id.to_string(),
+            key_prefix: String::new(),
+            write_interval: std::time::Duration::from_secs(10),
+            writes: Arc::default(),
+        }
+    }
+
+    /// Prefixes the IDs of the documents, to share a collection between applications.
+    pub fn with_key_prefix(self, key_prefix: &str) -> Self {
+        Self {
+            key_prefix: key_prefix.to_string(),
+            ..self
+        }
+    }
+
+    /// Sets the shortest time between two writes of the state of a target. `Duration::ZERO`
+    /// writes every state right away.
+    pub fn with_write_interval(self, write_interval: std::time::Duration) -> Self {
+        Self {
+            write_interval,
+            ..self
+        }
+    }
+
+    fn writes(&self) -> std::sync::MutexGuard<'_, FirestoreDocListenStateWrites> {
+        self.writes
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The state held back for a target, which is more recent than the stored one.
+    fn pending_state(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> Option<FirestoreListenerResumeState> {
+        self.writes().pending.get(target).cloned()
+    }
+
+    async fn write_state(&self, state: FirestoreListenerResumeState) -> FirestoreResult<()> {
+        let target = state.target.clone();
+        let stored = FirestoreStoredListenState {
+            target_id: *state.target.value(),
+            resume_token: hex::encode(state.token.value()),
+            labels: state.labels,
+            read_time: state.read_time.map(FirestoreTimestamp),
+            updated_at: FirestoreTimestamp::now(),
+        };
+
+        self.db
+            .fluent()
+            .update()
+            .in_col(&self.collection_id)
+            .document_id(self.document_id(&target))
+            .object(&stored)
+            .execute::<()>()
+            .await?;
+        self.writes().written(&target, std::time::Instant::now());
+        Ok(())
+    }
+
+    fn document_id(&self, target: &FirestoreListenerTarget) -> String {
+        format!("{}{}", self.key_prefix, target.value())
+    }
+
+    async fn read_stored_state(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> FirestoreResult<Option<FirestoreStoredListenState>> {
+        self.db
+            .fluent()
+            .select()
+            .by_id_in(&self.collection_id)
+            .obj()
+            .one(self.document_id(target))
+            .await
+    }
+}
+
+#[async_trait]
+impl FirestoreResumeStateStorage for FirestoreDocListenStateStorage {
+    async fn read_resume_state(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> Result<Option<FirestoreListenerTargetResumeType>, Box<dyn std::error::Error + Send + Sync>>
+    {
+        if let Some(state) = self.pending_state(target) {
+            return Ok(Some(FirestoreListenerTargetResumeType::Token(state.token)));
+        }
+        Ok(self
+            .read_stored_state(target)
+            .await?
+            .map(|stored| hex::decode(stored.resume_token))
+            .transpose()?
+            .map(|token| {
+                FirestoreListenerTargetResumeType::Token(FirestoreListenerToken::new(token))
+            }))
+    }
+
+    async fn update_resume_token(
+        &self,
+        target: &FirestoreListenerTarget,
+        token: FirestoreListenerToken,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.update_resume_state(FirestoreListenerResumeState::new(
+            target.clone(),
+            token,
+            HashMap::new(),
+        ))
+        .await
+    }
+
+    async fn update_resume_state(
+        &self,
+        state: FirestoreListenerResumeState,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let state =
+            self.writes()
+                .write_or_hold(state, self.write_interval, std::time::Instant::now());
+        if let Some(state) = state {
+            self.write_state(state).await?;
+        }
+        Ok(())
+    }
+
+    async fn delete_resume_state(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.writes().forget(target);
+        self.db
+            .fluent()
+            .delete()
+            .from(self.collection_id.as_str())
+            .document_id(self.document_id(target))
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn read_resume_read_time(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> Result<Option<FirestoreInstant>, Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(state) = self.pending_state(target) {
+            return Ok(state.read_time);
+        }
+        Ok(self
+            .read_stored_state(target)
+            .await?
+            .and_then(|stored| stored.read_time)
+            .map(|read_time| read_time.0))
+    }
+
+    async fn flush(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let pending: Vec<FirestoreListenerResumeState> = self
+            .writes()
+            .pending
+            .drain()
+            .map(|(_, state)| state)
+            .collect();
+        let mut pending = pending.into_iter();
+        while let Some(state) = pending.next() {
+            if let Err(err) = self.write_state(state.clone()).await {
+                // Held back again, unless a more recent state came in meanwhile
+                let mut writes = self.writes();
+                for state in std::iter::once(state).chain(pending) {
+                    writes.pending.entry(state.target.clone()).or_insert(state);
+                }
+                return Err(Box::new(err));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "caching-persistent")]
+const RESUME_TOKENS_TABLE: redb::TableDefinition<u32, &[u8]> =
+    redb::TableDefinition::new("firestore-listen-resume-tokens");
+
+/// Stores resume tokens in a local [redb](https://github.com/cberner/redb) database, which
+/// survives restarts and can be kept on a volume shared by the instances of a deployment.
+#[cfg(feature = "caching-persistent")]
+#[derive(Clone)]
+pub struct FirestoreRedbListenStateStorage {
+    redb: Arc<redb::Database>,
+}
+
+#[cfg(feature = "caching-persistent")]
+impl FirestoreRedbListenStateStorage {
+    /// Opens the database at the given file path, creating it if needed.
+    pub fn new<P: AsRef<std::path::Path>>(
+        data_file_path: P,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        debug!(
+            data_file_path = ?data_file_path.as_ref(),
+            "Opening database for listen state storage...",
+        );
+        Ok(Self::with_database(Arc::new(redb::Database::create(
+            data_file_path,
+        )?)))
+    }
+
+    /// Uses an already opened database.
+    pub fn with_database(redb: Arc<redb::Database>) -> Self {
+        Self { redb }
+    }
+}
+
+#[cfg(feature = "caching-persistent")]
+impl std::fmt::Debug for FirestoreRedbListenStateStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FirestoreRedbListenStateStorage")
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "caching-persistent")]
+#[async_trait]
+impl FirestoreResumeStateStorage for FirestoreRedbListenStateStorage {
+    async fn read_resume_state(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> Result<Option<FirestoreListenerTargetResumeType>, Box<dyn std::error::Error + Send + Sync>>
+    {
+        use redb::ReadableDatabase;
+
+        let read_tx = self.redb.begin_read()?;
+        let table = match read_tx.open_table(RESUME_TOKENS_TABLE) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(err) => return Err(Box::new(err)),
+        };
+        Ok(table.get(*target.value())?.map(|token| {
+            FirestoreListenerTargetResumeType::Token(FirestoreListenerToken::new(
+                token.value().to_vec(),
+            ))
+        }))
+    }
+
+    async fn update_resume_token(
+        &self,
+        target: &FirestoreListenerTarget,
+        token: FirestoreListenerToken,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let write_tx = self.redb.begin_write()?;
+        {
+            let mut table = write_tx.open_table(RESUME_TOKENS_TABLE)?;
+            table.insert(*target.value(), token.value().as_slice())?;
+        }
+        write_tx.commit()?;
+        Ok(())
+    }
+
+    async fn delete_resume_state(
+        &self,
+        target: &FirestoreListenerTarget,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let write_tx = self.redb.begin_write()?;
+        {
+            let mut table = write_tx.open_table(RESUME_TOKENS_TABLE)?;
+            table.remove(*target.value())?;
+        }
+        write_tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holds_back_states_within_the_write_interval() {
+        let interval = std::time::Duration::from_secs(10);
+        let started_at = std::time::Instant::now();
+        let target = FirestoreListenerTarget::new(7);
+        let state = |token: &str| {
+            FirestoreListenerResumeState::new(
+                target.clone(),
+                token.as_bytes().to_vec().into(),
+                HashMap::new(),
+            )
+        };
+        let token = |state: Option<FirestoreListenerResumeState>| {
+            state.map(|state| String::from_utf8(state.token.into_value()).unwrap())
+        };
+        let mut writes = FirestoreDocListenStateWrites::default();
+
+        assert_eq!(
+            token(writes.write_or_hold(state("t1"), interval, started_at)),
+            Some("t1".to_string())
+        );
+        writes.written(&target, started_at);
+
+        let later = |secs| started_at + std::time::Duration::from_secs(secs);
+        assert_eq!(
+            token(writes.write_or_hold(state("t2"), interval, later(3))),
+            None
+        );
+        assert_eq!(
+            token(writes.write_or_hold(state("t3"), interval, later(6))),
+            None
+        );
+        assert_eq!(
+            token(writes.pending.get(&target).cloned()),
+            Some("t3".to_string())
+        );
+
+        assert_eq!(
+            token(writes.write_or_hold(state("t4"), interval, later(11))),
+            Some("t4".to_string())
+        );
+        assert!(writes.pending.is_empty());
+
+        writes.written(&target, later(11));
+        writes.forget(&target);
+        assert_eq!(
+            token(writes.write_or_hold(state("t5"), interval, later(12))),
+            Some("t5".to_string())
+        );
+    }
+
+    #[cfg(feature = "caching-persistent")]
+    #[tokio::test]
+    async fn redb_storage_round_trip() {
+        let data_file_path = std::env::temp_dir().join(format!(
+            "firestore-listen-state-{}.redb",
+            rand::random::<u64>()
+        ));
+        let storage = FirestoreRedbListenStateStorage::new(&data_file_path).unwrap();
+        let target = FirestoreListenerTarget::new(7);
+
+        assert!(storage.read_resume_state(&target).await.unwrap().is_none());
+        storage
+            .update_resume_token(&target, b"token".to_vec().into())
+            .await
+            .unwrap();
+        assert!(matches!(
+            storage.read_resume_state(&target).await.unwrap(),
+            Some(FirestoreListenerTargetResumeType::Token(token)) if token.value() == b"token"
+        ));
+        storage.delete_resume_state(&target).await.unwrap();
+        assert!(storage.read_resume_state(&target).await.unwrap().is_none());
+
+        drop(storage);
+        std::fs::remove_file(data_file_path).ok();
+    }
 }
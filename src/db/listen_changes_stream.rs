@@ -0,0 +1,395 @@
+use crate::db::split_document_path;
+use crate::db::support::FirestoreListenSupport;
+use crate::errors::AnyBoxedErrResult;
+use crate::timestamp_utils::from_timestamp;
+use crate::{
+    FirestoreDb, FirestoreDocument, FirestoreInstant, FirestoreListenEvent, FirestoreListener,
+    FirestoreListenerTarget, FirestoreResult, FirestoreResumeStateStorage,
+};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use gcloud_sdk::google::firestore::v1::target_change::TargetChangeType;
+use gcloud_sdk::google::firestore::v1::TargetChange;
+use rvstruct::ValueStruct;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use tracing::*;
+
+/// A change to the result set of a listener target, like the document changes of query snapshots
+/// in the official Firestore SDKs.
+///
+/// Produced by [`FirestoreListenChangesTracker`] from the raw listen events, and streamed by
+/// [`FirestoreListener::start_changes_stream`] and
+/// [`FirestoreDocChangesListenerInitBuilder::stream_changes`](crate::FirestoreDocChangesListenerInitBuilder::stream_changes).
+#[derive(Debug, Clone, PartialEq)]
+pub enum FirestoreListenChange<T> {
+    /// The document entered the result set of the target.
+    Added(FirestoreListenDocChange<T>),
+    /// A document of the result set has been updated.
+    Modified(FirestoreListenDocChange<T>),
+    /// The document left the result set of the target, for example because it does not match the
+    /// query anymore. It may still exist.
+    Removed(FirestoreListenDocChange<T>),
+    /// The document has been deleted.
+    Deleted(FirestoreListenDocChange<T>),
+}
+
+/// The document of a [`FirestoreListenChange`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirestoreListenDocChange<T> {
+    /// The listener target whose result set changed.
+    pub target: FirestoreListenerTarget,
+    /// The full path to the document.
+    pub document_path: String,
+    /// The document as of the change. `None` for removed and deleted documents.
+    pub object: Option<T>,
+    /// The update time of the document as the target last saw it, `None` for added documents and
+    /// for documents the tracker did not see before, such as after resuming a listener.
+    pub old_update_time: Option<FirestoreInstant>,
+    /// The update time of the document as of the change. `None` for removed and deleted
+    /// documents.
+    pub new_update_time: Option<FirestoreInstant>,
+}
+
+impl<T> FirestoreListenDocChange<T> {
+    /// The ID of the document, the last segment of its path.
+    pub fn document_id(&self) -> &str {
+        split_document_path(&self.document_path).1
+    }
+
+    fn try_map_object<U, F>(self, f: F) -> FirestoreResult<FirestoreListenDocChange<U>>
+    where
+        F: FnOnce(T) -> FirestoreResult<U>,
+    {
+        Ok(FirestoreListenDocChange {
+            target: self.target,
+            document_path: self.document_path,
+            object: self.object.map(f).transpose()?,
+            old_update_time: self.old_update_time,
+            new_update_time: self.new_update_time,
+        })
+    }
+}
+
+impl<T> FirestoreListenChange<T> {
+    /// The changed document, whatever the kind of change.
+    pub fn doc_change(&self) -> &FirestoreListenDocChange<T> {
+        match self {
+            Self::Added(change)
+            | Self::Modified(change)
+            | Self::Removed(change)
+            | Self::Deleted(change) => change,
+        }
+    }
+
+    /// Consumes the change and returns the changed document.
+    pub fn into_doc_change(self) -> FirestoreListenDocChange<T> {
+        match self {
+            Self::Added(change)
+            | Self::Modified(change)
+            | Self::Removed(change)
+            | Self::Deleted(change) => change,
+        }
+    }
+
+    /// Converts the object of the change, keeping the kind of change.
+    pub fn try_map_object<U, F>(self, f: F) -> FirestoreResult<FirestoreListenChange<U>>
+    where
+        F: FnOnce(T) -> FirestoreResult<U>,
+    {
+        Ok(match self {
+            Self::Added(change) => FirestoreListenChange::Added(change.try_map_object(f)?),
+            Self::Modified(change) => FirestoreListenChange::Modified(change.try_map_object(f)?),
+            Self::Removed(change) => FirestoreListenChange::Removed(change.try_map_object(f)?),
+            Self::Deleted(change) => FirestoreListenChange::Deleted(change.try_map_object(f)?),
+        })
+    }
+}
+
+impl FirestoreListenChange<FirestoreDocument> {
+    /// Deserializes the document of the change.
+    pub fn deserialize_object<T>(self) -> FirestoreResult<FirestoreListenChange<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        self.try_map_object(|doc| FirestoreDb::deserialize_doc_to(&doc))
+    }
+}
+
+/// Follows the result sets of listener targets through the raw listen events, and turns them
+/// into [`FirestoreListenChange`]s.
+///
+/// Only the paths and update times of the documents are kept. When Firestore resets a target,
+/// the documents it does not send again before the target is current are reported as removed.
+///
+/// [`FirestoreListener::start_changes_stream`] uses it for you. Feed it the events yourself to get
+/// the same changes from a [`FirestoreListener::start`] callback.
+#[derive(Debug, Default)]
+pub struct FirestoreListenChangesTracker {
+    targets: HashMap<FirestoreListenerTarget, FirestoreListenTargetDocs>,
+}
+
+#[derive(Debug, Default)]
+struct FirestoreListenTargetDocs {
+    update_times: HashMap<String, Option<FirestoreInstant>>,
+    /// The documents received since the target was reset, until it is current again.
+    received_since_reset: Option<HashSet<String>>,
+}
+
+impl FirestoreListenChangesTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies a listen event and returns the changes it makes to the result sets of the
+    /// targets.
+    pub fn on_event(
+        &mut self,
+        event: FirestoreListenEvent,
+    ) -> FirestoreResult<Vec<FirestoreListenChange<FirestoreDocument>>> {
+        let mut changes = Vec::new();
+        match event {
+            FirestoreListenEvent::DocumentChange(doc_change) => {
+                if let Some(doc) = doc_change.document {
+                    let new_update_time = doc.update_time.map(from_timestamp).transpose()?;
+                    for target_id in doc_change.target_ids {
+                        let target = FirestoreListenerTarget::try_from(target_id)?;
+                        let target_docs = self.targets.entry(target.clone()).or_default();
+                        if let Some(received) = &mut target_docs.received_since_reset {
+                            received.insert(doc.name.clone());
+                        }
+                        let change = FirestoreListenDocChange {
+                            target,
+                            document_path: doc.name.clone(),
+                            object: Some(doc.clone()),
+                            old_update_time: None,
+                            new_update_time,
+                        };
+                        match target_docs
+                            .update_times
+                            .insert(doc.name.clone(), new_update_time)
+                        {
+                            None => changes.push(FirestoreListenChange::Added(change)),
+                            // Resent as it was, after a reset of the target
+                            Some(old_update_time)
+                                if old_update_time.is_some()
+                                    && old_update_time == new_update_time => {}
+                            Some(old_update_time) => changes.push(FirestoreListenChange::Modified(
+                                FirestoreListenDocChange {
+                                    old_update_time,
+                                    ..change
+                                },
+                            )),
+                        }
+                    }
+                    for target_id in doc_change.removed_target_ids {
+                        changes.push(FirestoreListenChange::Removed(self.remove_doc(
+                            FirestoreListenerTarget::try_from(target_id)?,
+                            &doc.name,
+                        )));
+                    }
+                }
+            }
+            FirestoreListenEvent::DocumentDelete(doc_delete) => {
+                for target_id in doc_delete.removed_target_ids {
+                    changes.push(FirestoreListenChange::Deleted(self.remove_doc(
+                        FirestoreListenerTarget::try_from(target_id)?,
+                        &doc_delete.document,
+                    )));
+                }
+            }
+            FirestoreListenEvent::DocumentRemove(doc_remove) => {
+                for target_id in doc_remove.removed_target_ids {
+                    changes.push(FirestoreListenChange::Removed(self.remove_doc(
+                        FirestoreListenerTarget::try_from(target_id)?,
+                        &doc_remove.document,
+                    )));
+                }
+            }
+            FirestoreListenEvent::TargetChange(target_change) => {
+                self.on_target_change(target_change, &mut changes)?;
+            }
+            // The document count of a resumed target, the tracker knows the documents instead
+            FirestoreListenEvent::Filter(_) => {}
+        }
+        Ok(changes)
+    }
+
+    fn on_target_change(
+        &mut self,
+        target_change: TargetChange,
+        changes: &mut Vec<FirestoreListenChange<FirestoreDocument>>,
+    ) -> FirestoreResult<()> {
+        // Target changes without target IDs apply to all targets
+        let targets: Vec<FirestoreListenerTarget> = if target_change.target_ids.is_empty() {
+            self.targets.keys().cloned().collect()
+        } else {
+            target_change
+                .target_ids
+                .iter()
+                .map(|target_id| FirestoreListenerTarget::try_from(*target_id))
+                .collect::<FirestoreResult<_>>()?
+        };
+
+        match TargetChangeType::try_from(target_change.target_change_type) {
+            Ok(TargetChangeType::Reset) => {
+                for target in targets {
+                    self.targets.entry(target).or_default().received_since_reset =
+                        Some(HashSet::new());
+                }
+            }
+            Ok(TargetChangeType::Current) => {
+                for target in targets {
+                    let Some(received) = self
+                        .targets
+                        .get_mut(&target)
+                        .and_then(|target_docs| target_docs.received_since_reset.take())
+                    else {
+                        continue;
+                    };
+                    let stale_paths: Vec<String> = self.targets[&target]
+                        .update_times
+                        .keys()
+                        .filter(|document_path| !received.contains(*document_path))
+                        .cloned()
+                        .collect();
+                    debug!(
+                        target = *target.value(),
+                        removed_docs = stale_paths.len(),
+                        "Listener target has been resynchronized.",
+                    );
+                    for document_path in stale_paths {
+                        changes.push(FirestoreListenChange::Removed(
+                            self.remove_doc(target.clone(), &document_path),
+                        ));
+                    }
+                }
+            }
+            Ok(TargetChangeType::Remove) => {
+                for target in targets {
+                    if let Some(target_docs) = self.targets.remove(&target) {
+                        changes.extend(target_docs.update_times.into_iter().map(
+                            |(document_path, old_update_time)| {
+                                FirestoreListenChange::Removed(FirestoreListenDocChange {
+                                    target: target.clone(),
+                                    document_path,
+                                    object: None,
+                                    old_update_time,
+                                    new_update_time: None,
+                                })
+                            },
+                        ));
+                    }
+                }
+            }
+            Ok(TargetChangeType::NoChange) | Ok(TargetChangeType::Add) | Err(_) => {}
+        }
+        Ok(())
+    }
+
+    fn remove_doc(
+        &mut self,
+        target: FirestoreListenerTarget,
+        document_path: &str,
+    ) -> FirestoreListenDocChange<FirestoreDocument> {
+        let old_update_time = self
+            .targets
+            .get_mut(&target)
+            .and_then(|target_docs| target_docs.update_times.remove(document_path))
+            .flatten();
+        FirestoreListenDocChange {
+            target,
+            document_path: document_path.to_string(),
+            object: None,
+            old_update_time,
+            new_update_time: None,
+        }
+    }
+}
+
+impl<D, S> FirestoreListener<D, S>
+where
+    D: FirestoreListenSupport + Clone + Send + Sync + 'static,
+    S: FirestoreResumeStateStorage + Clone + Send + Sync + 'static,
+{
+    /// Starts the listener and returns the changes to the result sets of its targets as a
+    /// stream. The stream owns the listener, which is shut down when the stream is dropped.
+    ///
+    /// Errors converting an event are returned as items of the stream, which goes on with the
+    /// next events.
+    pub async fn start_changes_stream<'b>(
+        self,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<FirestoreListenChange<FirestoreDocument>>>>
+    {
+        let mut tracker = FirestoreListenChangesTracker::new();
+        Ok(self
+            .start_events_stream()
+            .await?
+            .flat_map(move |event| {
+                futures::stream::iter(match tracker.on_event(event) {
+                    Ok(changes) => changes.into_iter().map(Ok).collect(),
+                    Err(err) => vec![Err(err)],
+                })
+            })
+            .boxed())
+    }
+
+    /// Starts the listener and returns its raw events as a stream, which owns the listener.
+    pub(crate) async fn start_events_stream<'b>(
+        mut self,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreListenEvent>> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<FirestoreListenEvent>();
+
+        self.start_with_target_changes(move |event| {
+            let sent = tx.send(event);
+            async move {
+                sent.map_err(|err| -> Box<dyn std::error::Error + Send + Sync> { Box::new(err) })
+                    as AnyBoxedErrResult<()>
+            }
+        })
+        .await?;
+
+        Ok(
+            futures::stream::unfold((self, rx), |(listener, mut rx)| async move {
+                rx.recv().await.map(|event| (event, (listener, rx)))
+            })
+            .boxed(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::listen_changes_test_fixtures::*;
+
+    fn summary(changes: Vec<FirestoreListenChange<FirestoreDocument>>) -> Vec<String> {
+        changes.iter().map(change_summary).collect()
+    }
+
+    #[test]
+    fn tracks_the_result_set_of_a_target() {
+        let mut tracker = FirestoreListenChangesTracker::new();
+        let mut apply = |event| summary(tracker.on_event(event).unwrap());
+
+        assert_eq!(apply(doc_change("a", 1)), vec!["added a"]);
+        assert_eq!(apply(doc_change("b", 1)), vec!["added b"]);
+        assert_eq!(apply(doc_change("a", 2)), vec!["modified a"]);
+        assert_eq!(apply(doc_delete("b")), vec!["deleted b"]);
+
+        // After a reset, what is not sent again has left the result set
+        assert!(apply(target_change(TargetChangeType::Reset, vec![TARGET])).is_empty());
+        assert!(apply(doc_change("a", 2)).is_empty());
+        assert_eq!(apply(doc_change("c", 3)), vec!["added c"]);
+        assert!(apply(target_change(TargetChangeType::Current, vec![TARGET])).is_empty());
+        assert_eq!(apply(doc_change("d", 4)), vec!["added d"]);
+        assert!(apply(target_change(TargetChangeType::Reset, vec![TARGET])).is_empty());
+        let mut removed = apply(target_change(TargetChangeType::Current, vec![TARGET]));
+        removed.sort();
+        assert_eq!(removed, vec!["removed a", "removed c", "removed d"]);
+        assert_eq!(apply(doc_change("a", 2)), vec!["added a"]);
+
+        let removed = apply(target_change(TargetChangeType::Remove, vec![TARGET]));
+        assert_eq!(removed, vec!["removed a"]);
+    }
+}
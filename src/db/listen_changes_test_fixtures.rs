@@ -0,0 +1,153 @@
+//! Fixtures shared by the tests of the listeners and of the streams built on them.
+
+use crate::db::support::{FirestoreListenSupport, FirestoreListenerTargetUpdate};
+use crate::timestamp_utils::to_timestamp;
+use crate::{
+    FirestoreDocument, FirestoreError, FirestoreInstant, FirestoreListenChange,
+    FirestoreListenEvent, FirestoreListenerTargetParams, FirestoreListenerTargetResumeType,
+    FirestoreResult,
+};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use gcloud_sdk::google::firestore::v1::target_change::TargetChangeType;
+use gcloud_sdk::google::firestore::v1::{
+    DocumentChange, DocumentDelete, ListenResponse, TargetChange,
+};
+use rvstruct::ValueStruct;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub(crate) const DOCS: &str = "projects/test/databases/(default)/documents";
+pub(crate) const TARGET: i32 = 17;
+
+pub(crate) fn timestamp(secs: i64) -> gcloud_sdk::prost_types::Timestamp {
+    to_timestamp(FirestoreInstant::from_second(secs).unwrap())
+}
+
+pub(crate) fn doc_change(id: &str, updated_at_secs: i64) -> FirestoreListenEvent {
+    FirestoreListenEvent::DocumentChange(DocumentChange {
+        document: Some(FirestoreDocument {
+            name: format!("{DOCS}/cities/{id}"),
+            fields: HashMap::new(),
+            create_time: None,
+            update_time: Some(timestamp(updated_at_secs)),
+        }),
+        target_ids: vec![TARGET],
+        removed_target_ids: vec![],
+    })
+}
+
+pub(crate) fn doc_delete(id: &str) -> FirestoreListenEvent {
+    FirestoreListenEvent::DocumentDelete(DocumentDelete {
+        document: format!("{DOCS}/cities/{id}"),
+        removed_target_ids: vec![TARGET],
+        read_time: None,
+    })
+}
+
+pub(crate) fn target_change(
+    change_type: TargetChangeType,
+    target_ids: Vec<i32>,
+) -> FirestoreListenEvent {
+    FirestoreListenEvent::TargetChange(TargetChange {
+        target_change_type: change_type as i32,
+        target_ids,
+        read_time: Some(timestamp(100)),
+        ..Default::default()
+    })
+}
+
+/// A change as `"<kind> <document ID>"`, e.g. `"added a"`.
+pub(crate) fn change_summary(change: &FirestoreListenChange<FirestoreDocument>) -> String {
+    let kind = match change {
+        FirestoreListenChange::Added(_) => "added",
+        FirestoreListenChange::Modified(_) => "modified",
+        FirestoreListenChange::Removed(_) => "removed",
+        FirestoreListenChange::Deleted(_) => "deleted",
+    };
+    format!("{kind} {}", change.doc_change().document_id())
+}
+
+/// Serves scripted listen streams, one per connection, and records how each connection
+/// resumed.
+///
+/// Connections fail with the `connection_errors` first, and streams end with the
+/// `stream_errors`, one per stream, or stay open once their responses are sent.
+#[derive(Clone, Default)]
+pub(crate) struct ScriptedListenSupport {
+    pub connections: Arc<Mutex<Vec<Vec<ListenResponse>>>>,
+    pub connection_errors: Arc<Mutex<Vec<FirestoreError>>>,
+    pub stream_errors: Arc<Mutex<Vec<FirestoreError>>>,
+    pub resumed_from: Arc<Mutex<Vec<Option<Vec<u8>>>>>,
+    pub target_updates: Arc<Mutex<Vec<String>>>,
+}
+
+#[async_trait]
+impl FirestoreListenSupport for ScriptedListenSupport {
+    async fn listen_doc_changes<'a, 'b>(
+        &'a self,
+        targets: Vec<FirestoreListenerTargetParams>,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>> {
+        self.resumed_from
+            .lock()
+            .unwrap()
+            .push(
+                targets[0]
+                    .resume_type
+                    .as_ref()
+                    .and_then(|resume_type| match resume_type {
+                        FirestoreListenerTargetResumeType::Token(token) => {
+                            Some(token.value().clone())
+                        }
+                        FirestoreListenerTargetResumeType::ReadTime(_) => None,
+                    }),
+            );
+        let mut connection_errors = self.connection_errors.lock().unwrap();
+        if !connection_errors.is_empty() {
+            return Err(connection_errors.remove(0));
+        }
+        let mut connections = self.connections.lock().unwrap();
+        let responses = if connections.is_empty() {
+            Vec::new()
+        } else {
+            connections.remove(0)
+        };
+        let mut stream_errors = self.stream_errors.lock().unwrap();
+        let end = if stream_errors.is_empty() {
+            futures::stream::pending().boxed()
+        } else {
+            futures::stream::once(futures::future::ready(Err(stream_errors.remove(0)))).boxed()
+        };
+        Ok(futures::stream::iter(responses.into_iter().map(Ok))
+            .chain(end)
+            .boxed())
+    }
+
+    async fn listen_doc_changes_with_updates<'a, 'b>(
+        &'a self,
+        targets: Vec<FirestoreListenerTargetParams>,
+        mut target_updates: BoxStream<'static, FirestoreListenerTargetUpdate>,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>> {
+        let recorded_updates = self.target_updates.clone();
+        tokio::spawn(async move {
+            while let Some(target_update) = target_updates.next().await {
+                recorded_updates.lock().unwrap().push(match target_update {
+                    FirestoreListenerTargetUpdate::Add(target_params) => format!(
+                        "add {} from {}",
+                        target_params.target.value(),
+                        match target_params.resume_type {
+                            Some(FirestoreListenerTargetResumeType::Token(_)) => "token",
+                            Some(FirestoreListenerTargetResumeType::ReadTime(_)) => "read time",
+                            None => "now",
+                        }
+                    ),
+                    FirestoreListenerTargetUpdate::Remove(target) => {
+                        format!("remove {}", target.value())
+                    }
+                });
+            }
+        });
+        self.listen_doc_changes(targets).await
+    }
+}
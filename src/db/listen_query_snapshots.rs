@@ -0,0 +1,395 @@
+use crate::db::support::FirestoreListenSupport;
+use crate::timestamp_utils::from_timestamp;
+use crate::{
+    FirestoreDb, FirestoreDocument, FirestoreInstant, FirestoreListenChange,
+    FirestoreListenChangesTracker, FirestoreListenDocChange, FirestoreListenEvent,
+    FirestoreListener, FirestoreListenerTarget, FirestoreResult, FirestoreResumeStateStorage,
+};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use gcloud_sdk::google::firestore::v1::target_change::TargetChangeType;
+use rvstruct::ValueStruct;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use tracing::*;
+
+/// A consistent view of the result set of a listener target, with the changes since the previous
+/// snapshot of the target.
+///
+/// Snapshots are only produced at the points where Firestore tells that all the current targets
+/// of the listener are consistent, so they never show a partially applied update.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirestoreQuerySnapshot<T> {
+    /// The listener target of the snapshot.
+    pub target: FirestoreListenerTarget,
+    /// The time the result set is consistent at.
+    pub read_time: FirestoreInstant,
+    /// The documents of the result set, ordered by their paths.
+    pub documents: Vec<T>,
+    /// The changes since the previous snapshot of the target, ordered by document paths. Each
+    /// document appears at most once. For the first snapshot, all documents are added.
+    pub changes: Vec<FirestoreListenChange<T>>,
+}
+
+impl FirestoreQuerySnapshot<FirestoreDocument> {
+    /// Deserializes the documents and the changes of the snapshot.
+    pub fn deserialize_objects<T>(self) -> FirestoreResult<FirestoreQuerySnapshot<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        Ok(FirestoreQuerySnapshot {
+            target: self.target,
+            read_time: self.read_time,
+            documents: self
+                .documents
+                .iter()
+                .map(FirestoreDb::deserialize_doc_to)
+                .collect::<FirestoreResult<_>>()?,
+            changes: self
+                .changes
+                .into_iter()
+                .map(|change| change.deserialize_object())
+                .collect::<FirestoreResult<_>>()?,
+        })
+    }
+}
+
+/// Accumulates the raw listen events of listener targets and turns them into
+/// [`FirestoreQuerySnapshot`]s at each global consistency point.
+///
+/// Firestore sends the changes of a target incrementally, marks the target as current once it
+/// has sent its whole result set, and then sends a target change without target IDs and with a
+/// read time when all the current targets are consistent. The tracker produces a snapshot there
+/// for each current target that changed since its previous snapshot, like the official SDKs.
+#[derive(Debug, Default)]
+pub struct FirestoreQuerySnapshotsTracker {
+    changes: FirestoreListenChangesTracker,
+    targets: HashMap<FirestoreListenerTarget, FirestoreQuerySnapshotTargetState>,
+}
+
+#[derive(Debug, Default)]
+struct FirestoreQuerySnapshotTargetState {
+    documents: BTreeMap<String, FirestoreDocument>,
+    pending_changes: BTreeMap<String, FirestoreListenChange<FirestoreDocument>>,
+    is_current: bool,
+    has_snapshot: bool,
+}
+
+impl FirestoreQuerySnapshotsTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies a listen event and returns the snapshots it completes.
+    pub fn on_event(
+        &mut self,
+        event: FirestoreListenEvent,
+    ) -> FirestoreResult<Vec<FirestoreQuerySnapshot<FirestoreDocument>>> {
+        let target_change = match &event {
+            FirestoreListenEvent::TargetChange(target_change) => Some(target_change.clone()),
+            _ => None,
+        };
+
+        for change in self.changes.on_event(event)? {
+            self.apply_change(change);
+        }
+
+        let Some(target_change) = target_change else {
+            return Ok(Vec::new());
+        };
+
+        let targets: Vec<FirestoreListenerTarget> = if target_change.target_ids.is_empty() {
+            self.targets.keys().cloned().collect()
+        } else {
+            target_change
+                .target_ids
+                .iter()
+                .map(|target_id| FirestoreListenerTarget::try_from(*target_id))
+                .collect::<FirestoreResult<_>>()?
+        };
+
+        match TargetChangeType::try_from(target_change.target_change_type) {
+            Ok(TargetChangeType::Add) | Ok(TargetChangeType::Reset) => {
+                for target in targets {
+                    self.targets.entry(target).or_default().is_current = false;
+                }
+            }
+            Ok(TargetChangeType::Current) => {
+                for target in targets {
+                    self.targets.entry(target).or_default().is_current = true;
+                }
+            }
+            Ok(TargetChangeType::Remove) => {
+                for target in targets {
+                    self.targets.remove(&target);
+                }
+            }
+            Ok(TargetChangeType::NoChange) => {
+                if let Some(read_time) = target_change
+                    .read_time
+                    .filter(|_| target_change.target_ids.is_empty())
+                {
+                    return Ok(self.take_snapshots(from_timestamp(read_time)?));
+                }
+            }
+            Err(_) => {}
+        }
+
+        Ok(Vec::new())
+    }
+
+    fn apply_change(&mut self, change: FirestoreListenChange<FirestoreDocument>) {
+        let doc_change = change.doc_change();
+        let document_path = doc_change.document_path.clone();
+        let state = self.targets.entry(doc_change.target.clone()).or_default();
+
+        match &change {
+            FirestoreListenChange::Added(doc_change)
+            | FirestoreListenChange::Modified(doc_change) => {
+                if let Some(doc) = &doc_change.object {
+                    state.documents.insert(document_path.clone(), doc.clone());
+                }
+            }
+            FirestoreListenChange::Removed(_) | FirestoreListenChange::Deleted(_) => {
+                state.documents.remove(&document_path);
+            }
+        }
+
+        let coalesced = match state.pending_changes.remove(&document_path) {
+            Some(pending) => coalesce_changes(pending, change),
+            None => Some(change),
+        };
+        if let Some(coalesced) = coalesced {
+            state.pending_changes.insert(document_path, coalesced);
+        }
+    }
+
+    fn take_snapshots(
+        &mut self,
+        read_time: FirestoreInstant,
+    ) -> Vec<FirestoreQuerySnapshot<FirestoreDocument>> {
+        let mut snapshots = Vec::new();
+        for (target, state) in self.targets.iter_mut() {
+            if !state.is_current || (state.has_snapshot && state.pending_changes.is_empty()) {
+                continue;
+            }
+            state.has_snapshot = true;
+            let changes: Vec<_> = std::mem::take(&mut state.pending_changes)
+                .into_values()
+                .collect();
+            debug!(
+                target = *target.value(),
+                documents = state.documents.len(),
+                changes = changes.len(),
+                "Listener target has a consistent snapshot.",
+            );
+            snapshots.push(FirestoreQuerySnapshot {
+                target: target.clone(),
+                read_time,
+                documents: state.documents.values().cloned().collect(),
+                changes,
+            });
+        }
+        snapshots
+    }
+}
+
+/// Merges two successive changes to a document into the change between the two snapshots, if
+/// any.
+fn coalesce_changes<T>(
+    pending: FirestoreListenChange<T>,
+    change: FirestoreListenChange<T>,
+) -> Option<FirestoreListenChange<T>> {
+    use FirestoreListenChange::*;
+
+    match (pending, change) {
+        (Added(_), Modified(change)) => Some(Added(FirestoreListenDocChange {
+            old_update_time: None,
+            ..change
+        })),
+        (Added(_), Removed(_) | Deleted(_)) => None,
+        (Modified(pending), Modified(change)) => Some(Modified(FirestoreListenDocChange {
+            old_update_time: pending.old_update_time,
+            ..change
+        })),
+        (Modified(pending), Removed(change)) => Some(Removed(FirestoreListenDocChange {
+            old_update_time: pending.old_update_time,
+            ..change
+        })),
+        (Modified(pending), Deleted(change)) => Some(Deleted(FirestoreListenDocChange {
+            old_update_time: pending.old_update_time,
+            ..change
+        })),
+        (Removed(pending) | Deleted(pending), Added(change)) => {
+            Some(Modified(FirestoreListenDocChange {
+                old_update_time: pending.old_update_time,
+                ..change
+            }))
+        }
+        (_, change) => Some(change),
+    }
+}
+
+impl<D, S> FirestoreListener<D, S>
+where
+    D: FirestoreListenSupport + Clone + Send + Sync + 'static,
+    S: FirestoreResumeStateStorage + Clone + Send + Sync + 'static,
+{
+    /// Starts the listener and returns the consistent snapshots of its targets as a stream. The
+    /// stream owns the listener, which is shut down when the stream is dropped.
+    ///
+    /// The targets never resume from the states in the storage, as the first snapshot must
+    /// contain the complete result set rather than what changed since the last run.
+    ///
+    /// Errors converting an event are returned as items of the stream, which goes on with the
+    /// next events.
+    pub async fn start_query_snapshots_stream<'b>(
+        mut self,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<FirestoreQuerySnapshot<FirestoreDocument>>>>
+    {
+        self.ignore_stored_resume_states();
+        let mut tracker = FirestoreQuerySnapshotsTracker::new();
+        Ok(self
+            .start_events_stream()
+            .await?
+            .flat_map(move |event| {
+                futures::stream::iter(match tracker.on_event(event) {
+                    Ok(snapshots) => snapshots.into_iter().map(Ok).collect(),
+                    Err(err) => vec![Err(err)],
+                })
+            })
+            .boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::listen_changes_test_fixtures::*;
+    use crate::FirestoreMemListenStateStorage;
+    use gcloud_sdk::google::firestore::v1::ListenResponse;
+
+    fn consistent() -> FirestoreListenEvent {
+        target_change(TargetChangeType::NoChange, vec![])
+    }
+
+    fn summary(snapshot: &FirestoreQuerySnapshot<FirestoreDocument>) -> (Vec<String>, Vec<String>) {
+        let documents = snapshot
+            .documents
+            .iter()
+            .map(|doc| crate::db::split_document_path(&doc.name).1.to_string())
+            .collect();
+        let changes = snapshot.changes.iter().map(change_summary).collect();
+        (documents, changes)
+    }
+
+    #[test]
+    fn emits_snapshots_at_consistency_points() {
+        let mut tracker = FirestoreQuerySnapshotsTracker::new();
+        let mut apply = |event| tracker.on_event(event).unwrap();
+
+        assert!(apply(target_change(TargetChangeType::Add, vec![TARGET])).is_empty());
+        assert!(apply(doc_change("b", 1)).is_empty());
+        assert!(apply(doc_change("a", 1)).is_empty());
+        // Not current yet
+        assert!(apply(consistent()).is_empty());
+        assert!(apply(doc_change("a", 2)).is_empty());
+        assert!(apply(target_change(TargetChangeType::Current, vec![TARGET])).is_empty());
+
+        let snapshots = apply(consistent());
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(
+            summary(&snapshots[0]),
+            (
+                vec!["a".to_string(), "b".to_string()],
+                vec!["added a".to_string(), "added b".to_string()]
+            )
+        );
+
+        // Nothing changed since the previous snapshot
+        assert!(apply(consistent()).is_empty());
+
+        assert!(apply(doc_change("c", 3)).is_empty());
+        assert!(apply(doc_delete("c")).is_empty());
+        assert!(apply(doc_change("a", 4)).is_empty());
+        assert!(apply(doc_delete("b")).is_empty());
+
+        let snapshots = apply(consistent());
+        assert_eq!(
+            summary(&snapshots[0]),
+            (
+                vec!["a".to_string()],
+                vec!["modified a".to_string(), "deleted b".to_string()]
+            )
+        );
+        match &snapshots[0].changes[0] {
+            FirestoreListenChange::Modified(change) => {
+                assert_eq!(
+                    change.old_update_time,
+                    Some(FirestoreInstant::from_second(2).unwrap())
+                );
+                assert_eq!(
+                    change.new_update_time,
+                    Some(FirestoreInstant::from_second(4).unwrap())
+                );
+            }
+            other => panic!("Unexpected change: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn emits_empty_result_sets() {
+        let mut tracker = FirestoreQuerySnapshotsTracker::new();
+
+        tracker
+            .on_event(target_change(TargetChangeType::Current, vec![TARGET]))
+            .unwrap();
+        let snapshots = tracker.on_event(consistent()).unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert!(snapshots[0].documents.is_empty());
+        assert!(snapshots[0].changes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn starts_snapshot_targets_without_the_stored_resume_state() {
+        let db = ScriptedListenSupport::default();
+        *db.connections.lock().unwrap() = vec![[
+            doc_change("a", 1),
+            target_change(TargetChangeType::Current, vec![TARGET]),
+            consistent(),
+        ]
+        .into_iter()
+        .map(|event| ListenResponse {
+            response_type: Some(event),
+        })
+        .collect()];
+        let target = FirestoreListenerTarget::new(TARGET as u32);
+        let storage = FirestoreMemListenStateStorage::new();
+        storage
+            .update_resume_token(&target, b"stored".to_vec().into())
+            .await
+            .unwrap();
+
+        let mut listener =
+            FirestoreListener::new(db.clone(), storage, crate::FirestoreListenerParams::new())
+                .await
+                .unwrap();
+        listener
+            .add_target(crate::FirestoreListenerTargetParams::new(
+                target,
+                crate::FirestoreTargetType::Query(crate::FirestoreQueryParams::new(
+                    "cities".into(),
+                )),
+                HashMap::new(),
+            ))
+            .unwrap();
+        let mut snapshots = listener.start_query_snapshots_stream().await.unwrap();
+
+        let snapshot = snapshots.next().await.unwrap().unwrap();
+        assert_eq!(
+            summary(&snapshot),
+            (vec!["a".to_string()], vec!["added a".to_string()])
+        );
+        assert_eq!(*db.resumed_from.lock().unwrap(), vec![None]);
+    }
+}
@@ -14,6 +14,14 @@ mod update;
 /// Module for document deletion operations.
 mod delete;
 
+/// Module for optimistic read-modify-write updates based on update time preconditions.
+mod compare_and_swap;
+pub use compare_and_swap::*;
+
+/// Module for updating a write-through cache with the documents written.
+#[cfg(feature = "caching")]
+mod cache_write_through;
+
 /// Module defining models used in queries (filters, orders, etc.).
 mod query_models;
 pub use query_models::*;
@@ -37,6 +45,29 @@ pub use list::*;
 mod listen_changes;
 pub use listen_changes::*;
 
+/// Module for the reconnection policy and lifecycle hooks of listeners.
+mod listen_changes_retry;
+pub use listen_changes_retry::*;
+
+/// Module for electing one active listener among instances with a lease document.
+mod listen_changes_lease;
+pub use listen_changes_lease::*;
+
+/// Module for streaming typed changes to the result sets of listener targets.
+mod listen_changes_stream;
+pub use listen_changes_stream::*;
+
+/// Module for consistent snapshots of the result sets of listener targets.
+mod listen_query_snapshots;
+pub use listen_query_snapshots::*;
+
+/// Module for sharing the changes of a listener with many filtered subscribers.
+mod listen_change_hub;
+pub use listen_change_hub::*;
+
+#[cfg(test)]
+mod listen_changes_test_fixtures;
+
 /// Module for storing the state of listen operations (e.g., resume tokens).
 mod listen_changes_state_storage;
 pub use listen_changes_state_storage::*;
@@ -646,7 +677,8 @@ impl FirestoreDb {
     /// collection could only answer them with partial results.
     ///
     /// Results are eventually consistent; see [`FirestoreCache`](crate::FirestoreCache) for what
-    /// that means in practice.
+    /// that means in practice. If the cache is configured with a maximum staleness, reads go to
+    /// Firestore while its listener has been disconnected for longer than that.
     ///
     /// This method is only available if the `caching` feature is enabled.
     ///
@@ -659,7 +691,7 @@ impl FirestoreDb {
         LS: FirestoreResumeStateStorage + Clone + Send + Sync + 'static,
     {
         self.with_cache(crate::FirestoreDbSessionCacheMode::ReadThroughCache(
-            cache.backend(),
+            cache.read_through_backend(),
         ))
     }
 
@@ -690,6 +722,35 @@ impl FirestoreDb {
             cache.backend(),
         ))
     }
+
+    /// Clones this `FirestoreDb` so that reads go through the given cache, like
+    /// [`read_through_cache`](Self::read_through_cache), and writes update it immediately.
+    ///
+    /// Without this, your own writes reach the cache only when the listener delivers them, so a
+    /// read right after a write may still return the previous version of the document. With it,
+    /// documents created, updated or deleted through this handle - including in committed
+    /// transactions and simple batch writes - are updated in the cache as soon as Firestore has
+    /// accepted the write. The listener keeps delivering changes as usual, so writes made
+    /// elsewhere still arrive eventually.
+    ///
+    /// Failing to update the cache does not fail the write, which has already happened; it is
+    /// logged instead. Writes whose result cannot be known locally, such as array transforms,
+    /// remove the document from the cache so that it is read from Firestore again.
+    ///
+    /// This method is only available if the `caching` feature is enabled.
+    ///
+    /// # Arguments
+    /// * `cache`: A reference to the [`FirestoreCache`](crate::FirestoreCache) to use.
+    #[cfg(feature = "caching")]
+    pub fn write_through_cache<B, LS>(&self, cache: &FirestoreCache<B, LS>) -> Self
+    where
+        B: FirestoreCacheBackend + Send + Sync + 'static,
+        LS: FirestoreResumeStateStorage + Clone + Send + Sync + 'static,
+    {
+        self.with_cache(crate::FirestoreDbSessionCacheMode::WriteThroughCache(
+            cache.read_through_backend(),
+        ))
+    }
 }
 
 /// Ensures that a URL string has a scheme (e.g., "http://").
@@ -745,6 +806,31 @@ pub(crate) fn split_document_path(path: &str) -> (&str, &str) {
     }
 }
 
+#[cfg(all(test, any(feature = "caching-memory", feature = "caching-persistent")))]
+impl FirestoreDb {
+    /// A client for unit tests that don't expect to send requests. It is connected to a local
+    /// socket that accepts connections and never answers.
+    pub(crate) async fn for_offline_tests() -> FirestoreDb {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut connections = Vec::new();
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        Self::with_options_token_source(
+            FirestoreDbOptions::new("test-project".to_string())
+                .with_firebase_api_url(format!("http://{address}")),
+            vec![],
+            TokenSourceType::ExternalSource(Box::new(FirestoreEmulatorTokenSource)),
+        )
+        .await
+        .unwrap()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -112,96 +112,98 @@ impl FirestoreDb {
 
     #[cfg(feature = "caching")]
     #[inline]
-    async fn query_docs_from_cache<'b>(
+    pub(crate) async fn query_docs_from_cache<'b>(
         &self,
         params: &FirestoreQueryParams,
     ) -> FirestoreResult<FirestoreCachedValue<BoxStream<'b, FirestoreResult<FirestoreDocument>>>>
     {
-        match &params.collection_id {
-            FirestoreQueryCollection::Group(group_id) => {
-                // Collection group queries span collections the cache does not model, so they can
-                // never be served from it. Under ReadCachedOnly we must say so rather than
-                // quietly falling through to Firestore.
-                if matches!(
+        let collection_id = match &params.collection_id {
+            FirestoreQueryCollection::Single(collection_id) => collection_id,
+            FirestoreQueryCollection::Group(collection_ids) if collection_ids.len() == 1 => {
+                &collection_ids[0]
+            }
+            FirestoreQueryCollection::Group(collection_ids) => {
+                // Queries over several collection IDs span collections the cache does not model,
+                // so they can never be served from it. Under ReadCachedOnly we must say so rather
+                // than quietly falling through to Firestore.
+                return if matches!(
                     self.session_params.cache_mode,
                     FirestoreDbSessionCacheMode::ReadCachedOnly(_)
                 ) {
                     Err(crate::cache_incomplete_collection_error(
-                        &group_id.join(","),
-                        "collection group queries cannot be served from a cache",
+                        &collection_ids.join(","),
+                        "queries over several collection IDs cannot be served from a cache",
                     ))
                 } else {
                     Ok(FirestoreCachedValue::SkipCache)
-                }
+                };
             }
-            FirestoreQueryCollection::Single(collection_id) => {
-                if let FirestoreDbSessionCacheMode::ReadCachedOnly(ref cache)
-                | FirestoreDbSessionCacheMode::ReadThroughCache(ref cache) =
-                    self.session_params.cache_mode
-                {
-                    let span = span!(
-                        Level::DEBUG,
-                        "Firestore Query Cached",
-                        "/firestore/collection_name" = collection_id.as_str(),
-                        "/firestore/cache_result" = field::Empty,
-                        "/firestore/response_time" = field::Empty
-                    );
+        };
 
-                    let begin_query_utc: FirestoreInstant = FirestoreInstant::now();
+        if let FirestoreDbSessionCacheMode::ReadCachedOnly(ref cache)
+        | FirestoreDbSessionCacheMode::ReadThroughCache(ref cache)
+        | FirestoreDbSessionCacheMode::WriteThroughCache(ref cache) =
+            self.session_params.cache_mode
+        {
+            let span = span!(
+                Level::DEBUG,
+                "Firestore Query Cached",
+                "/firestore/collection_name" = collection_id.as_str(),
+                "/firestore/cache_result" = field::Empty,
+                "/firestore/response_time" = field::Empty
+            );
 
-                    let collection_path = if let Some(parent) = params.parent.as_ref() {
-                        format!("{}/{}", parent, collection_id)
-                    } else {
-                        format!("{}/{}", self.get_documents_path(), collection_id.as_str())
-                    };
+            let begin_query_utc: FirestoreInstant = FirestoreInstant::now();
 
-                    let result = cache.query_docs(&collection_path, params).await?;
+            let collection_path = if let Some(parent) = params.parent.as_ref() {
+                format!("{}/{}", parent, collection_id)
+            } else {
+                format!("{}/{}", self.get_documents_path(), collection_id.as_str())
+            };
 
-                    let end_query_utc: FirestoreInstant = FirestoreInstant::now();
-                    let query_duration = end_query_utc.duration_since(begin_query_utc);
+            let result = cache.query_docs(&collection_path, params).await?;
 
-                    span.record("/firestore/response_time", query_duration.as_millis());
+            let end_query_utc: FirestoreInstant = FirestoreInstant::now();
+            let query_duration = end_query_utc.duration_since(begin_query_utc);
 
-                    match result {
-                        FirestoreCachedValue::UseCached(stream) => {
-                            span.record("/firestore/cache_result", "hit");
-                            span.in_scope(|| {
-                                debug!(collection_id, "Querying documents from cache.");
-                            });
-                            Ok(FirestoreCachedValue::UseCached(stream))
-                        }
-                        FirestoreCachedValue::SkipCache => {
-                            span.record("/firestore/cache_result", "miss");
-                            if matches!(
-                                self.session_params.cache_mode,
-                                FirestoreDbSessionCacheMode::ReadCachedOnly(_)
-                            ) {
-                                span.in_scope(|| {
-                                    debug!(collection_id,
+            span.record("/firestore/response_time", query_duration.as_millis());
+
+            match result {
+                FirestoreCachedValue::UseCached(stream) => {
+                    span.record("/firestore/cache_result", "hit");
+                    span.in_scope(|| {
+                        debug!(collection_id, "Querying documents from cache.");
+                    });
+                    Ok(FirestoreCachedValue::UseCached(stream))
+                }
+                FirestoreCachedValue::SkipCache => {
+                    span.record("/firestore/cache_result", "miss");
+                    if matches!(
+                        self.session_params.cache_mode,
+                        FirestoreDbSessionCacheMode::ReadCachedOnly(_)
+                    ) {
+                        span.in_scope(|| {
+                            debug!(collection_id,
                                 "Cache cannot serve this query completely and cache mode is ReadCachedOnly, so returning an error.",
                             );
-                                });
-                                Err(crate::cache_incomplete_collection_error(
-                                    collection_id,
-                                    "either the collection is not configured to be preloaded, or \
-                                     the query uses features the cached query engine does not \
-                                     support",
-                                ))
-                            } else {
-                                span.in_scope(|| {
-                                    debug!(
-                                        collection_id,
-                                        "Querying documents from cache skipped.",
-                                    );
-                                });
-                                Ok(FirestoreCachedValue::SkipCache)
-                            }
-                        }
+                        });
+                        Err(crate::cache_incomplete_collection_error(
+                            collection_id,
+                            "either the collection is not configured to be preloaded, \
+                             the query uses features the cached query engine does not \
+                             support, or the collection is being resynchronized with \
+                             Firestore",
+                        ))
+                    } else {
+                        span.in_scope(|| {
+                            debug!(collection_id, "Querying documents from cache skipped.");
+                        });
+                        Ok(FirestoreCachedValue::SkipCache)
                     }
-                } else {
-                    Ok(FirestoreCachedValue::SkipCache)
                 }
             }
+        } else {
+            Ok(FirestoreCachedValue::SkipCache)
         }
     }
 }
@@ -40,6 +40,7 @@ pub struct FirestoreDbSessionParams {
 /// This enum is used in [`FirestoreDbSessionParams`] to control how and if
 /// caching is utilized for read operations.
 #[derive(Clone)]
+#[non_exhaustive]
 pub enum FirestoreDbSessionCacheMode {
     /// No caching is performed. All read operations go directly to Firestore.
     None,
@@ -65,6 +66,17 @@ pub enum FirestoreDbSessionCacheMode {
     /// This mode is only available if the `caching` feature is enabled.
     #[cfg(feature = "caching")]
     ReadCachedOnly(FirestoreSharedCacheBackend),
+    /// Reads through the cache like [`ReadThroughCache`](Self::ReadThroughCache), and also
+    /// writes through it.
+    ///
+    /// Documents written with this session - created, updated or deleted, including in committed
+    /// transactions and simple batch writes - are updated in the cache as soon as Firestore has
+    /// accepted the write, instead of when the listener delivers the change. This way a read
+    /// right after your own write does not return the previous version of the document.
+    ///
+    /// This mode is only available if the `caching` feature is enabled.
+    #[cfg(feature = "caching")]
+    WriteThroughCache(FirestoreSharedCacheBackend),
 }
 
 /// A type alias for a thread-safe, shareable Firestore cache backend.
@@ -155,6 +155,37 @@ pub trait FirestoreGetByIdSupport {
     where
         S: AsRef<str> + Send;
 
+    /// Like [`get_doc`](Self::get_doc), but never served from a session cache, which may hold
+    /// an older version of the document. Implementations without a session cache can rely on
+    /// the default, which calls `get_doc`.
+    async fn get_doc_uncached<S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+    ) -> FirestoreResult<Document>
+    where
+        S: AsRef<str> + Send,
+        Self: Sync,
+    {
+        self.get_doc(collection_id, document_id, None).await
+    }
+
+    /// Like [`get_doc_at`](Self::get_doc_at), but never served from a session cache, which may
+    /// hold an older version of the document.
+    async fn get_doc_at_uncached<S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+    ) -> FirestoreResult<Document>
+    where
+        S: AsRef<str> + Send,
+        Self: Sync,
+    {
+        self.get_doc_at(parent, collection_id, document_id, None)
+            .await
+    }
+
     async fn get_obj<T, S>(&self, collection_id: &str, document_id: S) -> FirestoreResult<T>
     where
         for<'de> T: Deserialize<'de>,
@@ -455,10 +486,32 @@ pub trait FirestoreListingSupport {
     ) -> FirestoreResult<BoxStream<String>>;
 }
 
+/// A change to the targets of an open listen stream.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum FirestoreListenerTargetUpdate {
+    Add(FirestoreListenerTargetParams),
+    Remove(FirestoreListenerTarget),
+}
+
 #[async_trait]
 pub trait FirestoreListenSupport {
     async fn listen_doc_changes<'a, 'b>(
         &'a self,
         targets: Vec<FirestoreListenerTargetParams>,
     ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>>;
+
+    /// Listens like `listen_doc_changes`, and adds or removes targets on the open stream as the
+    /// updates arrive. Implementations that cannot change the targets of an open stream ignore
+    /// the updates, which then only apply once the listener reconnects.
+    async fn listen_doc_changes_with_updates<'a, 'b>(
+        &'a self,
+        targets: Vec<FirestoreListenerTargetParams>,
+        _target_updates: BoxStream<'static, FirestoreListenerTargetUpdate>,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>>
+    where
+        Self: Sync,
+    {
+        self.listen_doc_changes(targets).await
+    }
 }
@@ -2,18 +2,28 @@
 // available on both `FirestoreTransaction` and `FirestoreTransactionData`, which lets callers
 // write transaction-agnostic abstractions over the trait. See
 // https://github.com/abdolence/firestore-rs/issues/206.
+use crate::db::safe_document_path;
 pub use crate::db::transaction_ops::FirestoreTransactionOps;
 use crate::errors::*;
 use crate::timestamp_utils::from_timestamp;
 use crate::{
-    FirestoreConsistencySelector, FirestoreDb, FirestoreError, FirestoreRequestOptions,
-    FirestoreResult, FirestoreTransactionId, FirestoreTransactionMode, FirestoreTransactionOptions,
-    FirestoreTransactionResponse, FirestoreWriteResult,
+    FirestoreConsistencySelector, FirestoreDb, FirestoreDbSessionCacheMode, FirestoreDuration,
+    FirestoreError, FirestoreGetByIdSupport, FirestoreInstant, FirestoreQueryParams,
+    FirestoreQuerySupport, FirestoreRequestOptions, FirestoreResult,
+    FirestoreTransactionAttemptFailure, FirestoreTransactionId, FirestoreTransactionMode,
+    FirestoreTransactionOptions, FirestoreTransactionResponse, FirestoreWriteResult,
 };
+use backoff::backoff::Backoff;
 use backoff::future::retry;
 use backoff::ExponentialBackoffBuilder;
 use futures::future::BoxFuture;
-use gcloud_sdk::google::firestore::v1::{BeginTransactionRequest, CommitRequest, RollbackRequest};
+use gcloud_sdk::google::firestore::v1::{
+    BeginTransactionRequest, CommitRequest, RollbackRequest, Write,
+};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use std::time::Duration;
 use tracing::*;
 
@@ -93,6 +103,9 @@ pub struct FirestoreTransaction<'a> {
     data: FirestoreTransactionData,
     finished: bool,
     request_options: Option<FirestoreRequestOptions>,
+    read_documents: BTreeSet<String>,
+    forbid_blind_writes: bool,
+    started_at: FirestoreInstant,
 }
 
 impl<'a> FirestoreTransaction<'a> {
@@ -104,6 +117,10 @@ impl<'a> FirestoreTransaction<'a> {
             Level::DEBUG,
             "Firestore Transaction",
             "/firestore/transaction_id" = field::Empty,
+            "/firestore/attempt" = field::Empty,
+            "/firestore/failure_reason" = field::Empty,
+            "/firestore/writes_count" = field::Empty,
+            "/firestore/transaction_duration" = field::Empty,
             "/firestore/commit_time" = field::Empty
         );
 
@@ -141,6 +158,9 @@ impl<'a> FirestoreTransaction<'a> {
             data,
             finished: false,
             request_options: options.request_options,
+            read_documents: BTreeSet::new(),
+            forbid_blind_writes: options.forbid_blind_writes,
+            started_at: FirestoreInstant::now(),
         })
     }
 
@@ -154,7 +174,135 @@ impl<'a> FirestoreTransaction<'a> {
         self.db
     }
 
+    /// Reads a document in the transaction, recording it in the read set.
+    ///
+    /// Returns `None` if the document doesn't exist. Its absence is recorded as a read too, so
+    /// writing it afterwards isn't a blind write for
+    /// [`FirestoreTransactionOptions::forbid_blind_writes`].
+    /// Fails if the transaction already has writes, since Firestore requires all reads of a
+    /// transaction to happen before its writes.
+    pub async fn get<T, S>(
+        &mut self,
+        collection_id: &str,
+        document_id: S,
+    ) -> FirestoreResult<Option<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        let parent = self.data.document_path.clone();
+        self.get_at(parent.as_str(), collection_id, document_id)
+            .await
+    }
+
+    pub async fn get_at<T, S>(
+        &mut self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+    ) -> FirestoreResult<Option<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.ensure_no_writes_before_read()?;
+        let document_path = safe_document_path(parent, collection_id, document_id.as_ref())?;
+
+        let found = match self
+            .transactional_db()
+            .get_doc_at(parent, collection_id, document_id, None)
+            .await
+        {
+            Ok(doc) => Some(FirestoreDb::deserialize_doc_to(&doc)?),
+            Err(FirestoreError::DataNotFoundError(_)) => None,
+            Err(err) => return Err(err),
+        };
+        self.read_documents.insert(document_path);
+        Ok(found)
+    }
+
+    /// Runs a query in the transaction, recording the returned documents in the read set.
+    ///
+    /// Like [`FirestoreTransaction::get`], fails if the transaction already has writes.
+    pub async fn query<T>(&mut self, params: FirestoreQueryParams) -> FirestoreResult<Vec<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        self.ensure_no_writes_before_read()?;
+
+        let docs = self.transactional_db().query_doc(params).await?;
+        let objs = docs
+            .iter()
+            .map(FirestoreDb::deserialize_doc_to)
+            .collect::<FirestoreResult<Vec<T>>>()?;
+        self.read_documents
+            .extend(docs.into_iter().map(|doc| doc.name));
+        Ok(objs)
+    }
+
+    /// The full paths of the documents read through [`FirestoreTransaction::get`] and
+    /// [`FirestoreTransaction::query`] so far.
+    #[inline]
+    pub fn read_documents(&self) -> &BTreeSet<String> {
+        &self.read_documents
+    }
+
+    /// The database handle for the reads of the transaction. They always go to Firestore, even
+    /// on a cached handle, so that Firestore locks the documents and checks them for conflicts.
+    fn transactional_db(&self) -> FirestoreDb {
+        self.db.clone_with_session_params(
+            self.db
+                .get_session_params()
+                .clone()
+                .with_consistency_selector(FirestoreConsistencySelector::Transaction(
+                    self.data.transaction_id.clone(),
+                ))
+                .with_cache_mode(FirestoreDbSessionCacheMode::None),
+        )
+    }
+
+    fn ensure_no_writes_before_read(&self) -> FirestoreResult<()> {
+        if self.data.writes.is_empty() {
+            Ok(())
+        } else {
+            Err(FirestoreError::InvalidParametersError(
+                FirestoreInvalidParametersError::new(FirestoreInvalidParametersPublicDetails::new(
+                    "transaction".to_string(),
+                    "All reads of a transaction must be done before its writes".to_string(),
+                )),
+            ))
+        }
+    }
+
+    /// Returns the path of the first document written without a precondition that wasn't read
+    /// in the transaction.
+    fn find_blind_write(&self) -> Option<&str> {
+        self.data
+            .writes
+            .iter()
+            .filter(|write| write.current_document.is_none())
+            .filter_map(write_document_path)
+            .find(|document_path| !self.read_documents.contains(*document_path))
+    }
+
     pub async fn commit(mut self) -> FirestoreResult<FirestoreTransactionResponse> {
+        if self.forbid_blind_writes {
+            if let Some(document_path) = self.find_blind_write() {
+                let err = FirestoreError::InvalidParametersError(
+                    FirestoreInvalidParametersError::new(
+                        FirestoreInvalidParametersPublicDetails::new(
+                            "forbid_blind_writes".to_string(),
+                            format!(
+                                "Document {document_path} is written without being read first in the transaction"
+                            ),
+                        ),
+                    ),
+                );
+                self.rollback().await.ok();
+                return Err(err);
+            }
+        }
+
         self.finished = true;
 
         if self.data.writes.is_empty() {
@@ -163,9 +311,18 @@ impl<'a> FirestoreTransaction<'a> {
             });
         }
 
+        let writes = std::mem::take(&mut self.data.writes);
+        let writes_count = writes.len();
+        self.data
+            .transaction_span
+            .record("/firestore/writes_count", writes_count);
+
+        #[cfg(feature = "caching")]
+        let cached_writes = self.db.is_writing_through_cache().then(|| writes.clone());
+
         let request = gcloud_sdk::tonic::Request::new(CommitRequest {
             database: self.db.get_database_path().clone(),
-            writes: std::mem::take(&mut self.data.writes),
+            writes,
             transaction: self.data.transaction_id.clone(),
             request_options: self
                 .db
@@ -181,7 +338,15 @@ impl<'a> FirestoreTransaction<'a> {
                 .map(|s| s.try_into())
                 .collect::<FirestoreResult<Vec<FirestoreWriteResult>>>()?,
         )
-        .opt_commit_time(response.commit_time.map(from_timestamp).transpose()?);
+        .opt_commit_time(response.commit_time.map(from_timestamp).transpose()?)
+        .with_writes_count(writes_count)
+        .with_elapsed(FirestoreInstant::now().duration_since(self.started_at));
+
+        if let Some(ref elapsed) = result.elapsed {
+            self.data
+                .transaction_span
+                .record("/firestore/transaction_duration", elapsed.as_millis());
+        }
 
         if let Some(ref commit_time) = result.commit_time {
             self.data
@@ -193,6 +358,13 @@ impl<'a> FirestoreTransaction<'a> {
             debug!("Transaction has been committed.");
         });
 
+        #[cfg(feature = "caching")]
+        if let Some(writes) = cached_writes {
+            self.db
+                .write_through_cache_writes(&writes, &result.write_results)
+                .await;
+        }
+
         Ok(result)
     }
 
@@ -228,6 +400,13 @@ impl<'a> FirestoreTransaction<'a> {
         self.data.is_empty()
     }
 
+    /// Records the number of the attempt, starting from 1, in the transaction span.
+    pub(crate) fn record_attempt(&self, attempt: usize) {
+        self.data
+            .transaction_span
+            .record("/firestore/attempt", attempt);
+    }
+
     #[inline]
     pub fn transaction_data(&self) -> &FirestoreTransactionData {
         &self.data
@@ -242,6 +421,9 @@ impl<'a> FirestoreTransaction<'a> {
             data,
             finished: false,
             request_options: None,
+            read_documents: BTreeSet::new(),
+            forbid_blind_writes: false,
+            started_at: FirestoreInstant::now(),
         }
     }
 
@@ -271,6 +453,16 @@ impl FirestoreTransactionOps for FirestoreTransaction<'_> {
     }
 }
 
+fn write_document_path(write: &Write) -> Option<&str> {
+    match write.operation.as_ref()? {
+        gcloud_sdk::google::firestore::v1::write::Operation::Update(doc) => Some(&doc.name),
+        gcloud_sdk::google::firestore::v1::write::Operation::Delete(name) => Some(name),
+        gcloud_sdk::google::firestore::v1::write::Operation::Transform(transform) => {
+            Some(&transform.document)
+        }
+    }
+}
+
 impl<'a> Drop for FirestoreTransaction<'a> {
     fn drop(&mut self) {
         if !self.finished {
@@ -317,10 +509,35 @@ impl FirestoreDb {
         ) -> BoxFuture<'b, std::result::Result<T, BackoffError<E>>>,
         E: std::error::Error + Send + Sync + 'static,
     {
+        self.run_transaction_with_response(func, options)
+            .await
+            .map(|(ret_val, _)| ret_val)
+    }
+
+    /// The same as [`FirestoreDb::run_transaction_with_options`], also returning the commit
+    /// response with the number of attempts, the total elapsed time and why the failed
+    /// attempts were retried.
+    pub async fn run_transaction_with_response<T, FN, E>(
+        &self,
+        func: FN,
+        options: FirestoreTransactionOptions,
+    ) -> FirestoreResult<(T, FirestoreTransactionResponse)>
+    where
+        for<'b> FN: Fn(
+            FirestoreDb,
+            &'b mut FirestoreTransaction,
+        ) -> BoxFuture<'b, std::result::Result<T, BackoffError<E>>>,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let started_at = FirestoreInstant::now();
+        let attempt_failures: Mutex<Vec<FirestoreTransactionAttemptFailure>> =
+            Mutex::new(Vec::new());
+
         // Perform our initial attempt. If this fails and the backend tells us we can retry,
         // we'll try again with exponential backoff using the first attempt's transaction ID.
         let (transaction_id, transaction_span, initial_backoff_duration) = {
             let mut transaction = self.begin_transaction_with_options(options.clone()).await?;
+            transaction.record_attempt(1);
             let transaction_id = transaction.transaction_id().clone();
             let transaction_span = transaction.data.transaction_span.clone();
             let mut initial_backoff_duration: Option<Duration> = None;
@@ -332,7 +549,12 @@ impl FirestoreDb {
             match func(cdb, &mut transaction).await {
                 Ok(ret_val) => {
                     match transaction.commit().await {
-                        Ok(_) => return Ok(ret_val),
+                        Ok(response) => {
+                            return Ok((
+                                ret_val,
+                                response_with_attempts(response, 1, started_at, Vec::new()),
+                            ))
+                        }
                         Err(err) => match err {
                             FirestoreError::DatabaseError(ref db_err) if db_err.retry_possible => {
                                 transaction_span.in_scope(|| {
@@ -341,6 +563,12 @@ impl FirestoreDb {
                                         "Transient error occurred while committing transaction.",
                                     )
                                 });
+                                record_attempt_failure(
+                                    &attempt_failures,
+                                    &transaction_span,
+                                    1,
+                                    &err,
+                                );
                                 // Ignore; we'll try again below
                             }
                             other => return Err(other),
@@ -352,6 +580,7 @@ impl FirestoreDb {
                         transaction_span.in_scope(|| {
                             warn!(%err, delay = ?retry_after, "Transient error occurred in transaction function. Retrying after the specified delay.");
                         });
+                        record_attempt_failure(&attempt_failures, &transaction_span, 1, &err);
                         initial_backoff_duration = retry_after;
                         transaction.finish().ok();
                     }
@@ -381,7 +610,10 @@ impl FirestoreDb {
             )))
             .build();
 
+        let attempts = AtomicUsize::new(1);
+
         let retry_result = retry(backoff, || async {
+            let attempt = attempts.fetch_add(1, Ordering::Relaxed) + 1;
             let options = FirestoreTransactionOptions {
                 mode: FirestoreTransactionMode::ReadWriteRetry(transaction_id.clone()),
                 ..options.clone()
@@ -389,8 +621,13 @@ impl FirestoreDb {
             let mut transaction = self
                 .begin_transaction_with_options(options)
                 .await
-                .map_err(firestore_err_to_backoff)?;
+                .map_err(|err| {
+                    record_attempt_failure(&attempt_failures, &transaction_span, attempt, &err);
+                    firestore_err_to_backoff(err)
+                })?;
+            transaction.record_attempt(attempt);
             let transaction_id = transaction.transaction_id().clone();
+            let attempt_span = transaction.data.transaction_span.clone();
 
             let cdb = self.clone_with_consistency_selector(
                 FirestoreConsistencySelector::Transaction(transaction_id.clone()),
@@ -403,6 +640,7 @@ impl FirestoreDb {
                         transaction_span.in_scope(|| {
                             warn!(%err, delay = ?retry_after, "Transient error occurred in transaction function. Retrying after the specified delay.");
                         });
+                        record_attempt_failure(&attempt_failures, &attempt_span, attempt, &err);
 
                         let firestore_err = FirestoreError::ErrorInTransaction(
                             FirestoreErrorInTransaction::new(
@@ -433,12 +671,12 @@ impl FirestoreDb {
                 }
             })?;
 
-            transaction
-                .commit()
-                .await
-                .map_err(firestore_err_to_backoff)?;
+            let response = transaction.commit().await.map_err(|err| {
+                record_attempt_failure(&attempt_failures, &attempt_span, attempt, &err);
+                firestore_err_to_backoff(err)
+            })?;
 
-            Ok(ret_val)
+            Ok::<_, BackoffError<FirestoreError>>((ret_val, response))
         })
         .await;
 
@@ -459,6 +697,334 @@ impl FirestoreDb {
             }
         }
 
-        retry_result
+        let (ret_val, response) = retry_result?;
+        Ok((
+            ret_val,
+            response_with_attempts(
+                response,
+                attempts.into_inner(),
+                started_at,
+                attempt_failures.into_inner().unwrap_or_default(),
+            ),
+        ))
+    }
+
+    /// Runs `func` in a read-write transaction and commits it, retrying the whole transaction
+    /// with exponential backoff while it fails with a retryable error.
+    ///
+    /// Unlike [`FirestoreDb::run_transaction`], the closure is a plain async closure: it needs no
+    /// boxed futures, and it reports failures with [`FirestoreTransactionError`] instead of
+    /// `backoff` types. A [`FirestoreError`] converts with `?`, so contention (`ABORTED`) and other
+    /// errors the server marks as retryable are retried automatically, and so are retryable
+    /// commit errors. Reads through the given `FirestoreDb` are done in the transaction.
+    ///
+    /// A fatal error rolls back the transaction and is returned as
+    /// [`FirestoreError::ErrorInTransaction`]. So is the last retryable error once
+    /// `max_elapsed_time` of the options runs out.
+    pub async fn run_transaction_fn<T, FN>(&self, func: FN) -> FirestoreResult<T>
+    where
+        FN: AsyncFnMut(
+            &FirestoreDb,
+            &mut FirestoreTransaction<'_>,
+        ) -> Result<T, FirestoreTransactionError>,
+    {
+        self.run_transaction_fn_with_options(func, FirestoreTransactionOptions::new())
+            .await
+    }
+
+    pub async fn run_transaction_fn_with_options<T, FN>(
+        &self,
+        func: FN,
+        options: FirestoreTransactionOptions,
+    ) -> FirestoreResult<T>
+    where
+        FN: AsyncFnMut(
+            &FirestoreDb,
+            &mut FirestoreTransaction<'_>,
+        ) -> Result<T, FirestoreTransactionError>,
+    {
+        self.run_transaction_fn_with_response(func, options)
+            .await
+            .map(|(ret_val, _)| ret_val)
+    }
+
+    /// The same as [`FirestoreDb::run_transaction_fn_with_options`], also returning the commit
+    /// response with the number of attempts, the total elapsed time and why the failed
+    /// attempts were retried.
+    pub async fn run_transaction_fn_with_response<T, FN>(
+        &self,
+        mut func: FN,
+        options: FirestoreTransactionOptions,
+    ) -> FirestoreResult<(T, FirestoreTransactionResponse)>
+    where
+        FN: AsyncFnMut(
+            &FirestoreDb,
+            &mut FirestoreTransaction<'_>,
+        ) -> Result<T, FirestoreTransactionError>,
+    {
+        let started_at = FirestoreInstant::now();
+        let mut backoff = ExponentialBackoffBuilder::new()
+            .with_max_elapsed_time(
+                options
+                    .max_elapsed_time
+                    .map(std::time::Duration::try_from)
+                    .transpose()?,
+            )
+            .build();
+        let mut retried_transaction_id: Option<FirestoreTransactionId> = None;
+        let mut attempt = 0;
+        let mut attempt_failures: Vec<FirestoreTransactionAttemptFailure> = Vec::new();
+
+        loop {
+            attempt += 1;
+            let attempt_options = match retried_transaction_id {
+                Some(ref transaction_id) => FirestoreTransactionOptions {
+                    mode: FirestoreTransactionMode::ReadWriteRetry(transaction_id.clone()),
+                    ..options.clone()
+                },
+                None => options.clone(),
+            };
+
+            let mut transaction = match self.begin_transaction_with_options(attempt_options).await {
+                Ok(transaction) => transaction,
+                Err(FirestoreError::DatabaseError(ref db_err)) if db_err.retry_possible => {
+                    match backoff.next_backoff() {
+                        Some(delay) => {
+                            attempt_failures.push(FirestoreTransactionAttemptFailure::new(
+                                attempt,
+                                db_err.to_string(),
+                            ));
+                            tokio::time::sleep(delay).await;
+                            continue;
+                        }
+                        None => return Err(FirestoreError::DatabaseError(db_err.clone())),
+                    }
+                }
+                Err(err) => return Err(err),
+            };
+            transaction.record_attempt(attempt);
+            let transaction_id = transaction.transaction_id().clone();
+            let transaction_span = transaction.data.transaction_span.clone();
+
+            let cdb = self.clone_with_consistency_selector(
+                FirestoreConsistencySelector::Transaction(transaction_id.clone()),
+            );
+
+            let attempt_result = match func(&cdb, &mut transaction).await {
+                Ok(ret_val) => transaction
+                    .commit()
+                    .await
+                    .map(|response| (ret_val, response))
+                    .map_err(FirestoreTransactionError::from),
+                Err(err) => {
+                    transaction.rollback().await.ok();
+                    Err(err)
+                }
+            };
+
+            let err = match attempt_result {
+                Ok((ret_val, response)) => {
+                    return Ok((
+                        ret_val,
+                        response_with_attempts(response, attempt, started_at, attempt_failures),
+                    ))
+                }
+                Err(err) => err,
+            };
+
+            let delay = match err {
+                FirestoreTransactionError::Retryable {
+                    ref source,
+                    retry_after,
+                } => {
+                    let delay = retry_delay(&mut backoff, retry_after)?;
+                    transaction_span.in_scope(|| {
+                        warn!(
+                            %source,
+                            ?delay,
+                            "Retryable error occurred in transaction.",
+                        )
+                    });
+                    delay
+                }
+                FirestoreTransactionError::Fatal(_) => None,
+            };
+
+            match delay {
+                Some(delay) => {
+                    attempt_failures.push(attempt_failure(&transaction_span, attempt, &err));
+                    tokio::time::sleep(delay).await;
+                    retried_transaction_id = Some(transaction_id);
+                }
+                None => {
+                    return Err(FirestoreError::ErrorInTransaction(
+                        FirestoreErrorInTransaction::new(transaction_id, err.into_source()),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// The delay before retrying a transaction, or `None` once the backoff has run out of time. The
+/// delay requested by the error replaces the backoff interval, but not its time limit.
+fn retry_delay(
+    backoff: &mut impl Backoff,
+    retry_after: Option<FirestoreDuration>,
+) -> FirestoreResult<Option<Duration>> {
+    let delay = backoff.next_backoff();
+    match retry_after {
+        Some(retry_after) if delay.is_some() => Ok(Some(Duration::try_from(retry_after)?)),
+        _ => Ok(delay),
+    }
+}
+
+/// Records why an attempt of a transaction failed in its span, returning the failure to report
+/// with the response.
+fn attempt_failure(
+    attempt_span: &Span,
+    attempt: usize,
+    err: &dyn std::fmt::Display,
+) -> FirestoreTransactionAttemptFailure {
+    let reason = err.to_string();
+    attempt_span.record("/firestore/failure_reason", reason.as_str());
+    FirestoreTransactionAttemptFailure::new(attempt, reason)
+}
+
+fn record_attempt_failure(
+    attempt_failures: &Mutex<Vec<FirestoreTransactionAttemptFailure>>,
+    attempt_span: &Span,
+    attempt: usize,
+    err: &dyn std::fmt::Display,
+) {
+    if let Ok(mut attempt_failures) = attempt_failures.lock() {
+        attempt_failures.push(attempt_failure(attempt_span, attempt, err));
+    }
+}
+
+fn response_with_attempts(
+    response: FirestoreTransactionResponse,
+    attempts: usize,
+    started_at: FirestoreInstant,
+    attempt_failures: Vec<FirestoreTransactionAttemptFailure>,
+) -> FirestoreTransactionResponse {
+    FirestoreTransactionResponse {
+        attempts,
+        elapsed: Some(FirestoreInstant::now().duration_since(started_at)),
+        attempt_failures,
+        ..response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delays_are_limited_by_max_elapsed_time() {
+        let retry_after = Some(FirestoreDuration::from_secs(3));
+
+        let mut backoff = ExponentialBackoffBuilder::new()
+            .with_max_elapsed_time(None)
+            .build();
+        assert_eq!(
+            retry_delay(&mut backoff, retry_after).unwrap(),
+            Some(Duration::from_secs(3))
+        );
+
+        let mut backoff = ExponentialBackoffBuilder::new()
+            .with_max_elapsed_time(Some(Duration::ZERO))
+            .build();
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(retry_delay(&mut backoff, retry_after).unwrap(), None);
+        assert_eq!(retry_delay(&mut backoff, None).unwrap(), None);
+    }
+
+    #[cfg(feature = "caching-memory")]
+    #[tokio::test]
+    async fn reads_from_firestore_on_cached_handles() {
+        use crate::timestamp_utils::to_timestamp;
+        use crate::*;
+        use gcloud_sdk::google::firestore::v1::target_change::TargetChangeType;
+        use gcloud_sdk::google::firestore::v1::{Document, DocumentChange, TargetChange};
+        use std::collections::HashMap;
+        use std::sync::Arc;
+
+        const DOCS: &str = "projects/test-project/databases/(default)/documents";
+        const TARGET: u32 = 42;
+
+        let config = FirestoreCacheConfiguration::new().add_collection_config_at(
+            DOCS,
+            FirestoreCacheCollectionConfiguration::new(
+                "cities",
+                FirestoreListenerTarget::new(TARGET),
+                FirestoreCacheCollectionLoadMode::PreloadAllDocs,
+            ),
+        );
+        let backend: FirestoreSharedCacheBackend =
+            Arc::new(FirestoreMemoryCacheBackend::new(config).unwrap());
+        backend
+            .on_listen_event(FirestoreListenEvent::DocumentChange(DocumentChange {
+                document: Some(Document {
+                    name: format!("{DOCS}/cities/a"),
+                    fields: HashMap::new(),
+                    create_time: None,
+                    update_time: None,
+                }),
+                target_ids: vec![TARGET as i32],
+                removed_target_ids: vec![],
+            }))
+            .await
+            .unwrap();
+        backend
+            .on_listen_event(FirestoreListenEvent::TargetChange(TargetChange {
+                target_change_type: TargetChangeType::Current as i32,
+                target_ids: vec![TARGET as i32],
+                read_time: Some(to_timestamp(FirestoreInstant::now())),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+
+        // The offline client never answers, so only reads served by the cache complete
+        let offline_db = FirestoreDb::for_offline_tests().await;
+        for db in [
+            offline_db.with_cache(FirestoreDbSessionCacheMode::ReadThroughCache(
+                backend.clone(),
+            )),
+            offline_db.with_cache(FirestoreDbSessionCacheMode::WriteThroughCache(
+                backend.clone(),
+            )),
+        ] {
+            let mut transaction = FirestoreTransaction {
+                db: &db,
+                data: FirestoreTransactionData::new(
+                    b"test-transaction".to_vec(),
+                    db.get_documents_path().clone(),
+                    Span::none(),
+                    vec![],
+                ),
+                finished: true,
+                request_options: None,
+                read_documents: BTreeSet::new(),
+                forbid_blind_writes: true,
+                started_at: FirestoreInstant::now(),
+            };
+
+            let read = tokio::time::timeout(
+                Duration::from_millis(200),
+                transaction.get::<HashMap<String, String>, _>("cities", "a"),
+            )
+            .await;
+            assert!(read.is_err(), "the read was served by the cache");
+
+            let query = tokio::time::timeout(
+                Duration::from_millis(200),
+                transaction
+                    .query::<HashMap<String, String>>(FirestoreQueryParams::new("cities".into())),
+            )
+            .await;
+            assert!(query.is_err(), "the query was served by the cache");
+        }
     }
 }
@@ -26,6 +26,14 @@ pub struct FirestoreTransactionOptions {
     /// Request options (e.g. request tags) attached to the `BeginTransaction`,
     /// `Commit` and `Rollback` requests of this transaction.
     pub request_options: Option<FirestoreRequestOptions>,
+
+    /// Fails the commit if the transaction writes a document it hasn't read first through
+    /// [`FirestoreTransaction::get`](crate::FirestoreTransaction::get) or
+    /// [`FirestoreTransaction::query`](crate::FirestoreTransaction::query).
+    /// Writes with a precondition are allowed, since the precondition guards them already.
+    /// Defaults to `false`.
+    #[default = "false"]
+    pub forbid_blind_writes: bool,
 }
 
 impl Default for FirestoreTransactionOptions {
@@ -35,6 +43,7 @@ impl Default for FirestoreTransactionOptions {
             max_elapsed_time: None,
             concurrent_mode: None,
             request_options: None,
+            forbid_blind_writes: false,
         }
     }
 }
@@ -143,6 +152,27 @@ pub struct FirestoreTransactionResponse {
     /// The time at which the transaction was committed.
     /// This is `None` if the transaction was read-only or did not involve writes.
     pub commit_time: Option<FirestoreInstant>,
+    /// The number of writes committed in the transaction.
+    #[default = "0"]
+    pub writes_count: usize,
+    /// How many times the transaction was attempted. Always 1 for a transaction committed
+    /// directly; the retrying runners count every attempt, including the failed ones.
+    #[default = "1"]
+    pub attempts: usize,
+    /// The time from the beginning of the (first) transaction to its commit.
+    pub elapsed: Option<FirestoreDuration>,
+    /// Why the failed attempts, if any, had to be retried.
+    #[default = "Vec::new()"]
+    pub attempt_failures: Vec<FirestoreTransactionAttemptFailure>,
+}
+
+/// A failed attempt of a transaction that was retried afterwards.
+#[derive(Debug, Eq, PartialEq, Clone, Builder)]
+pub struct FirestoreTransactionAttemptFailure {
+    /// The number of the attempt, starting from 1.
+    pub attempt: usize,
+    /// The error that failed the attempt.
+    pub reason: String,
 }
 
 #[cfg(test)]
@@ -163,4 +193,14 @@ mod tests {
             Some(FirestoreRequestOptions::from_tags(["checkout"]))
         );
     }
+
+    #[test]
+    fn test_transaction_response_defaults_to_a_single_attempt() {
+        let response = FirestoreTransactionResponse::new(vec![]);
+
+        assert_eq!(response.attempts, 1);
+        assert_eq!(response.writes_count, 0);
+        assert!(response.elapsed.is_none());
+        assert!(response.attempt_failures.is_empty());
+    }
 }
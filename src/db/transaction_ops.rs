@@ -1,9 +1,9 @@
 use crate::db::safe_document_path;
 use crate::{
-    FirestoreDb, FirestoreError, FirestoreFieldTransform, FirestoreResult,
-    FirestoreWritePrecondition,
+    firestore_generate_document_id, FirestoreDb, FirestoreError, FirestoreFieldTransform,
+    FirestoreResult, FirestoreWritePrecondition,
 };
-use gcloud_sdk::google::firestore::v1::Write;
+use gcloud_sdk::google::firestore::v1::{Document, Write};
 use serde::Serialize;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -57,6 +57,84 @@ where
     }
 }
 
+/// Creates a document from an object, failing if a document with the same ID already exists.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct CreateObjectOperation<'a, T>
+where
+    T: Serialize + Sync + Send,
+{
+    pub parent: String,
+    pub collection_id: String,
+    pub document_id: String,
+    pub obj: &'a T,
+}
+
+impl<'a, T> TryInto<Write> for CreateObjectOperation<'a, T>
+where
+    T: Serialize + Sync + Send,
+{
+    type Error = FirestoreError;
+
+    fn try_into(self) -> Result<Write, Self::Error> {
+        let document = FirestoreDb::serialize_to_doc(
+            safe_document_path(
+                &self.parent,
+                self.collection_id.as_str(),
+                self.document_id.as_str(),
+            )?,
+            &self.obj,
+        )?;
+        create_document_write(document)
+    }
+}
+
+/// Creates a raw document, failing if a document with the same ID already exists.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct CreateDocumentOperation {
+    pub parent: String,
+    pub collection_id: String,
+    pub document_id: String,
+    pub document: Document,
+}
+
+impl TryInto<Write> for CreateDocumentOperation {
+    type Error = FirestoreError;
+
+    fn try_into(self) -> Result<Write, Self::Error> {
+        create_document_write(Document {
+            name: safe_document_path(
+                &self.parent,
+                self.collection_id.as_str(),
+                self.document_id.as_str(),
+            )?,
+            create_time: None,
+            update_time: None,
+            ..self.document
+        })
+    }
+}
+
+fn create_document_write(document: Document) -> FirestoreResult<Write> {
+    Ok(Write {
+        update_mask: None,
+        update_transforms: vec![],
+        current_document: Some(FirestoreWritePrecondition::Exists(false).try_into()?),
+        operation: Some(gcloud_sdk::google::firestore::v1::write::Operation::Update(
+            document,
+        )),
+    })
+}
+
+/// Returns the given document ID, or a generated one when none is given.
+pub(crate) fn document_id_or_generate<S>(document_id: Option<S>) -> String
+where
+    S: AsRef<str>,
+{
+    document_id
+        .map(|document_id| document_id.as_ref().to_string())
+        .unwrap_or_else(firestore_generate_document_id)
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub(crate) struct DeleteOperation<S>
 where
@@ -186,6 +264,85 @@ pub trait FirestoreTransactionOps {
         })
     }
 
+    /// Creates a document from an object, failing the whole write if it already exists.
+    ///
+    /// A random document ID is generated when `document_id` is `None`; use
+    /// [`firestore_generate_document_id`] up front instead when the ID is needed afterwards.
+    fn create_object<T, S>(
+        &mut self,
+        collection_id: &str,
+        document_id: Option<S>,
+        obj: &T,
+    ) -> FirestoreResult<&mut Self>
+    where
+        T: Serialize + Sync + Send,
+        S: AsRef<str>,
+    {
+        self.create_object_at(
+            &self.get_documents_path().clone(),
+            collection_id,
+            document_id,
+            obj,
+        )
+    }
+
+    fn create_object_at<T, S>(
+        &mut self,
+        parent: &str,
+        collection_id: &str,
+        document_id: Option<S>,
+        obj: &T,
+    ) -> FirestoreResult<&mut Self>
+    where
+        T: Serialize + Sync + Send,
+        S: AsRef<str>,
+    {
+        self.add(CreateObjectOperation {
+            parent: parent.to_string(),
+            collection_id: collection_id.to_string(),
+            document_id: document_id_or_generate(document_id),
+            obj,
+        })
+    }
+
+    /// Creates a raw document, failing the whole write if it already exists.
+    ///
+    /// A random document ID is generated when `document_id` is `None`.
+    fn create_document<S>(
+        &mut self,
+        collection_id: &str,
+        document_id: Option<S>,
+        document: Document,
+    ) -> FirestoreResult<&mut Self>
+    where
+        S: AsRef<str>,
+    {
+        self.create_document_at(
+            &self.get_documents_path().clone(),
+            collection_id,
+            document_id,
+            document,
+        )
+    }
+
+    fn create_document_at<S>(
+        &mut self,
+        parent: &str,
+        collection_id: &str,
+        document_id: Option<S>,
+        document: Document,
+    ) -> FirestoreResult<&mut Self>
+    where
+        S: AsRef<str>,
+    {
+        self.add(CreateDocumentOperation {
+            parent: parent.to_string(),
+            collection_id: collection_id.to_string(),
+            document_id: document_id_or_generate(document_id),
+            document,
+        })
+    }
+
     fn delete_by_id<S>(
         &mut self,
         collection_id: &str,
@@ -260,3 +417,77 @@ pub trait FirestoreTransactionOps {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gcloud_sdk::google::firestore::v1::precondition::ConditionType;
+    use gcloud_sdk::google::firestore::v1::write::Operation;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct TestCity {
+        name: String,
+    }
+
+    #[test]
+    fn creates_documents_only_if_absent() {
+        let city = TestCity {
+            name: "Oslo".to_string(),
+        };
+
+        let write: Write = CreateObjectOperation {
+            parent: "projects/p/databases/d/documents".to_string(),
+            collection_id: "cities".to_string(),
+            document_id: document_id_or_generate(Some("oslo")),
+            obj: &city,
+        }
+        .try_into()
+        .unwrap();
+
+        assert_eq!(
+            write.current_document.and_then(|cond| cond.condition_type),
+            Some(ConditionType::Exists(false))
+        );
+        match write.operation {
+            Some(Operation::Update(doc)) => {
+                assert_eq!(doc.name, "projects/p/databases/d/documents/cities/oslo");
+                assert!(doc.fields.contains_key("name"));
+            }
+            other => panic!("Unexpected operation: {other:?}"),
+        }
+
+        let write: Write = CreateDocumentOperation {
+            parent: "projects/p/databases/d/documents".to_string(),
+            collection_id: "cities".to_string(),
+            document_id: document_id_or_generate(None::<&str>),
+            document: Document::default(),
+        }
+        .try_into()
+        .unwrap();
+
+        match write.operation {
+            Some(Operation::Update(doc)) => {
+                let (_, document_id) = crate::db::split_document_path(&doc.name);
+                assert_eq!(
+                    document_id.len(),
+                    crate::firestore_document_functions::FIRESTORE_GENERATED_DOCUMENT_ID_LEN
+                );
+            }
+            other => panic!("Unexpected operation: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn rejects_created_document_ids_with_slashes() {
+        let result: FirestoreResult<Write> = CreateDocumentOperation {
+            parent: "projects/p/databases/d/documents".to_string(),
+            collection_id: "cities".to_string(),
+            document_id: "oslo/norway".to_string(),
+            document: Document::default(),
+        }
+        .try_into();
+
+        assert!(result.is_err());
+    }
+}
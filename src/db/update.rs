@@ -86,6 +86,11 @@ impl FirestoreUpdateSupport for FirestoreDb {
             "/firestore/response_time" = field::Empty,
         );
 
+        // Firestore returns only some of the fields, so the cache needs the written ones
+        #[cfg(feature = "caching")]
+        let written_for_cache = (return_only_fields.is_some() && self.is_writing_through_cache())
+            .then(|| (firestore_doc.clone(), update_only.clone()));
+
         let update_document_request = gcloud_sdk::tonic::Request::new(UpdateDocumentRequest {
             update_mask: update_only.map({
                 |vf| DocumentMask {
@@ -115,6 +120,17 @@ impl FirestoreUpdateSupport for FirestoreDb {
             debug!(collection_id, document_id, "Updated the document.");
         });
 
-        Ok(update_response.into_inner())
+        let updated_doc = update_response.into_inner();
+
+        #[cfg(feature = "caching")]
+        match written_for_cache {
+            Some((written_doc, update_only)) => {
+                self.write_through_cache_update(&written_doc, update_only.as_deref(), &updated_doc)
+                    .await;
+            }
+            None => self.write_through_cache_doc(&updated_doc).await,
+        }
+
+        Ok(updated_doc)
     }
 }
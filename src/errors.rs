@@ -118,6 +118,7 @@ impl std::error::Error for FirestoreSystemError {}
 ///
 /// This often wraps errors returned by the Firestore gRPC API.
 #[derive(Debug, Clone, Builder)]
+#[non_exhaustive]
 pub struct FirestoreDatabaseError {
     /// Generic public details about the error.
     pub public: FirestoreErrorPublicGenericDetails,
@@ -125,6 +126,8 @@ pub struct FirestoreDatabaseError {
     pub details: String,
     /// Indicates whether retrying the operation might succeed.
     pub retry_possible: bool,
+    /// The gRPC status code of the failed request, when the error comes from one.
+    pub grpc_code: Option<gcloud_sdk::tonic::Code>,
 }
 
 impl Display for FirestoreDatabaseError {
@@ -144,11 +147,14 @@ impl std::error::Error for FirestoreDatabaseError {}
 /// This can occur, for example, if trying to create a document that already exists
 /// or if an optimistic locking condition (e.g., based on `update_time`) is not met.
 #[derive(Debug, Clone, Builder)]
+#[non_exhaustive]
 pub struct FirestoreDataConflictError {
     /// Generic public details about the error.
     pub public: FirestoreErrorPublicGenericDetails,
     /// Specific details about the data conflict.
     pub details: String,
+    /// The gRPC status code of the failed request, when the error comes from one.
+    pub grpc_code: Option<gcloud_sdk::tonic::Code>,
 }
 
 impl Display for FirestoreDataConflictError {
@@ -168,11 +174,14 @@ impl std::error::Error for FirestoreDataConflictError {}
 /// This is typically returned when trying to access a document or resource
 /// that does not exist in Firestore.
 #[derive(Debug, Clone, Builder)]
+#[non_exhaustive]
 pub struct FirestoreDataNotFoundError {
     /// Generic public details about the error.
     pub public: FirestoreErrorPublicGenericDetails,
     /// A message providing more details about what data was not found.
     pub data_detail_message: String,
+    /// The gRPC status code of the failed request, when the error comes from one.
+    pub grpc_code: Option<gcloud_sdk::tonic::Code>,
 }
 
 impl Display for FirestoreDataNotFoundError {
@@ -269,34 +278,40 @@ impl From<gcloud_sdk::error::Error> for FirestoreError {
 impl From<gcloud_sdk::tonic::Status> for FirestoreError {
     fn from(status: gcloud_sdk::tonic::Status) -> Self {
         match status.code() {
-            gcloud_sdk::tonic::Code::AlreadyExists => {
-                FirestoreError::DataConflictError(FirestoreDataConflictError::new(
+            gcloud_sdk::tonic::Code::AlreadyExists => FirestoreError::DataConflictError(
+                FirestoreDataConflictError::new(
                     FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
                     format!("{status}"),
-                ))
-            }
-            gcloud_sdk::tonic::Code::NotFound => {
-                FirestoreError::DataNotFoundError(FirestoreDataNotFoundError::new(
+                )
+                .with_grpc_code(status.code()),
+            ),
+            gcloud_sdk::tonic::Code::NotFound => FirestoreError::DataNotFoundError(
+                FirestoreDataNotFoundError::new(
                     FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
                     format!("{status}"),
-                ))
-            }
+                )
+                .with_grpc_code(status.code()),
+            ),
             gcloud_sdk::tonic::Code::Aborted
             | gcloud_sdk::tonic::Code::Cancelled
             | gcloud_sdk::tonic::Code::Unavailable
-            | gcloud_sdk::tonic::Code::ResourceExhausted => {
-                FirestoreError::DatabaseError(FirestoreDatabaseError::new(
+            | gcloud_sdk::tonic::Code::ResourceExhausted => FirestoreError::DatabaseError(
+                FirestoreDatabaseError::new(
                     FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
                     format!("{status}"),
                     true,
-                ))
-            }
+                )
+                .with_grpc_code(status.code()),
+            ),
             gcloud_sdk::tonic::Code::Unknown => check_hyper_errors(status),
-            _ => FirestoreError::DatabaseError(FirestoreDatabaseError::new(
-                FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
-                format!("{status}"),
-                false,
-            )),
+            _ => FirestoreError::DatabaseError(
+                FirestoreDatabaseError::new(
+                    FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
+                    format!("{status}"),
+                    false,
+                )
+                .with_grpc_code(status.code()),
+            ),
         }
     }
 }
@@ -318,11 +333,14 @@ fn check_hyper_errors(status: gcloud_sdk::tonic::Status) -> FirestoreError {
                     true,
                 ))
             }
-            Some(err) => FirestoreError::DatabaseError(FirestoreDatabaseError::new(
-                FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
-                format!("Hyper error: {err}"),
-                false,
-            )),
+            Some(err) => FirestoreError::DatabaseError(
+                FirestoreDatabaseError::new(
+                    FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
+                    format!("Hyper error: {err}"),
+                    false,
+                )
+                .with_grpc_code(status.code()),
+            ),
             _ if status.code() == gcloud_sdk::tonic::Code::Unknown
                 && status.message().contains("transport error") =>
             {
@@ -332,17 +350,23 @@ fn check_hyper_errors(status: gcloud_sdk::tonic::Status) -> FirestoreError {
                     true,
                 ))
             }
-            _ => FirestoreError::DatabaseError(FirestoreDatabaseError::new(
+            _ => FirestoreError::DatabaseError(
+                FirestoreDatabaseError::new(
+                    FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
+                    format!("{status}"),
+                    false,
+                )
+                .with_grpc_code(status.code()),
+            ),
+        },
+        _ => FirestoreError::DatabaseError(
+            FirestoreDatabaseError::new(
                 FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
-                format!("{status}"),
+                format!("{status} without root cause"),
                 false,
-            )),
-        },
-        _ => FirestoreError::DatabaseError(FirestoreDatabaseError::new(
-            FirestoreErrorPublicGenericDetails::new(format!("{:?}", status.code())),
-            format!("{status} without root cause"),
-            false,
-        )),
+            )
+            .with_grpc_code(status.code()),
+        ),
     }
 }
 
@@ -520,6 +544,97 @@ impl std::error::Error for FirestoreErrorInTransaction {
     }
 }
 
+/// An error returned from a [`FirestoreDb::run_transaction_fn`](crate::FirestoreDb::run_transaction_fn)
+/// closure, telling the runner whether the transaction is worth another attempt.
+///
+/// Any [`FirestoreError`] converts into it with `?`: database errors the server marks as retryable,
+/// such as `ABORTED` on contention, become [`FirestoreTransactionError::Retryable`], and all others
+/// become [`FirestoreTransactionError::Fatal`].
+#[derive(Debug)]
+pub enum FirestoreTransactionError {
+    /// The attempt failed in a way that may succeed on another attempt. The transaction is rolled
+    /// back and the closure runs again after a backoff delay, or after `retry_after` if set.
+    Retryable {
+        /// The error that failed the attempt.
+        source: Box<dyn std::error::Error + Send + Sync>,
+        /// The delay to wait before the next attempt, instead of the backoff delay.
+        retry_after: Option<crate::FirestoreDuration>,
+    },
+    /// The attempt failed for good. The transaction is rolled back and not retried.
+    Fatal(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl FirestoreTransactionError {
+    /// Wraps an error that should make the runner retry the transaction.
+    pub fn retryable<E: std::error::Error + Send + Sync + 'static>(source: E) -> Self {
+        FirestoreTransactionError::Retryable {
+            source: Box::new(source),
+            retry_after: None,
+        }
+    }
+
+    /// Wraps an error that should make the runner retry the transaction after the given delay.
+    pub fn retry_after<E: std::error::Error + Send + Sync + 'static>(
+        source: E,
+        retry_after: crate::FirestoreDuration,
+    ) -> Self {
+        FirestoreTransactionError::Retryable {
+            source: Box::new(source),
+            retry_after: Some(retry_after),
+        }
+    }
+
+    /// Wraps an error that should make the runner give up on the transaction.
+    pub fn fatal<E: std::error::Error + Send + Sync + 'static>(source: E) -> Self {
+        FirestoreTransactionError::Fatal(Box::new(source))
+    }
+
+    /// Returns `true` if the transaction should be retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, FirestoreTransactionError::Retryable { .. })
+    }
+
+    pub(crate) fn into_source(self) -> Box<dyn std::error::Error + Send + Sync> {
+        match self {
+            FirestoreTransactionError::Retryable { source, .. } => source,
+            FirestoreTransactionError::Fatal(source) => source,
+        }
+    }
+}
+
+impl From<FirestoreError> for FirestoreTransactionError {
+    fn from(err: FirestoreError) -> Self {
+        match err {
+            FirestoreError::DatabaseError(ref db_err) if db_err.retry_possible => {
+                FirestoreTransactionError::retryable(err)
+            }
+            other => FirestoreTransactionError::fatal(other),
+        }
+    }
+}
+
+impl Display for FirestoreTransactionError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            FirestoreTransactionError::Retryable { source, .. } => {
+                write!(f, "Retryable error in transaction: {source}")
+            }
+            FirestoreTransactionError::Fatal(source) => {
+                write!(f, "Fatal error in transaction: {source}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FirestoreTransactionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FirestoreTransactionError::Retryable { source, .. } => Some(source.as_ref()),
+            FirestoreTransactionError::Fatal(source) => Some(source.as_ref()),
+        }
+    }
+}
+
 /// A re-export of [`backoff::Error`], used by the retrying transaction API.
 ///
 /// You need this type to signal from a [`FirestoreDb::run_transaction`](crate::FirestoreDb::run_transaction)
@@ -569,3 +684,23 @@ impl From<gcloud_sdk::prost::DecodeError> for FirestoreError {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_firestore_errors_in_transactions() {
+        let aborted: FirestoreTransactionError =
+            FirestoreError::from(gcloud_sdk::tonic::Status::aborted("contention")).into();
+        assert!(aborted.is_retryable());
+
+        let not_found: FirestoreTransactionError =
+            FirestoreError::from(gcloud_sdk::tonic::Status::not_found("missing")).into();
+        assert!(!not_found.is_retryable());
+
+        let invalid: FirestoreTransactionError =
+            FirestoreError::from(gcloud_sdk::tonic::Status::invalid_argument("invalid")).into();
+        assert!(!invalid.is_retryable());
+    }
+}
@@ -56,10 +56,7 @@ pub fn firestore_doc_get_field_by_path<'d>(
     doc: &'d FirestoreDocument,
     field_path: &str,
 ) -> Option<&'d gcloud_sdk::google::firestore::v1::value::ValueType> {
-    let field_path: Vec<String> = field_path
-        .split('.')
-        .map(|s| s.to_string().replace('`', ""))
-        .collect();
+    let field_path: Vec<String> = split_field_path(field_path);
     firestore_doc_get_field_by_path_arr(&doc.fields, &field_path)
 }
 
@@ -85,3 +82,147 @@ fn firestore_doc_get_field_by_path_arr<'d>(
         })
     })
 }
+
+#[cfg(feature = "caching")]
+/// Returns a copy of the document with only the given fields, the way Firestore applies a
+/// `DocumentMask` projection to what it returns.
+///
+/// Field paths use the same dot-separated notation as [`firestore_doc_get_field_by_path`], so
+/// nested fields are projected into their enclosing maps. Paths that do not exist in the document
+/// are skipped.
+pub(crate) fn firestore_doc_project_fields(
+    doc: &FirestoreDocument,
+    field_paths: &[String],
+) -> FirestoreDocument {
+    let mut fields = HashMap::new();
+    for field_path in field_paths {
+        let field_path_arr = split_field_path(field_path);
+        if let Some(value) = firestore_doc_get_field_by_path_arr(&doc.fields, &field_path_arr) {
+            firestore_fields_set_by_path_arr(
+                &mut fields,
+                &field_path_arr,
+                gcloud_sdk::google::firestore::v1::Value {
+                    value_type: Some(value.clone()),
+                },
+            );
+        }
+    }
+    FirestoreDocument {
+        fields,
+        ..doc.clone()
+    }
+}
+
+pub(crate) fn split_field_path(field_path: &str) -> Vec<String> {
+    field_path
+        .split('.')
+        .map(|s| s.to_string().replace('`', ""))
+        .collect()
+}
+
+#[cfg(feature = "caching")]
+/// Sets a field at the path, creating (or replacing non-map values with) the intermediate maps.
+pub(crate) fn firestore_fields_set_by_path_arr(
+    fields: &mut HashMap<String, gcloud_sdk::google::firestore::v1::Value>,
+    field_path_arr: &[String],
+    value: gcloud_sdk::google::firestore::v1::Value,
+) {
+    match field_path_arr {
+        [] => {}
+        [field_name] => {
+            fields.insert(field_name.clone(), value);
+        }
+        [field_name, rest @ ..] => {
+            let entry = fields.entry(field_name.clone()).or_default();
+            if !matches!(
+                entry.value_type,
+                Some(gcloud_sdk::google::firestore::v1::value::ValueType::MapValue(_))
+            ) {
+                entry.value_type = Some(
+                    gcloud_sdk::google::firestore::v1::value::ValueType::MapValue(
+                        gcloud_sdk::google::firestore::v1::MapValue::default(),
+                    ),
+                );
+            }
+            if let Some(gcloud_sdk::google::firestore::v1::value::ValueType::MapValue(
+                ref mut map_value,
+            )) = entry.value_type
+            {
+                firestore_fields_set_by_path_arr(&mut map_value.fields, rest, value);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "caching")]
+/// Removes a field at the path, leaving the enclosing maps in place.
+pub(crate) fn firestore_fields_remove_by_path_arr(
+    fields: &mut HashMap<String, gcloud_sdk::google::firestore::v1::Value>,
+    field_path_arr: &[String],
+) {
+    match field_path_arr {
+        [] => {}
+        [field_name] => {
+            fields.remove(field_name);
+        }
+        [field_name, rest @ ..] => {
+            if let Some(gcloud_sdk::google::firestore::v1::value::ValueType::MapValue(
+                ref mut map_value,
+            )) = fields
+                .get_mut(field_name)
+                .and_then(|value| value.value_type.as_mut())
+            {
+                firestore_fields_remove_by_path_arr(&mut map_value.fields, rest);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "caching")]
+/// Applies an update mask to `fields`, the way Firestore updates a document with one: the masked
+/// fields are copied from `input_doc`, and those missing from it are removed.
+pub(crate) fn firestore_fields_apply_update_mask(
+    fields: &mut HashMap<String, gcloud_sdk::google::firestore::v1::Value>,
+    input_doc: &FirestoreDocument,
+    field_paths: &[String],
+) {
+    for field_path in field_paths {
+        let field_path_arr = split_field_path(field_path);
+        match firestore_doc_get_field_by_path(input_doc, field_path) {
+            Some(value) => firestore_fields_set_by_path_arr(
+                fields,
+                &field_path_arr,
+                gcloud_sdk::google::firestore::v1::Value {
+                    value_type: Some(value.clone()),
+                },
+            ),
+            None => firestore_fields_remove_by_path_arr(fields, &field_path_arr),
+        }
+    }
+}
+
+/// The length of the document IDs generated by [`firestore_generate_document_id`].
+pub(crate) const FIRESTORE_GENERATED_DOCUMENT_ID_LEN: usize = 20;
+
+/// Generates a random document ID the way the Firestore client SDKs do: 20 alphanumeric
+/// characters, which makes collisions practically impossible.
+///
+/// Useful when the ID of a new document has to be known before it is written, e.g. to create it
+/// in a transaction or a batch and refer to it afterwards.
+///
+/// # Examples
+/// ```rust
+/// use firestore::firestore_generate_document_id;
+///
+/// let document_id = firestore_generate_document_id();
+/// assert_eq!(document_id.len(), 20);
+/// assert!(document_id.chars().all(|c| c.is_ascii_alphanumeric()));
+/// ```
+pub fn firestore_generate_document_id() -> String {
+    use rand::RngExt;
+
+    let mut rng = rand::rng();
+    (0..FIRESTORE_GENERATED_DOCUMENT_ID_LEN)
+        .map(|_| char::from(rng.sample(rand::distr::Alphanumeric)))
+        .collect()
+}
@@ -1,10 +1,11 @@
-use crate::errors::FirestoreError;
+use crate::errors::{FirestoreError, FirestoreSerializationError};
 use crate::timestamp_utils::{from_duration, from_timestamp};
-use crate::FirestoreTransactionId;
 use crate::{FirestoreDuration, FirestoreInstant};
+use crate::{FirestoreResult, FirestoreTransactionId, FirestoreWritePrecondition};
 use gcloud_sdk::google::firestore::v1::{Document, ExplainMetrics, RunQueryResponse};
 use gcloud_sdk::prost_types::value::Kind;
 use rsb_derive::Builder;
+use serde::Deserialize;
 use std::collections::BTreeMap;
 
 /// A container that pairs a document (or other data `T`) with its associated Firestore metadata.
@@ -26,6 +27,55 @@ pub struct FirestoreWithMetadata<T> {
     pub metadata: FirestoreDocumentMetadata,
 }
 
+/// An object read from Firestore together with the update time of its document.
+///
+/// The update time is the version of the document: writing the object back with
+/// [`FirestoreVersioned::precondition`] (or the fluent `update().versioned()`) only succeeds
+/// if nobody else has written the document in between, and fails with `FAILED_PRECONDITION`
+/// otherwise. [`FirestoreDb::compare_and_swap`](crate::FirestoreDb::compare_and_swap) runs
+/// that read-modify-write cycle in a loop.
+#[derive(Debug, PartialEq, Clone)]
+pub struct FirestoreVersioned<T> {
+    /// The object deserialized from the document.
+    pub object: T,
+    /// The time the document was last updated.
+    pub update_time: FirestoreInstant,
+}
+
+impl<T> FirestoreVersioned<T> {
+    /// A precondition that holds only while the document is still at this version.
+    pub fn precondition(&self) -> FirestoreWritePrecondition {
+        FirestoreWritePrecondition::UpdateTime(self.update_time)
+    }
+
+    /// Returns the object, dropping the version.
+    pub fn into_inner(self) -> T {
+        self.object
+    }
+}
+
+impl<T> FirestoreVersioned<T>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    /// Deserializes a document read from Firestore, which always carries an update time.
+    pub fn from_doc(doc: &Document) -> FirestoreResult<Self> {
+        let update_time = doc.update_time.ok_or_else(|| {
+            FirestoreError::DeserializeError(
+                FirestoreSerializationError::from_message(
+                    "Document has no update time to version it with",
+                )
+                .with_document_path(doc.name.clone()),
+            )
+        })?;
+
+        Ok(Self {
+            object: crate::firestore_serde::firestore_document_to_serializable(doc)?,
+            update_time: from_timestamp(update_time)?,
+        })
+    }
+}
+
 /// Metadata associated with a Firestore document or a query operation.
 #[derive(Debug, PartialEq, Clone, Builder)]
 pub struct FirestoreDocumentMetadata {
@@ -4,7 +4,10 @@
 //! and the data to be inserted into Firestore. It supports inserting both raw
 //! [`Document`](gcloud_sdk::google::firestore::v1::Document) types and serializable Rust objects.
 
-use crate::{FirestoreCreateSupport, FirestoreResult};
+use crate::{
+    FirestoreBatch, FirestoreBatchWriter, FirestoreCreateSupport, FirestoreResult,
+    FirestoreTransactionOps,
+};
 use gcloud_sdk::google::firestore::v1::Document;
 use serde::{Deserialize, Serialize};
 
@@ -284,6 +287,71 @@ where
                 .await
         }
     }
+
+    /// Adds this insert operation to a [`FirestoreTransaction`](crate::FirestoreTransaction).
+    ///
+    /// The write carries an `exists: false` precondition, so the whole transaction fails if the
+    /// document already exists. Fields to return are ignored, since writes in a transaction
+    /// return no documents.
+    ///
+    /// # Arguments
+    /// * `transaction`: A mutable reference to the transaction.
+    ///
+    /// # Returns
+    /// A `FirestoreResult` containing the mutable reference to the transaction.
+    #[inline]
+    pub fn add_to_transaction<'t, TO>(self, transaction: &'t mut TO) -> FirestoreResult<&'t mut TO>
+    where
+        TO: FirestoreTransactionOps,
+    {
+        if let Some(parent) = self.parent {
+            transaction.create_document_at(
+                parent.as_str(),
+                self.collection_id.as_str(),
+                self.document_id,
+                self.document,
+            )
+        } else {
+            transaction.create_document(
+                self.collection_id.as_str(),
+                self.document_id,
+                self.document,
+            )
+        }
+    }
+
+    /// Adds this insert operation to a [`FirestoreBatch`].
+    ///
+    /// The write carries an `exists: false` precondition, so it fails if the document
+    /// already exists.
+    ///
+    /// # Arguments
+    /// * `batch`: A mutable reference to the batch writer.
+    ///
+    /// # Type Parameters
+    /// * `W`: The type of the batch writer.
+    ///
+    /// # Returns
+    /// A `FirestoreResult` containing the mutable reference to the batch.
+    #[inline]
+    pub fn add_to_batch<'t, W>(
+        self,
+        batch: &'a mut FirestoreBatch<'t, W>,
+    ) -> FirestoreResult<&'a mut FirestoreBatch<'t, W>>
+    where
+        W: FirestoreBatchWriter,
+    {
+        if let Some(parent) = self.parent {
+            batch.create_document_at(
+                parent.as_str(),
+                self.collection_id.as_str(),
+                self.document_id,
+                self.document,
+            )
+        } else {
+            batch.create_document(self.collection_id.as_str(), self.document_id, self.document)
+        }
+    }
 }
 
 /// A builder for executing an insert operation with a serializable Rust object.
@@ -359,4 +427,65 @@ where
                 .await
         }
     }
+
+    /// Adds this insert operation to a [`FirestoreTransaction`](crate::FirestoreTransaction).
+    ///
+    /// The write carries an `exists: false` precondition, so the whole transaction fails if the
+    /// document already exists. Fields to return are ignored, since writes in a transaction
+    /// return no documents.
+    ///
+    /// # Arguments
+    /// * `transaction`: A mutable reference to the transaction.
+    ///
+    /// # Returns
+    /// A `FirestoreResult` containing the mutable reference to the transaction.
+    #[inline]
+    pub fn add_to_transaction<'t, TO>(self, transaction: &'t mut TO) -> FirestoreResult<&'t mut TO>
+    where
+        TO: FirestoreTransactionOps,
+    {
+        if let Some(parent) = self.parent {
+            transaction.create_object_at(
+                parent.as_str(),
+                self.collection_id.as_str(),
+                self.document_id,
+                self.object,
+            )
+        } else {
+            transaction.create_object(self.collection_id.as_str(), self.document_id, self.object)
+        }
+    }
+
+    /// Adds this insert operation to a [`FirestoreBatch`].
+    ///
+    /// The write carries an `exists: false` precondition, so it fails if the document
+    /// already exists.
+    ///
+    /// # Arguments
+    /// * `batch`: A mutable reference to the batch writer.
+    ///
+    /// # Type Parameters
+    /// * `W`: The type of the batch writer.
+    ///
+    /// # Returns
+    /// A `FirestoreResult` containing the mutable reference to the batch.
+    #[inline]
+    pub fn add_to_batch<'t, W>(
+        self,
+        batch: &'a mut FirestoreBatch<'t, W>,
+    ) -> FirestoreResult<&'a mut FirestoreBatch<'t, W>>
+    where
+        W: FirestoreBatchWriter,
+    {
+        if let Some(parent) = self.parent {
+            batch.create_object_at(
+                parent.as_str(),
+                self.collection_id.as_str(),
+                self.document_id,
+                self.object,
+            )
+        } else {
+            batch.create_object(self.collection_id.as_str(), self.document_id, self.object)
+        }
+    }
 }
@@ -5,21 +5,26 @@
 //! projections, and fetching documents by ID. It also serves as a base for
 //! aggregation queries and real-time listeners.
 
-use crate::errors::FirestoreError;
+use crate::errors::{
+    FirestoreError, FirestoreInvalidParametersError, FirestoreInvalidParametersPublicDetails,
+};
 use crate::select_aggregation_builder::FirestoreAggregationBuilder;
 use crate::select_filter_builder::FirestoreQueryFilterBuilder;
 use crate::{
     FirestoreAggregatedQueryParams, FirestoreAggregatedQuerySupport, FirestoreAggregation,
     FirestoreCollectionDocuments, FirestoreExplainOptions, FirestoreFindNearestDistanceMeasure,
-    FirestoreFindNearestOptions, FirestoreGetByIdSupport, FirestoreListenSupport,
-    FirestoreListener, FirestoreListenerParams, FirestoreListenerTarget,
-    FirestoreListenerTargetParams, FirestorePartition, FirestorePartitionQueryParams,
-    FirestoreQueryCollection, FirestoreQueryCursor, FirestoreQueryFilter, FirestoreQueryOrder,
-    FirestoreQueryParams, FirestoreQuerySupport, FirestoreRequestOptions, FirestoreRequestTag,
-    FirestoreResult, FirestoreResumeStateStorage, FirestoreTargetType, FirestoreVector,
+    FirestoreFindNearestOptions, FirestoreGetByIdSupport, FirestoreInstant, FirestoreListenChange,
+    FirestoreListenSupport, FirestoreListener, FirestoreListenerParams,
+    FirestoreListenerRetryPolicy, FirestoreListenerTarget, FirestoreListenerTargetParams,
+    FirestoreListenerTargetResumeType, FirestoreListenerToken, FirestorePartition,
+    FirestorePartitionQueryParams, FirestoreQueryCollection, FirestoreQueryCursor,
+    FirestoreQueryFilter, FirestoreQueryOrder, FirestoreQueryParams, FirestoreQuerySnapshot,
+    FirestoreQuerySupport, FirestoreRequestOptions, FirestoreRequestTag, FirestoreResult,
+    FirestoreResumeStateStorage, FirestoreTargetType, FirestoreVector, FirestoreVersioned,
     FirestoreWithMetadata,
 };
 use futures::stream::BoxStream;
+use futures::StreamExt;
 use gcloud_sdk::google::firestore::v1::Document;
 use serde::Deserialize;
 use std::collections::HashMap;
@@ -455,6 +460,19 @@ where
         }
     }
 
+    /// Sets up a real-time listener for consistent snapshots of the documents matching this query.
+    ///
+    /// Unlike [`listen()`](Self::listen), which forwards changes as they arrive, the listener
+    /// accumulates them and emits the full result set with its changes each time Firestore marks
+    /// it as consistent.
+    ///
+    /// # Returns
+    /// A [`FirestoreQuerySnapshotsListenerInitBuilder`] to configure and start the listener.
+    #[inline]
+    pub fn listen_query_snapshots(self) -> FirestoreQuerySnapshotsListenerInitBuilder<'a, D> {
+        FirestoreQuerySnapshotsListenerInitBuilder::new(self.listen())
+    }
+
     /// Specifies aggregations to be performed over the documents matching this query.
     ///
     /// The `aggregation` argument is a closure that receives a [`FirestoreAggregationBuilder`]
@@ -956,6 +974,54 @@ where
         }
     }
 
+    /// Fetches a single document by its ID and deserializes it into type `T`, together with
+    /// the document's update time.
+    ///
+    /// Write the object back with `update().versioned()` to only update it if it hasn't changed
+    /// since it was read. The document is always read from Firestore, as a cached version may be
+    /// outdated, and in full, since `versioned()` writes back the whole object: selecting
+    /// `fields()` is rejected with an invalid parameters error.
+    ///
+    /// # Arguments
+    /// * `document_id`: The ID of the document to fetch.
+    ///
+    /// # Returns
+    /// A `FirestoreResult` containing an `Option<FirestoreVersioned<T>>`. `None` if the document doesn't exist.
+    pub async fn one_versioned<S>(
+        self,
+        document_id: S,
+    ) -> FirestoreResult<Option<FirestoreVersioned<T>>>
+    where
+        S: AsRef<str> + Send,
+        D: Sync,
+    {
+        if self.return_only_fields.is_some() {
+            return Err(FirestoreError::InvalidParametersError(
+                FirestoreInvalidParametersError::new(FirestoreInvalidParametersPublicDetails::new(
+                    "return_only_fields".to_string(),
+                    "Versioned objects are written back in full, so they can't be read with selected fields"
+                        .to_string(),
+                )),
+            ));
+        }
+
+        let doc = if let Some(parent) = self.parent {
+            self.db
+                .get_doc_at_uncached(parent.as_str(), self.collection.as_str(), document_id)
+                .await
+        } else {
+            self.db
+                .get_doc_uncached(self.collection.as_str(), document_id)
+                .await
+        };
+
+        match doc {
+            Ok(doc) => Ok(Some(FirestoreVersioned::from_doc(&doc)?)),
+            Err(FirestoreError::DataNotFoundError(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
     /// Fetches multiple documents by their IDs in a batch and deserializes them into type `T`.
     ///
     /// Returns a stream of `(String, Option<T>)` tuples.
@@ -1224,11 +1290,14 @@ pub struct FirestoreDocChangesListenerInitBuilder<'a, D>
 where
     D: FirestoreListenSupport + Clone,
 {
-    _db: &'a D, // Keep a reference to the DB for lifetime, though not directly used in all methods
+    db: &'a D,
     listener_params: FirestoreListenerParams,
     target_type: FirestoreTargetType,
     labels: HashMap<String, String>,
     request_options: Option<FirestoreRequestOptions>,
+    resume_type: Option<FirestoreListenerTargetResumeType>,
+    fallback_read_time: Option<FirestoreInstant>,
+    limit_to_last: Option<u32>,
 }
 
 impl<'a, D> FirestoreDocChangesListenerInitBuilder<'a, D>
@@ -1239,11 +1308,14 @@ where
     #[inline]
     pub(crate) fn new(db: &'a D, target_type: FirestoreTargetType) -> Self {
         Self {
-            _db: db,
+            db,
             listener_params: FirestoreListenerParams::new(),
             target_type,
             labels: HashMap::new(),
             request_options: None,
+            resume_type: None,
+            fallback_read_time: None,
+            limit_to_last: None,
         }
     }
 
@@ -1307,6 +1379,94 @@ where
         }
     }
 
+    /// Sets how the listener reconnects after an error, instead of a fixed retry delay.
+    ///
+    /// # Arguments
+    /// * `retry_policy`: The [`FirestoreListenerRetryPolicy`] to use.
+    ///
+    /// # Returns
+    /// The builder instance with the retry policy set.
+    #[inline]
+    pub fn retry_policy(self, retry_policy: FirestoreListenerRetryPolicy) -> Self {
+        Self {
+            listener_params: self.listener_params.with_retry_policy(retry_policy),
+            ..self
+        }
+    }
+
+    /// Starts listening from a point in time, delivering the changes since then first.
+    ///
+    /// Firestore accepts read times within its version retention window: one hour, or up to
+    /// seven days with point-in-time recovery enabled. The resume state stored for the target is
+    /// not used.
+    ///
+    /// # Arguments
+    /// * `read_time`: The point in time to replay the changes from.
+    ///
+    /// # Returns
+    /// The builder instance with the read time set.
+    #[inline]
+    pub fn resume_from(self, read_time: FirestoreInstant) -> Self {
+        Self {
+            resume_type: Some(FirestoreListenerTargetResumeType::ReadTime(read_time)),
+            ..self
+        }
+    }
+
+    /// Starts listening from a resume token, instead of the one stored for the target.
+    ///
+    /// # Arguments
+    /// * `token`: The resume token to listen from.
+    ///
+    /// # Returns
+    /// The builder instance with the resume token set.
+    #[inline]
+    pub fn resume_from_token(self, token: FirestoreListenerToken) -> Self {
+        Self {
+            resume_type: Some(FirestoreListenerTargetResumeType::Token(token)),
+            ..self
+        }
+    }
+
+    /// Sets the read time to resume from when Firestore rejects the resume token of the target,
+    /// for instance because it expired.
+    ///
+    /// Without it, the listener resumes from the read time stored with the token when the
+    /// storage keeps one, or from now otherwise.
+    ///
+    /// # Arguments
+    /// * `read_time`: The point in time to replay the changes from.
+    ///
+    /// # Returns
+    /// The builder instance with the fallback read time set.
+    #[inline]
+    pub fn fallback_read_time(self, read_time: FirestoreInstant) -> Self {
+        Self {
+            fallback_read_time: Some(read_time),
+            ..self
+        }
+    }
+
+    /// Listens to the last `limit` documents of the query in its ordering, like `limit_to_last`
+    /// in the official SDKs.
+    ///
+    /// Firestore only limits queries from their start, so the listened query has its ordering
+    /// reversed and its cursors swapped. The query needs an ordering and no other limit, which is
+    /// checked when the target is added.
+    ///
+    /// # Arguments
+    /// * `limit`: The number of last documents to listen to.
+    ///
+    /// # Returns
+    /// The builder instance with the limit set.
+    #[inline]
+    pub fn limit_to_last(self, limit: u32) -> Self {
+        Self {
+            limit_to_last: Some(limit),
+            ..self
+        }
+    }
+
     /// Adds the configured target to an existing [`FirestoreListener`].
     ///
     /// This method finalizes the listener target configuration and registers it
@@ -1320,7 +1480,8 @@ where
     /// * `S`: The type of storage used for persisting resume states for the listener.
     ///
     /// # Returns
-    /// A `FirestoreResult` indicating success or failure of adding the target.
+    /// A `FirestoreResult` indicating success or failure of adding the target. Queries Firestore
+    /// cannot listen to are rejected: see [`FirestoreTargetType::validate`].
     #[inline]
     pub fn add_target<S>(
         self,
@@ -1330,13 +1491,220 @@ where
     where
         S: FirestoreResumeStateStorage + Send + Sync + Clone + 'static,
     {
+        let target_type = match self.limit_to_last {
+            Some(limit) => self.target_type.limit_to_last(limit)?,
+            None => self.target_type,
+        };
         listener.add_target(
-            FirestoreListenerTargetParams::new(target, self.target_type, self.labels)
+            FirestoreListenerTargetParams::new(target, target_type, self.labels)
+                .opt_resume_type(self.resume_type)
+                .opt_fallback_read_time(self.fallback_read_time)
                 .opt_request_options(self.request_options),
         )?;
 
         Ok(())
     }
+
+    /// Starts a dedicated listener for the configured target and streams the changes to its
+    /// result set, with the documents deserialized to `T`.
+    ///
+    /// Like the document changes of query snapshots in the official SDKs, each document is
+    /// reported as [`Added`](FirestoreListenChange::Added) when it enters the result set,
+    /// [`Modified`](FirestoreListenChange::Modified) when it is updated, and
+    /// [`Removed`](FirestoreListenChange::Removed) or [`Deleted`](FirestoreListenChange::Deleted)
+    /// when it leaves it. Dropping the stream shuts the listener down.
+    ///
+    /// # Arguments
+    /// * `target`: The target ID for the listener.
+    /// * `storage`: The storage for the resume state of the listener.
+    ///
+    /// # Type Parameters
+    /// * `T`: The type to deserialize the documents into.
+    /// * `S`: The type of storage used for persisting resume states for the listener.
+    ///
+    /// # Returns
+    /// A `FirestoreResult` containing a `BoxStream` of `FirestoreResult<FirestoreListenChange<T>>`.
+    /// Errors, including deserialization errors, are yielded as `Err` items in the stream.
+    pub async fn stream_changes<T, S>(
+        self,
+        target: FirestoreListenerTarget,
+        storage: S,
+    ) -> FirestoreResult<BoxStream<'a, FirestoreResult<FirestoreListenChange<T>>>>
+    where
+        for<'de> T: Deserialize<'de> + Send + 'a,
+        S: FirestoreResumeStateStorage + Send + Sync + Clone + 'static,
+    {
+        Ok(self
+            .stream_doc_changes(target, storage)
+            .await?
+            .map(|change| change.and_then(|change| change.deserialize_object()))
+            .boxed())
+    }
+
+    /// Starts a dedicated listener for the configured target and streams the changes to its
+    /// result set, with the raw documents.
+    ///
+    /// See [`stream_changes`](Self::stream_changes).
+    pub async fn stream_doc_changes<S>(
+        self,
+        target: FirestoreListenerTarget,
+        storage: S,
+    ) -> FirestoreResult<BoxStream<'a, FirestoreResult<FirestoreListenChange<Document>>>>
+    where
+        S: FirestoreResumeStateStorage + Send + Sync + Clone + 'static,
+    {
+        let mut listener =
+            FirestoreListener::new(self.db.clone(), storage, self.listener_params.clone()).await?;
+        self.add_target(target, &mut listener)?;
+        listener.start_changes_stream().await
+    }
+}
+
+/// Builder for initializing a listener of consistent query snapshots.
+///
+/// Created by [`FirestoreSelectDocBuilder::listen_query_snapshots()`].
+#[derive(Clone, Debug)]
+pub struct FirestoreQuerySnapshotsListenerInitBuilder<'a, D>
+where
+    D: FirestoreListenSupport + Clone,
+{
+    listener_init: FirestoreDocChangesListenerInitBuilder<'a, D>,
+}
+
+impl<'a, D> FirestoreQuerySnapshotsListenerInitBuilder<'a, D>
+where
+    D: FirestoreListenSupport + Clone + Send + Sync + 'static,
+{
+    /// Creates a new `FirestoreQuerySnapshotsListenerInitBuilder`.
+    #[inline]
+    pub(crate) fn new(listener_init: FirestoreDocChangesListenerInitBuilder<'a, D>) -> Self {
+        Self { listener_init }
+    }
+
+    /// Sets labels for the listener.
+    ///
+    /// See [`FirestoreDocChangesListenerInitBuilder::labels`].
+    #[inline]
+    pub fn labels(self, labels: HashMap<String, String>) -> Self {
+        Self::new(self.listener_init.labels(labels))
+    }
+
+    /// Attaches request tags to the listen requests.
+    ///
+    /// See [`FirestoreDocChangesListenerInitBuilder::request_tags`].
+    #[inline]
+    pub fn request_tags<I>(self, request_tags: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<FirestoreRequestTag>,
+    {
+        Self::new(self.listener_init.request_tags(request_tags))
+    }
+
+    /// Attaches request options to the listen requests.
+    ///
+    /// See [`FirestoreDocChangesListenerInitBuilder::request_options`].
+    #[inline]
+    pub fn request_options(self, options: FirestoreRequestOptions) -> Self {
+        Self::new(self.listener_init.request_options(options))
+    }
+
+    /// Sets the initial delay for retrying the listener connection on failure.
+    ///
+    /// See [`FirestoreDocChangesListenerInitBuilder::retry_delay`].
+    #[inline]
+    pub fn retry_delay(self, delay: std::time::Duration) -> Self {
+        Self::new(self.listener_init.retry_delay(delay))
+    }
+
+    /// Sets how the listener reconnects after an error.
+    ///
+    /// See [`FirestoreDocChangesListenerInitBuilder::retry_policy`].
+    #[inline]
+    pub fn retry_policy(self, retry_policy: FirestoreListenerRetryPolicy) -> Self {
+        Self::new(self.listener_init.retry_policy(retry_policy))
+    }
+
+    /// Starts listening from a point in time.
+    ///
+    /// See [`FirestoreDocChangesListenerInitBuilder::resume_from`].
+    #[inline]
+    pub fn resume_from(self, read_time: FirestoreInstant) -> Self {
+        Self::new(self.listener_init.resume_from(read_time))
+    }
+
+    /// Listens to the last `limit` documents of the query in its ordering.
+    ///
+    /// See [`FirestoreDocChangesListenerInitBuilder::limit_to_last`].
+    #[inline]
+    pub fn limit_to_last(self, limit: u32) -> Self {
+        Self::new(self.listener_init.limit_to_last(limit))
+    }
+
+    /// Sets the read time to resume from when Firestore rejects the resume token.
+    ///
+    /// See [`FirestoreDocChangesListenerInitBuilder::fallback_read_time`].
+    #[inline]
+    pub fn fallback_read_time(self, read_time: FirestoreInstant) -> Self {
+        Self::new(self.listener_init.fallback_read_time(read_time))
+    }
+
+    /// Starts a dedicated listener for the query and streams its consistent snapshots, with the
+    /// documents deserialized to `T`.
+    ///
+    /// The first snapshot contains the whole result set, with all documents added, as the resume
+    /// state stored for the target is not used. The next ones are only emitted when the result
+    /// set changed. Dropping the stream shuts the listener down.
+    ///
+    /// # Arguments
+    /// * `target`: The target ID for the listener.
+    /// * `storage`: The storage for the resume state of the listener.
+    ///
+    /// # Type Parameters
+    /// * `T`: The type to deserialize the documents into.
+    /// * `S`: The type of storage used for persisting resume states for the listener.
+    ///
+    /// # Returns
+    /// A `FirestoreResult` containing a `BoxStream` of `FirestoreResult<FirestoreQuerySnapshot<T>>`.
+    /// Errors, including deserialization errors, are yielded as `Err` items in the stream.
+    pub async fn stream<T, S>(
+        self,
+        target: FirestoreListenerTarget,
+        storage: S,
+    ) -> FirestoreResult<BoxStream<'a, FirestoreResult<FirestoreQuerySnapshot<T>>>>
+    where
+        for<'de> T: Deserialize<'de> + Send + 'a,
+        S: FirestoreResumeStateStorage + Send + Sync + Clone + 'static,
+    {
+        Ok(self
+            .stream_docs(target, storage)
+            .await?
+            .map(|snapshot| snapshot.and_then(|snapshot| snapshot.deserialize_objects()))
+            .boxed())
+    }
+
+    /// Starts a dedicated listener for the query and streams its consistent snapshots, with the
+    /// raw documents.
+    ///
+    /// See [`stream`](Self::stream).
+    pub async fn stream_docs<S>(
+        self,
+        target: FirestoreListenerTarget,
+        storage: S,
+    ) -> FirestoreResult<BoxStream<'a, FirestoreResult<FirestoreQuerySnapshot<Document>>>>
+    where
+        S: FirestoreResumeStateStorage + Send + Sync + Clone + 'static,
+    {
+        let listener_init = self.listener_init;
+        let mut listener = FirestoreListener::new(
+            listener_init.db.clone(),
+            storage,
+            listener_init.listener_params.clone(),
+        )
+        .await?;
+        listener_init.add_target(target, &mut listener)?;
+        listener.start_query_snapshots_stream().await
+    }
 }
 
 /// A builder for configuring and executing an aggregated query, returning raw documents.
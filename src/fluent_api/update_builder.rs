@@ -7,7 +7,7 @@
 use crate::document_transform_builder::FirestoreTransformBuilder;
 use crate::{
     FirestoreBatch, FirestoreBatchWriter, FirestoreFieldTransform, FirestoreResult,
-    FirestoreTransaction, FirestoreTransactionOps, FirestoreUpdateSupport,
+    FirestoreTransaction, FirestoreTransactionOps, FirestoreUpdateSupport, FirestoreVersioned,
     FirestoreWritePrecondition,
 };
 use gcloud_sdk::google::firestore::v1::Document;
@@ -386,6 +386,33 @@ where
         )
     }
 
+    /// Specifies a versioned object, read with `one_versioned()`, to update the document with.
+    ///
+    /// The update only succeeds if the document hasn't been updated since the object was read,
+    /// and fails with a `FailedPrecondition` database error otherwise. This replaces any
+    /// precondition set before.
+    ///
+    /// # Arguments
+    /// * `versioned`: A reference to the versioned object.
+    ///
+    /// # Returns
+    /// A [`FirestoreUpdateObjExecuteBuilder`] to execute the operation or add it to a batch/transaction.
+    #[inline]
+    pub fn versioned<T>(
+        self,
+        versioned: &'a FirestoreVersioned<T>,
+    ) -> FirestoreUpdateObjExecuteBuilder<'a, D, T>
+    where
+        T: Serialize + Sync + Send,
+        for<'de> T: Deserialize<'de>,
+    {
+        Self {
+            precondition: Some(versioned.precondition()),
+            ..self
+        }
+        .object(&versioned.object)
+    }
+
     /// Specifies server-side field transformations to apply.
     /// This method is used when the update consists *only* of transformations,
     /// without merging an object's fields.
@@ -0,0 +1,1809 @@
+//! An in-process fake of a Firestore database, for testing code built on the Fluent API.
+//!
+//! [`FirestoreInMemoryDb`] keeps documents in memory and answers the same Fluent API as
+//! [`FirestoreDb`]: reads by ID, queries (filters, ordering, cursors, offset and limit),
+//! aggregations, listings, creates, updates and deletes, including write preconditions. Queries
+//! are evaluated with the same engine the cache uses to serve them locally.
+//!
+//! It is a test double rather than an emulator. Listening to changes, transactions, batch writes,
+//! field transforms and vector search are not supported, and nothing is persisted.
+
+use crate::cache::cache_aggregation_engine::FirestoreCacheAggregationEngine;
+use crate::cache::cache_query_engine::FirestoreCacheQueryEngine;
+use crate::db::safe_document_path;
+use crate::errors::*;
+use crate::timestamp_utils::to_timestamp;
+use crate::*;
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::FutureExt;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use gcloud_sdk::google::firestore::v1::{Document, ListenResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Formatter;
+use std::future;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::*;
+
+struct FirestoreInMemoryDbState {
+    documents: BTreeMap<String, FirestoreDocument>,
+    last_write_time: Option<FirestoreInstant>,
+}
+
+struct FirestoreInMemoryDbInner {
+    database_path: String,
+    doc_path: String,
+    state: RwLock<FirestoreInMemoryDbState>,
+}
+
+/// An in-memory implementation of the Firestore database for offline tests.
+///
+/// Provides the same [Fluent API](FirestoreInMemoryDb::fluent) as [`FirestoreDb`], without a
+/// Firestore instance or the emulator. Documents get `create_time`/`update_time` on writes, so
+/// [`FirestoreWritePrecondition::UpdateTime`] works as it does against Firestore.
+///
+/// Instances are cheap to clone and clones share the same documents.
+///
+/// This type is only available if the `in-memory-db` feature is enabled.
+///
+/// # Examples
+///
+/// ```rust
+/// use firestore::*;
+/// use serde::{Deserialize, Serialize};
+///
+/// #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+/// struct City {
+///     name: String,
+///     population: u64,
+/// }
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> FirestoreResult<()> {
+/// let db = FirestoreInMemoryDb::new("test-project");
+///
+/// let city = City {
+///     name: "Stockholm".to_string(),
+///     population: 975_000,
+/// };
+///
+/// db.fluent()
+///     .insert()
+///     .into("cities")
+///     .document_id("sto")
+///     .object(&city)
+///     .execute::<()>()
+///     .await?;
+///
+/// let big_cities: Vec<City> = db
+///     .fluent()
+///     .select()
+///     .from("cities")
+///     .filter(|q| q.field(path!(City::population)).greater_than(500_000))
+///     .obj()
+///     .query()
+///     .await?;
+///
+/// assert_eq!(big_cities, vec![city]);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct FirestoreInMemoryDb {
+    inner: Arc<FirestoreInMemoryDbInner>,
+}
+
+impl FirestoreInMemoryDb {
+    /// Creates a new empty database for the specified Google Project ID.
+    pub fn new<S>(google_project_id: S) -> Self
+    where
+        S: AsRef<str>,
+    {
+        Self::with_options(FirestoreDbOptions::new(
+            google_project_id.as_ref().to_string(),
+        ))
+    }
+
+    /// Creates a new empty database with the specified options.
+    ///
+    /// Only the project and database IDs are used, to build the same document paths
+    /// [`FirestoreDb`] would.
+    pub fn with_options(options: FirestoreDbOptions) -> Self {
+        let database_path = format!(
+            "projects/{}/databases/{}",
+            options.google_project_id, options.database_id
+        );
+        let doc_path = format!("{database_path}/documents");
+
+        Self {
+            inner: Arc::new(FirestoreInMemoryDbInner {
+                database_path,
+                doc_path,
+                state: RwLock::new(FirestoreInMemoryDbState {
+                    documents: BTreeMap::new(),
+                    last_write_time: None,
+                }),
+            }),
+        }
+    }
+
+    /// Returns the full database path string (e.g., "projects/my-project/databases/(default)").
+    #[inline]
+    pub fn get_database_path(&self) -> &String {
+        &self.inner.database_path
+    }
+
+    /// Returns the base path for documents within this database
+    /// (e.g., "projects/my-project/databases/(default)/documents").
+    #[inline]
+    pub fn get_documents_path(&self) -> &String {
+        &self.inner.doc_path
+    }
+
+    /// Constructs a [`ParentPathBuilder`] for creating paths to sub-collections
+    /// under a specified document.
+    ///
+    /// # Errors
+    /// Returns [`FirestoreError::InvalidParametersError`] if the `document_id` is invalid.
+    #[inline]
+    pub fn parent_path<S>(
+        &self,
+        collection_name: &str,
+        document_id: S,
+    ) -> FirestoreResult<ParentPathBuilder>
+    where
+        S: AsRef<str>,
+    {
+        Ok(ParentPathBuilder::new(safe_document_path(
+            self.inner.doc_path.as_str(),
+            collection_name,
+            document_id.as_ref(),
+        )?))
+    }
+
+    /// Provides access to the Fluent API, in the same way as [`FirestoreDb::fluent()`].
+    #[inline]
+    pub fn fluent(&self) -> FirestoreExprBuilder<'_, FirestoreInMemoryDb> {
+        FirestoreExprBuilder::new(self)
+    }
+
+    /// Removes all documents.
+    pub async fn clear(&self) {
+        let mut state = self.inner.state.write().await;
+        state.documents.clear();
+    }
+
+    fn next_write_time(state: &mut FirestoreInMemoryDbState) -> FirestoreResult<FirestoreInstant> {
+        // Firestore timestamps have microsecond precision, and every write must get a distinct
+        // update time for update time preconditions to be meaningful.
+        let now = FirestoreInstant::from_microsecond(FirestoreInstant::now().as_microsecond())?;
+        let write_time = match state.last_write_time {
+            Some(last_write_time) if now <= last_write_time => {
+                last_write_time.checked_add(FirestoreDuration::from_micros(1))?
+            }
+            _ => now,
+        };
+        state.last_write_time = Some(write_time);
+        Ok(write_time)
+    }
+
+    fn check_precondition(
+        document_path: &str,
+        existing: Option<&FirestoreDocument>,
+        precondition: Option<&FirestoreWritePrecondition>,
+    ) -> FirestoreResult<()> {
+        match (precondition, existing) {
+            (None, _) => Ok(()),
+            (Some(FirestoreWritePrecondition::Exists(true)), None) => {
+                Err(Self::not_found_error(document_path))
+            }
+            (Some(FirestoreWritePrecondition::Exists(false)), Some(_)) => {
+                Err(Self::already_exists_error(document_path))
+            }
+            (Some(FirestoreWritePrecondition::Exists(_)), _) => Ok(()),
+            (Some(FirestoreWritePrecondition::UpdateTime(update_time)), Some(existing))
+                if existing.update_time == Some(to_timestamp(*update_time)) =>
+            {
+                Ok(())
+            }
+            (Some(FirestoreWritePrecondition::UpdateTime(update_time)), _) => {
+                Err(FirestoreError::DatabaseError(FirestoreDatabaseError::new(
+                    FirestoreErrorPublicGenericDetails::new("FailedPrecondition".into()),
+                    format!(
+                        "Document {document_path} was not last updated at {update_time} or does not exist"
+                    ),
+                    false,
+                )))
+            }
+        }
+    }
+
+    fn not_found_error(document_path: &str) -> FirestoreError {
+        FirestoreError::DataNotFoundError(FirestoreDataNotFoundError::new(
+            FirestoreErrorPublicGenericDetails::new("NotFound".into()),
+            format!("Document {document_path} not found"),
+        ))
+    }
+
+    fn already_exists_error(document_path: &str) -> FirestoreError {
+        FirestoreError::DataConflictError(FirestoreDataConflictError::new(
+            FirestoreErrorPublicGenericDetails::new("AlreadyExists".into()),
+            format!("Document {document_path} already exists"),
+        ))
+    }
+
+    fn unsupported_error(field: &str, operation: &str) -> FirestoreError {
+        FirestoreError::InvalidParametersError(FirestoreInvalidParametersError::new(
+            FirestoreInvalidParametersPublicDetails::new(
+                field.to_string(),
+                format!("{operation} is not supported by the in-memory database"),
+            ),
+        ))
+    }
+
+    fn project_doc(doc: &FirestoreDocument, return_only_fields: Option<&Vec<String>>) -> Document {
+        match return_only_fields {
+            Some(fields) => firestore_doc_project_fields(doc, fields),
+            None => doc.clone(),
+        }
+    }
+
+    /// Returns `true` if the document lives directly in one of the collections, or anywhere
+    /// below the parent in one of them when `all_descendants` is set.
+    fn is_doc_in_collections(
+        parent: &str,
+        document_path: &str,
+        collection_ids: &[String],
+        all_descendants: bool,
+    ) -> bool {
+        document_path
+            .strip_prefix(parent)
+            .and_then(|relative_path| relative_path.strip_prefix('/'))
+            .map(|relative_path| {
+                let segments: Vec<&str> = relative_path.split('/').collect();
+                segments.len() >= 2
+                    && (all_descendants || segments.len() == 2)
+                    && collection_ids
+                        .iter()
+                        .any(|collection_id| collection_id == segments[segments.len() - 2])
+            })
+            .unwrap_or(false)
+    }
+
+    async fn write_doc(
+        &self,
+        document_path: String,
+        input_doc: Document,
+        update_only: Option<Vec<String>>,
+        precondition: Option<FirestoreWritePrecondition>,
+    ) -> FirestoreResult<Document> {
+        let mut state = self.inner.state.write().await;
+        let existing = state.documents.get(&document_path);
+
+        Self::check_precondition(&document_path, existing, precondition.as_ref())?;
+
+        let create_time = existing.and_then(|doc| doc.create_time);
+        let fields = match update_only {
+            Some(update_only) => {
+                let mut fields = existing.map(|doc| doc.fields.clone()).unwrap_or_default();
+                firestore_fields_apply_update_mask(&mut fields, &input_doc, &update_only);
+                fields
+            }
+            None => input_doc.fields,
+        };
+
+        let write_time = to_timestamp(Self::next_write_time(&mut state)?);
+        let doc = Document {
+            name: document_path.clone(),
+            fields,
+            create_time: create_time.or(Some(write_time)),
+            update_time: Some(write_time),
+        };
+        state.documents.insert(document_path, doc.clone());
+
+        Ok(doc)
+    }
+
+    async fn query_docs(&self, params: &FirestoreQueryParams) -> FirestoreResult<Vec<Document>> {
+        if params.find_nearest.is_some() {
+            return Err(Self::unsupported_error("find_nearest", "Vector search"));
+        }
+
+        let parent = params
+            .parent
+            .as_ref()
+            .unwrap_or_else(|| self.get_documents_path());
+        let collection_ids = match &params.collection_id {
+            FirestoreQueryCollection::Single(collection_id) => vec![collection_id.clone()],
+            FirestoreQueryCollection::Group(collection_ids) => collection_ids.clone(),
+        };
+        let all_descendants = params.all_descendants.unwrap_or(false);
+        let query_engine = FirestoreCacheQueryEngine::new(params);
+
+        let matched_docs: Vec<Document> = {
+            let state = self.inner.state.read().await;
+            state
+                .documents
+                .iter()
+                .filter(|(document_path, _)| {
+                    Self::is_doc_in_collections(
+                        parent,
+                        document_path,
+                        &collection_ids,
+                        all_descendants,
+                    )
+                })
+                .map(|(_, doc)| doc)
+                .filter(|doc| query_engine.matches_doc(doc))
+                .cloned()
+                .collect()
+        };
+
+        // The query engine applies the projection as well
+        query_engine
+            .process_query_stream(futures::stream::iter(matched_docs.into_iter().map(Ok)).boxed())
+            .await?
+            .try_collect()
+            .await
+    }
+
+    fn list_query_params(params: &FirestoreListDocParams) -> FirestoreQueryParams {
+        FirestoreQueryParams::new(FirestoreQueryCollection::Single(
+            params.collection_id.clone(),
+        ))
+        .opt_parent(params.parent.clone())
+        .opt_order_by(params.order_by.clone())
+        .opt_return_only_fields(params.return_only_fields.clone())
+    }
+
+    /// Page tokens of the in-memory database are simply the position of the next page.
+    fn parse_page_token(page_token: Option<&String>) -> FirestoreResult<usize> {
+        page_token
+            .map(|token| {
+                token.parse::<usize>().map_err(|_| {
+                    FirestoreError::InvalidParametersError(FirestoreInvalidParametersError::new(
+                        FirestoreInvalidParametersPublicDetails::new(
+                            "page_token".to_string(),
+                            format!("Invalid page token: {token}"),
+                        ),
+                    ))
+                })
+            })
+            .transpose()
+            .map(|position| position.unwrap_or(0))
+    }
+
+    fn page_of<T>(
+        items: Vec<T>,
+        page_token: Option<&String>,
+        page_size: usize,
+    ) -> FirestoreResult<(Vec<T>, Option<String>)> {
+        let position = Self::parse_page_token(page_token)?;
+        let total = items.len();
+        let page: Vec<T> = items.into_iter().skip(position).take(page_size).collect();
+        let next_position = position + page.len();
+        let next_page_token = if !page.is_empty() && next_position < total {
+            Some(next_position.to_string())
+        } else {
+            None
+        };
+        Ok((page, next_page_token))
+    }
+
+    async fn collection_ids(&self, parent: Option<&String>) -> Vec<String> {
+        let parent = parent.unwrap_or_else(|| self.get_documents_path());
+        let state = self.inner.state.read().await;
+        state
+            .documents
+            .keys()
+            .filter_map(|document_path| {
+                document_path
+                    .strip_prefix(parent.as_str())
+                    .and_then(|relative_path| relative_path.strip_prefix('/'))
+                    .and_then(|relative_path| relative_path.split('/').next())
+                    .map(|collection_id| collection_id.to_string())
+            })
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for FirestoreInMemoryDb {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FirestoreInMemoryDb")
+            .field("database_path", &self.inner.database_path)
+            .field("doc_path", &self.inner.doc_path)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl FirestoreCreateSupport for FirestoreInMemoryDb {
+    async fn create_doc<S>(
+        &self,
+        collection_id: &str,
+        document_id: Option<S>,
+        input_doc: Document,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Document>
+    where
+        S: AsRef<str> + Send,
+    {
+        self.create_doc_at(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            input_doc,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn create_doc_at<S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: Option<S>,
+        input_doc: Document,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Document>
+    where
+        S: AsRef<str> + Send,
+    {
+        let document_id = document_id
+            .map(|id| id.as_ref().to_string())
+            .unwrap_or_else(firestore_generate_document_id);
+        let document_path = safe_document_path(parent, collection_id, &document_id)?;
+
+        let doc = self
+            .write_doc(
+                document_path,
+                input_doc,
+                None,
+                Some(FirestoreWritePrecondition::Exists(false)),
+            )
+            .await?;
+
+        Ok(Self::project_doc(&doc, return_only_fields.as_ref()))
+    }
+
+    async fn create_obj<I, O, S>(
+        &self,
+        collection_id: &str,
+        document_id: Option<S>,
+        obj: &I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<O>
+    where
+        I: Serialize + Sync + Send,
+        for<'de> O: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.create_obj_at(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            obj,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn create_obj_at<I, O, S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: Option<S>,
+        obj: &I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<O>
+    where
+        I: Serialize + Sync + Send,
+        for<'de> O: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        let input_doc = FirestoreDb::serialize_to_doc("", obj)?;
+
+        let doc = self
+            .create_doc_at(
+                parent,
+                collection_id,
+                document_id,
+                input_doc,
+                return_only_fields,
+            )
+            .await?;
+
+        FirestoreDb::deserialize_doc_to(&doc)
+    }
+}
+
+#[async_trait]
+impl FirestoreUpdateSupport for FirestoreInMemoryDb {
+    async fn update_obj<I, O, S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+        obj: &I,
+        update_only: Option<Vec<String>>,
+        return_only_fields: Option<Vec<String>>,
+        precondition: Option<FirestoreWritePrecondition>,
+    ) -> FirestoreResult<O>
+    where
+        I: Serialize + Sync + Send,
+        for<'de> O: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.update_obj_at(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            obj,
+            update_only,
+            return_only_fields,
+            precondition,
+        )
+        .await
+    }
+
+    async fn update_obj_at<I, O, S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        obj: &I,
+        update_only: Option<Vec<String>>,
+        return_only_fields: Option<Vec<String>>,
+        precondition: Option<FirestoreWritePrecondition>,
+    ) -> FirestoreResult<O>
+    where
+        I: Serialize + Sync + Send,
+        for<'de> O: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        let firestore_doc = FirestoreDb::serialize_to_doc(
+            safe_document_path(parent, collection_id, document_id.as_ref())?.as_str(),
+            obj,
+        )?;
+
+        let doc = self
+            .update_doc(
+                collection_id,
+                firestore_doc,
+                update_only,
+                return_only_fields,
+                precondition,
+            )
+            .await?;
+
+        FirestoreDb::deserialize_doc_to(&doc)
+    }
+
+    async fn update_doc(
+        &self,
+        _collection_id: &str,
+        firestore_doc: Document,
+        update_only: Option<Vec<String>>,
+        return_only_fields: Option<Vec<String>>,
+        precondition: Option<FirestoreWritePrecondition>,
+    ) -> FirestoreResult<Document> {
+        if !firestore_doc
+            .name
+            .starts_with(&format!("{}/", self.get_documents_path()))
+        {
+            return Err(FirestoreError::InvalidParametersError(
+                FirestoreInvalidParametersError::new(FirestoreInvalidParametersPublicDetails::new(
+                    "name".to_string(),
+                    format!(
+                        "Document name {} is not a path in this database",
+                        firestore_doc.name
+                    ),
+                )),
+            ));
+        }
+
+        let doc = self
+            .write_doc(
+                firestore_doc.name.clone(),
+                firestore_doc,
+                update_only,
+                precondition,
+            )
+            .await?;
+
+        Ok(Self::project_doc(&doc, return_only_fields.as_ref()))
+    }
+}
+
+#[async_trait]
+impl FirestoreDeleteSupport for FirestoreInMemoryDb {
+    async fn delete_by_id<S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+        precondition: Option<FirestoreWritePrecondition>,
+    ) -> FirestoreResult<()>
+    where
+        S: AsRef<str> + Send,
+    {
+        self.delete_by_id_at(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            precondition,
+        )
+        .await
+    }
+
+    async fn delete_by_id_at<S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        precondition: Option<FirestoreWritePrecondition>,
+    ) -> FirestoreResult<()>
+    where
+        S: AsRef<str> + Send,
+    {
+        let document_path = safe_document_path(parent, collection_id, document_id.as_ref())?;
+
+        let mut state = self.inner.state.write().await;
+        Self::check_precondition(
+            &document_path,
+            state.documents.get(&document_path),
+            precondition.as_ref(),
+        )?;
+        state.documents.remove(&document_path);
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FirestoreGetByIdSupport for FirestoreInMemoryDb {
+    async fn get_doc<S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Document>
+    where
+        S: AsRef<str> + Send,
+    {
+        self.get_doc_at(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn get_doc_at<S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Document>
+    where
+        S: AsRef<str> + Send,
+    {
+        let document_path = safe_document_path(parent, collection_id, document_id.as_ref())?;
+
+        let state = self.inner.state.read().await;
+        state
+            .documents
+            .get(&document_path)
+            .map(|doc| Self::project_doc(doc, return_only_fields.as_ref()))
+            .ok_or_else(|| Self::not_found_error(&document_path))
+    }
+
+    async fn get_obj<T, S>(&self, collection_id: &str, document_id: S) -> FirestoreResult<T>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.get_obj_at(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+        )
+        .await
+    }
+
+    async fn get_obj_return_fields<T, S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<T>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.get_obj_at_return_fields(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn get_obj_at<T, S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+    ) -> FirestoreResult<T>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.get_obj_at_return_fields(parent, collection_id, document_id, None)
+            .await
+    }
+
+    async fn get_obj_at_return_fields<T, S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<T>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        let doc = self
+            .get_doc_at(parent, collection_id, document_id, return_only_fields)
+            .await?;
+        FirestoreDb::deserialize_doc_to(&doc)
+    }
+
+    async fn get_obj_if_exists<T, S>(
+        &self,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Option<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        self.get_obj_at_if_exists(
+            self.get_documents_path().as_str(),
+            collection_id,
+            document_id,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn get_obj_at_if_exists<T, S>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_id: S,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<Option<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+        S: AsRef<str> + Send,
+    {
+        match self
+            .get_obj_at_return_fields::<T, S>(
+                parent,
+                collection_id,
+                document_id,
+                return_only_fields,
+            )
+            .await
+        {
+            Ok(obj) => Ok(Some(obj)),
+            Err(FirestoreError::DataNotFoundError(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn batch_stream_get_docs<S, I>(
+        &self,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<(String, Option<Document>)>>
+    where
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        self.batch_stream_get_docs_at(
+            self.get_documents_path(),
+            collection_id,
+            document_ids,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn batch_stream_get_docs_with_errors<S, I>(
+        &self,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<FirestoreResult<(String, Option<Document>)>>>
+    where
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        self.batch_stream_get_docs_at_with_errors(
+            self.get_documents_path(),
+            collection_id,
+            document_ids,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn batch_stream_get_docs_at<S, I>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<(String, Option<Document>)>>
+    where
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        let doc_stream = self
+            .batch_stream_get_docs_at_with_errors(
+                parent,
+                collection_id,
+                document_ids,
+                return_only_fields,
+            )
+            .await?;
+
+        Ok(Box::pin(doc_stream.filter_map(|doc_res| {
+            future::ready(match doc_res {
+                Ok(doc_pair) => Some(doc_pair),
+                Err(err) => {
+                    error!(
+                        %err,
+                        "Error occurred while consuming batch get as a stream.",
+                    );
+                    None
+                }
+            })
+        })))
+    }
+
+    async fn batch_stream_get_docs_at_with_errors<S, I>(
+        &self,
+        parent: &str,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<FirestoreResult<(String, Option<Document>)>>>
+    where
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        let document_ids: Vec<String> = document_ids
+            .into_iter()
+            .map(|document_id| document_id.as_ref().to_string())
+            .collect();
+        let full_doc_ids: Vec<String> = document_ids
+            .iter()
+            .map(|document_id| safe_document_path(parent, collection_id, document_id))
+            .collect::<FirestoreResult<Vec<String>>>()?;
+
+        let state = self.inner.state.read().await;
+        let found_docs: Vec<FirestoreResult<(String, Option<Document>)>> = document_ids
+            .into_iter()
+            .zip(full_doc_ids)
+            .map(|(document_id, document_path)| {
+                Ok((
+                    document_id,
+                    state
+                        .documents
+                        .get(&document_path)
+                        .map(|doc| Self::project_doc(doc, return_only_fields.as_ref())),
+                ))
+            })
+            .collect();
+
+        Ok(futures::stream::iter(found_docs).boxed())
+    }
+
+    async fn batch_stream_get_objects<'a, T, S, I>(
+        &'a self,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<'a, (String, Option<T>)>>
+    where
+        for<'de> T: Deserialize<'de> + Send + 'a,
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        self.batch_stream_get_objects_at(
+            self.get_documents_path(),
+            collection_id,
+            document_ids,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn batch_stream_get_objects_with_errors<'a, T, S, I>(
+        &'a self,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<'a, FirestoreResult<(String, Option<T>)>>>
+    where
+        for<'de> T: Deserialize<'de> + Send + 'a,
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        self.batch_stream_get_objects_at_with_errors(
+            self.get_documents_path(),
+            collection_id,
+            document_ids,
+            return_only_fields,
+        )
+        .await
+    }
+
+    async fn batch_stream_get_objects_at<'a, T, S, I>(
+        &'a self,
+        parent: &str,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<'a, (String, Option<T>)>>
+    where
+        for<'de> T: Deserialize<'de> + Send + 'a,
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        let doc_stream = self
+            .batch_stream_get_docs_at(parent, collection_id, document_ids, return_only_fields)
+            .await?;
+
+        Ok(Box::pin(doc_stream.filter_map(
+            |(doc_id, maybe_doc)| async move {
+                match maybe_doc {
+                    Some(doc) => match FirestoreDb::deserialize_doc_to(&doc) {
+                        Ok(obj) => Some((doc_id, Some(obj))),
+                        Err(err) => {
+                            error!(
+                                %err,
+                                "Error occurred while consuming batch documents as a stream. Document: {}",
+                                doc_id
+                            );
+                            None
+                        }
+                    },
+                    None => Some((doc_id, None)),
+                }
+            },
+        )))
+    }
+
+    async fn batch_stream_get_objects_at_with_errors<'a, T, S, I>(
+        &'a self,
+        parent: &str,
+        collection_id: &str,
+        document_ids: I,
+        return_only_fields: Option<Vec<String>>,
+    ) -> FirestoreResult<BoxStream<'a, FirestoreResult<(String, Option<T>)>>>
+    where
+        for<'de> T: Deserialize<'de> + Send + 'a,
+        S: AsRef<str> + Send,
+        I: IntoIterator<Item = S> + Send,
+    {
+        let doc_stream = self
+            .batch_stream_get_docs_at_with_errors(
+                parent,
+                collection_id,
+                document_ids,
+                return_only_fields,
+            )
+            .await?;
+
+        Ok(Box::pin(doc_stream.and_then(|(doc_id, maybe_doc)| {
+            future::ready({
+                maybe_doc
+                    .map(|doc| FirestoreDb::deserialize_doc_to::<T>(&doc))
+                    .transpose()
+                    .map(|obj| (doc_id, obj))
+            })
+        })))
+    }
+}
+
+#[async_trait]
+impl FirestoreQuerySupport for FirestoreInMemoryDb {
+    async fn query_doc(&self, params: FirestoreQueryParams) -> FirestoreResult<Vec<Document>> {
+        self.query_docs(&params).await
+    }
+
+    async fn stream_query_doc<'b>(
+        &self,
+        params: FirestoreQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, Document>> {
+        let docs = self.query_docs(&params).await?;
+        Ok(futures::stream::iter(docs).boxed())
+    }
+
+    async fn stream_query_doc_with_errors<'b>(
+        &self,
+        params: FirestoreQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<Document>>> {
+        let docs = self.query_docs(&params).await?;
+        Ok(futures::stream::iter(docs.into_iter().map(Ok)).boxed())
+    }
+
+    async fn stream_query_doc_with_metadata<'b>(
+        &self,
+        params: FirestoreQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<FirestoreWithMetadata<FirestoreDocument>>>>
+    {
+        let docs = self.query_docs(&params).await?;
+        let read_time = FirestoreInstant::now();
+        Ok(futures::stream::iter(docs.into_iter().map(move |doc| {
+            Ok(FirestoreWithMetadata {
+                document: Some(doc),
+                metadata: FirestoreDocumentMetadata::new(0).with_read_time(read_time),
+            })
+        }))
+        .boxed())
+    }
+
+    async fn query_obj<T>(&self, params: FirestoreQueryParams) -> FirestoreResult<Vec<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let doc_vec = self.query_doc(params).await?;
+        doc_vec
+            .iter()
+            .map(|doc| FirestoreDb::deserialize_doc_to(doc))
+            .collect()
+    }
+
+    async fn stream_query_obj<'b, T>(
+        &self,
+        params: FirestoreQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, T>>
+    where
+        for<'de> T: Deserialize<'de>,
+        T: Send + 'b,
+    {
+        let doc_stream = self.stream_query_doc(params).await?;
+        Ok(Box::pin(doc_stream.filter_map(|doc| async move {
+            match FirestoreDb::deserialize_doc_to::<T>(&doc) {
+                Ok(obj) => Some(obj),
+                Err(err) => {
+                    error!(
+                        %err,
+                        "Error occurred while deserializing a document inside a stream. Document: {}",
+                        doc.name
+                    );
+                    None
+                }
+            }
+        })))
+    }
+
+    async fn stream_query_obj_with_errors<'b, T>(
+        &self,
+        params: FirestoreQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<T>>>
+    where
+        for<'de> T: Deserialize<'de>,
+        T: Send + 'b,
+    {
+        let doc_stream = self.stream_query_doc_with_errors(params).await?;
+        Ok(Box::pin(doc_stream.and_then(|doc| {
+            future::ready(FirestoreDb::deserialize_doc_to::<T>(&doc))
+        })))
+    }
+
+    async fn stream_query_obj_with_metadata<'b, T>(
+        &self,
+        params: FirestoreQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<FirestoreWithMetadata<T>>>>
+    where
+        for<'de> T: Deserialize<'de>,
+        T: Send + 'b,
+    {
+        let doc_stream = self.stream_query_doc_with_metadata(params).await?;
+        Ok(Box::pin(doc_stream.and_then(|doc_with_meta| {
+            future::ready({
+                doc_with_meta
+                    .document
+                    .map(|document| FirestoreDb::deserialize_doc_to::<T>(&document))
+                    .transpose()
+                    .map(|obj| FirestoreWithMetadata {
+                        document: obj,
+                        metadata: doc_with_meta.metadata,
+                    })
+            })
+        })))
+    }
+
+    fn stream_partition_cursors_with_errors(
+        &self,
+        _params: FirestorePartitionQueryParams,
+    ) -> BoxFuture<'_, FirestoreResult<PeekableBoxStream<'_, FirestoreResult<FirestoreQueryCursor>>>>
+    {
+        // Reporting no cursors means the query is too small to be partitioned, the same way
+        // Firestore reports it for small collections.
+        async move { Ok(futures::stream::empty().boxed().peekable()) }.boxed()
+    }
+
+    async fn stream_partition_query_doc_with_errors(
+        &self,
+        _parallelism: usize,
+        partition_params: FirestorePartitionQueryParams,
+    ) -> FirestoreResult<BoxStream<'_, FirestoreResult<(FirestorePartition, Document)>>> {
+        let doc_stream = self
+            .stream_query_doc_with_errors(partition_params.query_params)
+            .await?;
+
+        Ok(doc_stream
+            .and_then(|doc| future::ready(Ok((FirestorePartition::new(), doc))))
+            .boxed())
+    }
+
+    async fn stream_partition_query_obj_with_errors<'a, T>(
+        &'a self,
+        parallelism: usize,
+        partition_params: FirestorePartitionQueryParams,
+    ) -> FirestoreResult<BoxStream<'a, FirestoreResult<(FirestorePartition, T)>>>
+    where
+        for<'de> T: Deserialize<'de>,
+        T: Send + 'a,
+    {
+        let doc_stream = self
+            .stream_partition_query_doc_with_errors(parallelism, partition_params)
+            .await?;
+
+        Ok(Box::pin(doc_stream.and_then(|(partition, doc)| {
+            future::ready(FirestoreDb::deserialize_doc_to::<T>(&doc).map(|obj| (partition, obj)))
+        })))
+    }
+}
+
+#[async_trait]
+impl FirestoreAggregatedQuerySupport for FirestoreInMemoryDb {
+    async fn aggregated_query_doc(
+        &self,
+        params: FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<Vec<Document>> {
+        let docs = self
+            .query_docs(&params.query_params.clone().opt_return_only_fields(None))
+            .await?;
+
+        Ok(vec![FirestoreCacheAggregationEngine::new(
+            &params.aggregations,
+        )
+        .aggregate_docs(&docs)])
+    }
+
+    async fn stream_aggregated_query_doc<'b>(
+        &self,
+        params: FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, Document>> {
+        let docs = self.aggregated_query_doc(params).await?;
+        Ok(futures::stream::iter(docs).boxed())
+    }
+
+    async fn stream_aggregated_query_doc_with_errors<'b>(
+        &self,
+        params: FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<Document>>> {
+        let docs = self.aggregated_query_doc(params).await?;
+        Ok(futures::stream::iter(docs.into_iter().map(Ok)).boxed())
+    }
+
+    async fn aggregated_query_obj<T>(
+        &self,
+        params: FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<Vec<T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let doc_vec = self.aggregated_query_doc(params).await?;
+        doc_vec
+            .iter()
+            .map(|doc| FirestoreDb::deserialize_doc_to(doc))
+            .collect()
+    }
+
+    async fn stream_aggregated_query_obj<'b, T>(
+        &self,
+        params: FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, T>>
+    where
+        for<'de> T: Deserialize<'de>,
+    {
+        let doc_stream = self.stream_aggregated_query_doc(params).await?;
+        Ok(Box::pin(doc_stream.filter_map(|doc| async move {
+            match FirestoreDb::deserialize_doc_to::<T>(&doc) {
+                Ok(obj) => Some(obj),
+                Err(err) => {
+                    error!(
+                        %err,
+                        "Error occurred while consuming query document as a stream.",
+                    );
+                    None
+                }
+            }
+        })))
+    }
+
+    async fn stream_aggregated_query_obj_with_errors<'b, T>(
+        &self,
+        params: FirestoreAggregatedQueryParams,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<T>>>
+    where
+        for<'de> T: Deserialize<'de>,
+        T: Send + 'b,
+    {
+        let doc_stream = self.stream_aggregated_query_doc_with_errors(params).await?;
+        Ok(Box::pin(doc_stream.and_then(|doc| {
+            future::ready(FirestoreDb::deserialize_doc_to::<T>(&doc))
+        })))
+    }
+}
+
+#[async_trait]
+impl FirestoreListingSupport for FirestoreInMemoryDb {
+    async fn list_doc(
+        &self,
+        params: FirestoreListDocParams,
+    ) -> FirestoreResult<FirestoreListDocResult> {
+        let docs = self.query_docs(&Self::list_query_params(&params)).await?;
+        let (documents, page_token) =
+            Self::page_of(docs, params.page_token.as_ref(), params.page_size)?;
+
+        Ok(FirestoreListDocResult::new(documents).opt_page_token(page_token))
+    }
+
+    async fn stream_list_doc<'b>(
+        &self,
+        params: FirestoreListDocParams,
+    ) -> FirestoreResult<BoxStream<'b, Document>> {
+        let docs = self.query_docs(&Self::list_query_params(&params)).await?;
+        Ok(futures::stream::iter(docs).boxed())
+    }
+
+    async fn stream_list_doc_with_errors<'b>(
+        &self,
+        params: FirestoreListDocParams,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<Document>>> {
+        let docs = self.query_docs(&Self::list_query_params(&params)).await?;
+        Ok(futures::stream::iter(docs.into_iter().map(Ok)).boxed())
+    }
+
+    async fn stream_list_obj<'b, T>(
+        &self,
+        params: FirestoreListDocParams,
+    ) -> FirestoreResult<BoxStream<'b, T>>
+    where
+        for<'de> T: Deserialize<'de> + 'b,
+    {
+        let doc_stream = self.stream_list_doc(params).await?;
+
+        Ok(Box::pin(doc_stream.filter_map(|doc| async move {
+            match FirestoreDb::deserialize_doc_to::<T>(&doc) {
+                Ok(obj) => Some(obj),
+                Err(err) => {
+                    error!(
+                        %err,
+                        "Error occurred while deserializing a document inside a stream. Document: {}",
+                        doc.name
+                    );
+                    None
+                }
+            }
+        })))
+    }
+
+    async fn stream_list_obj_with_errors<'b, T>(
+        &self,
+        params: FirestoreListDocParams,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<T>>>
+    where
+        for<'de> T: Deserialize<'de> + 'b,
+    {
+        let doc_stream = self.stream_list_doc_with_errors(params).await?;
+
+        Ok(Box::pin(doc_stream.and_then(|doc| async move {
+            FirestoreDb::deserialize_doc_to::<T>(&doc)
+        })))
+    }
+
+    async fn list_collection_ids(
+        &self,
+        params: FirestoreListCollectionIdsParams,
+    ) -> FirestoreResult<FirestoreListCollectionIdsResult> {
+        let collection_ids = self.collection_ids(params.parent.as_ref()).await;
+        let (collection_ids, page_token) =
+            Self::page_of(collection_ids, params.page_token.as_ref(), params.page_size)?;
+
+        Ok(FirestoreListCollectionIdsResult::new(collection_ids).opt_page_token(page_token))
+    }
+
+    async fn stream_list_collection_ids_with_errors(
+        &self,
+        params: FirestoreListCollectionIdsParams,
+    ) -> FirestoreResult<BoxStream<FirestoreResult<String>>> {
+        let collection_ids = self.collection_ids(params.parent.as_ref()).await;
+        Ok(futures::stream::iter(collection_ids.into_iter().map(Ok)).boxed())
+    }
+
+    async fn stream_list_collection_ids(
+        &self,
+        params: FirestoreListCollectionIdsParams,
+    ) -> FirestoreResult<BoxStream<String>> {
+        let collection_ids = self.collection_ids(params.parent.as_ref()).await;
+        Ok(futures::stream::iter(collection_ids).boxed())
+    }
+}
+
+#[async_trait]
+impl FirestoreListenSupport for FirestoreInMemoryDb {
+    async fn listen_doc_changes<'a, 'b>(
+        &'a self,
+        _targets: Vec<FirestoreListenerTargetParams>,
+    ) -> FirestoreResult<BoxStream<'b, FirestoreResult<ListenResponse>>> {
+        Err(Self::unsupported_error("targets", "Listening to changes"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::struct_path::paths;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct TestCity {
+        name: String,
+        country: String,
+        population: u64,
+    }
+
+    fn city(name: &str, country: &str, population: u64) -> TestCity {
+        TestCity {
+            name: name.to_string(),
+            country: country.to_string(),
+            population,
+        }
+    }
+
+    async fn db_with_cities() -> FirestoreResult<FirestoreInMemoryDb> {
+        let db = FirestoreInMemoryDb::new("test-project");
+        for (id, obj) in [
+            ("sto", city("Stockholm", "SE", 975_000)),
+            ("got", city("Gothenburg", "SE", 600_000)),
+            ("osl", city("Oslo", "NO", 700_000)),
+            ("ber", city("Bergen", "NO", 285_000)),
+        ] {
+            db.fluent()
+                .insert()
+                .into("cities")
+                .document_id(id)
+                .object(&obj)
+                .execute::<()>()
+                .await?;
+        }
+        Ok(db)
+    }
+
+    #[tokio::test]
+    async fn crud_through_fluent_api() -> FirestoreResult<()> {
+        let db = db_with_cities().await?;
+
+        let found: Option<TestCity> = db
+            .fluent()
+            .select()
+            .by_id_in("cities")
+            .obj()
+            .one("sto")
+            .await?;
+        assert_eq!(found, Some(city("Stockholm", "SE", 975_000)));
+
+        let updated: TestCity = db
+            .fluent()
+            .update()
+            .in_col("cities")
+            .document_id("sto")
+            .object(&city("Stockholm", "SE", 1_000_000))
+            .execute()
+            .await?;
+        assert_eq!(updated.population, 1_000_000);
+
+        db.fluent()
+            .delete()
+            .from("cities")
+            .document_id("sto")
+            .execute()
+            .await?;
+
+        let deleted: Option<TestCity> = db
+            .fluent()
+            .select()
+            .by_id_in("cities")
+            .obj()
+            .one("sto")
+            .await?;
+        assert_eq!(deleted, None);
+
+        let missing: Vec<(String, Option<TestCity>)> = db
+            .fluent()
+            .select()
+            .by_id_in("cities")
+            .obj()
+            .batch(vec!["got", "sto"])
+            .await?
+            .collect()
+            .await;
+        assert_eq!(
+            missing,
+            vec![
+                ("got".to_string(), Some(city("Gothenburg", "SE", 600_000))),
+                ("sto".to_string(), None),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_rejects_existing_documents() -> FirestoreResult<()> {
+        let db = db_with_cities().await?;
+
+        let result = db
+            .fluent()
+            .insert()
+            .into("cities")
+            .document_id("sto")
+            .object(&city("Stockholm", "SE", 1))
+            .execute::<()>()
+            .await;
+        assert!(matches!(result, Err(FirestoreError::DataConflictError(_))));
+
+        db.fluent()
+            .insert()
+            .into("cities")
+            .generate_document_id()
+            .object(&city("Malmö", "SE", 360_000))
+            .execute::<()>()
+            .await?;
+        let generated: Vec<Document> = db
+            .fluent()
+            .select()
+            .from("cities")
+            .filter(|q| q.field(path!(TestCity::name)).eq("Malmö"))
+            .query()
+            .await?;
+        let (_, generated_id) = crate::db::split_document_path(&generated[0].name);
+        assert_eq!(
+            generated_id.len(),
+            crate::firestore_document_functions::FIRESTORE_GENERATED_DOCUMENT_ID_LEN
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn query_filters_orders_and_pages() -> FirestoreResult<()> {
+        let db = db_with_cities().await?;
+
+        let names = |cities: Vec<TestCity>| -> Vec<String> {
+            cities.into_iter().map(|city| city.name).collect()
+        };
+
+        let swedish: Vec<TestCity> = db
+            .fluent()
+            .select()
+            .from("cities")
+            .filter(|q| q.field(path!(TestCity::country)).eq("SE"))
+            .order_by([(
+                path!(TestCity::population),
+                FirestoreQueryDirection::Ascending,
+            )])
+            .obj()
+            .query()
+            .await?;
+        assert_eq!(names(swedish), vec!["Gothenburg", "Stockholm"]);
+
+        let second_page: Vec<TestCity> = db
+            .fluent()
+            .select()
+            .from("cities")
+            .order_by([(
+                path!(TestCity::population),
+                FirestoreQueryDirection::Descending,
+            )])
+            .offset(1)
+            .limit(2)
+            .obj()
+            .query()
+            .await?;
+        assert_eq!(names(second_page), vec!["Oslo", "Gothenburg"]);
+
+        let after_cursor: Vec<TestCity> = db
+            .fluent()
+            .select()
+            .from("cities")
+            .order_by([(
+                path!(TestCity::population),
+                FirestoreQueryDirection::Ascending,
+            )])
+            .start_at(FirestoreQueryCursor::AfterValue(vec![600_000.into()]))
+            .limit(1)
+            .obj()
+            .query()
+            .await?;
+        assert_eq!(names(after_cursor), vec!["Oslo"]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn aggregated_query() -> FirestoreResult<()> {
+        let db = db_with_cities().await?;
+
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Stats {
+            cities: u64,
+            population: u64,
+        }
+
+        let stats: Vec<Stats> = db
+            .fluent()
+            .select()
+            .from("cities")
+            .filter(|q| q.field(path!(TestCity::country)).eq("NO"))
+            .aggregate(|a| {
+                a.fields([
+                    a.field("cities").count(),
+                    a.field("population").sum(path!(TestCity::population)),
+                ])
+            })
+            .obj()
+            .query()
+            .await?;
+
+        assert_eq!(
+            stats,
+            vec![Stats {
+                cities: 2,
+                population: 985_000
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_preconditions_and_masks() -> FirestoreResult<()> {
+        let db = db_with_cities().await?;
+
+        let doc = db
+            .fluent()
+            .select()
+            .by_id_in("cities")
+            .one("osl")
+            .await?
+            .expect("document must exist");
+        let update_time = doc
+            .update_time
+            .map(crate::timestamp_utils::from_timestamp)
+            .transpose()?
+            .expect("update time must be set");
+
+        let updated: TestCity = db
+            .fluent()
+            .update()
+            .fields(paths!(TestCity::population))
+            .in_col("cities")
+            .precondition(FirestoreWritePrecondition::UpdateTime(update_time))
+            .document_id("osl")
+            .object(&city("Ignored", "Ignored", 710_000))
+            .execute()
+            .await?;
+        assert_eq!(updated, city("Oslo", "NO", 710_000));
+
+        let stale_update = db
+            .fluent()
+            .update()
+            .in_col("cities")
+            .precondition(FirestoreWritePrecondition::UpdateTime(update_time))
+            .document_id("osl")
+            .object(&city("Oslo", "NO", 720_000))
+            .execute::<TestCity>()
+            .await;
+        assert!(matches!(
+            stale_update,
+            Err(FirestoreError::DatabaseError(ref err)) if err.public.code == "FailedPrecondition"
+        ));
+
+        let missing_update = db
+            .fluent()
+            .update()
+            .in_col("cities")
+            .precondition(FirestoreWritePrecondition::Exists(true))
+            .document_id("missing")
+            .object(&city("Nowhere", "NO", 0))
+            .execute::<TestCity>()
+            .await;
+        assert!(matches!(
+            missing_update,
+            Err(FirestoreError::DataNotFoundError(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn versioned_reads_and_updates() -> FirestoreResult<()> {
+        let db = db_with_cities().await?;
+
+        let versioned: FirestoreVersioned<TestCity> = db
+            .fluent()
+            .select()
+            .by_id_in("cities")
+            .obj()
+            .one_versioned("osl")
+            .await?
+            .expect("document must exist");
+        assert_eq!(versioned.object.name, "Oslo");
+
+        let changed = FirestoreVersioned {
+            object: city("Oslo", "NO", 710_000),
+            ..versioned.clone()
+        };
+        let updated: TestCity = db
+            .fluent()
+            .update()
+            .in_col("cities")
+            .document_id("osl")
+            .versioned(&changed)
+            .execute()
+            .await?;
+        assert_eq!(updated.population, 710_000);
+
+        let stale_update = db
+            .fluent()
+            .update()
+            .in_col("cities")
+            .document_id("osl")
+            .versioned(&versioned)
+            .execute::<TestCity>()
+            .await;
+        assert!(matches!(
+            stale_update,
+            Err(FirestoreError::DatabaseError(ref err)) if err.public.code == "FailedPrecondition"
+        ));
+
+        let missing: Option<FirestoreVersioned<TestCity>> = db
+            .fluent()
+            .select()
+            .by_id_in("cities")
+            .obj()
+            .one_versioned("missing")
+            .await?;
+        assert!(missing.is_none());
+
+        // Writing back a projection would delete the other fields
+        let projected = db
+            .fluent()
+            .select()
+            .fields(["name"])
+            .by_id_in("cities")
+            .obj::<TestCity>()
+            .one_versioned("osl")
+            .await;
+        assert!(matches!(
+            projected,
+            Err(FirestoreError::InvalidParametersError(ref err)) if err.public.field == "return_only_fields"
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listing_and_nested_collections() -> FirestoreResult<()> {
+        let db = db_with_cities().await?;
+
+        let parent = db.parent_path("cities", "sto")?;
+        db.fluent()
+            .insert()
+            .into("districts")
+            .document_id("sodermalm")
+            .parent(&parent)
+            .object(&city("Södermalm", "SE", 130_000))
+            .execute::<()>()
+            .await?;
+
+        let first_page = db
+            .fluent()
+            .list()
+            .from("cities")
+            .page_size(3)
+            .get_page()
+            .await?;
+        assert_eq!(first_page.documents.len(), 3);
+
+        let second_page = db
+            .fluent()
+            .list()
+            .from("cities")
+            .page_size(3)
+            .page_token(first_page.page_token.expect("must have a next page"))
+            .get_page()
+            .await?;
+        assert_eq!(second_page.documents.len(), 1);
+        assert_eq!(second_page.page_token, None);
+
+        let districts: Vec<TestCity> = db
+            .fluent()
+            .select()
+            .from("districts")
+            .parent(&parent)
+            .obj()
+            .query()
+            .await?;
+        assert_eq!(districts, vec![city("Södermalm", "SE", 130_000)]);
+
+        let group: Vec<Document> = db
+            .fluent()
+            .select()
+            .from("districts")
+            .all_descendants()
+            .query()
+            .await?;
+        assert_eq!(group.len(), 1);
+
+        let root_collections: Vec<String> = db
+            .fluent()
+            .list()
+            .collections()
+            .stream_all()
+            .await?
+            .collect()
+            .await;
+        assert_eq!(root_collections, vec!["cities".to_string()]);
+
+        let projected: Vec<Document> = db
+            .fluent()
+            .select()
+            .fields(paths!(TestCity::name))
+            .from("cities")
+            .filter(|q| q.field(path!(TestCity::name)).eq("Bergen"))
+            .query()
+            .await?;
+        assert_eq!(
+            projected
+                .iter()
+                .map(|doc| doc.fields.keys().cloned().collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+            vec![vec!["name".to_string()]]
+        );
+
+        Ok(())
+    }
+}
@@ -298,3 +298,17 @@ mod cache;
 /// It includes types like [`FirestoreCache`] and various
 /// caching backends and configurations.
 pub use cache::*;
+
+#[cfg(feature = "in-memory-db")]
+/// Provides an in-process fake of the database for offline tests.
+///
+/// This module is only available if the `in-memory-db` feature is enabled.
+mod in_memory_db;
+
+#[cfg(feature = "in-memory-db")]
+/// Re-exports all public items from the `in_memory_db` module.
+///
+/// This is only available if the `in-memory-db` feature is enabled.
+/// It includes [`FirestoreInMemoryDb`], which supports the Fluent API without
+/// a Firestore instance or the emulator.
+pub use in_memory_db::*;
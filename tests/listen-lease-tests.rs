@@ -0,0 +1,80 @@
+use crate::common::setup;
+use firestore::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+mod common;
+
+async fn wait_for_leader<S>(coordinators: &[&FirestoreListenerLeaseCoordinator<S>]) -> usize
+where
+    S: FirestoreResumeStateStorage + Clone + Send + Sync + 'static,
+{
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(leader) = coordinators.iter().position(|c| c.is_leader()) {
+                return leader;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("No leader elected")
+}
+
+#[tokio::test]
+async fn listener_lease_tests() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let db = setup().await?;
+
+    const TEST_COLLECTION_NAME: &str = "integration-test-listen-lease";
+    const TEST_LEASE_COLLECTION_NAME: &str = "integration-test-listen-leases";
+    const TEST_LEASE_ID: &str = "lease-tests";
+
+    db.fluent()
+        .delete()
+        .from(TEST_LEASE_COLLECTION_NAME)
+        .document_id(TEST_LEASE_ID)
+        .execute()
+        .await?;
+
+    let storage = FirestoreMemListenStateStorage::new();
+    let mut coordinators = Vec::new();
+    for holder_id in ["holder-1", "holder-2"] {
+        let mut coordinator = db.create_listener_lease_coordinator(
+            storage.clone(),
+            FirestoreListenerParams::new(),
+            FirestoreListenerLeaseParams::new(
+                TEST_LEASE_COLLECTION_NAME.to_string(),
+                TEST_LEASE_ID.to_string(),
+            )
+            .with_holder_id(holder_id.to_string())
+            .with_lease_duration(Duration::from_secs(3))
+            .with_renew_interval(Duration::from_millis(500)),
+        );
+        coordinator.add_target(FirestoreListenerTargetParams::new(
+            FirestoreListenerTarget::new(17),
+            FirestoreTargetType::Query(FirestoreQueryParams::new(TEST_COLLECTION_NAME.into())),
+            HashMap::new(),
+        ))?;
+        coordinator.start(|_| async { Ok(()) }).await?;
+        coordinators.push(coordinator);
+    }
+
+    let leader = wait_for_leader(&coordinators.iter().collect::<Vec<_>>()).await;
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(
+        coordinators.iter().filter(|c| c.is_leader()).count(),
+        1,
+        "Only one instance should hold the lease"
+    );
+
+    // The standby instance takes over once the leader releases the lease
+    let mut previous_leader = coordinators.remove(leader);
+    previous_leader.shutdown().await?;
+    wait_for_leader(&coordinators.iter().collect::<Vec<_>>()).await;
+
+    for mut coordinator in coordinators {
+        coordinator.shutdown().await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,66 @@
+use crate::common::setup;
+use firestore::*;
+
+mod common;
+
+#[tokio::test]
+async fn doc_listen_state_storage_tests() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let db = setup().await?;
+
+    const TEST_COLLECTION_NAME: &str = "integration-test-listen-state";
+
+    let storage = FirestoreDocListenStateStorage::new(db.clone(), TEST_COLLECTION_NAME)
+        .with_key_prefix("listen-state-tests-");
+    let target = FirestoreListenerTarget::new(42);
+
+    storage.delete_resume_state(&target).await?;
+    assert!(storage.read_resume_state(&target).await?.is_none());
+
+    storage
+        .update_resume_token(&target, b"test-token".to_vec().into())
+        .await?;
+    match storage.read_resume_state(&target).await? {
+        Some(FirestoreListenerTargetResumeType::Token(token)) => {
+            assert_eq!(token.into_value(), b"test-token".to_vec())
+        }
+        other => panic!("Unexpected resume state: {other:?}"),
+    }
+
+    storage.delete_resume_state(&target).await?;
+    assert!(storage.read_resume_state(&target).await?.is_none());
+
+    // Tokens coming in within the write interval are written when the storage is flushed
+    storage
+        .update_resume_token(&target, b"first-token".to_vec().into())
+        .await?;
+    storage
+        .update_resume_token(&target, b"second-token".to_vec().into())
+        .await?;
+
+    let other_storage = FirestoreDocListenStateStorage::new(db, TEST_COLLECTION_NAME)
+        .with_key_prefix("listen-state-tests-");
+    let stored_token = |state: Option<FirestoreListenerTargetResumeType>| match state {
+        Some(FirestoreListenerTargetResumeType::Token(token)) => token.into_value(),
+        other => panic!("Unexpected resume state: {other:?}"),
+    };
+
+    assert_eq!(
+        stored_token(storage.read_resume_state(&target).await?),
+        b"second-token".to_vec()
+    );
+    assert_eq!(
+        stored_token(other_storage.read_resume_state(&target).await?),
+        b"first-token".to_vec()
+    );
+
+    storage.flush().await?;
+    assert_eq!(
+        stored_token(other_storage.read_resume_state(&target).await?),
+        b"second-token".to_vec()
+    );
+
+    storage.delete_resume_state(&target).await?;
+    assert!(storage.read_resume_state(&target).await?.is_none());
+
+    Ok(())
+}
@@ -4,6 +4,7 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 mod common;
+use firestore::errors::{FirestoreError, FirestoreTransactionError};
 use firestore::*;
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -113,6 +114,345 @@ async fn transaction_tests() -> Result<(), Box<dyn std::error::Error + Send + Sy
     Ok(())
 }
 
+#[tokio::test]
+async fn transaction_fn_tests() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let db = setup().await?;
+
+    const TEST_COLLECTION_NAME: &str = "integration-test-transaction-fn";
+
+    let my_struct = MyTestStructure {
+        some_id: "test-fn-1".to_string(),
+        some_string: "Test".to_string(),
+    };
+
+    db.fluent()
+        .update()
+        .in_col(TEST_COLLECTION_NAME)
+        .document_id(&my_struct.some_id)
+        .object(&my_struct)
+        .execute::<()>()
+        .await?;
+
+    // Retrying until the closure succeeds, from a spawned task
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let updated: MyTestStructure = {
+        let db = db.clone();
+        let attempts = attempts.clone();
+        tokio::spawn(async move {
+            db.run_transaction_fn(async move |db, transaction| {
+                let mut current: MyTestStructure = db
+                    .fluent()
+                    .select()
+                    .by_id_in(TEST_COLLECTION_NAME)
+                    .obj()
+                    .one("test-fn-1")
+                    .await?
+                    .ok_or_else(|| {
+                        FirestoreTransactionError::fatal(common::CustomUserError::new("missing"))
+                    })?;
+
+                if attempts.fetch_add(1, Ordering::Relaxed) < 2 {
+                    return Err(FirestoreTransactionError::retryable(
+                        common::CustomUserError::new("test error"),
+                    ));
+                }
+
+                current.some_string = "Updated".to_string();
+                db.fluent()
+                    .update()
+                    .in_col(TEST_COLLECTION_NAME)
+                    .document_id(&current.some_id)
+                    .object(&current)
+                    .add_to_transaction(transaction)?;
+                Ok(current)
+            })
+            .await
+        })
+        .await??
+    };
+    assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    assert_eq!(updated.some_string, "Updated");
+
+    // Fatal errors are not retried
+    let attempts = AtomicUsize::new(0);
+    let res: FirestoreResult<()> = db
+        .run_transaction_fn(async |_db, _transaction| {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err(FirestoreTransactionError::fatal(
+                common::CustomUserError::new("test error"),
+            ))
+        })
+        .await;
+    assert!(matches!(res, Err(FirestoreError::ErrorInTransaction(_))));
+    assert_eq!(attempts.load(Ordering::Relaxed), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn transaction_response_stats_tests() -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+{
+    let db = setup().await?;
+
+    const TEST_COLLECTION_NAME: &str = "integration-test-transaction-stats";
+
+    let my_struct = MyTestStructure {
+        some_id: "test-stats-1".to_string(),
+        some_string: "Test".to_string(),
+    };
+
+    let attempts = AtomicUsize::new(0);
+    let (_, response) = db
+        .run_transaction_fn_with_response(
+            async |db, transaction| {
+                if attempts.fetch_add(1, Ordering::Relaxed) < 2 {
+                    return Err(FirestoreTransactionError::retryable(
+                        common::CustomUserError::new("test error"),
+                    ));
+                }
+                db.fluent()
+                    .update()
+                    .in_col(TEST_COLLECTION_NAME)
+                    .document_id(&my_struct.some_id)
+                    .object(&my_struct)
+                    .add_to_transaction(transaction)?;
+                Ok(())
+            },
+            FirestoreTransactionOptions::new(),
+        )
+        .await?;
+
+    assert_eq!(response.attempts, 3);
+    assert_eq!(response.writes_count, 1);
+    assert!(response.elapsed.is_some());
+    assert_eq!(
+        response
+            .attempt_failures
+            .iter()
+            .map(|failure| failure.attempt)
+            .collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+
+    let counter = AtomicUsize::new(0);
+    let (_, response) = db
+        .run_transaction_with_response(
+            |_db, _tx| {
+                let counter = counter.fetch_add(1, Ordering::Relaxed);
+                Box::pin(async move {
+                    if counter > 0 {
+                        return Ok(());
+                    }
+                    Err(backoff::Error::Transient {
+                        err: common::CustomUserError::new("test error"),
+                        retry_after: None,
+                    })
+                })
+            },
+            FirestoreTransactionOptions::new(),
+        )
+        .await?;
+
+    assert_eq!(response.attempts, 2);
+    assert_eq!(response.attempt_failures.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn transaction_read_tracking_tests() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let db = setup().await?;
+
+    const TEST_COLLECTION_NAME: &str = "integration-test-transaction-reads";
+
+    let my_struct = MyTestStructure {
+        some_id: "test-reads-1".to_string(),
+        some_string: "Test".to_string(),
+    };
+
+    db.fluent()
+        .update()
+        .in_col(TEST_COLLECTION_NAME)
+        .document_id(&my_struct.some_id)
+        .object(&my_struct)
+        .execute::<()>()
+        .await?;
+    db.fluent()
+        .delete()
+        .from(TEST_COLLECTION_NAME)
+        .document_id("test-reads-missing")
+        .execute()
+        .await?;
+
+    let options = FirestoreTransactionOptions::new().with_forbid_blind_writes(true);
+
+    // Reading documents records them, including missing ones
+    {
+        let mut transaction = db.begin_transaction_with_options(options.clone()).await?;
+        let found: Option<MyTestStructure> = transaction
+            .get(TEST_COLLECTION_NAME, &my_struct.some_id)
+            .await?;
+        assert_eq!(found, Some(my_struct.clone()));
+        let missing: Option<MyTestStructure> = transaction
+            .get(TEST_COLLECTION_NAME, "test-reads-missing")
+            .await?;
+        assert_eq!(missing, None);
+        let queried: Vec<MyTestStructure> = transaction
+            .query(FirestoreQueryParams::new(TEST_COLLECTION_NAME.into()))
+            .await?;
+        assert_eq!(queried, vec![my_struct.clone()]);
+        assert_eq!(transaction.read_documents().len(), 2);
+
+        transaction.update_object(
+            TEST_COLLECTION_NAME,
+            &my_struct.some_id,
+            &my_struct,
+            None,
+            None,
+            vec![],
+        )?;
+
+        // Reads after writes are rejected
+        let read_after_write: FirestoreResult<Option<MyTestStructure>> = transaction
+            .get(TEST_COLLECTION_NAME, &my_struct.some_id)
+            .await;
+        assert!(matches!(
+            read_after_write,
+            Err(FirestoreError::InvalidParametersError(_))
+        ));
+
+        transaction.commit().await?;
+    }
+
+    // Writing an unread document fails the commit
+    {
+        let mut transaction = db.begin_transaction_with_options(options.clone()).await?;
+        transaction.update_object(
+            TEST_COLLECTION_NAME,
+            &my_struct.some_id,
+            &my_struct,
+            None,
+            None,
+            vec![],
+        )?;
+        match transaction.commit().await {
+            Err(FirestoreError::InvalidParametersError(err)) => {
+                assert_eq!(err.public.field, "forbid_blind_writes")
+            }
+            other => panic!("Unexpected commit result: {other:?}"),
+        }
+    }
+
+    // Writes with a precondition are not blind
+    {
+        let mut transaction = db.begin_transaction_with_options(options).await?;
+        transaction.update_object(
+            TEST_COLLECTION_NAME,
+            &my_struct.some_id,
+            &my_struct,
+            None,
+            Some(FirestoreWritePrecondition::Exists(true)),
+            vec![],
+        )?;
+        transaction.commit().await?;
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn transaction_insert_tests() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let db = setup().await?;
+
+    const TEST_COLLECTION_NAME: &str = "integration-test-transaction-inserts";
+
+    let my_struct = MyTestStructure {
+        some_id: "test-insert-1".to_string(),
+        some_string: "Test".to_string(),
+    };
+    let generated_id = firestore_generate_document_id();
+
+    for document_id in [&my_struct.some_id, &generated_id] {
+        db.fluent()
+            .delete()
+            .from(TEST_COLLECTION_NAME)
+            .document_id(document_id)
+            .execute()
+            .await?;
+    }
+
+    // Creating absent documents in a transaction
+    {
+        let mut transaction = db.begin_transaction().await?;
+        db.fluent()
+            .insert()
+            .into(TEST_COLLECTION_NAME)
+            .document_id(&my_struct.some_id)
+            .object(&my_struct)
+            .add_to_transaction(&mut transaction)?;
+        transaction.create_object(TEST_COLLECTION_NAME, Some(&generated_id), &my_struct)?;
+        transaction.commit().await?;
+    }
+
+    let created: Option<MyTestStructure> = db
+        .fluent()
+        .select()
+        .by_id_in(TEST_COLLECTION_NAME)
+        .obj()
+        .one(&generated_id)
+        .await?;
+    assert_eq!(created, Some(my_struct.clone()));
+
+    // Creating an existing document fails the whole transaction
+    {
+        let mut transaction = db.begin_transaction().await?;
+        transaction.update_object(
+            TEST_COLLECTION_NAME,
+            &generated_id,
+            &MyTestStructure {
+                some_id: generated_id.clone(),
+                some_string: "Updated".to_string(),
+            },
+            None,
+            None,
+            vec![],
+        )?;
+        transaction.create_object(TEST_COLLECTION_NAME, Some(&my_struct.some_id), &my_struct)?;
+        assert!(transaction.commit().await.is_err());
+    }
+
+    let not_updated: Option<MyTestStructure> = db
+        .fluent()
+        .select()
+        .by_id_in(TEST_COLLECTION_NAME)
+        .obj()
+        .one(&generated_id)
+        .await?;
+    assert_eq!(not_updated, Some(my_struct.clone()));
+
+    // Creating documents in batches
+    let batch_writer = db.create_simple_batch_writer().await?;
+    let mut batch = batch_writer.new_batch();
+    db.fluent()
+        .insert()
+        .into(TEST_COLLECTION_NAME)
+        .document_id(&my_struct.some_id)
+        .object(&my_struct)
+        .add_to_batch(&mut batch)?;
+    db.fluent()
+        .insert()
+        .into(TEST_COLLECTION_NAME)
+        .generate_document_id()
+        .object(&my_struct)
+        .add_to_batch(&mut batch)?;
+    let response = batch.write().await?;
+    assert_eq!(response.statuses.len(), 2);
+    assert_ne!(response.statuses[0].code, 0);
+    assert_eq!(response.statuses[1].code, 0);
+
+    Ok(())
+}
+
 /// `FirestoreTransactionOps` is public on purpose: it exists so that transaction operations are
 /// available on both `FirestoreTransaction` and `FirestoreTransactionData`, which lets callers
 /// write transaction-agnostic abstractions over the trait.
@@ -131,6 +471,7 @@ where
 {
     ops.update_object(collection_id, document_id, obj, None, None, vec![])?;
     ops.delete_by_id(collection_id, document_id, None)?;
+    ops.create_object(collection_id, Some(document_id), obj)?;
     Ok(())
 }
 
@@ -69,3 +69,69 @@ async fn precondition_tests() -> Result<(), Box<dyn std::error::Error + Send + S
 
     Ok(())
 }
+
+#[tokio::test]
+async fn compare_and_swap_tests() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let db = setup().await?;
+
+    const TEST_COLLECTION_NAME: &str = "integration-test-compare-and-swap";
+
+    let my_struct = MyTestStructure {
+        some_id: "test-cas-1".to_string(),
+        some_string: "0".to_string(),
+    };
+
+    db.fluent()
+        .update()
+        .in_col(TEST_COLLECTION_NAME)
+        .document_id(&my_struct.some_id)
+        .object(&my_struct)
+        .execute::<()>()
+        .await?;
+
+    // Concurrent increments are all applied, retrying on conflicts
+    let increments = (0..5).map(|_| {
+        db.compare_and_swap(
+            TEST_COLLECTION_NAME,
+            &my_struct.some_id,
+            |mut current: MyTestStructure| {
+                let value: u32 = current.some_string.parse().unwrap();
+                current.some_string = (value + 1).to_string();
+                Ok(current)
+            },
+        )
+    });
+    for result in futures::future::join_all(increments).await {
+        result?;
+    }
+
+    let versioned: FirestoreVersioned<MyTestStructure> = db
+        .fluent()
+        .select()
+        .by_id_in(TEST_COLLECTION_NAME)
+        .obj()
+        .one_versioned(&my_struct.some_id)
+        .await?
+        .expect("document must exist");
+    assert_eq!(versioned.object.some_string, "5");
+
+    // Writing back a stale version fails
+    db.fluent()
+        .update()
+        .in_col(TEST_COLLECTION_NAME)
+        .document_id(&my_struct.some_id)
+        .versioned(&versioned)
+        .execute::<()>()
+        .await?;
+    let stale_update: FirestoreResult<()> = db
+        .fluent()
+        .update()
+        .in_col(TEST_COLLECTION_NAME)
+        .document_id(&my_struct.some_id)
+        .versioned(&versioned)
+        .execute()
+        .await;
+    assert!(stale_update.is_err());
+
+    Ok(())
+}